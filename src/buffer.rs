@@ -1,330 +1,1516 @@
 use crate::{
-    file::{BlockId, FileManager, Page},
-    log::LogManager,
+    clock::{Clock, SystemClock},
+    file::{BlockId, BlockStorage, FileError, Page, U64_SIZE},
+    log::{LogManager, Lsn},
+    replacement::{AccessHint, NaivePolicy, ReplacementPolicy},
 };
 use std::{
-    sync::{Arc, Mutex},
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
     thread,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant, SystemTime},
 };
 
 pub const MAX_TIME: u128 = 10000;
+/// the lsn of a buffer that holds no logged modification
+const NO_LSN: i32 = -1;
+
+/// the first bytes of every data block hold its page lsn, so values start at this offset.
+/// see `Buffer::page_lsn`.
+pub const PAGE_DATA_OFFSET: i32 = U64_SIZE as i32;
+
+/// the longest a waiting pin sleeps before it checks the clock again. unpins in other
+/// partitions don't wake it, so this is also how often it looks for a buffer to borrow
+const WAIT_SLICE: Duration = Duration::from_millis(100);
+
+/// by default a partition holds at least this many buffers
+pub const MIN_PARTITION_SIZE: u64 = 8;
 
 #[derive(Debug)]
-pub struct Buffer {
-    file_manager: Arc<Mutex<FileManager>>,
-    log_manager: Arc<Mutex<LogManager>>,
-    contents: Page,
+pub enum BufferError {
+    /// no buffer became available before the waiting time ran out
+    BufferAbort,
+    /// the buffer is pinned for reading only and can not be modified
+    SharedPin,
+    /// the block is past the end of its file, or its file doesn't exist
+    BlockOutOfRange(BlockId),
+    /// buffers are still pinned when they should all have been released
+    LeakedPins(Vec<OutstandingPin>),
+    FileError(FileError),
+}
+
+impl fmt::Display for BufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferError::BufferAbort => {
+                write!(f, "Algorithm using now can not get replace buffers")
+            }
+            BufferError::SharedPin => write!(f, "Buffer is pinned in shared mode"),
+            BufferError::BlockOutOfRange(block) => {
+                write!(f, "Block {} is past the end of its file", block)
+            }
+            BufferError::LeakedPins(pins) => {
+                write!(f, "Buffers are still pinned:")?;
+                for (block, owner, count) in pins {
+                    let owner = owner.as_deref().unwrap_or("unknown owner");
+                    write!(f, " {} by {} ({} pins);", block, owner, count)?;
+                }
+                Ok(())
+            }
+            BufferError::FileError(err) => write!(f, "Buffer error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BufferError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BufferError::FileError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<FileError> for BufferError {
+    fn from(value: FileError) -> Self {
+        BufferError::FileError(value)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, BufferError>;
+
+/// a pinned block, who pinned it (if known) and how many pins it holds
+pub type OutstandingPin = (BlockId, Option<String>, u64);
+
+/// the intent of a pin.
+/// shared pins may coexist on the same buffer, exclusive pins wait for them to drain.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PinMode {
+    Shared,
+    #[default]
+    Exclusive,
+}
+
+/// modification state of a buffer, guarded separately from the page
+#[derive(Debug)]
+struct BufferState {
     block: Option<BlockId>,
-    pins: u64,
     /// transaction number
     txnum: i32,
     lsn: i32,
+    mode: PinMode,
+}
+
+/// a page of the buffer pool and the block it holds.
+///
+/// The page is behind its own latch: any number of readers may hold `contents` at the same
+/// time, while `contents_mut` is exclusive. The modification state has a separate lock,
+/// so the manager can look at it without waiting for a caller that is writing the page.
+///
+/// Lock order: the page latch first, then the modification state.
+/// `set_modified` is meant to be called after the page was changed, before unpinning.
+#[derive(Debug)]
+pub struct Buffer {
+    file_manager: Arc<Mutex<dyn BlockStorage>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    contents: RwLock<Page>,
+    state: Mutex<BufferState>,
 }
 
 impl Buffer {
-    pub fn new(fm: Arc<Mutex<FileManager>>, lm: Arc<Mutex<LogManager>>) -> Self {
+    pub fn new(fm: Arc<Mutex<dyn BlockStorage>>, lm: Arc<Mutex<LogManager>>) -> Self {
         let block_size = fm.lock().unwrap().block_size();
         Buffer {
             file_manager: fm,
             log_manager: lm,
-            contents: Page::new(block_size),
-            block: None,
-            pins: 0,
-            txnum: -1,
-            lsn: -1,
+            contents: RwLock::new(Page::new(block_size)),
+            state: Mutex::new(BufferState {
+                block: None,
+                txnum: -1,
+                lsn: NO_LSN,
+                mode: PinMode::default(),
+            }),
         }
     }
 
-    pub fn contents(&mut self) -> &mut Page {
-        &mut self.contents
+    /// latches the page for reading
+    pub fn contents(&self) -> RwLockReadGuard<'_, Page> {
+        self.contents.read().unwrap()
+    }
+
+    /// latches the page for writing
+    pub fn contents_mut(&self) -> RwLockWriteGuard<'_, Page> {
+        self.contents.write().unwrap()
+    }
+
+    pub fn block(&self) -> Option<BlockId> {
+        self.state.lock().unwrap().block.clone()
     }
 
-    pub fn set_modified(&mut self, txnum: i32, lsn: i32) {
-        self.txnum = txnum;
+    pub fn set_modified(&self, txnum: i32, lsn: i32) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.mode == PinMode::Shared {
+            return Err(BufferError::SharedPin);
+        }
+        state.txnum = txnum;
         if lsn >= 0 {
-            self.lsn = lsn;
+            state.lsn = lsn;
         }
+        Ok(())
     }
 
-    pub fn is_pinned(&self) -> bool {
-        self.pins > 0
+    pub fn mode(&self) -> PinMode {
+        self.state.lock().unwrap().mode
+    }
+
+    fn set_mode(&self, mode: PinMode) {
+        self.state.lock().unwrap().mode = mode;
     }
 
     pub fn modifying_tx(&self) -> i32 {
-        self.txnum
+        self.state.lock().unwrap().txnum
     }
 
-    pub fn assign_to_block(&mut self, block: BlockId) {
-        self.flush();
+    /// writes the current block back if it is dirty and reads `block` into the page.
+    /// If the read fails the buffer is left empty, holding no block.
+    pub fn assign_to_block(&self, block: BlockId) -> Result<()> {
+        let mut page = self.contents_mut();
+        let mut state = self.state.lock().unwrap();
+        self.flush_buffer(&page, &mut state)?;
+        Self::reset(&mut page, &mut state);
         let mut fm = self.file_manager.lock().unwrap();
-        fm.read(&block, &mut self.contents).unwrap();
-        self.block = Some(block);
-        self.pins = 0;
+        fm.read(&block, &mut page)?;
+        state.block = Some(block);
+        Ok(())
     }
 
-    fn flush(&mut self) {
-        if self.txnum >= 0 {
-            let mut lm = self.log_manager.lock().unwrap();
-            lm.flush_with_lsn(self.lsn).unwrap();
-            if let Some(blk) = &self.block {
-                let mut fm = self.file_manager.lock().unwrap();
-                fm.write(blk, &mut self.contents).unwrap();
-                self.txnum -= 1;
-            }
+    /// associates the buffer with a block that was just appended.
+    /// the new block is all zeros on disk, so the page is zeroed instead of read.
+    fn assign_to_fresh_block(&self, block: BlockId) -> Result<()> {
+        let mut page = self.contents_mut();
+        let mut state = self.state.lock().unwrap();
+        self.flush_buffer(&page, &mut state)?;
+        Self::reset(&mut page, &mut state);
+        state.block = Some(block);
+        Ok(())
+    }
+
+    /// forgets the previous block, so nothing of it leaks into the next one
+    fn reset(page: &mut Page, state: &mut BufferState) {
+        state.block = None;
+        state.txnum = -1;
+        state.lsn = NO_LSN;
+        page.contents().fill(0);
+    }
+
+    /// empties the buffer without writing it back
+    fn discard(&self) {
+        let mut page = self.contents_mut();
+        let mut state = self.state.lock().unwrap();
+        Self::reset(&mut page, &mut state);
+    }
+
+    /// the lsn of the newest logged update in the page, whether it reached the disk yet or not.
+    /// it is written to the start of the block when the page is flushed. a block no logged
+    /// update has reached has lsn 0, lower than any record's.
+    pub fn page_lsn(&self) -> Result<Lsn> {
+        let stamped = self.contents().get_u64(0)? as Lsn;
+        Ok(stamped.max(self.state.lock().unwrap().lsn))
+    }
+
+    pub fn is_modified(&self) -> bool {
+        self.state.lock().unwrap().txnum >= 0
+    }
+
+    /// writes the page to disk if it is dirty. Returns the block that was written.
+    fn flush(&self) -> Result<Option<BlockId>> {
+        let page = self.contents();
+        let mut state = self.state.lock().unwrap();
+        self.flush_buffer(&page, &mut state)
+    }
+
+    /// the only place a data page is written to disk: flush, flush_all, checkpoints,
+    /// eviction and resizing all end up here.
+    ///
+    /// Write-ahead logging: the log is made durable up to the buffer's lsn before the page
+    /// is written, so that every change on disk can be undone after a crash. The block is
+    /// written with the buffer's lsn as its page lsn, so redo can tell which updates it holds.
+    fn flush_buffer(&self, page: &Page, state: &mut BufferState) -> Result<Option<BlockId>> {
+        if state.txnum < 0 {
+            return Ok(None);
+        }
+        let mut lm = self.log_manager.lock().unwrap();
+        lm.flush_with_lsn(state.lsn)?;
+        debug_assert!(
+            lm.durable_lsn() >= state.lsn,
+            "lsn {} of the buffer was never appended to the log",
+            state.lsn
+        );
+        let Some(blk) = state.block.clone() else {
+            return Ok(None);
+        };
+        let mut fm = self.file_manager.lock().unwrap();
+        if state.lsn > page.get_u64(0)? as Lsn {
+            // readers may hold the page, so the lsn is stamped on a copy
+            let mut stamped = Page::new(fm.block_size());
+            stamped.contents().copy_from_slice(page.bytes());
+            stamped.set_u64(0, state.lsn as u64)?;
+            fm.write(&blk, &stamped)?;
+        } else {
+            fm.write(&blk, page)?;
+        }
+        state.txnum = -1;
+        Ok(Some(blk))
+    }
+}
+
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        match &state.block {
+            Some(block) => write!(f, "{}", block)?,
+            None => write!(f, "empty")?,
+        }
+        write!(
+            f,
+            " dirty={} txnum={} lsn={}",
+            state.txnum >= 0,
+            state.txnum,
+            state.lsn
+        )
+    }
+}
+
+/// one slot of the buffer pool. the metadata is kept in the manager state so that
+/// searching the pool never has to lock the buffers themselves.
+#[derive(Debug)]
+struct Frame {
+    buffer: Arc<Buffer>,
+    block: Option<BlockId>,
+    pins: u64,
+    mode: PinMode,
+    /// pins taken by `pin_for`, by owner. only recorded when pins are tracked
+    owners: HashMap<String, u64>,
+}
+
+impl Frame {
+    fn new(buffer: Buffer) -> Self {
+        Frame {
+            buffer: Arc::new(buffer),
+            block: None,
+            pins: 0,
+            mode: PinMode::default(),
+            owners: HashMap::new(),
         }
     }
 
-    fn pin(&mut self) {
-        self.pins += 1;
+    /// replaces the block held by this frame, returning what was evicted if it held one.
+    /// `fresh` blocks were just appended and are known to be zeros on disk.
+    fn assign(&mut self, block: BlockId, fresh: bool) -> Result<Option<EvictionEvent>> {
+        let buf = &self.buffer;
+        let dirty = buf.is_modified();
+        let assigned = if fresh {
+            buf.assign_to_fresh_block(block.clone())
+        } else {
+            buf.assign_to_block(block.clone())
+        };
+        if let Err(err) = assigned {
+            // the old block may be gone already, keep the frame in sync with the buffer
+            self.block = buf.block();
+            return Err(err);
+        }
+        let evicted = self.block.replace(block.clone());
+        Ok(evicted.map(|evicted| EvictionEvent {
+            evicted,
+            dirty,
+            incoming: Some(block),
+            timestamp: SystemTime::now(),
+            kind: EvictionKind::Replacement,
+        }))
     }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionKind {
+    /// the buffer was reused for another block
+    Replacement,
+    /// the buffer was written to disk by a checkpoint and keeps its block
+    Checkpoint,
+}
 
-    fn unpin(&mut self) {
-        self.pins -= 1;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionEvent {
+    pub evicted: BlockId,
+    /// whether the evicted block had to be written to disk
+    pub dirty: bool,
+    /// the block that took over the buffer, `None` for checkpoint flushes
+    pub incoming: Option<BlockId>,
+    pub timestamp: SystemTime,
+    pub kind: EvictionKind,
+}
+
+/// a newly pinned buffer, the eviction it caused and whether its block was resident already
+type Pinned = (Arc<Buffer>, Option<EvictionEvent>, bool);
+
+pub type EvictionObserver = Box<dyn Fn(EvictionEvent) + Send + Sync>;
+
+struct Observer(EvictionObserver);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observer")
     }
 }
 
 #[derive(Debug)]
-pub struct BufferManager {
-    buffer_pool: Vec<Arc<Mutex<Buffer>>>,
+struct PoolState {
+    frames: Vec<Frame>,
     num_available: u64,
+    /// set while a quiescent checkpoint is running; new pins wait until it is released
+    quiesced: bool,
+    policy: Box<dyn ReplacementPolicy>,
+    /// tickets of the pins waiting for a free buffer, in arrival order
+    waiters: VecDeque<u64>,
+    next_ticket: u64,
 }
 
-impl BufferManager {
-    pub fn new(fm: Arc<Mutex<FileManager>>, lm: Arc<Mutex<LogManager>>, num_buffs: u64) -> Self {
-        let mut buffer_pool: Vec<Arc<Mutex<Buffer>>> = Vec::new();
-        for index in 0..num_buffs {
-            let buf = Buffer::new(Arc::clone(&fm), Arc::clone(&lm));
-            buffer_pool.insert(index as usize, Arc::new(Mutex::new(buf)));
+impl PoolState {
+    /// whether a pin holding `ticket` has to let earlier waiters go first.
+    /// Pins of resident blocks don't need a free buffer and never wait in line.
+    fn must_queue(&self, ticket: Option<u64>, block: &BlockId) -> bool {
+        match self.waiters.front() {
+            Some(head) => Some(*head) != ticket && self.find_existing_buffer(block).is_none(),
+            None => false,
+        }
+    }
+
+    fn find_existing_buffer(&self, block: &BlockId) -> Option<usize> {
+        self.frames
+            .iter()
+            .position(|f| f.block.as_ref() == Some(block))
+    }
+
+    fn choose_unpinned_buffer(&mut self) -> Option<usize> {
+        self.choose_unpinned_buffer_except(&[])
+    }
+
+    /// an empty buffer if there is one, otherwise the victim chosen by the replacement policy.
+    /// buffers holding one of `keep` are never chosen.
+    fn choose_unpinned_buffer_except(&mut self, keep: &[BlockId]) -> Option<usize> {
+        let candidates: Vec<usize> = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.pins == 0 && f.block.as_ref().is_none_or(|b| !keep.contains(b)))
+            .map(|(index, _)| index)
+            .collect();
+        candidates
+            .iter()
+            .find(|index| self.frames[**index].block.is_none())
+            .copied()
+            .or_else(|| self.policy.choose_victim(&candidates))
+    }
+}
+
+/// returned by `BufferManager::checkpoint`; new pins are blocked until it is released.
+#[derive(Debug)]
+#[must_use = "pins stay blocked until the token is released"]
+pub struct CheckpointToken {
+    _private: (),
+}
+
+#[derive(Debug)]
+pub struct BufferManagerOptions {
+    /// creates the replacement policy of each partition
+    pub policy: fn() -> Box<dyn ReplacementPolicy>,
+    /// time source for pin timeouts
+    pub clock: Arc<dyn Clock>,
+    /// how long a pin waits for a free buffer before giving up
+    pub max_wait: Duration,
+    /// number of partitions the pool is split into.
+    /// `None` picks min(number of cpus, pool size / `MIN_PARTITION_SIZE`).
+    pub partitions: Option<usize>,
+    /// whether `pin` rejects blocks past the end of their file with `BlockOutOfRange`
+    pub check_eof: bool,
+    /// whether the owners passed to `pin_for` are recorded, for finding leaked pins
+    pub track_pins: bool,
+}
+
+impl Default for BufferManagerOptions {
+    fn default() -> Self {
+        BufferManagerOptions {
+            policy: || Box::new(NaivePolicy::new()),
+            clock: Arc::new(SystemClock),
+            max_wait: Duration::from_millis(MAX_TIME as u64),
+            partitions: None,
+            check_eof: true,
+            track_pins: false,
         }
+    }
+}
+
+/// how a block that is not resident yet gets into its buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Load {
+    /// read from disk after checking that the block exists
+    Checked,
+    /// read from disk even if the block is past the end of its file
+    Unchecked,
+    /// just appended and known to be zeros on disk, nothing is read
+    Fresh,
+}
+
+/// pin counters of a `BufferManager`, see `BufferManager::stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BufferStats {
+    /// pins of blocks that were in the pool already
+    pub hits: u64,
+    /// pins that had to bring their block into the pool
+    pub misses: u64,
+    /// blocks that left the pool to make room for another one
+    pub evictions: u64,
+}
+
+impl BufferStats {
+    /// the share of pins that were hits, none before the first pin
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let pins = self.hits + self.misses;
+        (pins > 0).then(|| self.hits as f64 / pins as f64)
+    }
+}
+
+/// the counters behind `BufferStats`, updated without taking a partition lock
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+/// a slice of the buffer pool with its own state lock, replacement policy and waiters.
+/// a block is only ever cached in the partition selected by its hash. when that partition
+/// has no unpinned buffer left, one is moved over from another partition, see `steal_frame`.
+#[derive(Debug)]
+struct Partition {
+    state: Mutex<PoolState>,
+    /// notified whenever a buffer of this partition becomes unpinned
+    available: Condvar,
+    /// how many pins had to wait for a buffer of this partition
+    waits: AtomicU64,
+}
+
+/// `BufferManager` is internally synchronized and can be shared between threads.
+///
+/// The pool is split into partitions so that pins of unrelated blocks don't
+/// contend on one lock; the partitions are invisible to callers. A pin only fails with
+/// `BufferAbort` if no partition had an unpinned buffer to spare while it waited.
+///
+/// Lock order: the state of one partition is locked first, then at most one buffer at a time.
+/// A buffer is never locked while searching the pool, and callers must release
+/// their page latches before calling back into the manager (e.g. `pin`).
+#[derive(Debug)]
+pub struct BufferManager {
+    file_manager: Arc<Mutex<dyn BlockStorage>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    partitions: Vec<Partition>,
+    clock: Arc<dyn Clock>,
+    max_wait: Duration,
+    check_eof: bool,
+    track_pins: bool,
+    observer: RwLock<Option<Observer>>,
+    counters: Counters,
+}
+
+impl BufferManager {
+    pub fn new(
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        num_buffs: u64,
+    ) -> Self {
+        Self::new_with_options(fm, lm, num_buffs, BufferManagerOptions::default())
+    }
+
+    pub fn new_with_options(
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        num_buffs: u64,
+        options: BufferManagerOptions,
+    ) -> Self {
+        let num_partitions = options
+            .partitions
+            .unwrap_or_else(|| Self::default_partitions(num_buffs))
+            .clamp(1, num_buffs.max(1) as usize);
+        let partitions = (0..num_partitions)
+            .map(|p| {
+                // spread the remainder over the first partitions
+                let size = num_buffs as usize / num_partitions
+                    + usize::from(p < num_buffs as usize % num_partitions);
+                Partition {
+                    state: Mutex::new(PoolState {
+                        frames: (0..size)
+                            .map(|_| Frame::new(Buffer::new(Arc::clone(&fm), Arc::clone(&lm))))
+                            .collect(),
+                        num_available: size as u64,
+                        quiesced: false,
+                        waiters: VecDeque::new(),
+                        next_ticket: 0,
+                        policy: (options.policy)(),
+                    }),
+                    available: Condvar::new(),
+                    waits: AtomicU64::new(0),
+                }
+            })
+            .collect();
         BufferManager {
-            buffer_pool,
-            num_available: num_buffs,
+            file_manager: fm,
+            log_manager: lm,
+            partitions,
+            clock: options.clock,
+            max_wait: options.max_wait,
+            check_eof: options.check_eof,
+            track_pins: options.track_pins,
+            observer: RwLock::new(None),
+            counters: Counters::default(),
         }
     }
 
+    fn default_partitions(num_buffs: u64) -> usize {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        cpus.min((num_buffs / MIN_PARTITION_SIZE) as usize)
+    }
+
     pub fn available(&self) -> u64 {
-        self.num_available
+        self.partitions
+            .iter()
+            .map(|p| p.state.lock().unwrap().num_available)
+            .sum()
     }
 
-    pub fn flush_all(&mut self, txnum: i32) {
-        for buf in self.buffer_pool.iter() {
-            let mut buf = buf.lock().unwrap();
-            if buf.modifying_tx() == txnum {
-                buf.flush();
-            }
+    /// calls `observer` every time a block leaves a buffer or a checkpoint writes one out.
+    ///
+    /// The observer is called without holding any lock of the pool, so it may call back
+    /// into the manager.
+    pub fn set_eviction_observer(&self, observer: EvictionObserver) {
+        *self.observer.write().unwrap() = Some(Observer(observer));
+    }
+
+    fn notify_eviction(&self, event: Option<EvictionEvent>) {
+        if event
+            .as_ref()
+            .is_some_and(|event| event.kind == EvictionKind::Replacement)
+        {
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        if let (Some(event), Some(Observer(observer))) = (event, &*self.observer.read().unwrap()) {
+            observer(event);
         }
     }
 
-    pub fn unpin(&mut self, buf: Arc<Mutex<Buffer>>) {
-        let mut buf = buf.lock().unwrap();
-        buf.unpin();
-        if !buf.is_pinned() {
-            self.num_available += 1;
+    /// the hits, misses and evictions of the pool since it was created. prefetches aren't pins
+    /// and aren't counted, but the evictions they cause are
+    pub fn stats(&self) -> BufferStats {
+        BufferStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
         }
     }
 
-    pub fn pin(&mut self, block: BlockId) -> Result<Arc<Mutex<Buffer>>, String> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        while !Self::waiting_too_long(timestamp) {
-            if let Some(buf) = self.try_to_pin(block.clone()) {
-                return Ok(buf);
+    /// whether a thread panicked while it held the lock of a partition
+    pub fn is_poisoned(&self) -> bool {
+        self.partitions.iter().any(|p| p.state.is_poisoned())
+    }
+
+    /// the number of buffers in the pool
+    pub fn size(&self) -> u64 {
+        self.partitions
+            .iter()
+            .map(|p| p.state.lock().unwrap().frames.len() as u64)
+            .sum()
+    }
+
+    /// the block held by every slot of the pool, in slot order
+    pub fn resident_blocks(&self) -> Vec<Option<BlockId>> {
+        self.partitions
+            .iter()
+            .flat_map(|p| {
+                let state = p.state.lock().unwrap();
+                state
+                    .frames
+                    .iter()
+                    .map(|f| f.block.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// renders one line per slot with its block, pins, and modification state.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        let mut index = 0;
+        for partition in self.partitions.iter() {
+            let state = partition.state.lock().unwrap();
+            for frame in state.frames.iter() {
+                out.push_str(&format!(
+                    "{}: pins={} {}\n",
+                    index, frame.pins, frame.buffer
+                ));
+                index += 1;
             }
-            thread::sleep(Duration::new(1, 0));
         }
-        Err("Algorithm using now can not get replace buffers".to_string())
+        out
     }
 
-    fn waiting_too_long(start: u128) -> bool {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            - start
-            > MAX_TIME
+    /// panics with the pool contents if any buffer is still pinned
+    #[track_caller]
+    pub fn assert_all_unpinned(&self) {
+        let pinned = self
+            .partitions
+            .iter()
+            .any(|p| p.state.lock().unwrap().frames.iter().any(|f| f.pins > 0));
+        if pinned {
+            panic!("buffers are still pinned:\n{}", self.dump());
+        }
     }
 
-    /// Naive algorithm: choose first unpinned buffer
+    /// grows or shrinks the pool to `new_size` buffers.
     ///
-    /// if (find existing buffer){
-    ///     - return buffer
-    /// } else if(find unpinned buffer){
-    ///     - associates the buffer with a disk block.
-    ///     - return buffer
-    /// } else {
-    ///     Error!: this algorithm doesn't have replacement rule.
-    /// }
-    fn try_to_pin(&mut self, block: BlockId) -> Option<Arc<Mutex<Buffer>>> {
-        if let Some(buf) = self.find_existing_buffer(&block) {
-            let mut b = buf.as_ref().lock().unwrap();
-            if !b.is_pinned() {
-                self.num_available -= 1;
-            };
-            b.pin();
-            drop(b);
-            Some(Arc::clone(&buf))
-        } else if let Some(buf) = self.choose_unpinned_buffer() {
-            let mut b = buf.as_ref().lock().unwrap();
-            b.assign_to_block(block);
-            if !b.is_pinned() {
-                self.num_available -= 1;
+    /// Growing adds empty buffers to the smallest partitions. Shrinking removes unpinned
+    /// buffers from the largest partitions, flushing dirty ones first; pinned buffers are
+    /// never removed and every partition keeps at least one buffer, so the pool may end up
+    /// larger than requested. Returns the resulting size of the pool.
+    pub fn resize(&self, new_size: u64) -> Result<u64> {
+        let mut size = self.size();
+        while size < new_size {
+            let partition = self.smallest_partition();
+            let mut state = partition.state.lock().unwrap();
+            let buf = Buffer::new(
+                Arc::clone(&self.file_manager),
+                Arc::clone(&self.log_manager),
+            );
+            state.frames.push(Frame::new(buf));
+            state.num_available += 1;
+            partition.available.notify_all();
+            size += 1;
+        }
+
+        let mut shrinkable: Vec<&Partition> = self.partitions.iter().collect();
+        while size > new_size {
+            shrinkable.sort_by_key(|p| std::cmp::Reverse(p.state.lock().unwrap().frames.len()));
+            let Some(partition) = shrinkable.first() else {
+                break;
             };
-            b.pin();
-            drop(b);
-            Some(Arc::clone(&buf))
-        } else {
-            None
+            let mut state = partition.state.lock().unwrap();
+            let victim = state.frames.iter().position(|f| f.pins == 0);
+            match victim.filter(|_| state.frames.len() > 1) {
+                Some(index) => {
+                    state.frames[index].buffer.flush()?;
+                    state.frames.remove(index);
+                    state.num_available -= 1;
+                    state.policy.on_remove(index);
+                    size -= 1;
+                }
+                None => {
+                    drop(state);
+                    shrinkable.remove(0);
+                }
+            }
         }
+        Ok(size)
     }
 
-    fn find_existing_buffer(&self, block: &BlockId) -> Option<Arc<Mutex<Buffer>>> {
-        self.buffer_pool
+    fn smallest_partition(&self) -> &Partition {
+        self.partitions
             .iter()
-            .find(|b| {
-                if let Some(block_id) = &b.lock().unwrap().block {
-                    block_id.eq(block)
-                } else {
-                    false
-                }
-            })
-            .map(Arc::clone)
+            .min_by_key(|p| p.state.lock().unwrap().frames.len())
+            .expect("the pool has at least one partition")
     }
 
-    fn choose_unpinned_buffer(&self) -> Option<Arc<Mutex<Buffer>>> {
-        self.buffer_pool
+    /// for each partition, how many pins had to wait for a free buffer
+    pub fn partition_waits(&self) -> Vec<u64> {
+        self.partitions
             .iter()
-            .find(|b| !b.lock().unwrap().is_pinned())
-            .map(Arc::clone)
+            .map(|p| p.waits.load(Ordering::Relaxed))
+            .collect()
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::{Buffer, BufferManager};
-    use crate::{
-        file::{BlockId, FileManager},
-        log::LogManager,
-    };
-    use std::{
-        fs,
-        sync::{Arc, Mutex},
-    };
+    pub fn flush_all(&self, txnum: i32) -> Result<()> {
+        for buf in self.buffers() {
+            // only the modification state is looked at, the page is latched just for writing
+            if buf.modifying_tx() == txnum {
+                buf.flush()?;
+            }
+        }
+        Ok(())
+    }
 
-    #[test]
-    fn test_buffer() {
-        let fm = Arc::new(Mutex::new(FileManager::new("__test_4").unwrap()));
-        let lm = Arc::new(Mutex::new(LogManager::new(
-            Arc::clone(&fm),
-            "test_log".to_string(),
-        )));
-        let mut bm = BufferManager::new(Arc::clone(&fm), Arc::clone(&lm), 3);
-        //
-        // buffer pool:
-        //      capacity = 3
-        // -------------- buffer pool ----------------
-        // |  [empty buf] [empty buf] [empty buf]    |
-        // ------------------------------------------
-        //
+    /// quiescent checkpoint: blocks new pins, then flushes the log and every dirty buffer.
+    ///
+    /// Buffers that are already pinned stay pinned; dirty ones are flushed in place.
+    /// Pins are blocked until the returned token is passed to `release`.
+    pub fn checkpoint(&self) -> Result<CheckpointToken> {
+        for partition in self.partitions.iter() {
+            partition.state.lock().unwrap().quiesced = true;
+        }
+        let token = CheckpointToken { _private: () };
+        if let Err(err) = self.checkpoint_nonquiescent() {
+            self.release(token);
+            return Err(err);
+        }
+        Ok(token)
+    }
 
-        let buf1 = bm.pin(BlockId::new("testfile", 1)).unwrap();
-        {
-            let mut buf1 = buf1.lock().unwrap();
-            let p = buf1.contents();
-            let n = p.get_u64(80).unwrap();
-            p.set_u64(80, n + 1).unwrap();
-            buf1.set_modified(1, 0);
+    /// flushes the log and every dirty buffer regardless of the modifying transaction,
+    /// without blocking new pins.
+    pub fn checkpoint_nonquiescent(&self) -> Result<()> {
+        self.log_manager.lock().unwrap().flush()?;
+        for buf in self.buffers() {
+            let event = buf.flush()?.map(|block| EvictionEvent {
+                evicted: block,
+                dirty: true,
+                incoming: None,
+                timestamp: SystemTime::now(),
+                kind: EvictionKind::Checkpoint,
+            });
+            self.notify_eviction(event);
         }
-        bm.unpin(buf1);
+        Ok(())
+    }
 
-        let buf2 = bm.pin(BlockId::new("testfile2", 2)).unwrap();
-        let _buf3 = bm.pin(BlockId::new("testfile3", 3)).unwrap();
-        let _buf4 = bm.pin(BlockId::new("testfile4", 4)).unwrap();
+    pub fn release(&self, _token: CheckpointToken) {
+        for partition in self.partitions.iter() {
+            partition.state.lock().unwrap().quiesced = false;
+            partition.available.notify_all();
+        }
+    }
 
-        bm.unpin(buf2);
-        let buf2 = bm.pin(BlockId::new("testfile5", 11)).unwrap();
-        {
-            let mut b2 = buf2.lock().unwrap();
-            let p2 = b2.contents();
-            p2.set_u64(80, 9999).unwrap();
-            b2.set_modified(1, 0);
+    /// releases a pin of `buf`. unpinning a buffer that isn't pinned, or that doesn't belong
+    /// to this pool, does nothing, so a double unpin can't make the buffer available twice
+    pub fn unpin(&self, buf: Arc<Buffer>) {
+        self.with_frame_of(&buf, |partition, state, index| {
+            let frame = &mut state.frames[index];
+            if frame.pins == 0 {
+                return;
+            }
+            frame.pins -= 1;
+            if frame.pins == 0 {
+                frame.owners.clear();
+                state.policy.on_unpin(index);
+                state.num_available += 1;
+                partition.available.notify_all();
+            }
+        });
+    }
+
+    /// pins `block` for modification on behalf of `owner` (e.g. a transaction number).
+    /// When pins are tracked, the owner shows up in `outstanding_pins` until `unpin_for`.
+    pub fn pin_for(&self, block: BlockId, owner: impl Into<String>) -> Result<Arc<Buffer>> {
+        let buf = self.pin(block)?;
+        if self.track_pins {
+            self.with_frame_of(&buf, |_, state, index| {
+                *state.frames[index].owners.entry(owner.into()).or_insert(0) += 1;
+            });
         }
-        bm.unpin(buf2);
+        Ok(buf)
+    }
 
-        fs::remove_dir_all("__test_4").expect("failed to remove dir");
+    /// releases a pin taken by `pin_for` with the same `owner`
+    pub fn unpin_for(&self, buf: Arc<Buffer>, owner: &str) {
+        if self.track_pins {
+            self.with_frame_of(&buf, |_, state, index| {
+                let owners = &mut state.frames[index].owners;
+                if let Some(count) = owners.get_mut(owner) {
+                    *count -= 1;
+                    if *count == 0 {
+                        owners.remove(owner);
+                    }
+                }
+            });
+        }
+        self.unpin(buf);
     }
 
-    #[test]
-    fn test_buffer_manager() {
-        let fm = Arc::new(Mutex::new(FileManager::new("__test_5").unwrap()));
-        let lm = Arc::new(Mutex::new(LogManager::new(
-            Arc::clone(&fm),
-            "test_log".to_string(),
-        )));
-        let mut bm = BufferManager::new(Arc::clone(&fm), Arc::clone(&lm), 3);
-        assert_eq!(bm.available(), 3);
-        //
-        // buffer pool:
-        //      capacity = 3
-        // -------------- buffer pool ----------------
-        // |  [empty buf] [empty buf] [empty buf]    |
-        // ------------------------------------------
-        //
+    /// every pinned block with its pin count, broken down by owner when pins are tracked.
+    /// pins taken without an owner are reported with `None`.
+    pub fn outstanding_pins(&self) -> Vec<OutstandingPin> {
+        let mut pins = Vec::new();
+        for partition in self.partitions.iter() {
+            let state = partition.state.lock().unwrap();
+            for frame in state.frames.iter().filter(|f| f.pins > 0) {
+                let Some(block) = &frame.block else {
+                    continue;
+                };
+                let mut owners: Vec<_> = frame.owners.iter().collect();
+                owners.sort();
+                for (owner, count) in owners {
+                    pins.push((block.clone(), Some(owner.clone()), *count));
+                }
+                let unowned = frame.pins.saturating_sub(frame.owners.values().sum());
+                if unowned > 0 {
+                    pins.push((block.clone(), None, unowned));
+                }
+            }
+        }
+        pins
+    }
 
-        let mut buf: Vec<Option<Arc<Mutex<Buffer>>>> = vec![None; 6];
+    /// fails with `LeakedPins` if any buffer is still pinned
+    pub fn verify_no_pins(&self) -> Result<()> {
+        let pins = self.outstanding_pins();
+        if pins.is_empty() {
+            Ok(())
+        } else {
+            Err(BufferError::LeakedPins(pins))
+        }
+    }
 
-        buf[0] = bm.pin(BlockId::new("t0", 0)).unwrap().into();
-        assert_eq!(bm.available(), 2);
-        //
-        // buffer pool:
-        //      capacity = 3
-        // -------------- buffer pool ---------------
-        // |  [t0, 0] [empty buf] [empty buf]       |
-        // ------------------------------------------
-        //
+    /// runs `f` on the partition and frame that hold `buf`, none if no frame of the pool does
+    fn with_frame_of<T>(
+        &self,
+        buf: &Arc<Buffer>,
+        f: impl FnOnce(&Partition, &mut PoolState, usize) -> T,
+    ) -> Option<T> {
+        for partition in self.partitions.iter() {
+            let mut state = partition.state.lock().unwrap();
+            let Some(index) = state
+                .frames
+                .iter()
+                .position(|f| Arc::ptr_eq(&f.buffer, buf))
+            else {
+                continue;
+            };
+            return Some(f(partition, &mut state, index));
+        }
+        None
+    }
 
-        buf[1] = bm.pin(BlockId::new("t1", 1)).unwrap().into();
-        assert_eq!(bm.available(), 1);
-        //
-        // buffer pool:
-        //      capacity = 3
-        // ------------- buffer pool -----------
-        // |     [t0, 0] [t1, 1] [empty buf]   |
-        // -------------------------------------
-        //
+    /// same as `pin_exclusive`
+    pub fn pin(&self, block: BlockId) -> Result<Arc<Buffer>> {
+        self.pin_exclusive(block)
+    }
 
-        buf[2] = bm.pin(BlockId::new("t2", 2)).unwrap().into();
-        assert_eq!(bm.available(), 0);
+    /// like `pin`, and tells whether the block was in the pool already (a hit) rather than
+    /// read from disk (a miss)
+    pub fn pin_reporting_hit(&self, block: BlockId) -> Result<(Arc<Buffer>, bool)> {
+        self.pin_with_mode(block, PinMode::Exclusive, AccessHint::Normal, Load::Checked)
+    }
+
+    /// pins `block` for modification without checking that it exists,
+    /// for callers that are about to extend the file themselves.
+    pub fn pin_allow_beyond_eof(&self, block: BlockId) -> Result<Arc<Buffer>> {
+        let (buf, _) = self.pin_with_mode(
+            block,
+            PinMode::Exclusive,
+            AccessHint::Normal,
+            Load::Unchecked,
+        )?;
+        Ok(buf)
+    }
+
+    /// pins `block` for modification, telling the replacement policy how it will be accessed.
+    pub fn pin_with_hint(&self, block: BlockId, hint: AccessHint) -> Result<Arc<Buffer>> {
+        let (buf, _) = self.pin_with_mode(block, PinMode::Exclusive, hint, Load::Checked)?;
+        Ok(buf)
+    }
+
+    /// pins `block` for reading only. other shared pins on the same block are allowed.
+    pub fn pin_shared(&self, block: BlockId) -> Result<Arc<Buffer>> {
+        let (buf, _) =
+            self.pin_with_mode(block, PinMode::Shared, AccessHint::Normal, Load::Checked)?;
+        Ok(buf)
+    }
+
+    /// pins `block` for modification, waiting for shared pins on it to be released.
+    pub fn pin_exclusive(&self, block: BlockId) -> Result<Arc<Buffer>> {
+        let (buf, _) =
+            self.pin_with_mode(block, PinMode::Exclusive, AccessHint::Normal, Load::Checked)?;
+        Ok(buf)
+    }
+
+    /// unless the block is resident already, it is loaded as told by `load`. also returns
+    /// whether it was resident.
+    fn pin_with_mode(
+        &self,
+        block: BlockId,
+        mode: PinMode,
+        hint: AccessHint,
+        load: Load,
+    ) -> Result<(Arc<Buffer>, bool)> {
+        let partition = self.partition_of(&block);
+        let timestamp = self.clock.now();
+        let mut state = partition.state.lock().unwrap();
+        // waiters take a ticket and are granted free buffers in ticket order
+        let mut ticket = None;
+        let mut waited = false;
+        let pinned = loop {
+            if !state.quiesced && !state.must_queue(ticket, &block) {
+                match self.try_to_pin(&mut state, &block, mode, hint, load) {
+                    Ok(Some(pinned)) => break Ok(pinned),
+                    Ok(None) => {}
+                    Err(err) => break Err(err),
+                }
+            }
+            if self.waiting_too_long(timestamp) {
+                break Err(BufferError::BufferAbort);
+            }
+            if !waited {
+                waited = true;
+                partition.waits.fetch_add(1, Ordering::Relaxed);
+            }
+            // waiting for other pins of a resident block to be released doesn't need a ticket
+            if ticket.is_none() && state.find_existing_buffer(&block).is_none() {
+                let next = state.next_ticket;
+                state.next_ticket += 1;
+                state.waiters.push_back(next);
+                ticket = Some(next);
+            }
+            state = partition
+                .available
+                .wait_timeout(state, self.remaining_time(timestamp))
+                .unwrap()
+                .0;
+        };
+        if let Some(ticket) = ticket {
+            // let the next waiter in line check whether there is a buffer left for it
+            state.waiters.retain(|t| *t != ticket);
+            partition.available.notify_all();
+        }
+        drop(state);
+        let (buf, evicted, hit) = pinned?;
+        self.notify_eviction(evicted);
+        Ok((buf, hit))
+    }
+
+    /// pins `block` only if it can be done immediately, without waiting for a free buffer.
+    pub fn try_pin(&self, block: &BlockId) -> Result<Option<Arc<Buffer>>> {
+        let partition = self.partition_of(block);
+        let mut state = partition.state.lock().unwrap();
+        if state.quiesced || state.must_queue(None, block) {
+            return Ok(None);
+        }
+        let pinned = self.try_to_pin(
+            &mut state,
+            block,
+            PinMode::Exclusive,
+            AccessHint::Normal,
+            Load::Checked,
+        )?;
+        drop(state);
+        Ok(pinned.map(|(buf, evicted, _)| {
+            self.notify_eviction(evicted);
+            buf
+        }))
+    }
+
+    /// pins every block or none of them.
+    ///
+    /// Blocks are acquired in a canonical order (by file name, then block number) so that
+    /// two callers pinning overlapping sets can not wait on each other in a cycle.
+    /// If any pin fails, the buffers pinned so far are released before returning the error.
+    /// The returned buffers are in the same order as `blocks`.
+    pub fn pin_many(&self, blocks: &[BlockId]) -> Result<Vec<Arc<Buffer>>> {
+        let mut order: Vec<usize> = (0..blocks.len()).collect();
+        order.sort_by(|a, b| {
+            let (a, b) = (&blocks[*a], &blocks[*b]);
+            (a.filename(), a.number()).cmp(&(b.filename(), b.number()))
+        });
+
+        let mut pinned: Vec<Option<Arc<Buffer>>> = vec![None; blocks.len()];
+        for index in order {
+            match self.pin(blocks[index].clone()) {
+                Ok(buf) => pinned[index] = Some(buf),
+                Err(err) => {
+                    for buf in pinned.into_iter().flatten() {
+                        self.unpin(buf);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(pinned.into_iter().flatten().collect())
+    }
+
+    /// reads the blocks that are not resident yet into unpinned buffers, without pinning them,
+    /// so that a following pin of those blocks doesn't touch the disk.
+    ///
+    /// Dirty victims are flushed before they are reused. Prefetching never waits:
+    /// a block is skipped when its partition has no unpinned buffer left,
+    /// and blocks past the end of their file are skipped as well.
+    /// Returns how many blocks were read.
+    pub fn prefetch(&self, blocks: &[BlockId]) -> Result<usize> {
+        let mut fetched = 0;
+        for block in blocks {
+            let partition = self.partition_of(block);
+            let mut state = partition.state.lock().unwrap();
+            if state.quiesced || state.find_existing_buffer(block).is_some() {
+                continue;
+            }
+            match self.check_in_range(block) {
+                Err(BufferError::BlockOutOfRange(_)) => continue,
+                checked => checked?,
+            }
+            let Some(index) = state.choose_unpinned_buffer_except(blocks) else {
+                continue;
+            };
+            let evicted = state.frames[index].assign(block.clone(), false)?;
+            drop(state);
+            self.notify_eviction(evicted);
+            fetched += 1;
+        }
+        Ok(fetched)
+    }
+
+    /// writes the blocks currently held by the pool to `path`, one `<number> <filename>` per line,
+    /// so that a later `warm_up` can read them back in. Returns how many blocks were written.
+    pub fn save_residents(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let residents: Vec<BlockId> = self.resident_blocks().into_iter().flatten().collect();
+        let mut contents = String::new();
+        for block in residents.iter() {
+            contents.push_str(&format!("{} {}\n", block.number(), block.filename()));
+        }
+        fs::write(path, contents).map_err(FileError::from)?;
+        Ok(residents.len())
+    }
+
+    /// prefetches the blocks saved by `save_residents`, at most as many as the pool holds.
+    /// Blocks of files that no longer exist, or past the end of their file, are skipped.
+    /// Returns how many blocks were read.
+    pub fn warm_up(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let contents = fs::read_to_string(path).map_err(FileError::from)?;
+        let blocks: Vec<BlockId> = {
+            let mut fm = self.file_manager.lock().unwrap();
+            let mut blocks = Vec::new();
+            for line in contents.lines() {
+                let Some((number, filename)) = line.split_once(' ') else {
+                    continue;
+                };
+                let Ok(number) = number.parse::<i32>() else {
+                    continue;
+                };
+                if fm.exists(filename) && number < fm.length(filename)? {
+                    blocks.push(BlockId::new(filename, number));
+                }
+            }
+            blocks
+        };
+        let limit = (self.size() as usize).min(blocks.len());
+        self.prefetch(&blocks[..limit])
+    }
+
+    /// appends a new block to `filename` and pins it in one step.
+    ///
+    /// A buffer for the next block is pinned before the file grows, so a pin that fails
+    /// (e.g. with `BufferAbort`) leaves the file as it was. The block is only appended if it
+    /// is still the next one under the FileManager lock; otherwise another caller took it and
+    /// the next block is tried, so concurrent callers always get distinct blocks.
+    /// The new block is known to be zeros, so it is not read from disk.
+    pub fn pin_new(&self, filename: &str) -> Result<(BlockId, Arc<Buffer>)> {
+        loop {
+            let block = BlockId::new(filename, self.length(filename)?);
+            let (buf, _) = self.pin_with_mode(
+                block.clone(),
+                PinMode::Exclusive,
+                AccessHint::Normal,
+                Load::Fresh,
+            )?;
+            let appended = {
+                let mut fm = self.file_manager.lock().unwrap();
+                match fm.length(filename) {
+                    Ok(len) if len == block.number() => fm.append(filename).map(Some),
+                    Ok(_) => Ok(None),
+                    Err(err) => Err(err),
+                }
+            };
+            match appended {
+                Ok(Some(_)) => return Ok((block, buf)),
+                Ok(None) => self.unpin_reserved(buf),
+                Err(err) => {
+                    self.unpin_reserved(buf);
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+
+    /// unpins a buffer `pin_new` reserved for a block it didn't append. unless someone else
+    /// pinned or modified it meanwhile, the buffer is emptied, so the zeros it holds can't
+    /// stand in for whatever was written to the block since
+    fn unpin_reserved(&self, buf: Arc<Buffer>) {
+        self.unpin(Arc::clone(&buf));
+        self.with_frame_of(&buf, |_, state, index| {
+            let frame = &mut state.frames[index];
+            if frame.pins == 0 && !frame.buffer.is_modified() {
+                frame.buffer.discard();
+                frame.block = None;
+            }
+        });
+    }
+
+    /// the number of blocks in `filename`
+    pub fn length(&self, filename: &str) -> Result<i32> {
+        Ok(self.file_manager.lock().unwrap().length(filename)?)
+    }
+
+    /// shrinks `filename` to its first `len` blocks.
+    ///
+    /// Buffers holding the removed blocks are emptied without being written, so that a later
+    /// flush can't grow the file again. Fails with `LeakedPins` if any of them is pinned.
+    pub fn truncate(&self, filename: &str, len: i32) -> Result<()> {
+        let removed = |block: &Option<BlockId>| {
+            block
+                .as_ref()
+                .is_some_and(|b| b.filename() == filename && b.number() >= len)
+        };
+        let mut states: Vec<_> = self
+            .partitions
+            .iter()
+            .map(|p| p.state.lock().unwrap())
+            .collect();
+        let pinned: Vec<OutstandingPin> = states
+            .iter()
+            .flat_map(|state| state.frames.iter())
+            .filter(|f| f.pins > 0 && removed(&f.block))
+            .map(|f| (f.block.clone().unwrap(), None, f.pins))
+            .collect();
+        if !pinned.is_empty() {
+            return Err(BufferError::LeakedPins(pinned));
+        }
+        for state in states.iter_mut() {
+            for frame in state.frames.iter_mut().filter(|f| removed(&f.block)) {
+                frame.buffer.discard();
+                frame.block = None;
+            }
+        }
+        self.file_manager.lock().unwrap().truncate(filename, len)?;
+        Ok(())
+    }
+
+    /// fails with `BlockOutOfRange` if `block` doesn't exist on disk, unless the check is disabled.
+    /// never creates the file.
+    fn check_in_range(&self, block: &BlockId) -> Result<()> {
+        if !self.check_eof {
+            return Ok(());
+        }
+        let mut fm = self.file_manager.lock().unwrap();
+        let filename = block.filename();
+        if block.number() < 0 || !fm.exists(filename) || block.number() >= fm.length(filename)? {
+            return Err(BufferError::BlockOutOfRange(block.clone()));
+        }
+        Ok(())
+    }
+
+    fn waiting_too_long(&self, start: Instant) -> bool {
+        self.clock.now().saturating_duration_since(start) > self.max_wait
+    }
+
+    /// how long to sleep before checking the clock again.
+    /// the clock may be advanced by someone else, so never sleep longer than `WAIT_SLICE`.
+    fn remaining_time(&self, start: Instant) -> Duration {
+        let elapsed = self.clock.now().saturating_duration_since(start);
+        self.max_wait
+            .saturating_sub(elapsed)
+            .clamp(Duration::from_millis(1), WAIT_SLICE)
+    }
+
+    fn partition_index(&self, block: &BlockId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        block.hash(&mut hasher);
+        hasher.finish() as usize % self.partitions.len()
+    }
+
+    fn partition_of(&self, block: &BlockId) -> &Partition {
+        &self.partitions[self.partition_index(block)]
+    }
+
+    /// every buffer of the pool. the partition locks are released before returning,
+    /// so the caller may lock the buffers one by one.
+    fn buffers(&self) -> Vec<Arc<Buffer>> {
+        self.partitions
+            .iter()
+            .flat_map(|p| {
+                let state = p.state.lock().unwrap();
+                state
+                    .frames
+                    .iter()
+                    .map(|f| Arc::clone(&f.buffer))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// if (find existing buffer){
+    ///     - return buffer
+    /// } else if(find unpinned buffer chosen by the replacement policy
+    ///           or steal one from another partition){
+    ///     - associates the buffer with a disk block.
+    ///     - return buffer
+    /// } else {
+    ///     wait until a buffer is unpinned
+    /// }
+    ///
+    /// Returns the pinned buffer and the eviction it caused, which the caller reports
+    /// once the partition lock is released.
+    fn try_to_pin(
+        &self,
+        state: &mut PoolState,
+        block: &BlockId,
+        mode: PinMode,
+        hint: AccessHint,
+        load: Load,
+    ) -> Result<Option<Pinned>> {
+        let mut evicted = None;
+        let resident = state.find_existing_buffer(block);
+        let index = if let Some(index) = resident {
+            let frame = &state.frames[index];
+            if frame.pins > 0 && frame.mode != mode {
+                return Ok(None);
+            }
+            index
+        } else if let Some(index) = {
+            if load == Load::Checked {
+                self.check_in_range(block)?;
+            }
+            match state.choose_unpinned_buffer() {
+                Some(index) => Some(index),
+                None => self.steal_frame(state, block)?.map(|(index, stolen)| {
+                    evicted = stolen;
+                    index
+                }),
+            }
+        } {
+            if let Some(assigned) =
+                state.frames[index].assign(block.clone(), load == Load::Fresh)?
+            {
+                evicted = Some(assigned);
+            }
+            state.policy.on_load(index);
+            index
+        } else {
+            return Ok(None);
+        };
+        let frame = &mut state.frames[index];
+        if frame.pins == 0 {
+            state.num_available -= 1;
+            frame.mode = mode;
+            frame.buffer.set_mode(mode);
+        }
+        frame.pins += 1;
+        let buf = Arc::clone(&frame.buffer);
+        state.policy.on_pin(index, hint);
+        let counter = match resident {
+            Some(_) => &self.counters.hits,
+            None => &self.counters.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(Some((buf, evicted, resident.is_some())))
+    }
+
+    /// moves an unpinned buffer of another partition into `state`, the partition of `block`,
+    /// which has none left. Returns the index of the buffer in `state` and the eviction of
+    /// the block it held, which is written to disk first.
+    ///
+    /// The partition of `block` is locked already, so the others are only taken if they
+    /// are free: two partitions stealing from each other can't deadlock. Partitions with
+    /// waiters of their own are left alone.
+    fn steal_frame(
+        &self,
+        state: &mut PoolState,
+        block: &BlockId,
+    ) -> Result<Option<(usize, Option<EvictionEvent>)>> {
+        let home = self.partition_index(block);
+        for (p, partition) in self.partitions.iter().enumerate() {
+            if p == home {
+                continue;
+            }
+            let Ok(mut other) = partition.state.try_lock() else {
+                continue;
+            };
+            if other.quiesced || !other.waiters.is_empty() {
+                continue;
+            }
+            let Some(index) = other.choose_unpinned_buffer() else {
+                continue;
+            };
+            let dirty = other.frames[index].buffer.is_modified();
+            other.frames[index].buffer.flush()?;
+            let mut frame = other.frames.remove(index);
+            other.num_available -= 1;
+            other.policy.on_remove(index);
+            drop(other);
+
+            let evicted = frame.block.take().map(|evicted| EvictionEvent {
+                evicted,
+                dirty,
+                incoming: Some(block.clone()),
+                timestamp: SystemTime::now(),
+                kind: EvictionKind::Replacement,
+            });
+            state.frames.push(frame);
+            state.num_available += 1;
+            return Ok(Some((state.frames.len() - 1, evicted)));
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for BufferManager {
+    fn drop(&mut self) {
+        // a panicking thread may have left pins behind (and poisoned locks), don't pile on
+        if !self.track_pins || thread::panicking() {
+            return;
+        }
+        if let Err(err) = self.verify_no_pins() {
+            eprintln!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Buffer, BufferError, BufferManager, BufferManagerOptions, BufferStats, EvictionEvent,
+        EvictionKind, MAX_TIME, NO_LSN, PAGE_DATA_OFFSET,
+    };
+    use crate::{
+        clock::{Clock, ManualClock},
+        file::{BlockId, BlockStorage, FileManager, Page},
+        log::LogManager,
+        replacement::{AccessHint, Lru2Policy, LruPolicy, ReplacementPolicy},
+        test_util::{FailingStorage, RecordingStorage},
+    };
+    use std::{
+        fs,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc, Arc, Barrier, Mutex,
+        },
+        thread::{self, ScopedJoinHandle},
+        time::Duration,
+    };
+
+    /// moves `clock` forward until the thread waiting on it gives up
+    fn advance_until_finished<T>(clock: &ManualClock, waiting: &ScopedJoinHandle<T>) {
+        while !waiting.is_finished() {
+            clock.advance(Duration::from_millis(MAX_TIME as u64 / 10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// where the tests keep their values, after the page lsn
+    const VALUE: u64 = PAGE_DATA_OFFSET as u64;
+
+    /// appends empty blocks to `filename` until it has `count` of them, so that they can be pinned
+    fn create_blocks(fm: &Mutex<FileManager>, filename: &str, count: i32) {
+        let mut fm = fm.lock().unwrap();
+        while fm.length(filename).unwrap() < count {
+            fm.append(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_buffer() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_4").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        create_blocks(&fm, "testfile", 2);
+        create_blocks(&fm, "testfile2", 3);
+        create_blocks(&fm, "testfile3", 4);
+        create_blocks(&fm, "testfile4", 5);
+        create_blocks(&fm, "testfile5", 12);
         //
         // buffer pool:
         //      capacity = 3
-        // ---------------- buffer pool ----------------
-        // |          [t0, 0] [t1, 1] [t2, 2]          |
-        // ---------------------------------------------
+        // -------------- buffer pool ----------------
+        // |  [empty buf] [empty buf] [empty buf]    |
+        // ------------------------------------------
         //
 
+        let buf1 = bm.pin(BlockId::new("testfile", 1)).unwrap();
+        {
+            let mut p = buf1.contents_mut();
+            let n = p.get_u64(80).unwrap();
+            p.set_u64(80, n + 1).unwrap();
+        }
+        buf1.set_modified(1, 0).unwrap();
+        bm.unpin(buf1);
+
+        let buf2 = bm.pin(BlockId::new("testfile2", 2)).unwrap();
+        let _buf3 = bm.pin(BlockId::new("testfile3", 3)).unwrap();
+        let _buf4 = bm.pin(BlockId::new("testfile4", 4)).unwrap();
+
+        bm.unpin(buf2);
+        let buf2 = bm.pin(BlockId::new("testfile5", 11)).unwrap();
+        buf2.contents_mut().set_u64(80, 9999).unwrap();
+        buf2.set_modified(1, 0).unwrap();
+        bm.unpin(buf2);
+
+        fs::remove_dir_all("__test_4").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn test_buffer_manager() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_5").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let clock = Arc::new(ManualClock::new());
+        let options = BufferManagerOptions {
+            clock: Arc::clone(&clock) as Arc<dyn Clock>,
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 3, options);
+        create_blocks(&fm, "t0", 1);
+        create_blocks(&fm, "t1", 2);
+        create_blocks(&fm, "t2", 3);
+        create_blocks(&fm, "t3", 4);
+        create_blocks(&fm, "t5", 6);
+        let block = |name: &str, n: i32| Some(BlockId::new(name, n));
+        assert_eq!(bm.available(), 3);
+        assert_eq!(bm.resident_blocks(), vec![None, None, None]);
+
+        let mut buf: Vec<Option<Arc<Buffer>>> = vec![None; 6];
+
+        buf[0] = bm.pin(BlockId::new("t0", 0)).unwrap().into();
+        assert_eq!(bm.available(), 2);
+        assert_eq!(bm.resident_blocks(), vec![block("t0", 0), None, None]);
+
+        buf[1] = bm.pin(BlockId::new("t1", 1)).unwrap().into();
+        assert_eq!(bm.available(), 1);
+        assert_eq!(
+            bm.resident_blocks(),
+            vec![block("t0", 0), block("t1", 1), None]
+        );
+
+        buf[2] = bm.pin(BlockId::new("t2", 2)).unwrap().into();
+        assert_eq!(bm.available(), 0);
+        assert_eq!(
+            bm.resident_blocks(),
+            vec![block("t0", 0), block("t1", 1), block("t2", 2)]
+        );
+
+        // an unpinned block stays resident until its buffer is chosen for another one
         bm.unpin(Arc::clone(buf[1].as_ref().unwrap()));
         buf[1] = None;
         assert_eq!(bm.available(), 1);
-        //
-        // buffer pool:
-        //      capacity = 3
-        // ------------ buffer pool -------------
-        // |         [t0, 0] [] [t2, 2]         |
-        // --------------------------------------
-        //
+        assert_eq!(
+            bm.resident_blocks(),
+            vec![block("t0", 0), block("t1", 1), block("t2", 2)]
+        );
 
         buf[3] = bm.pin(BlockId::new("t3", 3)).unwrap().into();
         assert_eq!(bm.available(), 0);
-        //
-        // buffer pool:
-        //      capacity = 3
-        // -------------- buffer pool ----------------
-        // |           [t0, 0] [t3, 3] [t2, 2]       |
-        // -------------------------------------------
-        //
+        assert_eq!(
+            bm.resident_blocks(),
+            vec![block("t0", 0), block("t3", 3), block("t2", 2)]
+        );
 
         // Get existing buffer
         let b4 = bm.pin(BlockId::new("t3", 3));
@@ -332,7 +1518,11 @@ mod tests {
         buf[4] = Some(b4.unwrap());
 
         // Pin buffer above the capacity should `error` in this naive algorithm.
-        let b5 = bm.pin(BlockId::new("t5", 5));
+        let b5 = thread::scope(|s| {
+            let pinning = s.spawn(|| bm.pin(BlockId::new("t5", 5)));
+            advance_until_finished(&clock, &pinning);
+            pinning.join().unwrap()
+        });
         println!("Algorithm using in this manager can not replace buffers");
         assert!(b5.is_err());
         buf[5] = None;
@@ -343,7 +1533,1108 @@ mod tests {
         assert!(buf[3].is_some());
         assert!(buf[4].is_some());
         assert!(buf[5].is_none());
+        assert_eq!(
+            bm.resident_blocks(),
+            vec![block("t0", 0), block("t3", 3), block("t2", 2)]
+        );
+        assert_eq!(
+            bm.dump(),
+            "0: pins=1 [file t0, block 0] dirty=false txnum=-1 lsn=-1\n\
+             1: pins=2 [file t3, block 3] dirty=false txnum=-1 lsn=-1\n\
+             2: pins=1 [file t2, block 2] dirty=false txnum=-1 lsn=-1\n"
+        );
+
+        for b in buf.into_iter().flatten() {
+            bm.unpin(b);
+        }
+        bm.assert_all_unpinned();
 
         fs::remove_dir_all("__test_5").expect("failed to remove dir");
     }
+
+    #[test]
+    fn try_pin_does_not_wait() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_7").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 2);
+        create_blocks(&fm, "t0", 1);
+        create_blocks(&fm, "t1", 2);
+        create_blocks(&fm, "t2", 3);
+
+        let b0 = bm.try_pin(&BlockId::new("t0", 0)).unwrap();
+        assert!(b0.is_some());
+        let b1 = bm.try_pin(&BlockId::new("t1", 1)).unwrap();
+        assert!(b1.is_some());
+        assert_eq!(bm.available(), 0);
+
+        // the pool is full: no buffer can be replaced
+        assert!(bm.try_pin(&BlockId::new("t2", 2)).unwrap().is_none());
+        // but an already pinned block can still be pinned again
+        assert!(bm.try_pin(&BlockId::new("t0", 0)).unwrap().is_some());
+
+        fs::remove_dir_all("__test_7").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn pin_many_is_all_or_nothing() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_8").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let clock = Arc::new(ManualClock::new());
+        let options = BufferManagerOptions {
+            clock: Arc::clone(&clock) as Arc<dyn Clock>,
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 3, options);
+        create_blocks(&fm, "t0", 4);
+        create_blocks(&fm, "t1", 2);
+
+        let blocks = vec![
+            BlockId::new("t1", 1),
+            BlockId::new("t0", 0),
+            BlockId::new("t0", 2),
+        ];
+        let bufs = bm.pin_many(&blocks).unwrap();
+        assert_eq!(bm.available(), 0);
+        for (block, buf) in blocks.iter().zip(bufs.iter()) {
+            assert_eq!(buf.block().as_ref(), Some(block));
+        }
+        for buf in bufs {
+            bm.unpin(buf);
+        }
+        assert_eq!(bm.available(), 3);
+
+        // the pool is too small to hold 4 blocks at the same time
+        let blocks = vec![
+            BlockId::new("t0", 0),
+            BlockId::new("t0", 1),
+            BlockId::new("t0", 2),
+            BlockId::new("t0", 3),
+        ];
+        let pinned = thread::scope(|s| {
+            let pinning = s.spawn(|| bm.pin_many(&blocks));
+            advance_until_finished(&clock, &pinning);
+            pinning.join().unwrap()
+        });
+        assert!(pinned.is_err());
+        assert_eq!(bm.available(), 3);
+
+        fs::remove_dir_all("__test_8").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn concurrent_pin_unpin_and_flush_all() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_9").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(fm.clone(), Arc::clone(&lm), 3));
+        create_blocks(&fm, "testfile", 5);
+        let (tx, rx) = mpsc::channel();
+
+        let mut handles: Vec<_> = (0..8)
+            .map(|t| {
+                let bm = Arc::clone(&bm);
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        let block = BlockId::new("testfile", (t + i) % 5);
+                        let buf = bm.pin(block).unwrap();
+                        {
+                            let mut p = buf.contents_mut();
+                            let n = p.get_i32(VALUE).unwrap();
+                            p.set_i32(VALUE, n + 1).unwrap();
+                        }
+                        buf.set_modified(t, -1).unwrap();
+                        bm.unpin(buf);
+                    }
+                })
+            })
+            .collect();
+        let flusher = {
+            let bm = Arc::clone(&bm);
+            thread::spawn(move || {
+                for t in 0..400 {
+                    bm.flush_all(t % 8).unwrap();
+                }
+            })
+        };
+        handles.push(flusher);
+        thread::spawn(move || {
+            for h in handles {
+                h.join().unwrap();
+            }
+            tx.send(()).unwrap();
+        });
+
+        rx.recv_timeout(Duration::from_secs(30))
+            .expect("pinning threads deadlocked");
+        assert_eq!(bm.available(), 3);
+
+        fs::remove_dir_all("__test_9").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn flush_all_runs_alongside_readers_and_writers() {
+        let dirname = "__test_24";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 4);
+        create_blocks(&fm, "testfile", 4);
+        let shared = BlockId::new("testfile", 3);
+        {
+            let buf = bm.pin(shared.clone()).unwrap();
+            buf.contents_mut().set_i32(VALUE, 7).unwrap();
+            bm.unpin(buf);
+        }
+        let writers_done = AtomicUsize::new(0);
+
+        thread::scope(|s| {
+            for t in 0..3 {
+                let (bm, writers_done) = (&bm, &writers_done);
+                s.spawn(move || {
+                    for _ in 0..500 {
+                        let buf = bm.pin(BlockId::new("testfile", t)).unwrap();
+                        {
+                            let mut p = buf.contents_mut();
+                            let n = p.get_i32(VALUE).unwrap();
+                            p.set_i32(VALUE, n + 1).unwrap();
+                        }
+                        buf.set_modified(t, -1).unwrap();
+                        bm.unpin(buf);
+                    }
+                    writers_done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+            for _ in 0..2 {
+                let (bm, shared, writers_done) = (&bm, &shared, &writers_done);
+                s.spawn(move || {
+                    while writers_done.load(Ordering::SeqCst) < 3 {
+                        let buf = bm.pin_shared(shared.clone()).unwrap();
+                        assert_eq!(buf.contents().get_i32(VALUE).unwrap(), 7);
+                        bm.unpin(buf);
+                    }
+                });
+            }
+            s.spawn(|| {
+                while writers_done.load(Ordering::SeqCst) < 3 {
+                    for t in 0..3 {
+                        bm.flush_all(t).unwrap();
+                    }
+                }
+            });
+        });
+
+        for t in 0..3 {
+            bm.flush_all(t).unwrap();
+        }
+        let mut fm = FileManager::new(dirname).unwrap();
+        let mut p = Page::new(fm.block_size());
+        for t in 0..3 {
+            fm.read(&BlockId::new("testfile", t), &mut p).unwrap();
+            assert_eq!(p.get_i32(VALUE).unwrap(), 500);
+        }
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn log_is_written_before_data_in_every_flush_path() {
+        let dirname = "__test_26";
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let storage: Arc<Mutex<dyn BlockStorage>> = Arc::new(Mutex::new(RecordingStorage {
+            inner: FileManager::new(dirname).unwrap(),
+            reads: Arc::default(),
+            writes: Arc::clone(&writes),
+        }));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&storage), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(Arc::clone(&storage), Arc::clone(&lm), 1);
+        {
+            let mut storage = storage.lock().unwrap();
+            while storage.length("testfile").unwrap() < 2 {
+                storage.append("testfile").unwrap();
+            }
+        }
+        let block = BlockId::new("testfile", 0);
+
+        // dirties `block` with a log record that is not on disk yet, then runs `flush`
+        // and checks that the log reached the disk before the data page did
+        let check = |path: &str, flush: &dyn Fn(&Arc<Buffer>)| {
+            let buf = bm.pin(block.clone()).unwrap();
+            let lsn = lm.lock().unwrap().append(vec![0; 8]).unwrap();
+            buf.contents_mut().set_i32(VALUE, lsn).unwrap();
+            buf.set_modified(1, lsn).unwrap();
+            writes.lock().unwrap().clear();
+            flush(&buf);
+            if bm.resident_blocks().contains(&Some(block.clone())) {
+                bm.unpin(buf);
+            }
+            let writes = writes.lock().unwrap();
+            let data = writes.iter().position(|b| *b == block);
+            let log = writes.iter().position(|b| b.filename() == "test_log");
+            assert!(data.is_some(), "{}: the data page was not written", path);
+            assert!(log.is_some() && log < data, "{}: wrote {:?}", path, writes);
+            assert!(lm.lock().unwrap().durable_lsn() >= lsn);
+        };
+
+        check("flush", &|buf| {
+            buf.flush().unwrap();
+        });
+        check("flush_all", &|_| bm.flush_all(1).unwrap());
+        check("checkpoint", &|_| bm.release(bm.checkpoint().unwrap()));
+        check("eviction", &|buf| {
+            bm.unpin(Arc::clone(buf));
+            bm.unpin(bm.pin(BlockId::new("testfile", 1)).unwrap());
+        });
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn checkpoint_writes_every_dirty_buffer() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_10").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(fm.clone(), Arc::clone(&lm), 3));
+        create_blocks(&fm, "testfile", 3);
+
+        let buf1 = bm.pin(BlockId::new("testfile", 0)).unwrap();
+        buf1.contents_mut().set_i32(80, 111).unwrap();
+        buf1.set_modified(1, 0).unwrap();
+        bm.unpin(buf1);
+        // a transaction still holds this dirty buffer while the checkpoint runs
+        let buf2 = bm.pin(BlockId::new("testfile", 1)).unwrap();
+        buf2.contents_mut().set_i32(80, 222).unwrap();
+        buf2.set_modified(2, 0).unwrap();
+
+        let token = bm.checkpoint().unwrap();
+        assert!(bm.try_pin(&BlockId::new("testfile", 2)).unwrap().is_none());
+        let waiter = {
+            let bm = Arc::clone(&bm);
+            thread::spawn(move || bm.pin(BlockId::new("testfile", 2)).is_ok())
+        };
+        bm.release(token);
+        assert!(waiter.join().unwrap());
+        assert!(!buf2.is_modified());
+        bm.unpin(buf2);
+
+        let mut fm = FileManager::new("__test_10").unwrap();
+        let mut p = Page::new(fm.block_size());
+        fm.read(&BlockId::new("testfile", 0), &mut p).unwrap();
+        assert_eq!(p.get_i32(80).unwrap(), 111);
+        fm.read(&BlockId::new("testfile", 1), &mut p).unwrap();
+        assert_eq!(p.get_i32(80).unwrap(), 222);
+
+        fs::remove_dir_all("__test_10").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn shared_pins_coexist_and_exclusive_waits() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_11").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(fm.clone(), Arc::clone(&lm), 3));
+        create_blocks(&fm, "testfile", 1);
+        let block = BlockId::new("testfile", 0);
+        let both_pinned = Arc::new(Barrier::new(3));
+        let unpinned = Arc::new(AtomicUsize::new(0));
+
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let (bm, block) = (Arc::clone(&bm), block.clone());
+                let (both_pinned, unpinned) = (Arc::clone(&both_pinned), Arc::clone(&unpinned));
+                thread::spawn(move || {
+                    let buf = bm.pin_shared(block).unwrap();
+                    both_pinned.wait();
+                    assert_eq!(buf.contents().get_i32(VALUE).unwrap(), 0);
+                    assert!(buf.set_modified(1, 0).is_err());
+                    thread::sleep(Duration::from_millis(100));
+                    unpinned.fetch_add(1, Ordering::SeqCst);
+                    bm.unpin(buf);
+                })
+            })
+            .collect();
+
+        both_pinned.wait();
+        assert_eq!(bm.available(), 2);
+        let buf = bm.pin_exclusive(block).unwrap();
+        assert_eq!(unpinned.load(Ordering::SeqCst), 2);
+        assert!(buf.set_modified(1, 0).is_ok());
+        bm.unpin(buf);
+        for r in readers {
+            r.join().unwrap();
+        }
+
+        fs::remove_dir_all("__test_11").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn prefetched_blocks_are_hits() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_12").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 4);
+        let blocks: Vec<BlockId> = (0..3).map(|n| BlockId::new("testfile", n)).collect();
+        {
+            let mut fm = fm.lock().unwrap();
+            for block in blocks.iter() {
+                let mut p = Page::new(fm.block_size());
+                p.set_i32(VALUE, block.number() + 100).unwrap();
+                fm.write(block, &p).unwrap();
+            }
+        }
+
+        let reads = fm.lock().unwrap().stats().blocks_read;
+        assert_eq!(bm.prefetch(&blocks).unwrap(), 3);
+        assert_eq!(fm.lock().unwrap().stats().blocks_read, reads + 3);
+        assert_eq!(bm.available(), 4);
+        // already resident: nothing to read
+        assert_eq!(bm.prefetch(&blocks).unwrap(), 0);
+
+        let reads = fm.lock().unwrap().stats().blocks_read;
+        for block in blocks.iter() {
+            let buf = bm.pin(block.clone()).unwrap();
+            let n = buf.contents().get_i32(VALUE).unwrap();
+            assert_eq!(n, block.number() + 100);
+            bm.unpin(buf);
+        }
+        assert_eq!(fm.lock().unwrap().stats().blocks_read, reads);
+
+        // no free buffer: prefetch backs off instead of waiting
+        create_blocks(&fm, "testfile", 14);
+        let pinned: Vec<_> = (10..14)
+            .map(|n| bm.pin(BlockId::new("testfile", n)).unwrap())
+            .collect();
+        assert_eq!(bm.prefetch(&blocks).unwrap(), 0);
+        for buf in pinned {
+            bm.unpin(buf);
+        }
+
+        fs::remove_dir_all("__test_12").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn sequential_hint_keeps_hot_block_resident() {
+        fn hot_block_misses(dirname: &str, hint: AccessHint) -> u64 {
+            let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+            let lm = Arc::new(Mutex::new(
+                LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+            ));
+            let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 5);
+            create_blocks(&fm, "hotfile", 1);
+            create_blocks(&fm, "scanfile", 100);
+            let hot = BlockId::new("hotfile", 0);
+            bm.unpin(bm.pin(hot.clone()).unwrap());
+
+            let mut misses = 0;
+            for n in 0..100 {
+                bm.unpin(bm.pin_with_hint(BlockId::new("scanfile", n), hint).unwrap());
+                let reads = fm.lock().unwrap().stats().blocks_read;
+                bm.unpin(bm.pin(hot.clone()).unwrap());
+                misses += fm.lock().unwrap().stats().blocks_read - reads;
+            }
+            fs::remove_dir_all(dirname).expect("failed to remove dir");
+            misses
+        }
+
+        assert_eq!(hot_block_misses("__test_13", AccessHint::Sequential), 0);
+        assert!(hot_block_misses("__test_14", AccessHint::Normal) > 0);
+    }
+
+    #[test]
+    fn lru2_keeps_hot_set_resident_during_scan() {
+        /// the fraction of hot set pins that didn't read from disk
+        fn hot_set_hit_rate(dirname: &str, policy: fn() -> Box<dyn ReplacementPolicy>) -> f64 {
+            let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+            let lm = Arc::new(Mutex::new(
+                LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+            ));
+            let options = BufferManagerOptions {
+                policy,
+                partitions: Some(1),
+                ..Default::default()
+            };
+            let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 8, options);
+            create_blocks(&fm, "hotfile", 4);
+            create_blocks(&fm, "scanfile", 100);
+            let hot: Vec<BlockId> = (0..4).map(|n| BlockId::new("hotfile", n)).collect();
+            for block in hot.iter().chain(hot.iter()) {
+                bm.unpin(bm.pin(block.clone()).unwrap());
+            }
+
+            // a 100 block scan, with the hot set touched after every 10 blocks
+            let (mut pins, mut hits) = (0, 0);
+            for chunk in 0..10 {
+                for n in 0..10 {
+                    let block = BlockId::new("scanfile", chunk * 10 + n);
+                    bm.unpin(bm.pin(block).unwrap());
+                }
+                for block in hot.iter() {
+                    let reads = fm.lock().unwrap().stats().blocks_read;
+                    bm.unpin(bm.pin(block.clone()).unwrap());
+                    pins += 1;
+                    if fm.lock().unwrap().stats().blocks_read == reads {
+                        hits += 1;
+                    }
+                }
+            }
+            fs::remove_dir_all(dirname).expect("failed to remove dir");
+            hits as f64 / pins as f64
+        }
+
+        assert!(hot_set_hit_rate("__test_20", || Box::new(Lru2Policy::new())) > 0.9);
+        assert!(hot_set_hit_rate("__test_21", || Box::new(LruPolicy::new())) < 0.5);
+    }
+
+    #[test]
+    fn waiters_get_buffers_in_arrival_order() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_22").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let clock = Arc::new(ManualClock::new());
+        let options = BufferManagerOptions {
+            clock: Arc::clone(&clock) as Arc<dyn Clock>,
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 1, options);
+        create_blocks(&fm, "testfile", 10);
+        let held = bm.pin(BlockId::new("testfile", 0)).unwrap();
+        let waits = || bm.partition_waits().iter().sum::<u64>();
+        let order = Mutex::new(Vec::new());
+
+        thread::scope(|s| {
+            // a waiter that times out leaves the queue without holding up the others
+            let gives_up = s.spawn(|| bm.pin(BlockId::new("testfile", 9)));
+            advance_until_finished(&clock, &gives_up);
+            assert!(gives_up.join().unwrap().is_err());
+
+            for n in 1..=3 {
+                let before = waits();
+                let (bm, order) = (&bm, &order);
+                s.spawn(move || {
+                    let buf = bm.pin(BlockId::new("testfile", n)).unwrap();
+                    order.lock().unwrap().push(n);
+                    bm.unpin(buf);
+                });
+                while waits() == before {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+            // a newcomer doesn't jump the queue
+            assert!(bm.try_pin(&BlockId::new("testfile", 4)).unwrap().is_none());
+            bm.unpin(held);
+        });
+
+        assert_eq!(*order.lock().unwrap(), vec![1, 2, 3]);
+        fs::remove_dir_all("__test_22").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn partitions_do_not_block_each_other() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_15").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let clock = Arc::new(ManualClock::new());
+        let options = BufferManagerOptions {
+            clock: Arc::clone(&clock) as Arc<dyn Clock>,
+            partitions: Some(16),
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 16, options);
+        assert_eq!(bm.available(), 16);
+
+        // one block for each partition
+        let mut blocks: Vec<Option<BlockId>> = vec![None; 16];
+        for n in 0.. {
+            let block = BlockId::new("testfile", n);
+            let p = bm.partition_index(&block);
+            if blocks[p].is_none() {
+                blocks[p] = Some(block);
+            }
+            if blocks.iter().all(|b| b.is_some()) {
+                break;
+            }
+        }
+        let last = blocks.iter().flatten().map(|b| b.number()).max().unwrap();
+        create_blocks(&fm, "testfile", last + 1);
+
+        thread::scope(|s| {
+            for block in blocks.iter().flatten() {
+                let bm = &bm;
+                s.spawn(move || {
+                    for _ in 0..100 {
+                        let buf = bm.pin(block.clone()).unwrap();
+                        bm.unpin(buf);
+                    }
+                });
+            }
+        });
+        assert_eq!(bm.partition_waits(), vec![0; 16]);
+
+        // a second block of a full partition borrows a buffer of another partition
+        let first = blocks[0].clone().unwrap();
+        let other = (0..)
+            .map(|n| BlockId::new("otherfile", n))
+            .find(|b| bm.partition_index(b) == 0)
+            .unwrap();
+        create_blocks(&fm, "otherfile", other.number() + 1);
+        let held = bm.pin(first).unwrap();
+        let borrowed = bm.pin(other.clone()).unwrap();
+        assert_eq!(bm.available(), 14);
+        assert_eq!(bm.size(), 16);
+        bm.unpin(borrowed);
+
+        // once every buffer is pinned, it has to wait, only in its own partition
+        let pinned: Vec<_> = blocks[1..]
+            .iter()
+            .flatten()
+            .map(|block| bm.pin(block.clone()).unwrap())
+            .collect();
+        assert_eq!(bm.available(), 0);
+        let waited = thread::scope(|s| {
+            let pinning = s.spawn(|| bm.pin(other));
+            advance_until_finished(&clock, &pinning);
+            pinning.join().unwrap()
+        });
+        assert!(waited.is_err());
+        bm.unpin(held);
+        for buf in pinned {
+            bm.unpin(buf);
+        }
+        assert_eq!(bm.available(), 16);
+        let waits = bm.partition_waits();
+        assert_eq!(waits[0], 1);
+        assert_eq!(waits[1..], vec![0; 15]);
+
+        fs::remove_dir_all("__test_15").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn pins_of_one_partition_can_use_the_whole_pool() {
+        let dirname = "__test_103";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let options = BufferManagerOptions {
+            max_wait: Duration::from_millis(10),
+            partitions: Some(2),
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 4, options);
+        let blocks: Vec<BlockId> = (0..)
+            .map(|n| BlockId::new("testfile", n))
+            .filter(|b| bm.partition_index(b) == 0)
+            .take(5)
+            .collect();
+        // dirty a buffer of the other partition, which is written out when borrowed
+        let other = (0..)
+            .map(|n| BlockId::new("testfile", n))
+            .find(|b| bm.partition_index(b) == 1)
+            .unwrap();
+        create_blocks(&fm, "testfile", blocks[4].number().max(other.number()) + 1);
+        let buf = bm.pin(other.clone()).unwrap();
+        buf.contents_mut().set_i32(VALUE, 42).unwrap();
+        buf.set_modified(1, -1).unwrap();
+        bm.unpin(buf);
+
+        let pinned: Vec<_> = blocks[..4]
+            .iter()
+            .map(|block| bm.pin(block.clone()).unwrap())
+            .collect();
+        assert_eq!(bm.available(), 0);
+        assert!(matches!(
+            bm.pin(blocks[4].clone()),
+            Err(BufferError::BufferAbort)
+        ));
+        let mut page = Page::new(fm.lock().unwrap().block_size());
+        fm.lock().unwrap().read(&other, &mut page).unwrap();
+        assert_eq!(page.get_i32(VALUE).unwrap(), 42);
+
+        for buf in pinned {
+            bm.unpin(buf);
+        }
+        assert_eq!(bm.available(), 4);
+        assert_eq!(bm.size(), 4);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn resize_pool() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_16").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        create_blocks(&fm, "testfile", 8);
+
+        assert_eq!(bm.resize(8).unwrap(), 8);
+        assert_eq!(bm.available(), 8);
+        let bufs: Vec<_> = (0..8)
+            .map(|n| bm.try_pin(&BlockId::new("testfile", n)).unwrap().unwrap())
+            .collect();
+        for (n, buf) in bufs.iter().enumerate() {
+            buf.contents_mut().set_i32(VALUE, n as i32 + 1).unwrap();
+            buf.set_modified(1, 0).unwrap();
+        }
+        assert_eq!(bm.available(), 0);
+
+        // pinned buffers are never removed
+        let mut bufs = bufs.into_iter();
+        for buf in bufs.by_ref().take(3) {
+            bm.unpin(buf);
+        }
+        assert_eq!(bm.resize(3).unwrap(), 5);
+        assert_eq!(bm.available(), 0);
+
+        for buf in bufs {
+            bm.unpin(buf);
+        }
+        assert_eq!(bm.resize(3).unwrap(), 3);
+        assert_eq!(bm.size(), 3);
+        assert_eq!(bm.available(), 3);
+
+        let mut fm = FileManager::new("__test_16").unwrap();
+        let mut p = Page::new(fm.block_size());
+        for n in 0..5 {
+            fm.read(&BlockId::new("testfile", n), &mut p).unwrap();
+            assert_eq!(p.get_i32(VALUE).unwrap(), n + 1);
+        }
+
+        fs::remove_dir_all("__test_16").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn eviction_observer_sees_every_eviction() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_17").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(fm.clone(), Arc::clone(&lm), 1));
+        create_blocks(&fm, "testfile", 3);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = Arc::clone(&events);
+            let manager = Arc::downgrade(&bm);
+            bm.set_eviction_observer(Box::new(move |event: EvictionEvent| {
+                // calling back into the manager must not deadlock
+                let available = manager.upgrade().unwrap().available();
+                events.lock().unwrap().push((event, available));
+            }));
+        }
+        let (a, b, c) = (
+            BlockId::new("testfile", 0),
+            BlockId::new("testfile", 1),
+            BlockId::new("testfile", 2),
+        );
+
+        let buf = bm.pin(a.clone()).unwrap();
+        buf.set_modified(1, 0).unwrap();
+        bm.unpin(buf);
+        bm.unpin(bm.pin(b.clone()).unwrap());
+        let buf = bm.pin(c.clone()).unwrap();
+        buf.set_modified(1, 0).unwrap();
+        bm.release(bm.checkpoint().unwrap());
+        bm.unpin(buf);
+
+        let events = events.lock().unwrap();
+        let summary: Vec<_> = events
+            .iter()
+            .map(|(e, available)| {
+                (
+                    e.evicted.clone(),
+                    e.dirty,
+                    e.incoming.clone(),
+                    e.kind,
+                    *available,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (a, true, Some(b.clone()), EvictionKind::Replacement, 0),
+                (b, false, Some(c.clone()), EvictionKind::Replacement, 0),
+                (c, true, None, EvictionKind::Checkpoint, 0),
+            ]
+        );
+
+        fs::remove_dir_all("__test_17").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn warm_up_restores_resident_blocks() {
+        let dirname = "__test_18";
+        let residents = format!("{}/residents", dirname);
+        let blocks: Vec<BlockId> = (0..3).map(|n| BlockId::new("testfile", n)).collect();
+        {
+            let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+            let lm = Arc::new(Mutex::new(
+                LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+            ));
+            let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 4);
+            create_blocks(&fm, "testfile", 3);
+            create_blocks(&fm, "removedfile", 1);
+            for block in blocks.iter() {
+                let buf = bm.pin(block.clone()).unwrap();
+                buf.contents_mut()
+                    .set_i32(VALUE, block.number() + 100)
+                    .unwrap();
+                buf.set_modified(1, 0).unwrap();
+                bm.unpin(buf);
+            }
+            let gone = bm.pin(BlockId::new("removedfile", 0)).unwrap();
+            bm.unpin(gone);
+            bm.flush_all(1).unwrap();
+            assert_eq!(bm.save_residents(&residents).unwrap(), 4);
+        }
+        fs::remove_file(format!("{}/removedfile", dirname)).unwrap();
+
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 4);
+        assert_eq!(bm.warm_up(&residents).unwrap(), 3);
+        assert!(!fm.lock().unwrap().exists("removedfile"));
+
+        let reads = fm.lock().unwrap().stats().blocks_read;
+        for block in blocks.iter() {
+            let buf = bm.pin(block.clone()).unwrap();
+            let n = buf.contents().get_i32(VALUE).unwrap();
+            assert_eq!(n, block.number() + 100);
+            bm.unpin(buf);
+        }
+        assert_eq!(fm.lock().unwrap().stats().blocks_read, reads);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn flush_stamps_the_page_lsn() {
+        let dirname = "__test_51";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 1);
+        create_blocks(&fm, "testfile", 2);
+        let block = BlockId::new("testfile", 0);
+        let on_disk = || {
+            let mut page = Page::new(fm.lock().unwrap().block_size());
+            fm.lock().unwrap().read(&block, &mut page).unwrap();
+            page
+        };
+
+        let buf = bm.pin(block.clone()).unwrap();
+        assert_eq!(buf.page_lsn().unwrap(), 0);
+        let lsn = lm.lock().unwrap().append(vec![1, 2, 3]).unwrap();
+        buf.contents_mut().set_i32(VALUE, 42).unwrap();
+        buf.set_modified(1, lsn).unwrap();
+        // pending until the page is flushed
+        assert_eq!(buf.page_lsn().unwrap(), lsn);
+        assert_eq!(on_disk().get_u64(0).unwrap(), 0);
+        bm.unpin(buf);
+        bm.flush_all(1).unwrap();
+        let page = on_disk();
+        assert_eq!(page.get_u64(0).unwrap(), lsn as u64);
+        assert_eq!(page.get_i32(VALUE).unwrap(), 42);
+
+        // a modification without a log record keeps the lsn the block has
+        let buf = bm.pin(block.clone()).unwrap();
+        buf.contents_mut().set_i32(VALUE, 43).unwrap();
+        buf.set_modified(2, -1).unwrap();
+        bm.unpin(buf);
+        bm.unpin(bm.pin(BlockId::new("testfile", 1)).unwrap());
+        let page = on_disk();
+        assert_eq!(page.get_u64(0).unwrap(), lsn as u64);
+        assert_eq!(page.get_i32(VALUE).unwrap(), 43);
+
+        let buf = bm.pin(block.clone()).unwrap();
+        assert_eq!(buf.page_lsn().unwrap(), lsn);
+        bm.unpin(buf);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn failed_assignment_does_not_leak_the_buffer() {
+        let dirname = "__test_19";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let fail_reads = Arc::new(AtomicBool::new(false));
+        let storage = Arc::new(Mutex::new(FailingStorage {
+            inner: FileManager::new(dirname).unwrap(),
+            fail_reads: Arc::clone(&fail_reads),
+            fail_writes: Arc::default(),
+        }));
+        let bm = BufferManager::new(storage, Arc::clone(&lm), 1);
+        create_blocks(&fm, "testfile", 2);
+
+        let buf = bm.pin(BlockId::new("testfile", 0)).unwrap();
+        let lsn = lm.lock().unwrap().append(vec![1, 2, 3]).unwrap();
+        buf.contents_mut().set_i32(VALUE, 42).unwrap();
+        buf.set_modified(1, lsn).unwrap();
+        bm.unpin(buf);
+
+        fail_reads.store(true, Ordering::SeqCst);
+        assert!(bm.pin(BlockId::new("testfile", 1)).is_err());
+        assert!(bm.pin(BlockId::new("testfile", 1)).is_err());
+        assert_eq!(bm.available(), 1);
+
+        fail_reads.store(false, Ordering::SeqCst);
+        let buf = bm.pin(BlockId::new("testfile", 1)).unwrap();
+        assert!(!buf.is_modified());
+        assert_eq!(buf.state.lock().unwrap().lsn, NO_LSN);
+        assert_eq!(buf.contents().get_i32(VALUE).unwrap(), 0);
+        // a modification without a log record must not inherit the old lsn
+        buf.set_modified(2, -1).unwrap();
+        assert_eq!(buf.state.lock().unwrap().lsn, NO_LSN);
+        bm.unpin(buf);
+        assert_eq!(bm.available(), 1);
+
+        // the dirty block 0 was written before the failed read replaced it
+        let buf = bm.pin(BlockId::new("testfile", 0)).unwrap();
+        assert_eq!(buf.contents().get_i32(VALUE).unwrap(), 42);
+        bm.unpin(buf);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn pin_rejects_blocks_past_end_of_file() {
+        let dirname = "__test_23";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        create_blocks(&fm, "testfile", 1);
+
+        let buf = bm.pin(BlockId::new("testfile", 0)).unwrap();
+        bm.unpin(buf);
+        for block in [
+            BlockId::new("testfile", 1),
+            BlockId::new("testfile", -1),
+            BlockId::new("typofile", 0),
+        ] {
+            match bm.pin(block.clone()) {
+                Err(BufferError::BlockOutOfRange(b)) => assert_eq!(b, block),
+                other => panic!("expected BlockOutOfRange, got {:?}", other),
+            }
+        }
+        assert!(matches!(
+            bm.try_pin(&BlockId::new("testfile", 1)),
+            Err(BufferError::BlockOutOfRange(_))
+        ));
+        assert_eq!(bm.prefetch(&[BlockId::new("testfile", 1)]).unwrap(), 0);
+        assert_eq!(bm.available(), 3);
+        // checking never creates the file
+        assert!(!fm.lock().unwrap().exists("typofile"));
+
+        // the escape hatch for callers that extend the file themselves
+        let block = BlockId::new("testfile", 1);
+        let buf = bm.pin_allow_beyond_eof(block.clone()).unwrap();
+        buf.contents_mut().set_i32(VALUE, 5).unwrap();
+        buf.set_modified(1, -1).unwrap();
+        bm.unpin(buf);
+        bm.flush_all(1).unwrap();
+        assert_eq!(fm.lock().unwrap().length("testfile").unwrap(), 2);
+        bm.unpin(bm.pin(block).unwrap());
+
+        // the check can be turned off for the whole pool
+        let options = BufferManagerOptions {
+            check_eof: false,
+            ..Default::default()
+        };
+        let unchecked = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 1, options);
+        unchecked.unpin(unchecked.pin(BlockId::new("testfile", 5)).unwrap());
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn leaked_pins_name_block_and_owner() {
+        let dirname = "__test_25";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let options = BufferManagerOptions {
+            track_pins: true,
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), Arc::clone(&lm), 3, options);
+        create_blocks(&fm, "testfile", 3);
+        let (a, b) = (BlockId::new("testfile", 0), BlockId::new("testfile", 1));
+
+        let released = bm.pin_for(a.clone(), "tx 1").unwrap();
+        let leaked = bm.pin_for(b.clone(), "tx 2").unwrap();
+        let twice = bm.pin_for(b.clone(), "tx 2").unwrap();
+        let anonymous = bm.pin(BlockId::new("testfile", 2)).unwrap();
+        bm.unpin_for(released, "tx 1");
+        bm.unpin(anonymous);
+        assert_eq!(
+            bm.outstanding_pins(),
+            vec![(b.clone(), Some("tx 2".to_string()), 2)]
+        );
+
+        bm.unpin_for(twice, "tx 2");
+        match bm.verify_no_pins() {
+            Err(BufferError::LeakedPins(pins)) => {
+                assert_eq!(pins, vec![(b.clone(), Some("tx 2".to_string()), 1)])
+            }
+            other => panic!("expected LeakedPins, got {:?}", other),
+        }
+
+        bm.unpin_for(leaked, "tx 2");
+        assert!(bm.verify_no_pins().is_ok());
+
+        // without tracking only the counts are known
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        let buf = bm.pin_for(a.clone(), "tx 3").unwrap();
+        assert_eq!(bm.outstanding_pins(), vec![(a, None, 1)]);
+        bm.unpin_for(buf, "tx 3");
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn pin_new_from_multiple_threads() {
+        let fm = Arc::new(Mutex::new(FileManager::new("__test_6").unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(fm.clone(), Arc::clone(&lm), 3));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let bm = Arc::clone(&bm);
+                thread::spawn(move || {
+                    let mut blocks = Vec::new();
+                    for _ in 0..25 {
+                        let (block, buf) = bm.pin_new("testfile").unwrap();
+                        assert!(buf.contents().bytes().iter().all(|b| *b == 0));
+                        bm.unpin(buf);
+                        blocks.push(block.number());
+                    }
+                    blocks
+                })
+            })
+            .collect();
+
+        let mut numbers: Vec<i32> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .collect();
+        numbers.sort();
+        numbers.dedup();
+        assert_eq!(numbers, (0..100).collect::<Vec<i32>>());
+        assert_eq!(fm.lock().unwrap().length("testfile").unwrap(), 100);
+
+        fs::remove_dir_all("__test_6").expect("failed to remove dir");
+    }
+
+    #[test]
+    fn unpinning_twice_does_not_free_the_buffer_twice() {
+        let dirname = "__test_102";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 2);
+        create_blocks(&fm, "testfile", 2);
+
+        let buf = bm.pin(BlockId::new("testfile", 0)).unwrap();
+        let held = bm.pin(BlockId::new("testfile", 1)).unwrap();
+        bm.unpin(Arc::clone(&buf));
+        bm.unpin(Arc::clone(&buf));
+        assert_eq!(bm.available(), 1);
+
+        // a buffer of another pool is ignored as well
+        bm.unpin(Arc::new(Buffer::new(fm.clone(), lm)));
+        assert_eq!(bm.available(), 1);
+        bm.unpin(held);
+        assert_eq!(bm.available(), 2);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn pin_new_in_a_full_pool_leaves_the_file_alone() {
+        let dirname = "__test_101";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let options = BufferManagerOptions {
+            max_wait: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), lm, 1, options);
+        let (block, held) = bm.pin_new("testfile").unwrap();
+        assert_eq!(block.number(), 0);
+
+        assert!(matches!(
+            bm.pin_new("testfile"),
+            Err(BufferError::BufferAbort)
+        ));
+        assert_eq!(bm.length("testfile").unwrap(), 1);
+
+        bm.unpin(held);
+        let (block, buf) = bm.pin_new("testfile").unwrap();
+        assert_eq!(block.number(), 1);
+        bm.unpin(buf);
+        assert_eq!(bm.length("testfile").unwrap(), 2);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn stats_count_hits_misses_and_evictions() {
+        let dirname = "__test_96";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        // with lru, pinning 2 evicts 1 and pinning 1 again evicts 0
+        let bm = BufferManagerOptions {
+            policy: || Box::new(LruPolicy::new()),
+            partitions: Some(1),
+            ..Default::default()
+        };
+        let bm = BufferManager::new_with_options(fm.clone(), lm, 2, bm);
+        create_blocks(&fm, "testfile", 3);
+        assert_eq!(bm.stats().hit_ratio(), None);
+
+        for n in [0, 1, 0, 2, 1] {
+            let buf = bm.pin(BlockId::new("testfile", n)).unwrap();
+            bm.unpin(buf);
+        }
+        let stats = bm.stats();
+        assert_eq!(
+            stats,
+            BufferStats {
+                hits: 1,
+                misses: 4,
+                evictions: 2,
+            }
+        );
+        assert_eq!(stats.hit_ratio(), Some(0.2));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
 }
@@ -0,0 +1,106 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// source of the current time for timeouts and timestamps.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// wall-clock time, for timestamps that mean something outside this process
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// `wall_time` in microseconds since the unix epoch
+    fn micros_since_epoch(&self) -> i64 {
+        self.wall_time()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_micros() as i64)
+    }
+}
+
+/// waits out a delay, such as the backoff between two attempts of a transaction
+pub trait Sleeper: fmt::Debug + Send + Sync {
+    fn sleep(&self, duration: Duration);
+}
+
+/// monotonic clock backed by `std::time::Instant`; it never jumps backwards.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl Sleeper for SystemClock {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// a clock that only moves when it is advanced. useful to test timeouts without sleeping.
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<(Instant, SystemTime)>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock {
+            now: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += duration;
+        now.1 += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.now.lock().unwrap().1
+    }
+}
+
+/// sleeping on a manual clock advances it instead of waiting
+impl Sleeper for ManualClock {
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_advanced() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(clock.now().duration_since(start), Duration::from_secs(10));
+
+        let micros = clock.micros_since_epoch();
+        assert_eq!(clock.micros_since_epoch(), micros);
+        clock.advance(Duration::from_millis(3));
+        assert_eq!(clock.micros_since_epoch(), micros + 3000);
+    }
+}
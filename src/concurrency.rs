@@ -0,0 +1,1198 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    file::BlockId,
+    tx,
+};
+
+/// how long a lock request waits by default before giving up
+pub const MAX_LOCK_WAIT: Duration = Duration::from_secs(10);
+
+/// the longest a waiting request sleeps before it checks the clock again
+const WAIT_SLICE: Duration = Duration::from_millis(100);
+
+/// how many block locks a transaction may hold in one file by default before they are
+/// escalated to a lock on the file
+pub const LOCK_ESCALATION_THRESHOLD: usize = 1000;
+
+#[derive(Debug)]
+pub enum ConcurrencyError {
+    /// the lock on the block wasn't granted before the waiting time ran out. the transaction
+    /// may be deadlocked, so it should roll back and retry
+    LockAbort(BlockId),
+    /// under `DeadlockPolicy::WaitDie`, an older transaction holds a conflicting lock on the
+    /// block. the transaction should roll back and retry
+    Die(BlockId),
+    /// under `DeadlockPolicy::Detect`, the request for the block closed a cycle of waiting
+    /// transactions, each waiting for the next, and this transaction is the youngest of them.
+    /// it should roll back and retry
+    Deadlock(BlockId, Vec<i32>),
+}
+
+impl fmt::Display for ConcurrencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConcurrencyError::LockAbort(block) => {
+                write!(f, "Timed out waiting for a lock on {}", block)
+            }
+            ConcurrencyError::Die(block) => {
+                write!(f, "An older transaction holds a lock on {}", block)
+            }
+            ConcurrencyError::Deadlock(block, cycle) => write!(
+                f,
+                "Deadlock waiting for a lock on {}: transactions {:?} wait for one another",
+                block, cycle
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConcurrencyError {}
+
+pub type Result<T> = std::result::Result<T, ConcurrencyError>;
+
+/// what the lock table does about requests that could be deadlocked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlockPolicy {
+    /// every conflicting request waits, and fails with `LockAbort` once it waited `max_wait`
+    #[default]
+    Timeout,
+    /// transaction numbers are ages: a request waits only for younger transactions, and fails
+    /// with `Die` at once if an older one is in its way. waits then only ever go from older to
+    /// younger transactions, so they can't form a cycle. `max_wait` still bounds every wait
+    WaitDie,
+    /// conflicting requests wait, and a request that closes a cycle of waits fails the
+    /// youngest transaction of the cycle with `Deadlock`, at once. only actual deadlocks are
+    /// broken this way; `max_wait` still bounds every wait
+    Detect,
+}
+
+/// the transactions holding a lock on one block, and those waiting to lock it exclusively
+#[derive(Debug, Default)]
+struct Lock {
+    shared: HashSet<i32>,
+    exclusive: Option<i32>,
+    exclusive_waiters: Vec<i32>,
+}
+
+impl Lock {
+    /// the transactions a shared request of `txnum` has to wait for: the exclusive holder, and
+    /// the waiting writers, so that a stream of readers can't keep them out forever. a
+    /// transaction that already holds a lock on the block doesn't queue behind writers
+    fn reader_blockers(&self, txnum: i32) -> Vec<i32> {
+        let mut blockers: Vec<i32> = self.exclusive.filter(|&x| x != txnum).into_iter().collect();
+        if !self.shared.contains(&txnum) && self.exclusive != Some(txnum) {
+            blockers.extend(&self.exclusive_waiters);
+        }
+        blockers
+    }
+
+    /// the transactions an exclusive request of `txnum` has to wait for: every other holder
+    fn writer_blockers(&self, txnum: i32) -> Vec<i32> {
+        self.shared
+            .iter()
+            .copied()
+            .chain(self.exclusive)
+            .filter(|&holder| holder != txnum)
+            .collect()
+    }
+
+    fn is_free(&self) -> bool {
+        self.shared.is_empty() && self.exclusive.is_none() && self.exclusive_waiters.is_empty()
+    }
+}
+
+/// how a transaction locks a whole file. the intention modes announce block locks: a
+/// transaction holds `IntentionShared` on a file while it holds shared locks on blocks of it,
+/// and `IntentionExclusive` while it holds exclusive ones, so that nobody locks the whole file
+/// in a conflicting mode meanwhile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLockMode {
+    IntentionShared,
+    IntentionExclusive,
+    Shared,
+    Exclusive,
+}
+
+impl FileLockMode {
+    /// whether one transaction may hold `self` on a file while another one holds `other`
+    pub fn is_compatible(self, other: FileLockMode) -> bool {
+        use FileLockMode::*;
+        match (self, other) {
+            (Exclusive, _) | (_, Exclusive) => false,
+            (IntentionShared, _) | (_, IntentionShared) => true,
+            (IntentionExclusive, IntentionExclusive) | (Shared, Shared) => true,
+            (IntentionExclusive, Shared) | (Shared, IntentionExclusive) => false,
+        }
+    }
+
+    /// whether holding `self` allows everything `other` does
+    fn covers(self, other: FileLockMode) -> bool {
+        use FileLockMode::*;
+        self == other
+            || matches!(
+                (self, other),
+                (Exclusive, _) | (Shared | IntentionExclusive, IntentionShared)
+            )
+    }
+
+    /// the weakest mode that allows everything `self` and `other` do. there is no mode for
+    /// reading the whole file while writing some blocks, so shared and intention exclusive
+    /// make exclusive
+    fn join(self, other: FileLockMode) -> FileLockMode {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            FileLockMode::Exclusive
+        }
+    }
+}
+
+/// the transactions holding a lock on one file, each in the strongest mode it asked for
+#[derive(Debug, Default)]
+struct FileLock {
+    holders: HashMap<i32, FileLockMode>,
+}
+
+impl FileLock {
+    /// the other transactions whose locks conflict with `txnum` holding `mode`, on top of what
+    /// it holds already
+    fn blockers(&self, txnum: i32, mode: FileLockMode) -> Vec<i32> {
+        let wanted = self
+            .holders
+            .get(&txnum)
+            .map_or(mode, |held| held.join(mode));
+        self.holders
+            .iter()
+            .filter(|&(&holder, &held)| holder != txnum && !wanted.is_compatible(held))
+            .map(|(&holder, _)| holder)
+            .collect()
+    }
+
+    fn grant(&mut self, txnum: i32, mode: FileLockMode) {
+        let held = self.holders.entry(txnum).or_insert(mode);
+        *held = held.join(mode);
+    }
+}
+
+/// the block and file locks of a lock table, under one mutex so that requests of either kind
+/// wait on the same condition
+#[derive(Debug, Default)]
+struct Locks {
+    blocks: HashMap<BlockId, Lock>,
+    files: HashMap<String, FileLock>,
+    /// the request each waiting transaction waits with. together with the locks they make
+    /// up the waits-for graph: a waiting transaction waits for the blockers of its request.
+    /// the edges aren't stored but worked out from the locks, so a release removes its own
+    waiting: HashMap<i32, Request>,
+    /// waiting transactions chosen to break a deadlock, with the cycle they were part of
+    victims: HashMap<i32, Vec<i32>>,
+}
+
+/// what a waiting transaction asked for
+#[derive(Debug)]
+enum Request {
+    Block(BlockId, LockType),
+    File(String, FileLockMode),
+}
+
+impl Locks {
+    /// takes the request of `txnum` out of the waits-for graph once it was granted or failed
+    fn stop_waiting(&mut self, txnum: i32) {
+        self.waiting.remove(&txnum);
+        self.victims.remove(&txnum);
+    }
+
+    /// the transactions `txnum` waits for, none if it isn't waiting
+    fn waits_for(&self, txnum: i32) -> Vec<i32> {
+        match self.waiting.get(&txnum) {
+            Some(Request::Block(block, lock_type)) => {
+                self.blocks
+                    .get(block)
+                    .map_or_else(Vec::new, |lock| match lock_type {
+                        LockType::Shared => lock.reader_blockers(txnum),
+                        LockType::Exclusive => lock.writer_blockers(txnum),
+                    })
+            }
+            Some(Request::File(filename, mode)) => self
+                .files
+                .get(filename)
+                .map_or_else(Vec::new, |lock| lock.blockers(txnum, *mode)),
+            None => Vec::new(),
+        }
+    }
+
+    /// a cycle of waits through `txnum`, starting with it, found by a depth-first search of
+    /// the waits-for graph
+    fn find_cycle(&self, txnum: i32) -> Option<Vec<i32>> {
+        let mut path = vec![txnum];
+        let mut visited = HashSet::from([txnum]);
+        self.extend_to_cycle(&mut path, &mut visited)
+            .then_some(path)
+    }
+
+    /// extends `path` with the waits from its last transaction until they lead back to its
+    /// first. returns whether they did
+    fn extend_to_cycle(&self, path: &mut Vec<i32>, visited: &mut HashSet<i32>) -> bool {
+        for blocker in self.waits_for(*path.last().unwrap()) {
+            if blocker == path[0] {
+                return true;
+            }
+            if visited.insert(blocker) {
+                path.push(blocker);
+                if self.extend_to_cycle(path, visited) {
+                    return true;
+                }
+                path.pop();
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug)]
+pub struct LockTableOptions {
+    /// time source for lock timeouts
+    pub clock: Arc<dyn Clock>,
+    /// how long a lock request waits before giving up with `LockAbort`
+    pub max_wait: Duration,
+    pub policy: DeadlockPolicy,
+    /// how many block locks a transaction may hold in one file before its concurrency manager
+    /// escalates them to a lock on the file
+    pub escalation_threshold: usize,
+}
+
+impl Default for LockTableOptions {
+    fn default() -> Self {
+        LockTableOptions {
+            clock: Arc::new(SystemClock),
+            max_wait: MAX_LOCK_WAIT,
+            policy: DeadlockPolicy::default(),
+            escalation_threshold: LOCK_ESCALATION_THRESHOLD,
+        }
+    }
+}
+
+/// the block locks of every transaction of a database. a request that conflicts with a lock
+/// another transaction holds waits until that lock is released, or fails with `LockAbort`
+/// once it waited `max_wait`. with `DeadlockPolicy::Timeout` deadlocks end when one of the
+/// requests times out and its transaction rolls back, `WaitDie` avoids them, and `Detect` finds
+/// them in a waits-for graph and breaks them at once.
+///
+/// Writers are preferred: while an exclusive request waits for a block, shared requests of
+/// transactions that hold no lock on it wait behind it.
+///
+/// Files are locked in a `FileLockMode`, with the same waiting and deadlock policy but no
+/// writer preference. the table doesn't check that block locks come with the intention lock
+/// on their file, that is up to the `ConcurrencyManager`.
+#[derive(Debug)]
+pub struct LockTable {
+    locks: Mutex<Locks>,
+    released: Condvar,
+    clock: Arc<dyn Clock>,
+    max_wait: Duration,
+    policy: DeadlockPolicy,
+    escalation_threshold: usize,
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self::new_with_options(LockTableOptions::default())
+    }
+
+    pub fn new_with_options(options: LockTableOptions) -> Self {
+        Self {
+            locks: Mutex::new(Locks::default()),
+            released: Condvar::new(),
+            clock: options.clock,
+            max_wait: options.max_wait,
+            policy: options.policy,
+            escalation_threshold: options.escalation_threshold,
+        }
+    }
+
+    /// takes a shared lock on `block` for `txnum`, once no other transaction holds it exclusively
+    pub fn slock(&self, block: &BlockId, txnum: i32) -> Result<()> {
+        self.acquire(block, txnum, LockType::Shared)
+    }
+
+    /// takes an exclusive lock on `block` for `txnum`, once no other transaction holds any lock
+    /// on it. a shared lock `txnum` already holds is upgraded
+    pub fn xlock(&self, block: &BlockId, txnum: i32) -> Result<()> {
+        self.acquire(block, txnum, LockType::Exclusive)
+    }
+
+    fn acquire(&self, block: &BlockId, txnum: i32, lock_type: LockType) -> Result<()> {
+        let timestamp = self.clock.now();
+        let mut locks = self.locks.lock().unwrap();
+        let mut waiting = false;
+        let granted = loop {
+            let lock = locks.blocks.entry(block.clone()).or_default();
+            let blockers = match lock_type {
+                LockType::Shared => lock.reader_blockers(txnum),
+                LockType::Exclusive => lock.writer_blockers(txnum),
+            };
+            if blockers.is_empty() {
+                break Ok(());
+            }
+            let request = Request::Block(block.clone(), lock_type);
+            if let Some(err) = self.give_up(&mut locks, request, block, txnum, &blockers, timestamp)
+            {
+                break Err(err);
+            }
+            if lock_type == LockType::Exclusive && !waiting {
+                waiting = true;
+                let lock = locks.blocks.get_mut(block).unwrap();
+                lock.exclusive_waiters.push(txnum);
+            }
+            locks = self
+                .released
+                .wait_timeout(locks, self.remaining_time(timestamp))
+                .unwrap()
+                .0;
+        };
+        locks.stop_waiting(txnum);
+        let lock = locks.blocks.get_mut(block).unwrap();
+        if waiting {
+            lock.exclusive_waiters.retain(|&waiter| waiter != txnum);
+        }
+        match granted {
+            Ok(()) => match lock_type {
+                LockType::Shared => {
+                    lock.shared.insert(txnum);
+                }
+                LockType::Exclusive => lock.exclusive = Some(txnum),
+            },
+            Err(_) => {
+                if lock.is_free() {
+                    locks.blocks.remove(block);
+                }
+                // readers that queued behind this request may go ahead now
+                if waiting {
+                    self.released.notify_all();
+                }
+            }
+        }
+        granted
+    }
+
+    /// releases every lock `txnum` holds on `block`
+    pub fn unlock(&self, block: &BlockId, txnum: i32) {
+        let mut locks = self.locks.lock().unwrap();
+        let Some(lock) = locks.blocks.get_mut(block) else {
+            return;
+        };
+        lock.shared.remove(&txnum);
+        if lock.exclusive == Some(txnum) {
+            lock.exclusive = None;
+        }
+        if lock.is_free() {
+            locks.blocks.remove(block);
+        }
+        self.released.notify_all();
+    }
+
+    /// locks the file of `block` in `mode` for `txnum`, once no other transaction holds a
+    /// conflicting lock on it. a lock `txnum` already holds on the file is upgraded. the lock
+    /// is taken on behalf of a request for `block`, which errors name
+    pub fn lock_file(&self, block: &BlockId, txnum: i32, mode: FileLockMode) -> Result<()> {
+        let timestamp = self.clock.now();
+        let filename = block.filename();
+        let mut locks = self.locks.lock().unwrap();
+        let granted = loop {
+            let lock = locks.files.entry(filename.to_string()).or_default();
+            let blockers = lock.blockers(txnum, mode);
+            if blockers.is_empty() {
+                break Ok(());
+            }
+            let request = Request::File(filename.to_string(), mode);
+            if let Some(err) = self.give_up(&mut locks, request, block, txnum, &blockers, timestamp)
+            {
+                break Err(err);
+            }
+            locks = self
+                .released
+                .wait_timeout(locks, self.remaining_time(timestamp))
+                .unwrap()
+                .0;
+        };
+        locks.stop_waiting(txnum);
+        let lock = locks.files.get_mut(filename).unwrap();
+        match granted {
+            Ok(()) => lock.grant(txnum, mode),
+            Err(_) => {
+                if lock.holders.is_empty() {
+                    locks.files.remove(filename);
+                }
+            }
+        }
+        granted
+    }
+
+    /// like `lock_file`, but gives up at once instead of waiting. returns whether the lock
+    /// was granted
+    pub fn try_lock_file(&self, filename: &str, txnum: i32, mode: FileLockMode) -> bool {
+        let mut locks = self.locks.lock().unwrap();
+        let lock = locks.files.entry(filename.to_string()).or_default();
+        let granted = lock.blockers(txnum, mode).is_empty();
+        if granted {
+            lock.grant(txnum, mode);
+        } else if lock.holders.is_empty() {
+            locks.files.remove(filename);
+        }
+        granted
+    }
+
+    /// releases the lock `txnum` holds on `filename`
+    pub fn unlock_file(&self, filename: &str, txnum: i32) {
+        let mut locks = self.locks.lock().unwrap();
+        let Some(lock) = locks.files.get_mut(filename) else {
+            return;
+        };
+        lock.holders.remove(&txnum);
+        if lock.holders.is_empty() {
+            locks.files.remove(filename);
+        }
+        self.released.notify_all();
+    }
+
+    /// the number of blocks and files that are locked or have a waiting request
+    pub fn entries(&self) -> usize {
+        let locks = self.locks.lock().unwrap();
+        locks.blocks.len() + locks.files.len()
+    }
+
+    /// the error `request` of `txnum`, made for `block` and held up by `blockers` since
+    /// `start`, fails with now, or none if it waits on. the request is entered in the
+    /// waits-for graph meanwhile
+    fn give_up(
+        &self,
+        locks: &mut Locks,
+        request: Request,
+        block: &BlockId,
+        txnum: i32,
+        blockers: &[i32],
+        start: Instant,
+    ) -> Option<ConcurrencyError> {
+        locks.waiting.insert(txnum, request);
+        match self.policy {
+            DeadlockPolicy::Timeout => {}
+            DeadlockPolicy::WaitDie => {
+                if blockers.iter().any(|&b| b < txnum) {
+                    return Some(ConcurrencyError::Die(block.clone()));
+                }
+            }
+            DeadlockPolicy::Detect => {
+                let cycle = locks
+                    .victims
+                    .remove(&txnum)
+                    .or_else(|| locks.find_cycle(txnum));
+                if let Some(cycle) = cycle {
+                    let victim = *cycle.iter().max().unwrap();
+                    if victim == txnum {
+                        return Some(ConcurrencyError::Deadlock(block.clone(), cycle));
+                    }
+                    // the victim is waiting as well, and has to wake up to give up
+                    locks.victims.insert(victim, cycle);
+                    self.released.notify_all();
+                }
+            }
+        }
+        if self.waiting_too_long(start) {
+            return Some(ConcurrencyError::LockAbort(block.clone()));
+        }
+        None
+    }
+
+    fn waiting_too_long(&self, start: Instant) -> bool {
+        self.clock.now().saturating_duration_since(start) > self.max_wait
+    }
+
+    /// how long to sleep before checking the clock again.
+    /// the clock may be advanced by someone else, so never sleep longer than `WAIT_SLICE`.
+    fn remaining_time(&self, start: Instant) -> Duration {
+        let elapsed = self.clock.now().saturating_duration_since(start);
+        self.max_wait
+            .saturating_sub(elapsed)
+            .clamp(Duration::from_millis(1), WAIT_SLICE)
+    }
+}
+
+/// the block standing for the end of `filename`. a transaction's `size` locks it shared and its
+/// `append` exclusively, so that a file doesn't grow under a transaction that read its size
+pub(crate) fn end_of_file(filename: &str) -> BlockId {
+    BlockId::new(filename, -1)
+}
+
+/// how much of the work of concurrent transactions a transaction may see. every level keeps
+/// exclusive locks until the transaction finishes, so none sees another overwrite its updates;
+/// they differ in how long shared locks are held.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// shared locks are held until the transaction finishes, the end of file lock of `size`
+    /// included, so no block it read nor the length of a file changes under it
+    #[default]
+    Serializable,
+    /// like `Serializable`, except that the end of file lock is released once the size was
+    /// read: a file may grow under the transaction, which then sees phantom blocks
+    RepeatableRead,
+    /// a shared lock is released as soon as the read is over. a read waits for the updates
+    /// of a block to be committed, but reading it again may see a newer value
+    ReadCommitted,
+    /// no shared locks at all, so reads see updates that aren't committed and may never be
+    ReadUncommitted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockType {
+    Shared,
+    Exclusive,
+}
+
+/// the locks a transaction was granted, upgrades included, and how long it waited for locks,
+/// granted or not
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LockStats {
+    pub slocks: usize,
+    pub xlocks: usize,
+    pub wait: Duration,
+}
+
+/// the locks of one transaction. it locks a block before the transaction reads or writes it
+/// and keeps every lock until `release`, at commit or rollback (strict two-phase locking), so
+/// that no other transaction writes a block this one read before it finishes.
+///
+/// Before a block lock it takes the matching intention lock on the block's file. once the
+/// transaction holds more block locks in one file than the lock table's escalation threshold,
+/// they are traded for a shared or exclusive lock on the whole file, if that can be had
+/// without waiting.
+///
+/// Below `IsolationLevel::Serializable` some shared locks only last for one read: the
+/// transaction calls `end_read` once it has read the block, and the lock is released then.
+#[derive(Debug)]
+pub struct ConcurrencyManager {
+    tx_num: i32,
+    lock_table: Arc<LockTable>,
+    isolation: IsolationLevel,
+    locks: HashMap<BlockId, LockType>,
+    files: HashMap<String, FileLockMode>,
+    /// the block of a read in progress whose lock `end_read` releases, and whether the
+    /// intention lock on its file was taken for that read alone
+    reading: Option<(BlockId, bool)>,
+    stats: LockStats,
+}
+
+impl ConcurrencyManager {
+    pub fn new(tx_num: i32, lock_table: Arc<LockTable>) -> Self {
+        Self::new_with_isolation(tx_num, lock_table, IsolationLevel::default())
+    }
+
+    pub fn new_with_isolation(
+        tx_num: i32,
+        lock_table: Arc<LockTable>,
+        isolation: IsolationLevel,
+    ) -> Self {
+        Self {
+            tx_num,
+            lock_table,
+            isolation,
+            locks: HashMap::new(),
+            files: HashMap::new(),
+            reading: None,
+            stats: LockStats::default(),
+        }
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    /// locks `block` for reading, unless the transaction already holds a lock on it or its
+    /// file. at `ReadUncommitted` nothing is locked, and a lock the isolation level holds for
+    /// the read alone lasts until `end_read`
+    pub fn slock(&mut self, block: &BlockId) -> tx::Result<()> {
+        let one_read = match self.isolation {
+            IsolationLevel::Serializable => false,
+            IsolationLevel::RepeatableRead => *block == end_of_file(block.filename()),
+            IsolationLevel::ReadCommitted => true,
+            IsolationLevel::ReadUncommitted => return Ok(()),
+        };
+        // a read that failed before it ended doesn't keep its lock
+        self.end_read();
+        if self.lock_type(block).is_some() {
+            return Ok(());
+        }
+        let file_locked = self.files.contains_key(block.filename());
+        self.lock_file(block, FileLockMode::IntentionShared)?;
+        if let Err(err) = self.timed(|table, txnum| table.slock(block, txnum)) {
+            if one_read && !file_locked {
+                self.unlock_file(block.filename());
+            }
+            return Err(err.into());
+        }
+        self.stats.slocks += 1;
+        if one_read {
+            self.reading = Some((block.clone(), !file_locked));
+        } else {
+            self.locks.insert(block.clone(), LockType::Shared);
+            self.escalate(block.filename());
+        }
+        Ok(())
+    }
+
+    /// releases the lock `slock` took for the read that is over, if the isolation level
+    /// doesn't hold it until the transaction finishes
+    pub fn end_read(&mut self) {
+        if let Some((block, file_too)) = self.reading.take() {
+            self.lock_table.unlock(&block, self.tx_num);
+            if file_too {
+                self.unlock_file(block.filename());
+            }
+        }
+    }
+
+    fn unlock_file(&mut self, filename: &str) {
+        self.files.remove(filename);
+        self.lock_table.unlock_file(filename, self.tx_num);
+    }
+
+    /// locks `block` for writing, upgrading a shared lock the transaction holds on it
+    pub fn xlock(&mut self, block: &BlockId) -> tx::Result<()> {
+        self.end_read();
+        if self.lock_type(block) != Some(LockType::Exclusive) {
+            self.lock_file(block, FileLockMode::IntentionExclusive)?;
+            self.timed(|table, txnum| table.xlock(block, txnum))?;
+            self.locks.insert(block.clone(), LockType::Exclusive);
+            self.stats.xlocks += 1;
+            self.escalate(block.filename());
+        }
+        Ok(())
+    }
+
+    /// takes `mode` on the file of `block`, unless the transaction holds it already
+    fn lock_file(&mut self, block: &BlockId, mode: FileLockMode) -> Result<()> {
+        let held = self.files.get(block.filename()).copied();
+        if held.is_some_and(|held| held.covers(mode)) {
+            return Ok(());
+        }
+        self.timed(|table, txnum| table.lock_file(block, txnum, mode))?;
+        let mode = held.map_or(mode, |held| held.join(mode));
+        self.files.insert(block.filename().to_string(), mode);
+        Ok(())
+    }
+
+    /// trades the block locks in `filename` for a lock on the file, if there are more than the
+    /// threshold and nobody else is in the way
+    fn escalate(&mut self, filename: &str) {
+        let blocks: Vec<(BlockId, LockType)> = self
+            .locks
+            .iter()
+            .filter(|(block, _)| block.filename() == filename)
+            .map(|(block, &lock_type)| (block.clone(), lock_type))
+            .collect();
+        if blocks.len() <= self.lock_table.escalation_threshold {
+            return;
+        }
+        let mode = if blocks.iter().any(|&(_, t)| t == LockType::Exclusive) {
+            FileLockMode::Exclusive
+        } else {
+            FileLockMode::Shared
+        };
+        if !self.lock_table.try_lock_file(filename, self.tx_num, mode) {
+            return;
+        }
+        let held = self.files.get_mut(filename).unwrap();
+        *held = held.join(mode);
+        for (block, _) in blocks {
+            self.locks.remove(&block);
+            self.lock_table.unlock(&block, self.tx_num);
+        }
+    }
+
+    pub fn stats(&self) -> LockStats {
+        self.stats
+    }
+
+    /// runs the lock request `request`, adding the time it took to the lock wait, by the clock
+    /// of the lock table
+    fn timed(&mut self, request: impl FnOnce(&LockTable, i32) -> Result<()>) -> Result<()> {
+        let start = self.lock_table.clock.now();
+        let res = request(&self.lock_table, self.tx_num);
+        self.stats.wait += self.lock_table.clock.now().duration_since(start);
+        res
+    }
+
+    /// the lock the transaction holds on `block`, if any, on the block itself or on its file
+    pub fn lock_type(&self, block: &BlockId) -> Option<LockType> {
+        let file = match self.files.get(block.filename()) {
+            Some(FileLockMode::Exclusive) => Some(LockType::Exclusive),
+            Some(FileLockMode::Shared) => Some(LockType::Shared),
+            Some(FileLockMode::IntentionShared | FileLockMode::IntentionExclusive) | None => None,
+        };
+        match (self.locks.get(block).copied(), file) {
+            (Some(LockType::Exclusive), _) | (_, Some(LockType::Exclusive)) => {
+                Some(LockType::Exclusive)
+            }
+            (block, file) => block.or(file),
+        }
+    }
+
+    /// the lock the transaction holds on `filename` itself, if any
+    pub fn file_lock_mode(&self, filename: &str) -> Option<FileLockMode> {
+        self.files.get(filename).copied()
+    }
+
+    /// releases every lock the transaction holds
+    pub fn release(&mut self) {
+        self.end_read();
+        for (block, _) in self.locks.drain() {
+            self.lock_table.unlock(&block, self.tx_num);
+        }
+        for (filename, _) in self.files.drain() {
+            self.lock_table.unlock_file(&filename, self.tx_num);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicI32, Ordering},
+            mpsc, Barrier,
+        },
+        thread,
+    };
+
+    use super::{ConcurrencyError::*, *};
+    use crate::{clock::ManualClock, tx::TransactionError};
+
+    /// runs `f` on another thread and reports whether it finished within a short wait
+    fn finishes_soon(f: impl FnOnce() + Send + 'static) -> (bool, thread::JoinHandle<()>) {
+        let (done_tx, done_rx) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            f();
+            // the receiver is gone if the wait is over
+            let _ = done_tx.send(());
+        });
+        let done = done_rx.recv_timeout(Duration::from_millis(50)).is_ok();
+        (done, handle)
+    }
+
+    #[test]
+    fn shared_locks_are_compatible_and_exclusive_ones_are_not() {
+        let table = Arc::new(LockTable::new());
+        let block = BlockId::new("testfile", 0);
+        let mut a = ConcurrencyManager::new(1, Arc::clone(&table));
+        let mut b = ConcurrencyManager::new(2, Arc::clone(&table));
+
+        a.slock(&block).unwrap();
+        b.slock(&block).unwrap();
+        assert_eq!(a.lock_type(&block), Some(LockType::Shared));
+        b.release();
+
+        // a is the only reader left, so it upgrades without waiting
+        a.xlock(&block).unwrap();
+        assert_eq!(a.lock_type(&block), Some(LockType::Exclusive));
+        a.slock(&block).unwrap();
+        assert_eq!(a.lock_type(&block), Some(LockType::Exclusive));
+
+        let (done, handle) = finishes_soon({
+            let (table, block) = (Arc::clone(&table), block.clone());
+            move || {
+                let mut b = ConcurrencyManager::new(2, table);
+                b.slock(&block).unwrap();
+                b.release();
+            }
+        });
+        assert!(!done);
+        a.release();
+        handle.join().unwrap();
+        assert_eq!(table.entries(), 0);
+    }
+
+    #[test]
+    fn readers_queue_behind_a_waiting_writer() {
+        let table = Arc::new(LockTable::new());
+        let block = BlockId::new("testfile", 0);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let request = |txnum: i32, lock: LockType| {
+            let (table, block, events) = (Arc::clone(&table), block.clone(), Arc::clone(&events));
+            move || {
+                let mut cm = ConcurrencyManager::new(txnum, table);
+                match lock {
+                    LockType::Shared => cm.slock(&block).unwrap(),
+                    LockType::Exclusive => cm.xlock(&block).unwrap(),
+                }
+                events.lock().unwrap().push(txnum);
+                cm.release();
+            }
+        };
+
+        let mut reader = ConcurrencyManager::new(1, Arc::clone(&table));
+        reader.slock(&block).unwrap();
+        let (done, writer) = finishes_soon(request(2, LockType::Exclusive));
+        assert!(!done);
+        // the block is only locked shared, but the writer came first
+        let (done, late_reader) = finishes_soon(request(3, LockType::Shared));
+        assert!(!done);
+        // a reader that holds a lock already isn't queued
+        table.slock(&block, 1).unwrap();
+
+        reader.release();
+        writer.join().unwrap();
+        late_reader.join().unwrap();
+        assert_eq!(*events.lock().unwrap(), [2, 3]);
+    }
+
+    #[test]
+    fn a_deadlock_ends_when_a_request_times_out() {
+        let max_wait = Duration::from_millis(200);
+        let table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            max_wait,
+            ..Default::default()
+        }));
+        let blocks = [BlockId::new("testfile", 1), BlockId::new("testfile", 2)];
+        let both_locked = Arc::new(Barrier::new(2));
+
+        let started = Instant::now();
+        let handles: Vec<_> = (0..2)
+            .map(|i| {
+                let (table, blocks) = (Arc::clone(&table), blocks.clone());
+                let both_locked = Arc::clone(&both_locked);
+                thread::spawn(move || {
+                    let mut cm = ConcurrencyManager::new(i as i32 + 1, table);
+                    cm.xlock(&blocks[i]).unwrap();
+                    both_locked.wait();
+                    let res = cm.xlock(&blocks[1 - i]);
+                    // an aborted transaction rolls back, releasing what it holds
+                    cm.release();
+                    res
+                })
+            })
+            .collect();
+        let results: Vec<tx::Result<()>> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert!(results
+            .iter()
+            .any(|res| matches!(res, Err(TransactionError::ConcurrencyError(LockAbort(_))))));
+        assert!(started.elapsed() < max_wait * 5);
+        assert_eq!(table.entries(), 0);
+    }
+
+    #[test]
+    fn requests_give_up_after_max_wait() {
+        let clock = Arc::new(ManualClock::new());
+        let table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        }));
+        let block = BlockId::new("testfile", 0);
+        table.xlock(&block, 1).unwrap();
+
+        thread::scope(|s| {
+            let waiting = s.spawn(|| table.slock(&block, 2));
+            while !waiting.is_finished() {
+                clock.advance(MAX_LOCK_WAIT / 10);
+                thread::sleep(Duration::from_millis(10));
+            }
+            let res = waiting.join().unwrap();
+            assert!(matches!(res, Err(ConcurrencyError::LockAbort(b)) if b == block));
+        });
+        // the aborted request left no trace
+        table.unlock(&block, 1);
+        assert_eq!(table.entries(), 0);
+    }
+
+    #[test]
+    fn lock_stats_count_grants_and_the_time_spent_waiting() {
+        let clock = Arc::new(ManualClock::new());
+        let table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            clock: clock.clone(),
+            ..Default::default()
+        }));
+        let block = BlockId::new("testfile", 0);
+        let mut a = ConcurrencyManager::new(1, Arc::clone(&table));
+        let mut b = ConcurrencyManager::new(2, Arc::clone(&table));
+
+        a.slock(&block).unwrap();
+        a.xlock(&block).unwrap();
+        a.xlock(&block).unwrap();
+        let b = thread::scope(|s| {
+            let waiting = s.spawn(move || {
+                assert!(b.slock(&block).is_err());
+                b
+            });
+            while !waiting.is_finished() {
+                clock.advance(MAX_LOCK_WAIT / 10);
+                thread::sleep(Duration::from_millis(10));
+            }
+            waiting.join().unwrap()
+        });
+        assert_eq!(
+            a.stats(),
+            LockStats {
+                slocks: 1,
+                xlocks: 1,
+                wait: Duration::ZERO,
+            }
+        );
+        // the request that gave up isn't counted, its wait is
+        assert_eq!(b.stats().slocks, 0);
+        assert!(b.stats().wait >= MAX_LOCK_WAIT);
+    }
+
+    #[test]
+    fn file_lock_modes_follow_the_compatibility_matrix() {
+        use FileLockMode::*;
+        let modes = [IntentionShared, IntentionExclusive, Shared, Exclusive];
+        // rows: the mode held, columns: the mode requested, in the order of `modes`
+        let matrix = [
+            [true, true, true, false],
+            [true, true, false, false],
+            [true, false, true, false],
+            [false, false, false, false],
+        ];
+        for (held, row) in modes.iter().zip(matrix) {
+            for (requested, compatible) in modes.iter().zip(row) {
+                assert_eq!(held.is_compatible(*requested), compatible);
+                let table = LockTable::new();
+                assert!(table.try_lock_file("testfile", 1, *held));
+                assert_eq!(
+                    table.try_lock_file("testfile", 2, *requested),
+                    compatible,
+                    "{:?} held, {:?} requested",
+                    held,
+                    requested
+                );
+                // a transaction never conflicts with itself
+                assert!(table.try_lock_file("testfile", 1, *requested));
+            }
+        }
+    }
+
+    #[test]
+    fn block_locks_are_escalated_to_a_file_lock_past_the_threshold() {
+        let table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            policy: DeadlockPolicy::WaitDie,
+            escalation_threshold: 3,
+            ..Default::default()
+        }));
+        let blocks: Vec<BlockId> = (0..5).map(|n| BlockId::new("testfile", n)).collect();
+        let mut a = ConcurrencyManager::new(1, Arc::clone(&table));
+        let mut b = ConcurrencyManager::new(2, Arc::clone(&table));
+
+        for block in &blocks[..3] {
+            a.slock(block).unwrap();
+        }
+        // three blocks and the intention lock on their file
+        assert_eq!(table.entries(), 4);
+        assert_eq!(
+            a.file_lock_mode("testfile"),
+            Some(FileLockMode::IntentionShared)
+        );
+        a.slock(&blocks[3]).unwrap();
+        assert_eq!(table.entries(), 1);
+        assert_eq!(a.file_lock_mode("testfile"), Some(FileLockMode::Shared));
+        // the file lock covers blocks a never locked one by one
+        a.slock(&blocks[4]).unwrap();
+        assert_eq!(a.lock_type(&blocks[4]), Some(LockType::Shared));
+        assert_eq!(table.entries(), 1);
+
+        // other readers still get in, writers don't
+        b.slock(&blocks[0]).unwrap();
+        assert!(matches!(
+            b.xlock(&blocks[1]),
+            Err(TransactionError::ConcurrencyError(Die(_)))
+        ));
+        b.slock(&BlockId::new("otherfile", 0)).unwrap();
+        b.release();
+
+        // writing a block upgrades the file lock
+        a.xlock(&blocks[0]).unwrap();
+        assert_eq!(a.file_lock_mode("testfile"), Some(FileLockMode::Exclusive));
+        a.release();
+        assert_eq!(table.entries(), 0);
+    }
+
+    #[test]
+    fn wait_die_aborts_the_younger_transaction_of_a_cycle_at_once() {
+        let table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            policy: DeadlockPolicy::WaitDie,
+            ..Default::default()
+        }));
+        let blocks = [BlockId::new("testfile", 1), BlockId::new("testfile", 2)];
+        let mut older = ConcurrencyManager::new(1, Arc::clone(&table));
+        let mut younger = ConcurrencyManager::new(2, Arc::clone(&table));
+        older.xlock(&blocks[0]).unwrap();
+        younger.xlock(&blocks[1]).unwrap();
+
+        let started = Instant::now();
+        let (done, older_waits) = finishes_soon({
+            let blocks = blocks.clone();
+            move || {
+                older.slock(&blocks[1]).unwrap();
+                older.release();
+            }
+        });
+        assert!(!done);
+        let res = younger.xlock(&blocks[0]);
+        assert!(matches!(res, Err(TransactionError::ConcurrencyError(Die(b))) if b == blocks[0]));
+        // so does a younger reader
+        let mut reader = ConcurrencyManager::new(3, Arc::clone(&table));
+        assert!(matches!(
+            reader.slock(&blocks[0]),
+            Err(TransactionError::ConcurrencyError(Die(_)))
+        ));
+        // it keeps the intention lock on the file until it finishes, like its other locks
+        reader.release();
+        younger.release();
+        older_waits.join().unwrap();
+        assert!(started.elapsed() < MAX_LOCK_WAIT / 10);
+        assert_eq!(table.entries(), 0);
+    }
+
+    fn detecting_table() -> Arc<LockTable> {
+        Arc::new(LockTable::new_with_options(LockTableOptions {
+            policy: DeadlockPolicy::Detect,
+            ..Default::default()
+        }))
+    }
+
+    fn sorted(mut cycle: Vec<i32>) -> Vec<i32> {
+        cycle.sort();
+        cycle
+    }
+
+    #[test]
+    fn a_deadlock_of_two_is_detected_at_once() {
+        let table = detecting_table();
+        let blocks = [BlockId::new("testfile", 1), BlockId::new("testfile", 2)];
+        let mut older = ConcurrencyManager::new(1, Arc::clone(&table));
+        let mut younger = ConcurrencyManager::new(2, Arc::clone(&table));
+        older.xlock(&blocks[0]).unwrap();
+        younger.xlock(&blocks[1]).unwrap();
+
+        let started = Instant::now();
+        let (done, older_waits) = finishes_soon({
+            let blocks = blocks.clone();
+            move || {
+                older.xlock(&blocks[1]).unwrap();
+                older.release();
+            }
+        });
+        assert!(!done);
+        // the younger transaction closes the cycle, and is the one to give up
+        match younger.xlock(&blocks[0]) {
+            Err(TransactionError::ConcurrencyError(Deadlock(b, cycle))) => {
+                assert_eq!(b, blocks[0]);
+                assert_eq!(cycle, [2, 1]);
+            }
+            res => panic!("expected a deadlock, got {:?}", res),
+        }
+        younger.release();
+        older_waits.join().unwrap();
+        assert!(started.elapsed() < MAX_LOCK_WAIT / 10);
+        assert_eq!(table.entries(), 0);
+    }
+
+    #[test]
+    fn the_youngest_transaction_of_a_deadlock_gives_up_even_if_it_waited_first() {
+        let table = detecting_table();
+        let blocks: Vec<BlockId> = (0..3).map(|i| BlockId::new("testfile", i)).collect();
+        let mut txs: Vec<ConcurrencyManager> = (1..=3)
+            .map(|txnum| ConcurrencyManager::new(txnum, Arc::clone(&table)))
+            .collect();
+        for (tx, block) in txs.iter_mut().zip(&blocks) {
+            tx.xlock(block).unwrap();
+        }
+        let mut third = txs.pop().unwrap();
+        let mut second = txs.pop().unwrap();
+        let mut first = txs.pop().unwrap();
+
+        let started = Instant::now();
+        // 2 waits for 3, and 3 for 1
+        let (done, second_waits) = finishes_soon({
+            let block = blocks[2].clone();
+            move || {
+                second.xlock(&block).unwrap();
+                second.release();
+            }
+        });
+        assert!(!done);
+        let (res_tx, res_rx) = mpsc::channel();
+        let (done, third_waits) = finishes_soon({
+            let block = blocks[0].clone();
+            move || {
+                res_tx.send(third.xlock(&block)).unwrap();
+                third.release();
+            }
+        });
+        assert!(!done);
+        // 1 waiting for 2 closes the cycle. 3 gives up, and 2 and then 1 get their locks
+        first.xlock(&blocks[1]).unwrap();
+        match res_rx.recv().unwrap() {
+            Err(TransactionError::ConcurrencyError(Deadlock(b, cycle))) => {
+                assert_eq!(b, blocks[0]);
+                assert_eq!(sorted(cycle), [1, 2, 3]);
+            }
+            res => panic!("expected a deadlock, got {:?}", res),
+        }
+        first.release();
+        second_waits.join().unwrap();
+        third_waits.join().unwrap();
+        assert!(started.elapsed() < MAX_LOCK_WAIT / 10);
+        assert_eq!(table.entries(), 0);
+    }
+
+    #[test]
+    fn contention_without_cycles_never_reports_a_deadlock() {
+        let table = detecting_table();
+        let txnums = Arc::new(AtomicI32::new(0));
+        let blocks: Vec<BlockId> = (0..4).map(|i| BlockId::new("testfile", i)).collect();
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let (table, txnums, blocks) =
+                    (Arc::clone(&table), Arc::clone(&txnums), blocks.clone());
+                thread::spawn(move || {
+                    let mut failures = 0;
+                    for _ in 0..50 {
+                        let txnum = txnums.fetch_add(1, Ordering::SeqCst);
+                        let mut tx = ConcurrencyManager::new(txnum, Arc::clone(&table));
+                        // every transaction locks the blocks in the same order, so waits
+                        // can't go around in a circle
+                        for (i, block) in blocks.iter().enumerate() {
+                            let res = match i % 2 {
+                                0 => tx.slock(block),
+                                _ => tx.xlock(block),
+                            };
+                            if res.is_err() {
+                                failures += 1;
+                                break;
+                            }
+                        }
+                        tx.release();
+                    }
+                    failures
+                })
+            })
+            .collect();
+        let failures: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(failures, 0);
+        assert_eq!(table.entries(), 0);
+    }
+}
@@ -25,6 +25,14 @@ impl fmt::Display for FileError {
     }
 }
 
+impl std::error::Error for FileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileError::IoError(err) => Some(err),
+        }
+    }
+}
+
 impl From<io::Error> for FileError {
     fn from(value: io::Error) -> Self {
         FileError::IoError(value)
@@ -34,6 +42,11 @@ impl From<io::Error> for FileError {
 pub type Result<T> = std::result::Result<T, FileError>;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
 struct BlockNum(pub i32);
 impl BlockNum {
     pub fn as_i32(&self) -> i32 {
@@ -43,8 +56,10 @@ impl BlockNum {
 
 /// `BlockId` identifies a specific block by its file name and logical block number
 #[derive(Debug, Hash, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BlockId {
     filename: String,
+    #[cfg_attr(feature = "serde", serde(rename = "number"))]
     block_id: BlockNum,
 }
 
@@ -85,19 +100,26 @@ impl Page {
         }
     }
 
+    /// fills `buf` with the bytes starting at offset value.
+    /// reading never moves the write position, so it only needs `&self`.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut cursor = Cursor::new(self.bb.get_ref().as_slice());
+        cursor.set_position(offset);
+        cursor.read_exact(buf)?;
+        Ok(())
+    }
+
     /// read 8 bytes from offset value
-    pub fn get_u64(&mut self, offset: u64) -> Result<u64> {
-        self.bb.seek(SeekFrom::Start(offset))?;
-        let buf: &mut [u8; U64_SIZE] = &mut [0; U64_SIZE];
-        self.bb.read_exact(buf)?;
-        Ok(u64::from_be_bytes(*buf))
+    pub fn get_u64(&self, offset: u64) -> Result<u64> {
+        let mut buf: [u8; U64_SIZE] = [0; U64_SIZE];
+        self.read_at(offset, &mut buf)?;
+        Ok(u64::from_be_bytes(buf))
     }
 
     /// read 4 bytes from offset value
-    pub fn get_i32(&mut self, offset: u64) -> Result<i32> {
-        self.bb.seek(SeekFrom::Start(offset))?;
+    pub fn get_i32(&self, offset: u64) -> Result<i32> {
         let mut buf: [u8; I32_SIZE] = [0; I32_SIZE];
-        self.bb.read_exact(&mut buf)?;
+        self.read_at(offset, &mut buf)?;
         Ok(i32::from_be_bytes(buf))
     }
 
@@ -117,11 +139,20 @@ impl Page {
         Ok(())
     }
 
-    /// read 4 bytes and return it
-    pub fn get_bytes(&mut self, offset: u64) -> Result<Vec<u8>> {
-        let len = self.get_i32(offset)? as usize;
-        let mut buf = vec![0; len];
-        self.bb.read_exact(buf.as_mut())?;
+    /// read 4 bytes and return it.
+    /// the stored length is checked against the page, so a corrupt length is an error
+    pub fn get_bytes(&self, offset: u64) -> Result<Vec<u8>> {
+        let len = self.get_i32(offset)?;
+        let available = (self.bytes().len() as u64).saturating_sub(offset + I32_SIZE as u64);
+        if len < 0 || len as u64 > available {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} bytes stored at {} don't fit in the page", len, offset),
+            )
+            .into());
+        }
+        let mut buf = vec![0; len as usize];
+        self.read_at(offset + I32_SIZE as u64, &mut buf)?;
         Ok(buf)
     }
 
@@ -132,9 +163,10 @@ impl Page {
     }
 
     /// read 4bytes and convert it to String
-    pub fn get_string(&mut self, offset: u64) -> Result<String> {
+    pub fn get_string(&self, offset: u64) -> Result<String> {
         let byte = self.get_bytes(offset)?;
-        Ok(String::from_utf8(byte).unwrap())
+        String::from_utf8(byte)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err).into())
     }
 
     pub fn set_string(&mut self, offset: u64, s: &str) -> Result<()> {
@@ -149,6 +181,10 @@ impl Page {
     pub fn contents(&mut self) -> &mut Vec<u8> {
         self.bb.get_mut()
     }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.bb.get_ref()
+    }
 }
 
 impl From<Vec<u8>> for Page {
@@ -157,6 +193,40 @@ impl From<Vec<u8>> for Page {
     }
 }
 
+/// I/O counters of a `BlockStorage`, counted in blocks
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileStats {
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    pub blocks_appended: u64,
+}
+
+/// block-level storage used by the buffer pool. `FileManager` is the on-disk implementation,
+/// `MemoryStorage` the one that keeps nothing once dropped.
+pub trait BlockStorage: fmt::Debug + Send {
+    fn block_size(&self) -> i32;
+
+    fn stats(&self) -> FileStats;
+
+    /// reads `block_id` into `p`
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> Result<()>;
+
+    /// writes `p` to `block_id`
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> Result<()>;
+
+    /// appends an empty block to `filename` and returns it
+    fn append(&mut self, filename: &str) -> Result<BlockId>;
+
+    /// the number of blocks in `filename`
+    fn length(&mut self, filename: &str) -> Result<i32>;
+
+    /// whether `filename` exists, without creating it
+    fn exists(&self, filename: &str) -> bool;
+
+    /// shrinks `filename` to its first `len` blocks. a file that is already shorter is left alone
+    fn truncate(&mut self, filename: &str, len: i32) -> Result<()>;
+}
+
 /// Read and Write pages to disk blocks
 #[derive(Debug)]
 pub struct FileManager {
@@ -164,10 +234,17 @@ pub struct FileManager {
     db_dir: PathBuf,
     block_size: i32,
     is_new: bool,
+    stats: FileStats,
 }
 
 impl FileManager {
     pub fn new(db_dir: impl AsRef<Path>) -> Result<Self> {
+        Self::new_with_block_size(db_dir, BLOCK_SIZE)
+    }
+
+    /// like `new`, for blocks of `block_size` bytes rather than `BLOCK_SIZE`. a database has
+    /// to be opened with the block size it was created with
+    pub fn new_with_block_size(db_dir: impl AsRef<Path>, block_size: i32) -> Result<Self> {
         let is_exist = db_dir.as_ref().exists();
         if !is_exist {
             fs::create_dir_all(&db_dir).expect("Failed to create dir");
@@ -180,9 +257,10 @@ impl FileManager {
         }
         Ok(FileManager {
             db_dir: db_dir.as_ref().to_path_buf(),
-            block_size: BLOCK_SIZE,
+            block_size,
             open_files: HashMap::new(),
             is_new: !is_exist,
+            stats: FileStats::default(),
         })
     }
 
@@ -194,6 +272,16 @@ impl FileManager {
         self.is_new
     }
 
+    /// whether `filename` exists in the database directory. unlike the other methods,
+    /// this never creates the file.
+    pub fn exists(&self, filename: &str) -> bool {
+        self.open_files.contains_key(filename) || self.db_dir.join(filename).exists()
+    }
+
+    pub fn stats(&self) -> FileStats {
+        self.stats
+    }
+
     pub fn length(&mut self, filename: &str) -> Result<i32> {
         let f = self.get_file(filename)?;
         let file_size = f.lock().unwrap().metadata()?.len() as i32;
@@ -202,46 +290,59 @@ impl FileManager {
 
     pub fn read(&mut self, block_id: &BlockId, p: &mut Page) -> Result<()> {
         let offset = self.block_size() * block_id.number();
-        match self.get_file(block_id.filename()) {
-            Ok(file) => {
-                let mut f = file.lock().expect("Failed to lock");
-                f.seek(SeekFrom::Start(offset as u64))?;
-                let _ = f.read(p.contents())?;
-            }
-            Err(_) => todo!(),
+        {
+            let mut f = self
+                .get_file(block_id.filename())?
+                .lock()
+                .expect("Failed to lock");
+            f.seek(SeekFrom::Start(offset as u64))?;
+            let _ = f.read(p.contents())?;
         }
+        self.stats.blocks_read += 1;
         Ok(())
     }
 
-    pub fn write(&mut self, block_id: &BlockId, p: &mut Page) -> Result<()> {
+    pub fn write(&mut self, block_id: &BlockId, p: &Page) -> Result<()> {
         let offset = self.block_size() * block_id.number();
-        match self.get_file(block_id.filename()) {
-            Ok(file) => {
-                let mut f = file.lock().expect("Failed to lock");
-                f.seek(SeekFrom::Start(offset as u64))?;
-                f.write_all(p.contents())?;
-            }
-            Err(_) => todo!(),
+        {
+            let mut f = self
+                .get_file(block_id.filename())?
+                .lock()
+                .expect("Failed to lock");
+            f.seek(SeekFrom::Start(offset as u64))?;
+            f.write_all(p.bytes())?;
         }
+        self.stats.blocks_written += 1;
         Ok(())
     }
 
-    /// `append` seeks to the end of the file and writes an empty array of bytes to it,
+    /// `append` seeks to the end of the file and writes an empty block to it,
     ///  which  causes the OS to automatically extend the file.
     pub fn append(&mut self, filename: &str) -> Result<BlockId> {
-        let blk_num = filename.len() as i32;
+        let blk_num = self.length(filename)?;
         let block = BlockId::new(filename.to_string(), blk_num);
         let offset = self.block_size * block.number();
 
-        let empty_buf = &[];
+        let empty_buf = vec![0; self.block_size as usize];
         {
             let mut file = self.get_file(filename)?.lock().expect("Failed to lock");
             file.seek(SeekFrom::Start(offset as u64))?;
-            file.write_all(empty_buf)?;
+            file.write_all(&empty_buf)?;
         }
+        self.stats.blocks_appended += 1;
         Ok(block)
     }
 
+    /// shrinks `filename` to its first `len` blocks. a file that is already shorter is left alone
+    pub fn truncate(&mut self, filename: &str, len: i32) -> Result<()> {
+        if self.length(filename)? <= len {
+            return Ok(());
+        }
+        let size = self.block_size() as u64 * len.max(0) as u64;
+        self.get_file(filename)?.lock().unwrap().set_len(size)?;
+        Ok(())
+    }
+
     pub fn get_file(&mut self, filename: &str) -> Result<&mut Arc<Mutex<File>>> {
         match self.open_files.entry(filename.to_string()) {
             Entry::Occupied(entry) => Ok(entry.into_mut()),
@@ -251,6 +352,7 @@ impl FileManager {
                     .write(true)
                     .read(true)
                     .create(true)
+                    .truncate(false)
                     .open(path)?;
                 Ok(entry.insert(Arc::new(Mutex::new(f))))
             }
@@ -258,6 +360,126 @@ impl FileManager {
     }
 }
 
+impl BlockStorage for FileManager {
+    fn block_size(&self) -> i32 {
+        FileManager::block_size(self)
+    }
+
+    fn stats(&self) -> FileStats {
+        FileManager::stats(self)
+    }
+
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> Result<()> {
+        FileManager::read(self, block_id, p)
+    }
+
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> Result<()> {
+        FileManager::write(self, block_id, p)
+    }
+
+    fn append(&mut self, filename: &str) -> Result<BlockId> {
+        FileManager::append(self, filename)
+    }
+
+    fn length(&mut self, filename: &str) -> Result<i32> {
+        FileManager::length(self, filename)
+    }
+
+    fn exists(&self, filename: &str) -> bool {
+        FileManager::exists(self, filename)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> Result<()> {
+        FileManager::truncate(self, filename, len)
+    }
+}
+
+/// blocks kept in memory, for databases that needn't outlive the process
+#[derive(Debug)]
+pub struct MemoryStorage {
+    files: HashMap<String, Vec<Vec<u8>>>,
+    block_size: i32,
+    stats: FileStats,
+}
+
+impl MemoryStorage {
+    pub fn new(block_size: i32) -> Self {
+        MemoryStorage {
+            files: HashMap::new(),
+            block_size,
+            stats: FileStats::default(),
+        }
+    }
+
+    fn file(&mut self, filename: &str) -> &mut Vec<Vec<u8>> {
+        self.files.entry(filename.to_string()).or_default()
+    }
+}
+
+impl BlockStorage for MemoryStorage {
+    fn block_size(&self) -> i32 {
+        self.block_size
+    }
+
+    fn stats(&self) -> FileStats {
+        self.stats
+    }
+
+    /// a block past the end of its file reads as zeros
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> Result<()> {
+        match self
+            .files
+            .get(block_id.filename())
+            .and_then(|blocks| blocks.get(block_id.number() as usize))
+        {
+            Some(bytes) => p.contents().copy_from_slice(bytes),
+            None => p.contents().fill(0),
+        }
+        self.stats.blocks_read += 1;
+        Ok(())
+    }
+
+    /// like a file, writing past the end fills the gap with empty blocks
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> Result<()> {
+        let block_size = self.block_size as usize;
+        let n = block_id.number() as usize;
+        let blocks = self.file(block_id.filename());
+        if blocks.len() <= n {
+            blocks.resize(n + 1, vec![0; block_size]);
+        }
+        blocks[n].copy_from_slice(p.bytes());
+        self.stats.blocks_written += 1;
+        Ok(())
+    }
+
+    fn append(&mut self, filename: &str) -> Result<BlockId> {
+        let empty = vec![0; self.block_size as usize];
+        let blocks = self.file(filename);
+        blocks.push(empty);
+        let block = BlockId::new(filename, blocks.len() as i32 - 1);
+        self.stats.blocks_appended += 1;
+        Ok(block)
+    }
+
+    fn length(&mut self, filename: &str) -> Result<i32> {
+        Ok(self
+            .files
+            .get(filename)
+            .map_or(0, |blocks| blocks.len() as i32))
+    }
+
+    fn exists(&self, filename: &str) -> bool {
+        self.files.contains_key(filename)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> Result<()> {
+        if let Some(blocks) = self.files.get_mut(filename) {
+            blocks.truncate(len.max(0) as usize);
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -292,6 +514,21 @@ mod tests {
         assert_eq!(p2.get_i32(0).unwrap(), 0);
     }
 
+    #[test]
+    fn corrupt_lengths_and_strings_are_errors() {
+        let mut p = Page::new(16);
+        p.set_i32(0, -1).unwrap();
+        assert!(p.get_bytes(0).is_err());
+        p.set_i32(0, 13).unwrap();
+        assert!(p.get_bytes(0).is_err());
+        p.set_i32(0, 12).unwrap();
+        assert_eq!(p.get_bytes(0).unwrap().len(), 12);
+        assert!(p.get_bytes(14).is_err());
+
+        p.set_bytes(0, &[0xff, 0xfe]).unwrap();
+        assert!(p.get_string(0).is_err());
+    }
+
     #[test]
     fn set_and_get_string_from_page() {
         let mut page = Page::new(4096);
@@ -314,7 +551,7 @@ mod tests {
         let mut p = Page::new(BLOCK_SIZE);
         p.set_string(0, "sample text").unwrap();
 
-        file_manager.write(&block, &mut p).unwrap();
+        file_manager.write(&block, &p).unwrap();
         file_manager.read(&block, &mut p).unwrap();
         assert_eq!(p.get_string(0).unwrap(), "sample text");
 
@@ -344,11 +581,43 @@ mod tests {
         let size = Page::max_length("abcdefghijklm".len()) as u64;
         let pos_2 = pos_1 + size;
         p1.set_i32(pos_2, 345).unwrap();
-        fm.write(&block_id, &mut p1).unwrap();
+        fm.write(&block_id, &p1).unwrap();
 
         if path.to_owned().exists() {
             fs::remove_dir_all(dirname).expect("failed to remove dir");
             fs::remove_dir("__test_2").expect("failed to remove dir");
         }
     }
+
+    #[test]
+    fn memory_storage_reads_what_was_written() {
+        let mut storage = MemoryStorage::new(BLOCK_SIZE);
+        assert!(!storage.exists("testfile"));
+        assert_eq!(storage.length("testfile").unwrap(), 0);
+
+        let mut p = Page::new(BLOCK_SIZE);
+        p.set_string(88, "sample text").unwrap();
+        storage.write(&BlockId::new("testfile", 2), &p).unwrap();
+        assert!(storage.exists("testfile"));
+        assert_eq!(storage.length("testfile").unwrap(), 3);
+        assert_eq!(storage.append("testfile").unwrap().number(), 3);
+
+        let mut p = Page::new(BLOCK_SIZE);
+        storage.read(&BlockId::new("testfile", 2), &mut p).unwrap();
+        assert_eq!(p.get_string(88).unwrap(), "sample text");
+        storage.read(&BlockId::new("testfile", 0), &mut p).unwrap();
+        assert!(p.bytes().iter().all(|&b| b == 0));
+
+        storage.truncate("testfile", 1).unwrap();
+        assert_eq!(storage.length("testfile").unwrap(), 1);
+        let stats = storage.stats();
+        assert_eq!(
+            (
+                stats.blocks_read,
+                stats.blocks_written,
+                stats.blocks_appended
+            ),
+            (2, 1, 1)
+        );
+    }
 }
@@ -1,35 +1,1294 @@
 pub mod buffer;
+pub mod clock;
+pub mod concurrency;
 pub mod file;
 pub mod log;
 pub mod log_records;
 pub mod record;
 pub mod recovery;
+pub mod replacement;
+pub mod schema;
+pub mod table;
+#[cfg(test)]
+mod test_util;
 pub mod tx;
 
-// use file::FileManager;
-// use log::LogManager;
-// use std::{
-//     io,
-//     path::Path,
-//     sync::{Arc, Mutex},
-// };
-
-// #[derive(Debug)]
-// pub struct RSDB {
-//     file_manager: Arc<Mutex<FileManager>>,
-//     log_manager: Arc<Mutex<LogManager>>,
-// }
-
-// impl RSDB {
-//     pub fn new(db_path: impl AsRef<Path>, _block_size: u64, _pool: u64) -> io::Result<Self> {
-//         let fm = Arc::new(Mutex::new(FileManager::new(db_path)?));
-//         let lm = Arc::new(Mutex::new(LogManager::new(
-//             Arc::clone(&fm),
-//             "log_test".to_string(),
-//         )));
-//         Ok(RSDB {
-//             file_manager: fm,
-//             log_manager: lm,
-//         })
-//     }
-// }
+use std::{
+    ffi::OsStr,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use buffer::{BufferError, BufferManager, BufferManagerOptions, BufferStats};
+use concurrency::{ConcurrencyError, DeadlockPolicy, LockTable, LockTableOptions};
+use file::{BlockStorage, FileError, FileManager, FileStats, MemoryStorage, BLOCK_SIZE};
+use log::{LogManager, LogStats, Lsn};
+use log_records::{write_record, CheckPointRecord, NqCheckPointRecord};
+use record::{create_log_record, LogRecord, LogRecordError, TxType};
+use recovery::RecoveryReport;
+use replacement::ReplacementPolicy;
+use table::TableError;
+use tx::{Transaction, TransactionError, TransactionManager, TransactionManagerOptions, TxMode};
+
+/// the file of the log, in the database directory, unless configured otherwise
+pub const LOG_FILE: &str = "rsdb.log";
+
+/// the size of the buffer pool, unless configured otherwise
+pub const POOL_SIZE: u64 = 64;
+
+/// how long `RSDB::close` waits for running transactions by default
+pub const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// how often `RSDB::close` looks for running transactions while it waits
+const CLOSE_POLL: Duration = Duration::from_millis(5);
+
+/// how often automatic checkpoints by `CheckpointSchedule::LogBytes` look at the log
+const CHECKPOINT_POLL: Duration = Duration::from_millis(10);
+
+/// the smallest and largest block sizes a database may use. a block holds its page lsn and has
+/// to fit the largest log record
+pub const MIN_BLOCK_SIZE: u64 = 512;
+pub const MAX_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// the error of everything the database does, wrapping the errors of the modules. every one
+/// converts into it, and `source` leads to the module error behind it:
+///
+/// ```
+/// use std::error::Error as _;
+///
+/// use rsdb::{Error, RSDB};
+///
+/// fn bump(db: &RSDB) -> rsdb::Result<i32> {
+///     let mut tx = db.new_tx()?;
+///     let block = tx.append("counter")?;
+///     let val = tx.with_block(&block, |view| {
+///         let val = view.get_int(80)? + 1;
+///         view.set_int(80, val, true)?;
+///         Ok(val)
+///     })?;
+///     tx.commit()?;
+///     Ok(val)
+/// }
+///
+/// let db = RSDB::new_in_memory(4096, 8)?;
+/// assert_eq!(bump(&db)?, 1);
+///
+/// let err = RSDB::new_in_memory(1000, 8).unwrap_err();
+/// assert!(matches!(err, Error::InvalidBlockSize(1000)));
+/// assert!(err.source().is_none());
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    /// the block size isn't a power of two between `MIN_BLOCK_SIZE` and `MAX_BLOCK_SIZE`
+    InvalidBlockSize(u64),
+    /// the buffer pool needs at least one buffer
+    InvalidPoolSize(u64),
+    /// the log file has to be a plain, non-empty file name
+    InvalidLogFile(String),
+    /// the residents file has to be a plain, non-empty file name
+    InvalidResidentsFile(String),
+    /// `close` gave up waiting for these transactions to finish
+    ActiveTransactions(Vec<i32>),
+    BufferError(BufferError),
+    ConcurrencyError(ConcurrencyError),
+    FileError(FileError),
+    LogRecordError(LogRecordError),
+    TableError(TableError),
+    TransactionError(TransactionError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidBlockSize(size) => write!(
+                f,
+                "Database error: the block size {} is not a power of two between {} and {}",
+                size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            ),
+            Error::InvalidPoolSize(size) => write!(
+                f,
+                "Database error: a buffer pool of {} buffers is too small",
+                size
+            ),
+            Error::InvalidLogFile(name) => write!(
+                f,
+                "Database error: {:?} is not a file name the log can have",
+                name
+            ),
+            Error::InvalidResidentsFile(name) => write!(
+                f,
+                "Database error: {:?} is not a file name the residents file can have",
+                name
+            ),
+            Error::ActiveTransactions(txnums) => write!(
+                f,
+                "Database error: transactions {:?} are still running",
+                txnums
+            ),
+            Error::BufferError(err) => write!(f, "Database error: {}", err),
+            Error::ConcurrencyError(err) => write!(f, "Database error: {}", err),
+            Error::FileError(err) => write!(f, "Database error: {}", err),
+            Error::LogRecordError(err) => write!(f, "Database error: {}", err),
+            Error::TableError(err) => write!(f, "Database error: {}", err),
+            Error::TransactionError(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::BufferError(err) => Some(err),
+            Error::ConcurrencyError(err) => Some(err),
+            Error::FileError(err) => Some(err),
+            Error::LogRecordError(err) => Some(err),
+            Error::TableError(err) => Some(err),
+            Error::TransactionError(err) => Some(err),
+            Error::InvalidBlockSize(_)
+            | Error::InvalidPoolSize(_)
+            | Error::InvalidLogFile(_)
+            | Error::InvalidResidentsFile(_)
+            | Error::ActiveTransactions(_) => None,
+        }
+    }
+}
+
+impl From<BufferError> for Error {
+    fn from(value: BufferError) -> Self {
+        Error::BufferError(value)
+    }
+}
+
+impl From<ConcurrencyError> for Error {
+    fn from(value: ConcurrencyError) -> Self {
+        Error::ConcurrencyError(value)
+    }
+}
+
+impl From<FileError> for Error {
+    fn from(value: FileError) -> Self {
+        Error::FileError(value)
+    }
+}
+
+impl From<LogRecordError> for Error {
+    fn from(value: LogRecordError) -> Self {
+        Error::LogRecordError(value)
+    }
+}
+
+impl From<TableError> for Error {
+    fn from(value: TableError) -> Self {
+        Error::TableError(value)
+    }
+}
+
+impl From<TransactionError> for Error {
+    fn from(value: TransactionError) -> Self {
+        Error::TransactionError(value)
+    }
+}
+
+/// a name in the directory, not a path, `.` or `..`
+fn is_file_name(name: &str) -> bool {
+    Path::new(name).file_name() == Some(OsStr::new(name))
+}
+
+/// the result of everything the database does, see `Error`
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// how to open a database. every option has a default, and `open` checks them all
+#[derive(Debug)]
+pub struct RSDBConfig {
+    /// `None` for a database kept in memory
+    path: Option<PathBuf>,
+    block_size: u64,
+    pool: u64,
+    log_file: String,
+    buffers: BufferManagerOptions,
+    locks: LockTableOptions,
+    transactions: TransactionManagerOptions,
+    close_timeout: Duration,
+    checkpoints: Option<CheckpointSchedule>,
+    residents_file: Option<String>,
+}
+
+impl RSDBConfig {
+    /// the configuration of the database in `path`, with every option at its default
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        RSDBConfig {
+            path: Some(path.as_ref().to_path_buf()),
+            block_size: BLOCK_SIZE as u64,
+            pool: POOL_SIZE,
+            log_file: LOG_FILE.to_string(),
+            buffers: BufferManagerOptions::default(),
+            locks: LockTableOptions::default(),
+            transactions: TransactionManagerOptions::default(),
+            close_timeout: CLOSE_TIMEOUT,
+            checkpoints: None,
+            residents_file: None,
+        }
+    }
+
+    /// the configuration of a database kept in memory, see `RSDB::new_in_memory`
+    pub fn new_in_memory() -> Self {
+        RSDBConfig {
+            path: None,
+            ..RSDBConfig::new("")
+        }
+    }
+
+    /// the size of a block in bytes, a power of two between `MIN_BLOCK_SIZE` and
+    /// `MAX_BLOCK_SIZE`. a database has to be opened with the block size it was created with
+    pub fn block_size(mut self, block_size: u64) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// the number of buffers in the pool, at least one
+    pub fn pool(mut self, pool: u64) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// the name of the log file in the database directory
+    pub fn log_file(mut self, log_file: impl Into<String>) -> Self {
+        self.log_file = log_file.into();
+        self
+    }
+
+    /// creates the replacement policy of each partition of the buffer pool
+    pub fn replacement_policy(mut self, policy: fn() -> Box<dyn ReplacementPolicy>) -> Self {
+        self.buffers.policy = policy;
+        self
+    }
+
+    /// how long a pin waits for a free buffer before giving up
+    pub fn pin_timeout(mut self, timeout: Duration) -> Self {
+        self.buffers.max_wait = timeout;
+        self
+    }
+
+    /// how long a lock request waits before giving up
+    pub fn lock_timeout(mut self, timeout: Duration) -> Self {
+        self.locks.max_wait = timeout;
+        self
+    }
+
+    pub fn deadlock_policy(mut self, policy: DeadlockPolicy) -> Self {
+        self.locks.policy = policy;
+        self
+    }
+
+    /// how long a transaction may run before it expires, see `TransactionManager`
+    pub fn max_tx_age(mut self, max_age: Duration) -> Self {
+        self.transactions.max_age = Some(max_age);
+        self
+    }
+
+    /// how long `RSDB::close` waits for running transactions to finish
+    pub fn close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+
+    /// takes checkpoints on a thread of the database, as `schedule` says. none by default
+    pub fn checkpoint_every(mut self, schedule: CheckpointSchedule) -> Self {
+        self.checkpoints = Some(schedule);
+        self
+    }
+
+    /// on a clean shutdown, saves which blocks the buffer pool holds to `residents_file` in the
+    /// database directory, and reads them back in when the database opens again, see
+    /// `BufferManager::warm_up`. off by default, and for a database kept in memory
+    pub fn residents_file(mut self, residents_file: impl Into<String>) -> Self {
+        self.residents_file = Some(residents_file.into());
+        self
+    }
+
+    /// opens the database, creating its directory if need be, and recovers it unless it was
+    /// shut down cleanly
+    pub fn open(self) -> Result<RSDB> {
+        let block_size = self.block_size;
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+        {
+            return Err(Error::InvalidBlockSize(block_size));
+        }
+        if self.pool == 0 {
+            return Err(Error::InvalidPoolSize(self.pool));
+        }
+        if !is_file_name(&self.log_file) {
+            return Err(Error::InvalidLogFile(self.log_file));
+        }
+        if let Some(name) = self
+            .residents_file
+            .as_ref()
+            .filter(|name| !is_file_name(name))
+        {
+            return Err(Error::InvalidResidentsFile(name.clone()));
+        }
+        let storage: Arc<Mutex<dyn BlockStorage>> = match &self.path {
+            Some(path) => Arc::new(Mutex::new(FileManager::new_with_block_size(
+                path,
+                block_size as i32,
+            )?)),
+            None => Arc::new(Mutex::new(MemoryStorage::new(block_size as i32))),
+        };
+        self.open_storage(storage)
+    }
+
+    /// opens the database in `storage`, with options `open` has checked
+    fn open_storage(self, storage: Arc<Mutex<dyn BlockStorage>>) -> Result<RSDB> {
+        let lm = LogManager::new(Arc::clone(&storage), self.log_file)?;
+        let lm = Arc::new(Mutex::new(lm));
+        let bm = BufferManager::new_with_options(
+            Arc::clone(&storage),
+            Arc::clone(&lm),
+            self.pool,
+            self.buffers,
+        );
+        let bm = Arc::new(bm);
+        let transactions = Arc::new(TransactionManager::recovered_with_options(
+            &mut lm.lock().unwrap(),
+            self.transactions,
+        )?);
+        let checkpointer = Arc::new(Checkpointer {
+            log_manager: Arc::clone(&lm),
+            buffer_manager: Arc::clone(&bm),
+            transactions: Arc::clone(&transactions),
+            gate: RwLock::new(()),
+            taken: AtomicU64::new(0),
+        });
+        let residents = self
+            .path
+            .as_ref()
+            .zip(self.residents_file)
+            .map(|(path, name)| path.join(name));
+        let mut db = RSDB {
+            path: self.path,
+            storage,
+            log_manager: lm,
+            buffer_manager: bm,
+            transactions,
+            lock_table: Arc::new(LockTable::new_with_options(self.locks)),
+            recovery: RecoveryReport::default(),
+            checkpointer,
+            scheduler: None,
+            close_timeout: self.close_timeout,
+            residents,
+            closed: false,
+        };
+        db.recovery = db.recover()?;
+        if let Some(residents) = db.residents.as_ref().filter(|path| path.exists()) {
+            db.buffer_manager.warm_up(residents)?;
+        }
+        db.scheduler = self
+            .checkpoints
+            .map(|schedule| Scheduler::spawn(Arc::clone(&db.checkpointer), schedule));
+        Ok(db)
+    }
+}
+
+/// a database: the files in one directory, or kept in memory, its log and its buffer pool,
+/// and what its transactions share
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug)]
+pub struct RSDB {
+    /// `None` for a database kept in memory
+    path: Option<PathBuf>,
+    storage: Arc<Mutex<dyn BlockStorage>>,
+    log_manager: Arc<Mutex<LogManager>>,
+    buffer_manager: Arc<BufferManager>,
+    transactions: Arc<TransactionManager>,
+    lock_table: Arc<LockTable>,
+    /// what recovery did when the database was opened
+    recovery: RecoveryReport,
+    checkpointer: Arc<Checkpointer>,
+    /// the thread of automatic checkpoints, if configured
+    scheduler: Option<Scheduler>,
+    close_timeout: Duration,
+    /// where a clean shutdown saves the blocks of the buffer pool, see
+    /// `RSDBConfig::residents_file`
+    residents: Option<PathBuf>,
+    /// set by `close`, so that dropping the database doesn't shut it down again
+    closed: bool,
+}
+
+impl RSDB {
+    /// opens the database in `db_path`, creating it if need be, with blocks of `block_size`
+    /// bytes and a pool of `pool` buffers. `RSDBConfig` sets the other options
+    pub fn new(db_path: impl AsRef<Path>, block_size: u64, pool: u64) -> Result<Self> {
+        RSDBConfig::new(db_path)
+            .block_size(block_size)
+            .pool(pool)
+            .open()
+    }
+
+    /// a database kept in memory, with blocks of `block_size` bytes and a pool of `pool`
+    /// buffers. it works as one on disk, but is gone once dropped: closing it or not makes no
+    /// difference
+    pub fn new_in_memory(block_size: u64, pool: u64) -> Result<Self> {
+        RSDBConfig::new_in_memory()
+            .block_size(block_size)
+            .pool(pool)
+            .open()
+    }
+
+    /// recovers the database with the recovery transaction, unless the log is empty or ends
+    /// with a checkpoint: then no transaction ran since the last clean shutdown or recovery
+    fn recover(&self) -> Result<RecoveryReport> {
+        let newest = {
+            let mut lm = self.log_manager.lock().unwrap();
+            let mut iter = lm.iterator()?;
+            let lsn = iter.next_lsn();
+            iter.next()
+                .transpose()?
+                .map(|bytes| (lsn, create_log_record(&bytes).map(|rec| rec.op())))
+        };
+        match newest {
+            None => {
+                return Ok(RecoveryReport {
+                    clean: true,
+                    ..Default::default()
+                })
+            }
+            Some((lsn, Ok(TxType::CheckPoint))) => {
+                return Ok(RecoveryReport {
+                    log_records_scanned: 1,
+                    checkpoint_lsn: Some(lsn),
+                    clean: true,
+                    ..Default::default()
+                })
+            }
+            Some(_) => {}
+        }
+        let mut tx = Transaction::new_recovery(
+            Arc::clone(&self.storage),
+            Arc::clone(&self.log_manager),
+            Arc::clone(&self.buffer_manager),
+            Arc::clone(&self.transactions),
+        )?;
+        let report = tx.recover()?;
+        tx.commit()?;
+        Ok(report)
+    }
+
+    /// what recovery did when the database was opened. `clean` if it had nothing to do
+    pub fn recovery_report(&self) -> RecoveryReport {
+        self.recovery
+    }
+
+    /// shuts the database down cleanly: once no transaction is running, waiting up to the
+    /// close timeout for that, every dirty buffer is flushed and the log ends with a
+    /// checkpoint, so that opening the database again recovers nothing
+    pub fn close(mut self) -> Result<()> {
+        self.stop_checkpoints();
+        self.shut_down(self.close_timeout)?;
+        self.closed = true;
+        Ok(())
+    }
+
+    fn shut_down(&self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            {
+                // no transaction may start between the check and the checkpoint
+                let _gate = self.checkpointer.gate.write().unwrap();
+                let active = self.transactions.active_transactions();
+                if active.is_empty() {
+                    self.checkpointer.quiescent()?;
+                    if let Some(residents) = &self.residents {
+                        self.buffer_manager.save_residents(residents)?;
+                    }
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    let txnums = active.iter().map(|info| info.txnum).collect();
+                    return Err(Error::ActiveTransactions(txnums));
+                }
+            }
+            thread::sleep(CLOSE_POLL);
+        }
+    }
+
+    /// stops the thread of automatic checkpoints, once its checkpoint is done if it is taking
+    /// one
+    fn stop_checkpoints(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.stop();
+        }
+    }
+
+    /// takes a checkpoint, so that recovery reads the log back no further than it. without
+    /// read-write transactions running, it is quiescent: recovery stops at the checkpoint.
+    /// otherwise it doesn't wait for them, and recovery reads back to the start of the
+    /// earliest. no transaction starts while a checkpoint runs. returns the lsn of the
+    /// checkpoint record
+    pub fn checkpoint(&self) -> Result<Lsn> {
+        self.checkpointer.checkpoint()
+    }
+
+    /// starts a read-write transaction
+    pub fn new_tx(&self) -> Result<Transaction> {
+        self.begin(TxMode::ReadWrite)
+    }
+
+    /// starts a transaction that only reads, see `TxMode::ReadOnly`
+    pub fn new_read_only_tx(&self) -> Result<Transaction> {
+        self.begin(TxMode::ReadOnly)
+    }
+
+    fn begin(&self, mode: TxMode) -> Result<Transaction> {
+        let _gate = self.checkpointer.gate.read().unwrap();
+        let tx = Transaction::new_with_mode(
+            Arc::clone(&self.storage),
+            Arc::clone(&self.log_manager),
+            Arc::clone(&self.buffer_manager),
+            Arc::clone(&self.transactions),
+            Arc::clone(&self.lock_table),
+            mode,
+        )?;
+        Ok(tx)
+    }
+
+    /// the storage of the database's blocks, for its stats say. reading and writing blocks
+    /// through it bypasses the log, the buffers and the locks of the transactions, and is at
+    /// the caller's own risk; so is using the other managers outside a transaction
+    pub fn storage(&self) -> Arc<Mutex<dyn BlockStorage>> {
+        Arc::clone(&self.storage)
+    }
+
+    /// the log manager, to iterate over the log
+    pub fn log_manager(&self) -> Arc<Mutex<LogManager>> {
+        Arc::clone(&self.log_manager)
+    }
+
+    pub fn buffer_manager(&self) -> Arc<BufferManager> {
+        Arc::clone(&self.buffer_manager)
+    }
+
+    pub fn block_size(&self) -> i32 {
+        self.storage.lock().unwrap().block_size()
+    }
+
+    /// the number of unpinned buffers in the pool
+    pub fn available(&self) -> u64 {
+        self.buffer_manager.available()
+    }
+
+    /// like `available`, by the name `Transaction` uses
+    pub fn available_buffs(&self) -> u64 {
+        self.available()
+    }
+
+    /// a snapshot of the counters of every manager. each is locked only long enough to copy
+    /// its counters, so a metrics exporter may call this as often as it likes
+    pub fn stats(&self) -> DbStats {
+        let file = self.storage.lock().unwrap().stats();
+        let (log, log_blocks) = {
+            let lm = self.log_manager.lock().unwrap();
+            (lm.stats(), lm.size())
+        };
+        let buffers = self.buffer_manager.stats();
+        let active = self.transactions.active_transactions();
+        DbStats {
+            file,
+            buffers,
+            log,
+            log_blocks,
+            active_transactions: active.len(),
+            oldest_transaction_age: active.first().map(|info| self.transactions.age(info)),
+            checkpoints: self.checkpointer.taken.load(Ordering::Relaxed),
+            disk_bytes: self.path.as_deref().map(disk_bytes),
+        }
+    }
+}
+
+/// the bytes of the files in `dir`
+fn disk_bytes(dir: &Path) -> u64 {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// how a database is doing, see `RSDB::stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DbStats {
+    pub file: FileStats,
+    pub buffers: BufferStats,
+    pub log: LogStats,
+    /// the size of the log in blocks
+    pub log_blocks: i32,
+    /// the checkpoints taken since the database was opened, those of recovery and shutdown
+    /// aside
+    pub checkpoints: u64,
+    pub active_transactions: usize,
+    /// how long the transaction that started first has been running, none if none is
+    pub oldest_transaction_age: Option<Duration>,
+    /// the size of the files of the database, none for a database kept in memory
+    pub disk_bytes: Option<u64>,
+}
+
+impl fmt::Display for DbStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "files: {} blocks read, {} written, {} appended",
+            self.file.blocks_read, self.file.blocks_written, self.file.blocks_appended
+        )?;
+        write!(
+            f,
+            "buffers: {} hits, {} misses",
+            self.buffers.hits, self.buffers.misses
+        )?;
+        if let Some(ratio) = self.buffers.hit_ratio() {
+            write!(f, " ({:.1}% hits)", ratio * 100.0)?;
+        }
+        writeln!(f, ", {} evictions", self.buffers.evictions)?;
+        writeln!(
+            f,
+            "log: {} records ({} bytes), {} flushes, {} blocks, {} checkpoints",
+            self.log.records_appended,
+            self.log.bytes_appended,
+            self.log.flushes,
+            self.log_blocks,
+            self.checkpoints
+        )?;
+        write!(f, "transactions: {} running", self.active_transactions)?;
+        if let Some(age) = self.oldest_transaction_age {
+            write!(f, ", the oldest for {:?}", age)?;
+        }
+        match self.disk_bytes {
+            Some(bytes) => write!(f, "\ndisk: {} bytes", bytes),
+            None => write!(f, "\ndisk: none, in memory"),
+        }
+    }
+}
+
+/// when the database takes checkpoints by itself, see `RSDBConfig::checkpoint_every`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointSchedule {
+    /// once per interval
+    Interval(Duration),
+    /// once this many bytes of log records were appended since the last checkpoint
+    LogBytes(u64),
+}
+
+/// takes the checkpoints of a database, for `RSDB::checkpoint` and the thread of automatic
+/// checkpoints
+#[derive(Debug)]
+struct Checkpointer {
+    log_manager: Arc<Mutex<LogManager>>,
+    buffer_manager: Arc<BufferManager>,
+    transactions: Arc<TransactionManager>,
+    /// held shared while a transaction starts and exclusively around a checkpoint, so that no
+    /// transaction starts during a non-quiescent checkpoint and no two checkpoints overlap
+    gate: RwLock<()>,
+    taken: AtomicU64,
+}
+
+impl Checkpointer {
+    fn checkpoint(&self) -> Result<Lsn> {
+        let _gate = self.gate.write().unwrap();
+        // read-only transactions log nothing, recovery has nothing of theirs to read
+        let running: Vec<i32> = self
+            .transactions
+            .active_transactions()
+            .iter()
+            .filter(|info| !info.read_only)
+            .map(|info| info.txnum)
+            .collect();
+        let lsn = if running.is_empty() {
+            self.quiescent()?
+        } else {
+            self.nonquiescent(running)?
+        };
+        self.taken.fetch_add(1, Ordering::Relaxed);
+        Ok(lsn)
+    }
+
+    /// see `RecoveryManager::checkpoint`. the caller holds the gate
+    fn quiescent(&self) -> Result<Lsn> {
+        let bm = &self.buffer_manager;
+        let token = bm.checkpoint()?;
+        let lsn =
+            write_record(Arc::clone(&self.log_manager), &CheckPointRecord::new()).and_then(|lsn| {
+                self.log_manager.lock().unwrap().flush_with_lsn(lsn)?;
+                Ok(lsn)
+            });
+        bm.release(token);
+        Ok(lsn?)
+    }
+
+    /// see `RecoveryManager::checkpoint_nonquiescent`. the caller holds the gate
+    fn nonquiescent(&self, running: Vec<i32>) -> Result<Lsn> {
+        self.buffer_manager.checkpoint_nonquiescent()?;
+        let rec = NqCheckPointRecord::new(running);
+        let lsn = write_record(Arc::clone(&self.log_manager), &rec)?;
+        self.log_manager.lock().unwrap().flush_with_lsn(lsn)?;
+        Ok(lsn)
+    }
+
+    fn log_bytes(&self) -> u64 {
+        self.log_manager.lock().unwrap().stats().bytes_appended
+    }
+}
+
+/// the thread of automatic checkpoints. it runs until `stop`
+#[derive(Debug)]
+struct Scheduler {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Scheduler {
+    fn spawn(checkpointer: Arc<Checkpointer>, schedule: CheckpointSchedule) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let poll = match schedule {
+            CheckpointSchedule::Interval(interval) => interval,
+            CheckpointSchedule::LogBytes(_) => CHECKPOINT_POLL,
+        };
+        let thread = thread::spawn(move || {
+            let mut logged = checkpointer.log_bytes();
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(poll) {
+                let due = match schedule {
+                    CheckpointSchedule::Interval(_) => true,
+                    CheckpointSchedule::LogBytes(bytes) => {
+                        checkpointer.log_bytes() - logged >= bytes
+                    }
+                };
+                if !due {
+                    continue;
+                }
+                if let Err(err) = checkpointer.checkpoint() {
+                    eprintln!("an automatic checkpoint failed: {}", err);
+                }
+                logged = checkpointer.log_bytes();
+            }
+        });
+        Scheduler { stop, thread }
+    }
+
+    fn stop(self) {
+        // the thread sees the channel closed
+        drop(self.stop);
+        if self.thread.join().is_err() {
+            eprintln!("the thread of automatic checkpoints panicked");
+        }
+    }
+}
+
+/// a database that wasn't closed is shut down as well as it can be, without waiting. if
+/// transactions are still running, the log and the buffers are flushed but the log doesn't end
+/// with a checkpoint, and the next open recovers the database
+impl Drop for RSDB {
+    fn drop(&mut self) {
+        self.stop_checkpoints();
+        if self.closed {
+            return;
+        }
+        // see `Transaction::drop`
+        if thread::panicking()
+            && (self.log_manager.is_poisoned() || self.buffer_manager.is_poisoned())
+        {
+            return;
+        }
+        let res = match self.shut_down(Duration::ZERO) {
+            Err(Error::ActiveTransactions(_)) => self
+                .buffer_manager
+                .checkpoint_nonquiescent()
+                .map_err(Error::from),
+            res => res,
+        };
+        if let Err(err) = res {
+            eprintln!("the database was not shut down cleanly: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::file::BlockId;
+
+    #[test]
+    fn new_builds_the_pool_with_the_block_size_asked_for() {
+        let dirname = "__test_89";
+        let db = RSDB::new(dirname, 1024, 2).unwrap();
+        assert_eq!(db.available(), 2);
+        assert_eq!(db.storage().lock().unwrap().block_size(), 1024);
+        // the log starts with one block
+        let log_len = fs::metadata(Path::new(dirname).join(LOG_FILE))
+            .unwrap()
+            .len();
+        assert_eq!(log_len, 1024);
+
+        assert!(matches!(
+            RSDB::new(dirname, 1024, 0),
+            Err(Error::InvalidPoolSize(0))
+        ));
+        for size in [0, 256, 1000, 128 * 1024] {
+            assert!(matches!(
+                RSDB::new(dirname, size, 2),
+                Err(Error::InvalidBlockSize(s)) if s == size
+            ));
+        }
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn every_config_option_takes_effect() {
+        let dirname = "__test_91";
+        let db = RSDBConfig::new(dirname)
+            .block_size(2048)
+            .pool(5)
+            .log_file("wal")
+            .lock_timeout(Duration::from_millis(20))
+            .deadlock_policy(DeadlockPolicy::WaitDie)
+            .open()
+            .unwrap();
+        assert_eq!(db.storage().lock().unwrap().block_size(), 2048);
+        assert_eq!(db.available(), 5);
+        let log = Path::new(dirname).join("wal");
+        assert_eq!(fs::metadata(log).unwrap().len(), 2048);
+        assert!(!Path::new(dirname).join(LOG_FILE).exists());
+
+        // an older transaction in the way: wait-die fails the younger one's request at once
+        let mut older = db.new_tx().unwrap();
+        let mut younger = db.new_tx().unwrap();
+        let block = older.append("testfile").unwrap();
+        assert!(matches!(
+            younger.size("testfile"),
+            Err(TransactionError::ConcurrencyError(ConcurrencyError::Die(_)))
+        ));
+        younger.rollback().unwrap();
+        older.commit().unwrap();
+        drop(db);
+
+        // a younger transaction in the way: the older one waits for the lock timeout
+        let db = RSDBConfig::new(dirname)
+            .block_size(2048)
+            .log_file("wal")
+            .lock_timeout(Duration::from_millis(20))
+            .open()
+            .unwrap();
+        let mut older = db.new_tx().unwrap();
+        let mut younger = db.new_tx().unwrap();
+        younger.pin(&block).unwrap();
+        younger.set_int(&block, 80, 1, true).unwrap();
+        older.pin(&block).unwrap();
+        assert!(matches!(
+            older.get_int(&block, 80),
+            Err(TransactionError::ConcurrencyError(
+                ConcurrencyError::LockAbort(_)
+            ))
+        ));
+        older.rollback().unwrap();
+        younger.commit().unwrap();
+        assert_eq!(db.available(), POOL_SIZE);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn invalid_configs_fail_to_open() {
+        let dirname = "__test_92";
+        let config = || RSDBConfig::new(dirname);
+        // too small for the page lsn and a log record
+        assert!(matches!(
+            config().block_size(8).open(),
+            Err(Error::InvalidBlockSize(8))
+        ));
+        assert!(matches!(
+            config().pool(0).open(),
+            Err(Error::InvalidPoolSize(0))
+        ));
+        for name in ["", "..", "logs/wal"] {
+            match config().log_file(name).open() {
+                Err(err @ Error::InvalidLogFile(_)) => {
+                    assert!(err.to_string().contains(&format!("{:?}", name)))
+                }
+                res => panic!("expected an invalid log file, got {:?}", res.map(|_| ())),
+            }
+        }
+        for name in ["", "residents/list"] {
+            assert!(matches!(
+                config().residents_file(name).open(),
+                Err(Error::InvalidResidentsFile(_))
+            ));
+        }
+        // nothing was created
+        assert!(!Path::new(dirname).exists());
+    }
+
+    #[test]
+    fn a_reopened_database_starts_with_the_blocks_it_held() {
+        let dirname = "__test_113";
+        let config = || RSDBConfig::new(dirname).pool(4).residents_file("residents");
+        let blocks: Vec<BlockId> = {
+            let db = config().open().unwrap();
+            let mut tx = db.new_tx().unwrap();
+            let blocks: Vec<BlockId> = (0..3).map(|_| tx.append("testfile").unwrap()).collect();
+            for (i, block) in blocks.iter().enumerate() {
+                tx.with_block(block, |view| view.set_int(80, i as i32, true))
+                    .unwrap();
+            }
+            tx.commit().unwrap();
+            db.close().unwrap();
+            blocks
+        };
+        assert!(Path::new(dirname).join("residents").exists());
+
+        let db = config().open().unwrap();
+        let before = db.stats();
+        let mut tx = db.new_read_only_tx().unwrap();
+        for (i, block) in blocks.iter().enumerate() {
+            let val = tx.with_block(block, |view| view.get_int(80)).unwrap();
+            assert_eq!(val, i as i32);
+        }
+        tx.commit().unwrap();
+        let after = db.stats();
+        assert_eq!(after.file.blocks_read, before.file.blocks_read);
+        assert_eq!(after.buffers.hits - before.buffers.hits, 3);
+        assert_eq!(after.buffers.misses, before.buffers.misses);
+        db.close().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn the_managers_show_what_a_transaction_did() {
+        let dirname = "__test_93";
+        let db = RSDB::new(dirname, 1024, 4).unwrap();
+        assert_eq!(db.block_size(), 1024);
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("testfile").unwrap();
+        tx.pin(&block).unwrap();
+        assert_eq!(db.available_buffs(), 3);
+        tx.set_int(&block, 80, 7, true).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.available_buffs(), 4);
+
+        let records: Vec<(TxType, i32)> = db
+            .log_manager()
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(|bytes| bytes.unwrap())
+            .map(|bytes| create_log_record(&bytes).unwrap())
+            .map(|rec| (rec.op(), rec.tx_num()))
+            .collect();
+        let txnum = tx.tx_num();
+        assert_eq!(
+            records,
+            [
+                (TxType::Commit, txnum),
+                (TxType::SetI32, txnum),
+                (TxType::ExtendFile, txnum),
+                (TxType::Start, txnum),
+            ]
+        );
+        // the commit wrote the page
+        assert!(db.storage().lock().unwrap().stats().blocks_written >= 1);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_database_that_was_closed_reopens_without_recovery() {
+        let dirname = "__test_94";
+        let db = RSDB::new(dirname, 4096, 8).unwrap();
+        assert!(db.recovery_report().clean);
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("testfile").unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+
+        // close waits for a transaction that is about to finish
+        let mut tx = db.new_tx().unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 2, true).unwrap();
+        let finishing = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            tx.commit().unwrap();
+        });
+        db.close().unwrap();
+        finishing.join().unwrap();
+
+        let db = RSDB::new(dirname, 4096, 8).unwrap();
+        let report = db.recovery_report();
+        assert!(report.clean);
+        assert_eq!((report.undo_actions, report.redo_actions), (0, 0));
+        let mut tx = db.new_read_only_tx().unwrap();
+        tx.pin(&block).unwrap();
+        assert_eq!(tx.get_int(&block, 80).unwrap(), 2);
+        tx.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_database_closed_with_a_transaction_running_is_recovered() {
+        let dirname = "__test_95";
+        let open = || {
+            RSDBConfig::new(dirname)
+                .close_timeout(Duration::from_millis(20))
+                .open()
+                .unwrap()
+        };
+        let db = open();
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("testfile").unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+        let mut tx = db.new_tx().unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 2, true).unwrap();
+        let txnum = tx.tx_num();
+        tx.forget();
+
+        match db.close() {
+            Err(Error::ActiveTransactions(txnums)) => assert_eq!(txnums, [txnum]),
+            res => panic!("expected running transactions, got {:?}", res),
+        }
+        // dropping the database flushed the update, and recovery undoes it
+        let db = open();
+        let report = db.recovery_report();
+        assert!(!report.clean);
+        assert_eq!(report.txs_undone, 1);
+        let mut tx = db.new_read_only_tx().unwrap();
+        tx.pin(&block).unwrap();
+        assert_eq!(tx.get_int(&block, 80).unwrap(), 1);
+        tx.commit().unwrap();
+        db.close().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn an_in_memory_database_commits_rolls_back_and_recovers() {
+        let db = RSDB::new_in_memory(1024, 4).unwrap();
+        assert!(db.recovery_report().clean);
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("testfile").unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.new_tx().unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 2, true).unwrap();
+        tx.rollback().unwrap();
+
+        // an update that reaches the storage, by a transaction that never finishes
+        let mut tx = db.new_tx().unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, 80, 3, true).unwrap();
+        db.buffer_manager().flush_all(tx.tx_num()).unwrap();
+        tx.forget();
+        // a restart over the same memory
+        let storage = db.storage();
+        drop(db);
+        let db = RSDBConfig::new_in_memory()
+            .block_size(1024)
+            .pool(4)
+            .open_storage(storage)
+            .unwrap();
+        let report = db.recovery_report();
+        assert!(!report.clean);
+        assert_eq!(report.txs_undone, 1);
+
+        let mut tx = db.new_read_only_tx().unwrap();
+        tx.pin(&block).unwrap();
+        assert_eq!(tx.get_int(&block, 80).unwrap(), 1);
+        tx.commit().unwrap();
+        assert_eq!(db.storage().lock().unwrap().length("testfile").unwrap(), 1);
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn errors_lead_to_the_module_error_behind_them() {
+        use std::error::Error as _;
+
+        let db = RSDBConfig::new_in_memory()
+            .lock_timeout(Duration::from_millis(10))
+            .open()
+            .unwrap();
+        let mut writer = db.new_tx().unwrap();
+        let block = writer.append("testfile").unwrap();
+        writer.pin(&block).unwrap();
+        writer.set_int(&block, 80, 1, true).unwrap();
+
+        let mut reader = db.new_tx().unwrap();
+        reader.pin(&block).unwrap();
+        let err = Error::from(reader.get_int(&block, 80).unwrap_err());
+        assert!(err
+            .to_string()
+            .starts_with("Database error: Transaction error: "));
+        let source = err.source().unwrap();
+        assert!(matches!(
+            source.downcast_ref(),
+            Some(TransactionError::ConcurrencyError(_))
+        ));
+        assert!(matches!(
+            source.source().unwrap().downcast_ref(),
+            Some(ConcurrencyError::LockAbort(_))
+        ));
+        reader.rollback().unwrap();
+        writer.commit().unwrap();
+
+        let mut tx = db.new_read_only_tx().unwrap();
+        tx.pin(&block).unwrap();
+        let err = Error::from(tx.set_int(&block, 80, 2, true).unwrap_err());
+        let source = err.source().unwrap();
+        assert!(matches!(
+            source.downcast_ref(),
+            Some(TransactionError::ReadOnly(_))
+        ));
+        assert!(source.source().is_none());
+        tx.commit().unwrap();
+
+        let err = Error::from(ConcurrencyError::LockAbort(block));
+        assert!(err.source().unwrap().is::<ConcurrencyError>());
+        db.close().unwrap();
+    }
+
+    #[test]
+    fn stats_add_up_the_stats_of_the_managers() {
+        let dirname = "__test_97";
+        let db = RSDB::new(dirname, 1024, 4).unwrap();
+        let mut tx = db.new_tx().unwrap();
+        let blocks: Vec<_> = (0..6).map(|_| tx.append("testfile").unwrap()).collect();
+        for (n, block) in blocks.iter().enumerate() {
+            tx.pin(block).unwrap();
+            tx.set_int(block, 80, n as i32, true).unwrap();
+            tx.unpin(block);
+        }
+        tx.commit().unwrap();
+        let mut running = db.new_read_only_tx().unwrap();
+        running.pin(&blocks[5]).unwrap();
+        running.get_int(&blocks[5], 80).unwrap();
+
+        let stats = db.stats();
+        assert_eq!(stats.file, db.storage().lock().unwrap().stats());
+        assert_eq!(stats.buffers, db.buffer_manager().stats());
+        assert!(stats.buffers.evictions >= 2);
+        let lm = db.log_manager();
+        assert_eq!(stats.log, lm.lock().unwrap().stats());
+        // start, six appends, six updates and the commit
+        assert_eq!(stats.log.records_appended, 14);
+        assert_eq!(stats.log_blocks, lm.lock().unwrap().size());
+        assert_eq!(stats.active_transactions, 1);
+        assert!(stats.oldest_transaction_age.is_some());
+        let log_bytes = fs::metadata(Path::new(dirname).join(LOG_FILE))
+            .unwrap()
+            .len();
+        assert_eq!(log_bytes, stats.log_blocks as u64 * 1024);
+        assert_eq!(stats.disk_bytes, Some(log_bytes + 6 * 1024));
+        let report = stats.to_string();
+        assert_eq!(report.lines().count(), 5);
+        assert!(report.contains("transactions: 1 running, the oldest for "));
+        running.commit().unwrap();
+        db.close().unwrap();
+
+        let db = RSDB::new_in_memory(1024, 4).unwrap();
+        let stats = db.stats();
+        assert_eq!((stats.active_transactions, stats.disk_bytes), (0, None));
+        assert!(stats.to_string().ends_with("disk: none, in memory"));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_checkpoint_shortens_recovery() {
+        // commits, then a transaction cut short by a crash, with or without a checkpoint
+        // while it runs
+        fn records_scanned(dirname: &str, checkpoint: bool) -> usize {
+            let db = RSDB::new(dirname, 4096, 8).unwrap();
+            let mut tx = db.new_tx().unwrap();
+            let block = tx.append("testfile").unwrap();
+            tx.commit().unwrap();
+            for n in 0..20 {
+                let mut tx = db.new_tx().unwrap();
+                tx.pin(&block).unwrap();
+                tx.set_int(&block, 80, n, true).unwrap();
+                tx.commit().unwrap();
+            }
+            let mut crashed = db.new_tx().unwrap();
+            crashed.pin(&block).unwrap();
+            crashed.set_int(&block, 80, -1, true).unwrap();
+            if checkpoint {
+                db.checkpoint().unwrap();
+            }
+            crashed.forget();
+            drop(db);
+
+            let db = RSDB::new(dirname, 4096, 8).unwrap();
+            let report = db.recovery_report();
+            assert_eq!(report.txs_undone, 1);
+            let mut tx = db.new_read_only_tx().unwrap();
+            tx.pin(&block).unwrap();
+            assert_eq!(tx.get_int(&block, 80).unwrap(), 19);
+            tx.commit().unwrap();
+            db.close().unwrap();
+            fs::remove_dir_all(dirname).expect("failed to remove dir");
+            report.log_records_scanned
+        }
+
+        // the start of recovery, the checkpoint, and the update and the start of the crashed
+        // transaction
+        assert_eq!(records_scanned("__test_98", true), 4);
+        assert!(records_scanned("__test_99", false) > 60);
+    }
+
+    #[test]
+    fn checkpoints_are_taken_automatically() {
+        for schedule in [
+            CheckpointSchedule::LogBytes(256),
+            CheckpointSchedule::Interval(Duration::from_millis(1)),
+        ] {
+            let db = RSDBConfig::new_in_memory()
+                .checkpoint_every(schedule)
+                .open()
+                .unwrap();
+            let mut tx = db.new_tx().unwrap();
+            let block = tx.append("testfile").unwrap();
+            tx.commit().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            let mut n = 0;
+            while db.stats().checkpoints == 0 {
+                assert!(Instant::now() < deadline, "no checkpoint by {:?}", schedule);
+                let mut tx = db.new_tx().unwrap();
+                tx.pin(&block).unwrap();
+                tx.set_string(&block, 80, &"x".repeat(n % 100), true)
+                    .unwrap();
+                tx.commit().unwrap();
+                n += 1;
+            }
+            // manual checkpoints take turns with the automatic ones
+            let before = db.stats().checkpoints;
+            db.checkpoint().unwrap();
+            assert!(db.stats().checkpoints > before);
+            db.close().unwrap();
+        }
+    }
+}
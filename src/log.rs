@@ -1,82 +1,169 @@
+use std::io;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-use crate::file::{BlockId, FileManager, Page, I32_SIZE};
+use crate::file::{BlockId, BlockStorage, FileManager, Page, I32_SIZE};
+use crate::record::{self, LogRecordError};
 
 use crate::file::Result;
 
+/// a log sequence number: the position of a record in the log, counting from 1
+pub type Lsn = i32;
+
+/// every log block starts with the offset of its newest record, followed by the lsn of the
+/// last record before the block. records fill the rest of the block from its end
+const BOUNDARY: u64 = 0;
+const BASE_LSN: u64 = I32_SIZE as u64;
+pub const LOG_HEADER_SIZE: usize = 2 * I32_SIZE;
+
+/// counters of a `LogManager` since it was created
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LogStats {
+    pub records_appended: u64,
+    /// the bytes of the records, without their lengths or padding
+    pub bytes_appended: u64,
+    /// writes of the current log block to disk
+    pub flushes: u64,
+}
+
 #[derive(Debug)]
 pub struct LogManager {
-    file_manager: Arc<Mutex<FileManager>>,
+    file_manager: Arc<Mutex<dyn BlockStorage>>,
     log_file_name: String,
     log_page: Page,
     cur_block: BlockId,
     latest_lsn: i32,
     last_saved_lsn: i32,
+    stats: LogStats,
 }
 
 impl LogManager {
-    pub fn new(fm: Arc<Mutex<FileManager>>, log_file_name: String) -> Self {
+    /// opens the log `log_file_name`, creating it if it doesn't exist. only its last block is
+    /// read, and a last block that can't be parsed fails with an `InvalidData` error
+    pub fn new(fm: Arc<Mutex<dyn BlockStorage>>, log_file_name: String) -> Result<Self> {
         let (log_page, cur_block) = {
             let mut fm = fm.lock().unwrap();
             let block_size = fm.block_size();
             let mut log_page = Page::new(block_size);
-            let log_size = fm.length(&log_file_name).unwrap();
+            let log_size = fm.length(&log_file_name)?;
             let cur_block = if log_size == 0 {
-                let block = fm.append(&log_file_name).unwrap();
-                log_page.set_i32(0, fm.block_size()).unwrap();
-                fm.write(&block, &mut log_page).unwrap();
+                let block = fm.append(&log_file_name)?;
+                log_page.set_i32(BOUNDARY, block_size)?;
+                log_page.set_i32(BASE_LSN, 0)?;
+                fm.write(&block, &log_page)?;
                 block
             } else {
                 let cur = BlockId::new(&log_file_name, log_size - 1);
-                fm.read(&cur, &mut log_page).unwrap();
+                fm.read(&cur, &mut log_page)?;
                 cur
             };
             (log_page, cur_block)
         };
+        // lsns number the records from the start of the log, so they stay the same across
+        // restarts. the header of the last block tells where its records start counting
+        let latest_lsn = log_page.get_i32(BASE_LSN)? + Self::records_in(&log_page)?;
 
-        LogManager {
+        Ok(LogManager {
             file_manager: fm,
             log_file_name,
             log_page,
             cur_block,
-            latest_lsn: 0,
-            last_saved_lsn: 0,
+            latest_lsn,
+            last_saved_lsn: latest_lsn,
+            stats: LogStats::default(),
+        })
+    }
+
+    /// the number of records in a log block, padding aside
+    fn records_in(page: &Page) -> Result<i32> {
+        let block_size = page.bytes().len() as i32;
+        let mut pos = page.get_i32(BOUNDARY)?;
+        if pos < LOG_HEADER_SIZE as i32 || pos > block_size {
+            let msg = format!("the records of a log block can't start at {}", pos);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
         }
+        let mut count = 0;
+        while pos < block_size {
+            let record = page.get_bytes(pos as u64)?;
+            pos += (I32_SIZE + record.len()) as i32;
+            if !record::is_padding(&record) {
+                count += 1;
+            }
+        }
+        Ok(count)
     }
 
     pub fn append(&mut self, log_record: Vec<u8>) -> Result<i32> {
-        let boundary = self.log_page.get_i32(0)?;
+        let boundary = self.log_page.get_i32(BOUNDARY)?;
         let record_size = log_record.len() as i32;
         let byte_needed = record_size + I32_SIZE as i32;
-        let boundary = if (boundary - byte_needed) < I32_SIZE.try_into().unwrap() {
+        let boundary = if (boundary - byte_needed) < LOG_HEADER_SIZE as i32 {
+            self.pad(boundary)?;
             self.flush()?;
             self.cur_block = self.append_new_block()?;
-            self.log_page.get_i32(0)?
+            self.log_page.get_i32(BOUNDARY)?
         } else {
             boundary
         };
         let record_pos = boundary - byte_needed;
         self.log_page
             .set_bytes(record_pos.try_into().unwrap(), &log_record)?;
-        self.log_page.set_i32(0, record_pos)?;
+        self.log_page.set_i32(BOUNDARY, record_pos)?;
         self.latest_lsn += 1;
+        self.stats.records_appended += 1;
+        self.stats.bytes_appended += record_size as u64;
         Ok(self.latest_lsn)
     }
 
+    /// fills the free space of the current block with a noop record, so that the block holds
+    /// nothing but records. space too small for a noop is left empty.
+    fn pad(&mut self, boundary: i32) -> Result<()> {
+        let free = boundary as usize - LOG_HEADER_SIZE;
+        if let Some(noop) = free.checked_sub(I32_SIZE).and_then(record::padding) {
+            self.log_page.set_bytes(LOG_HEADER_SIZE as u64, &noop)?;
+            self.log_page.set_i32(BOUNDARY, LOG_HEADER_SIZE as i32)?;
+        }
+        Ok(())
+    }
+
     pub fn append_new_block(&mut self) -> Result<BlockId> {
         let block = {
             let mut fm = self.file_manager.lock().expect("Failed to lock");
-            let block = fm.append(&self.log_file_name).unwrap();
-            self.log_page.set_i32(0, fm.block_size())?;
-            fm.write(&block, &mut self.log_page)?;
+            let block = fm.append(&self.log_file_name)?;
+            self.log_page.set_i32(BOUNDARY, fm.block_size())?;
+            self.log_page.set_i32(BASE_LSN, self.latest_lsn)?;
+            fm.write(&block, &self.log_page)?;
             block
         };
         Ok(block)
     }
 
     pub fn iterator(&mut self) -> Result<LogIterator> {
-        self.flush().unwrap();
-        Ok(LogIterator::new(Arc::clone(&self.file_manager), self.cur_block.clone()).unwrap())
+        self.flush()?;
+        LogIterator::new(
+            Arc::clone(&self.file_manager),
+            self.cur_block.clone(),
+            self.latest_lsn,
+        )
+    }
+
+    /// iterates the log from its first record to its last
+    pub fn forward_iterator(&mut self) -> Result<ForwardLogIterator> {
+        self.flush()?;
+        Ok(ForwardLogIterator {
+            file_manager: Arc::clone(&self.file_manager),
+            log_file_name: self.log_file_name.clone(),
+            next_block: 0,
+            last_block: self.cur_block.number(),
+            pending: Vec::new(),
+            next_lsn: 1,
+            include_padding: false,
+        })
+    }
+
+    /// the lsn of the last record known to be on disk
+    pub fn durable_lsn(&self) -> i32 {
+        self.last_saved_lsn
     }
 
     pub fn flush_with_lsn(&mut self, lsn: i32) -> Result<()> {
@@ -86,28 +173,84 @@ impl LogManager {
         Ok(())
     }
 
-    fn flush(&mut self) -> Result<()> {
+    /// writes the current log page to disk, making every appended record durable.
+    pub fn flush(&mut self) -> Result<()> {
         {
             self.file_manager
                 .lock()
                 .unwrap()
-                .write(&self.cur_block, &mut self.log_page)?;
+                .write(&self.cur_block, &self.log_page)?;
         }
         self.last_saved_lsn = self.latest_lsn;
+        self.stats.flushes += 1;
         Ok(())
     }
+
+    pub fn stats(&self) -> LogStats {
+        self.stats
+    }
+
+    /// the number of blocks of the log
+    pub fn size(&self) -> i32 {
+        self.cur_block.number() + 1
+    }
+}
+
+/// copies the log `log_file_name` from `src_dir` to `dst_dir`, rewriting every record in the
+/// current format with `record::migrate_record`, and returns the lsn of its last record.
+///
+/// Records keep their lsns, so compensation records still point at the right updates. the
+/// source log is left as it was, and a log already in `dst_dir` is never overwritten.
+pub fn migrate_log(
+    src_dir: impl AsRef<Path>,
+    dst_dir: impl AsRef<Path>,
+    log_file_name: &str,
+) -> std::result::Result<Lsn, LogRecordError> {
+    if !src_dir.as_ref().join(log_file_name).exists() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no log to migrate");
+        return Err(LogRecordError::FileError(err.into()));
+    }
+    if dst_dir.as_ref().join(log_file_name).exists() {
+        let err = io::Error::new(io::ErrorKind::AlreadyExists, "log already migrated");
+        return Err(LogRecordError::FileError(err.into()));
+    }
+    let src_fm = Arc::new(Mutex::new(FileManager::new(src_dir)?));
+    let dst_fm = Arc::new(Mutex::new(FileManager::new(dst_dir)?));
+    let mut src = LogManager::new(src_fm, log_file_name.to_string())?;
+    let mut dst = LogManager::new(dst_fm, log_file_name.to_string())?;
+
+    let mut lsn = 0;
+    for bytes in src.forward_iterator()? {
+        lsn = dst.append(record::migrate_record(&bytes?)?)?;
+    }
+    dst.flush()?;
+    Ok(lsn)
 }
 
+/// iterates the log newest record first, one block at a time.
+///
+/// A block that can't be read or parsed, e.g. one torn by a crash, ends the iteration with
+/// its error.
 pub struct LogIterator {
-    file_manager: Arc<Mutex<FileManager>>,
+    file_manager: Arc<Mutex<dyn BlockStorage>>,
     block_id: BlockId,
     page: Page,
     cur_pos: i32,
     boundary: i32,
+    next_lsn: i32,
+    include_padding: bool,
+    lower_bound: Option<Lsn>,
+    /// set once a block failed to read, after which nothing more is yielded
+    failed: bool,
 }
 
 impl LogIterator {
-    pub fn new(file_manager: Arc<Mutex<FileManager>>, block: BlockId) -> Result<Self> {
+    /// iterates backward from the last record in `block`, whose lsn is `lsn`
+    pub fn new(
+        file_manager: Arc<Mutex<dyn BlockStorage>>,
+        block: BlockId,
+        lsn: i32,
+    ) -> Result<Self> {
         let (page, cur_pos, boundary) = {
             let mut fm = file_manager.lock().expect("Failed to lock");
             let mut p = Page::new(fm.block_size());
@@ -123,40 +266,159 @@ impl LogIterator {
             page,
             cur_pos,
             boundary,
+            next_lsn: lsn,
+            include_padding: false,
+            lower_bound: None,
+            failed: false,
         })
     }
 
+    /// yields the noop records that pad the end of log blocks instead of skipping them
+    pub fn include_padding(mut self) -> Self {
+        self.include_padding = true;
+        self
+    }
+
+    /// ends after the record at `lsn`, without reading the blocks before it
+    pub fn down_to(mut self, lsn: Lsn) -> Self {
+        self.lower_bound = Some(lsn);
+        self
+    }
+
+    /// the lsn of the record the next call to `next` returns
+    pub fn next_lsn(&self) -> i32 {
+        self.next_lsn
+    }
+
     pub fn has_next(&self) -> bool {
         self.cur_pos < self.file_manager.lock().unwrap().block_size() || self.block_id.number() > 0
     }
 }
 
+impl LogIterator {
+    fn read_next(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut fm = self.file_manager.lock().expect("Failed to lock");
+        loop {
+            while self.cur_pos >= fm.block_size() {
+                if self.block_id.number() == 0 {
+                    return Ok(None);
+                }
+                self.block_id = BlockId::new(
+                    self.block_id.filename().to_string(),
+                    self.block_id.number() - 1,
+                );
+                fm.read(&self.block_id, &mut self.page)?;
+                self.boundary = self.page.get_i32(0)?;
+                self.cur_pos = self.boundary;
+            }
+            let record = self.page.get_bytes(self.cur_pos as u64)?;
+            self.cur_pos += (I32_SIZE + record.len()) as i32;
+            if record::is_padding(&record) {
+                if self.include_padding {
+                    return Ok(Some(record));
+                }
+                continue;
+            }
+            self.next_lsn -= 1;
+            return Ok(Some(record));
+        }
+    }
+}
+
 impl Iterator for LogIterator {
-    type Item = Vec<u8>;
+    type Item = Result<Vec<u8>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut fm = self.file_manager.lock().expect("Failed to lock");
-        if self.cur_pos >= fm.block_size() {
+        if self.failed || self.lower_bound.is_some_and(|lsn| self.next_lsn < lsn) {
             return None;
         }
-        if self.cur_pos == fm.block_size() {
-            let block = BlockId::new(
-                self.block_id.filename().to_string(),
-                self.block_id.number() - 1,
-            );
-            fm.read(&block, &mut self.page).unwrap();
-            self.boundary = self.page.get_i32(0).unwrap();
-            self.cur_pos = self.boundary;
-        };
-        let record = self.page.get_bytes(self.cur_pos as u64).unwrap();
-        self.cur_pos += (I32_SIZE + record.len()) as i32;
-        Some(record)
+        let next = self.read_next();
+        self.failed = next.is_err();
+        next.transpose()
+    }
+}
+
+/// iterates the log oldest record first, one block at a time.
+///
+/// A block that can't be read or parsed, e.g. one torn by a crash, ends the iteration with
+/// its error.
+pub struct ForwardLogIterator {
+    file_manager: Arc<Mutex<dyn BlockStorage>>,
+    log_file_name: String,
+    next_block: i32,
+    last_block: i32,
+    /// the records of the block being read, newest first
+    pending: Vec<Vec<u8>>,
+    next_lsn: i32,
+    include_padding: bool,
+}
+
+impl ForwardLogIterator {
+    /// the lsn of the record the next call to `next` returns
+    pub fn next_lsn(&self) -> i32 {
+        self.next_lsn
+    }
+
+    /// yields the noop records that pad the end of log blocks instead of skipping them
+    pub fn include_padding(mut self) -> Self {
+        self.include_padding = true;
+        self
+    }
+
+    fn read_block(&mut self, number: i32) -> Result<()> {
+        let mut fm = self.file_manager.lock().unwrap();
+        let block_size = fm.block_size();
+        let mut page = Page::new(block_size);
+        fm.read(&BlockId::new(&self.log_file_name, number), &mut page)?;
+        let mut pos = page.get_i32(0)?;
+        while pos < block_size {
+            let record = page.get_bytes(pos as u64)?;
+            pos += (I32_SIZE + record.len()) as i32;
+            self.pending.push(record);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for ForwardLogIterator {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            while self.pending.is_empty() {
+                if self.next_block > self.last_block {
+                    return None;
+                }
+                let read = self.read_block(self.next_block);
+                self.next_block += 1;
+                if let Err(err) = read {
+                    // nothing after a broken block is trusted
+                    self.pending.clear();
+                    self.next_block = self.last_block + 1;
+                    return Some(Err(err));
+                }
+            }
+            let record = self.pending.pop()?;
+            if record::is_padding(&record) {
+                if self.include_padding {
+                    return Some(Ok(record));
+                }
+                continue;
+            }
+            self.next_lsn += 1;
+            return Some(Ok(record));
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        record::{create_log_record as parse_record, RECORD_VERSION},
+        test_util::{v0_records, RecordingStorage, V0_RECORD_STRINGS},
+        tx::TxNumberAllocator,
+    };
     use std::{
         fs,
         path::PathBuf,
@@ -182,15 +444,15 @@ mod tests {
         }
 
         let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
-        let mut lm = LogManager::new(fm, filename.to_string());
+        let mut lm = LogManager::new(fm, filename.to_string()).unwrap();
         println!("creating records: ");
         for i in 0..35 {
             let rec = create_log_record(format!("record{}", i), i);
             lm.append(rec).unwrap();
         }
 
-        for rec in lm.iterator().unwrap() {
-            let mut page = Page::from(rec);
+        for rec in lm.iterator().unwrap().map(Result::unwrap) {
+            let page = Page::from(rec);
             let s = page.get_string(0).unwrap();
             let npos = Page::max_length(s.len());
             let val = page.get_i32(npos as u64).unwrap();
@@ -202,4 +464,206 @@ mod tests {
             fs::remove_dir("__test_3").expect("failed to remove dir");
         }
     }
+
+    #[test]
+    fn lsns_survive_restart_and_iteration_spans_blocks() {
+        let dirname = "__test_32";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let mut lm = LogManager::new(fm.clone(), "test_log".to_string()).unwrap();
+        for i in 1..=500 {
+            let lsn = lm
+                .append(create_log_record(format!("record{}", i), i))
+                .unwrap();
+            assert_eq!(lsn, i);
+        }
+        lm.flush().unwrap();
+        let blocks = fm.lock().unwrap().length("test_log").unwrap();
+        assert!(blocks > 1);
+        drop(lm);
+
+        // the lsns are counted from the header of the last block, the others aren't read
+        let reads = Arc::new(Mutex::new(Vec::new()));
+        let fm = Arc::new(Mutex::new(RecordingStorage {
+            inner: FileManager::new(dirname).unwrap(),
+            reads: Arc::clone(&reads),
+            writes: Arc::new(Mutex::new(Vec::new())),
+        }));
+        let mut lm = LogManager::new(fm, "test_log".to_string()).unwrap();
+        assert_eq!(
+            *reads.lock().unwrap(),
+            [BlockId::new("test_log", blocks - 1)]
+        );
+        assert_eq!(lm.durable_lsn(), 500);
+        assert_eq!(
+            lm.append(create_log_record("record501".to_string(), 501))
+                .unwrap(),
+            501
+        );
+
+        let mut iter = lm.iterator().unwrap();
+        let mut expected = 501;
+        while let Some(rec) = {
+            assert_eq!(iter.next_lsn(), expected);
+            iter.next()
+        } {
+            let page = Page::from(rec.unwrap());
+            let npos = Page::max_length(page.get_string(0).unwrap().len());
+            assert_eq!(page.get_i32(npos as u64).unwrap(), expected);
+            expected -= 1;
+        }
+        assert_eq!(expected, 0);
+        assert!(iter.next().is_none());
+
+        let iter = lm.iterator().unwrap().down_to(250);
+        assert_eq!(iter.count(), 252);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_corrupt_last_block_fails_to_open() {
+        let dirname = "__test_104";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let mut lm = LogManager::new(fm.clone(), "test_log".to_string()).unwrap();
+        lm.append(vec![1; 10]).unwrap();
+        lm.flush().unwrap();
+        drop(lm);
+
+        let block = BlockId::new("test_log", 0);
+        let mut page = Page::new(fm.lock().unwrap().block_size());
+        fm.lock().unwrap().read(&block, &mut page).unwrap();
+        page.set_i32(BOUNDARY, 2).unwrap();
+        fm.lock().unwrap().write(&block, &page).unwrap();
+        let err = LogManager::new(fm, "test_log".to_string()).unwrap_err();
+        assert!(err.to_string().contains("can't start at 2"));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn forward_iteration_stops_at_a_torn_block() {
+        let dirname = "__test_106";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let mut lm = LogManager::new(fm.clone(), "test_log".to_string()).unwrap();
+        for i in 0..1000 {
+            lm.append(create_log_record(format!("record{}", i), i))
+                .unwrap();
+        }
+        assert!(lm.size() > 2);
+
+        // the newest record of block 1 claims more bytes than the block holds
+        let block = BlockId::new("test_log", 1);
+        let mut page = Page::new(fm.lock().unwrap().block_size());
+        fm.lock().unwrap().read(&block, &mut page).unwrap();
+        let newest = page.get_i32(BOUNDARY).unwrap();
+        page.set_i32(newest as u64, i32::MAX).unwrap();
+        fm.lock().unwrap().write(&block, &page).unwrap();
+
+        let mut iter = lm.forward_iterator().unwrap();
+        let read = iter.by_ref().take_while(|rec| rec.is_ok()).count();
+        assert!(read > 0);
+        assert!(iter.next().is_none());
+        assert!(lm.forward_iterator().unwrap().any(|rec| rec.is_err()));
+        let mut backward = lm.iterator().unwrap();
+        assert!(backward.by_ref().any(|rec| rec.is_err()));
+        assert!(backward.next().is_none());
+        assert!(TxNumberAllocator::recovered(&mut lm).is_err());
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn end_of_block_is_padded_with_a_noop() {
+        let dirname = "__test_36";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let mut lm = LogManager::new(fm.clone(), "test_log".to_string()).unwrap();
+        let block_size = fm.lock().unwrap().block_size() as usize;
+
+        // leaves 80 free bytes in the first block, too few for the next record
+        lm.append(vec![1; block_size - LOG_HEADER_SIZE - I32_SIZE - 80])
+            .unwrap();
+        lm.append(vec![2; 100]).unwrap();
+        lm.append(vec![3; 10]).unwrap();
+
+        let forward: Vec<(i32, u8)> = {
+            let mut iter = lm.forward_iterator().unwrap();
+            let mut records = vec![];
+            while let Some(rec) = {
+                let lsn = iter.next_lsn();
+                iter.next().map(|rec| (lsn, rec.unwrap()[0]))
+            } {
+                records.push(rec);
+            }
+            records
+        };
+        assert_eq!(forward, vec![(1, 1), (2, 2), (3, 3)]);
+
+        let padded: Vec<Vec<u8>> = lm
+            .forward_iterator()
+            .unwrap()
+            .include_padding()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(padded.len(), 4);
+        assert!(record::is_padding(&padded[1]));
+        assert_eq!(padded[1].len(), 80 - I32_SIZE);
+
+        let backward: Vec<u8> = lm
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|rec| rec[0])
+            .collect();
+        assert_eq!(backward, vec![3, 2, 1]);
+        drop(lm);
+
+        let lm = LogManager::new(fm, "test_log".to_string()).unwrap();
+        assert_eq!(lm.durable_lsn(), 3);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn old_logs_migrate_to_the_current_format() {
+        let dirname = "__test_42";
+        let (src_dir, dst_dir) = (format!("{}/src", dirname), format!("{}/dst", dirname));
+        let fm = Arc::new(Mutex::new(FileManager::new(&src_dir).unwrap()));
+        let mut lm = LogManager::new(fm, "test_log".to_string()).unwrap();
+        // enough copies to span blocks, so that the old log is padded too
+        for _ in 0..50 {
+            for rec in v0_records() {
+                lm.append(rec).unwrap();
+            }
+        }
+        lm.flush().unwrap();
+        drop(lm);
+
+        let records = 50 * V0_RECORD_STRINGS.len() as i32;
+        assert_eq!(
+            migrate_log(&src_dir, &dst_dir, "test_log").unwrap(),
+            records
+        );
+
+        let fm = Arc::new(Mutex::new(FileManager::new(&dst_dir).unwrap()));
+        let mut lm = LogManager::new(fm, "test_log".to_string()).unwrap();
+        assert_eq!(lm.durable_lsn(), records);
+        let mut iter = lm.forward_iterator().unwrap();
+        loop {
+            let lsn = iter.next_lsn();
+            let Some(bytes) = iter.next() else {
+                break;
+            };
+            let bytes = bytes.unwrap();
+            assert_eq!(bytes[0], RECORD_VERSION);
+            let expected = V0_RECORD_STRINGS[(lsn - 1) as usize % V0_RECORD_STRINGS.len()];
+            assert_eq!(parse_record(&bytes).unwrap().to_string(), expected);
+        }
+        assert_eq!(iter.next_lsn(), records + 1);
+
+        assert!(migrate_log(&src_dir, &dst_dir, "test_log").is_err());
+        assert!(migrate_log(&dst_dir, &src_dir, "missing_log").is_err());
+        assert!(!PathBuf::from(&src_dir).join("missing_log").exists());
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
 }
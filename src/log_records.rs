@@ -1,15 +1,61 @@
 use std::{
-    fmt, mem,
+    fmt,
     sync::{Arc, Mutex},
 };
 
 use crate::{
-    file::{FileError, Page},
-    log::LogManager,
-    record::{LogRecord, TxType},
+    buffer::BufferError,
+    clock::Clock,
+    file::{BlockId, FileError, I32_SIZE},
+    log::{LogManager, Lsn},
+    record::{
+        encode_checked_record, encode_record, LogRecord, LogRecordBuilder, LogRecordError,
+        RecordCursor, RecordOp, TxType,
+    },
+    tx::{self, TxOps},
 };
 
-#[derive(Debug, Default)]
+/// pins `block` and redoes an update to it with `write`. only `ExtendFileRecord` grows files,
+/// so a block past the end of its file, e.g. one truncated since, is `BlockOutOfRange`.
+fn redo_write(
+    tx: &mut dyn TxOps,
+    block: &BlockId,
+    write: impl FnOnce(&mut dyn TxOps) -> tx::Result<()>,
+) -> tx::Result<()> {
+    if tx.size(block.filename())? <= block.number() {
+        return Err(BufferError::BlockOutOfRange(block.clone()).into());
+    }
+    tx.pin(block)?;
+    let res = write(tx);
+    tx.unpin(block);
+    res
+}
+
+/// appends `rec` to the log in the current record format and returns its lsn
+pub fn write_record(lm: Arc<Mutex<LogManager>>, rec: &impl LogRecord) -> Result<Lsn, FileError> {
+    lm.lock().unwrap().append(encode_record(&rec.to_bytes()))
+}
+
+/// like `write_record`, with a checksum after the record (see `encode_checked_record`)
+pub fn write_checked_record(
+    lm: Arc<Mutex<LogManager>>,
+    rec: &impl LogRecord,
+) -> Result<Lsn, FileError> {
+    lm.lock()
+        .unwrap()
+        .append(encode_checked_record(&rec.to_bytes()))
+}
+
+/// `s` cut to `width` chars, ending in `...` when anything was cut
+fn truncate(s: &str, width: usize) -> String {
+    match s.char_indices().nth(width) {
+        Some((end, _)) => format!("{}...", &s[..end]),
+        None => s.to_string(),
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CheckPointRecord {}
 
 impl fmt::Display for CheckPointRecord {
@@ -20,12 +66,17 @@ impl fmt::Display for CheckPointRecord {
 
 impl LogRecord for CheckPointRecord {
     fn op(&self) -> TxType {
-        TxType::CheckPoint
+        Self::OP
     }
 
     fn tx_num(&self) -> i32 {
         -1
     }
+
+    /// layout: op
+    fn to_bytes(&self) -> Vec<u8> {
+        LogRecordBuilder::new(self.op()).build()
+    }
 }
 
 impl CheckPointRecord {
@@ -33,182 +84,1795 @@ impl CheckPointRecord {
         Self {}
     }
 
-    pub fn write_to_log(lm: Arc<Mutex<LogManager>>) -> Result<i32, FileError> {
-        let reclen = mem::size_of::<i32>();
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(_bytes: &[u8]) -> Result<Self, LogRecordError> {
+        Ok(Self {})
+    }
+}
+
+/// `<NQCKPT txnum, ...>`: a checkpoint taken while the listed transactions were running.
+/// recovery reads the log back past it to the start of the earliest of them.
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NqCheckPointRecord {
+    txnums: Vec<i32>,
+}
+
+impl fmt::Display for NqCheckPointRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let txnums: Vec<String> = self.txnums.iter().map(i32::to_string).collect();
+        if txnums.is_empty() {
+            write!(f, "<NQCKPT>")
+        } else {
+            write!(f, "<NQCKPT {}>", txnums.join(", "))
+        }
+    }
+}
+
+impl LogRecord for NqCheckPointRecord {
+    fn op(&self) -> TxType {
+        Self::OP
+    }
+
+    fn tx_num(&self) -> i32 {
+        -1
+    }
+
+    /// layout: op | txnum...
+    fn to_bytes(&self) -> Vec<u8> {
+        self.txnums
+            .iter()
+            .fold(LogRecordBuilder::new(self.op()), |builder, &txnum| {
+                builder.i32(txnum)
+            })
+            .build()
+    }
+}
+
+impl NqCheckPointRecord {
+    pub fn new(txnums: Vec<i32>) -> Self {
+        Self { txnums }
+    }
 
-        let mut p = Page::new(reclen.try_into().unwrap());
-        p.set_i32(0, TxType::CheckPoint as i32)?;
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        let mut txnums = Vec::new();
+        while r.has_more() {
+            txnums.push(r.read_i32()?);
+        }
+        Ok(Self { txnums })
+    }
 
-        lm.lock().unwrap().append(p.contents().to_vec())
+    /// the transactions that were running when the checkpoint was taken
+    pub fn txnums(&self) -> &[i32] {
+        &self.txnums
     }
 }
 
-#[derive(Debug, Default)]
-pub struct StartRecord {}
+/// `<START txnum @timestamp>`, where the timestamp is in microseconds since the unix epoch.
+/// records written before timestamps were logged have none.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StartRecord {
+    txnum: i32,
+    timestamp: Option<i64>,
+}
 
 impl fmt::Display for StartRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<START>")
+        match self.timestamp {
+            Some(timestamp) => write!(f, "<START {} @{}>", self.txnum, timestamp),
+            None => write!(f, "<START {}>", self.txnum),
+        }
     }
 }
 
 impl LogRecord for StartRecord {
     fn op(&self) -> TxType {
-        TxType::Start
+        Self::OP
     }
 
     fn tx_num(&self) -> i32 {
-        -1
+        self.txnum
+    }
+
+    /// layout: op | txnum | timestamp, where the timestamp is left out when there is none
+    fn to_bytes(&self) -> Vec<u8> {
+        let builder = LogRecordBuilder::new(self.op()).i32(self.txnum);
+        match self.timestamp {
+            Some(timestamp) => builder.i64(timestamp).build(),
+            None => builder.build(),
+        }
     }
 }
 
 impl StartRecord {
-    pub fn new() -> Self {
-        Self {}
+    /// a record without a timestamp
+    pub fn new(txnum: i32) -> Self {
+        Self {
+            txnum,
+            timestamp: None,
+        }
     }
 
-    pub fn write_to_log(lm: Arc<Mutex<LogManager>>) -> Result<i32, FileError> {
-        let reclen = mem::size_of::<i32>();
+    /// a record stamped with the current time of `clock`
+    pub fn stamped(txnum: i32, clock: &dyn Clock) -> Self {
+        Self {
+            txnum,
+            timestamp: Some(clock.micros_since_epoch()),
+        }
+    }
 
-        let mut p = Page::new(reclen.try_into().unwrap());
-        p.set_i32(0, TxType::Start as i32)?;
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        let txnum = r.read_i32()?;
+        let timestamp = if r.has_more() {
+            Some(r.read_i64()?)
+        } else {
+            None
+        };
+        Ok(Self { txnum, timestamp })
+    }
 
-        lm.lock().unwrap().append(p.contents().to_vec())
+    /// when the record was written, in microseconds since the unix epoch
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
     }
 }
 
-#[derive(Debug, Default)]
-pub struct CommitRecord {}
+/// `<COMMIT txnum @timestamp>`, where the timestamp is in microseconds since the unix epoch.
+/// records written before timestamps were logged have none.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommitRecord {
+    txnum: i32,
+    timestamp: Option<i64>,
+}
 
 impl fmt::Display for CommitRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<COMMIT>")
+        match self.timestamp {
+            Some(timestamp) => write!(f, "<COMMIT {} @{}>", self.txnum, timestamp),
+            None => write!(f, "<COMMIT {}>", self.txnum),
+        }
     }
 }
 
 impl LogRecord for CommitRecord {
     fn op(&self) -> TxType {
-        TxType::Commit
+        Self::OP
     }
 
     fn tx_num(&self) -> i32 {
-        -1
+        self.txnum
+    }
+
+    /// layout: op | txnum | timestamp, where the timestamp is left out when there is none
+    fn to_bytes(&self) -> Vec<u8> {
+        let builder = LogRecordBuilder::new(self.op()).i32(self.txnum);
+        match self.timestamp {
+            Some(timestamp) => builder.i64(timestamp).build(),
+            None => builder.build(),
+        }
     }
 }
 
 impl CommitRecord {
-    pub fn new() -> Self {
-        Self {}
+    /// a record without a timestamp
+    pub fn new(txnum: i32) -> Self {
+        Self {
+            txnum,
+            timestamp: None,
+        }
     }
 
-    pub fn write_to_log(lm: Arc<Mutex<LogManager>>) -> Result<i32, FileError> {
-        let reclen = mem::size_of::<i32>();
+    /// a record stamped with the current time of `clock`
+    pub fn stamped(txnum: i32, clock: &dyn Clock) -> Self {
+        Self {
+            txnum,
+            timestamp: Some(clock.micros_since_epoch()),
+        }
+    }
 
-        let mut p = Page::new(reclen.try_into().unwrap());
-        p.set_i32(0, TxType::Commit as i32)?;
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        let txnum = r.read_i32()?;
+        let timestamp = if r.has_more() {
+            Some(r.read_i64()?)
+        } else {
+            None
+        };
+        Ok(Self { txnum, timestamp })
+    }
 
-        lm.lock().unwrap().append(p.contents().to_vec())
+    /// when the record was written, in microseconds since the unix epoch
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
     }
 }
 
-#[derive(Debug, Default)]
-pub struct RollbackRecord {}
+/// `<ROLLBACK txnum @timestamp>`, where the timestamp is in microseconds since the unix epoch.
+/// records written before timestamps were logged have none.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RollbackRecord {
+    txnum: i32,
+    timestamp: Option<i64>,
+}
 
 impl fmt::Display for RollbackRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<Rollback>")
+        match self.timestamp {
+            Some(timestamp) => write!(f, "<ROLLBACK {} @{}>", self.txnum, timestamp),
+            None => write!(f, "<ROLLBACK {}>", self.txnum),
+        }
     }
 }
 
 impl LogRecord for RollbackRecord {
     fn op(&self) -> TxType {
-        TxType::Rollback
+        Self::OP
     }
 
     fn tx_num(&self) -> i32 {
-        -1
+        self.txnum
+    }
+
+    /// layout: op | txnum | timestamp, where the timestamp is left out when there is none
+    fn to_bytes(&self) -> Vec<u8> {
+        let builder = LogRecordBuilder::new(self.op()).i32(self.txnum);
+        match self.timestamp {
+            Some(timestamp) => builder.i64(timestamp).build(),
+            None => builder.build(),
+        }
     }
 }
 
 impl RollbackRecord {
-    pub fn new() -> Self {
-        Self {}
+    /// a record without a timestamp
+    pub fn new(txnum: i32) -> Self {
+        Self {
+            txnum,
+            timestamp: None,
+        }
     }
 
-    pub fn write_to_log(lm: Arc<Mutex<LogManager>>) -> Result<i32, FileError> {
-        let reclen = mem::size_of::<i32>();
+    /// a record stamped with the current time of `clock`
+    pub fn stamped(txnum: i32, clock: &dyn Clock) -> Self {
+        Self {
+            txnum,
+            timestamp: Some(clock.micros_since_epoch()),
+        }
+    }
 
-        let mut p = Page::new(reclen.try_into().unwrap());
-        p.set_i32(0, TxType::Rollback as i32)?;
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        let txnum = r.read_i32()?;
+        let timestamp = if r.has_more() {
+            Some(r.read_i64()?)
+        } else {
+            None
+        };
+        Ok(Self { txnum, timestamp })
+    }
 
-        lm.lock().unwrap().append(p.contents().to_vec())
+    /// when the record was written, in microseconds since the unix epoch
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
     }
 }
 
-#[derive(Debug, Default)]
-pub struct SetI32Record {}
+/// `<SETI32 txnum [filename, block] offset old_val>`: the i32 at `offset` of
+/// `block` was overwritten by `txnum`; `old_val` is what it held before.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetI32Record {
+    txnum: i32,
+    block: BlockId,
+    offset: i32,
+    old_val: i32,
+    /// what `txnum` wrote. records written before new values were logged don't have it
+    new_val: Option<i32>,
+}
 
 impl fmt::Display for SetI32Record {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<SETI32>")
+        write!(
+            f,
+            "<SETI32 {} [{}, {}] {} {}",
+            self.txnum,
+            self.block.filename(),
+            self.block.number(),
+            self.offset,
+            self.old_val
+        )?;
+        if let Some(new_val) = self.new_val {
+            write!(f, " -> {}", new_val)?;
+        }
+        f.write_str(">")
     }
 }
 
 impl LogRecord for SetI32Record {
     fn op(&self) -> TxType {
-        TxType::SetI32
+        Self::OP
     }
 
     fn tx_num(&self) -> i32 {
-        -1
+        self.txnum
+    }
+
+    fn target(&self) -> Option<(&BlockId, i32)> {
+        Some((&self.block, self.offset))
+    }
+
+    fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        tx.pin(&self.block)?;
+        let res = tx.set_i32(&self.block, self.offset, self.old_val, false);
+        tx.unpin(&self.block);
+        res
+    }
+
+    /// fails with `RedoUnavailable` for records written before new values were logged
+    fn redo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        let Some(new_val) = self.new_val else {
+            return Err(LogRecordError::RedoUnavailable.into());
+        };
+        redo_write(tx, &self.block, |tx| {
+            tx.set_i32(&self.block, self.offset, new_val, false)
+        })
+    }
+
+    /// layout: op | txnum | filename | block number | offset | old_val [| new_val]
+    fn to_bytes(&self) -> Vec<u8> {
+        let bytes = LogRecordBuilder::new(self.op())
+            .i32(self.txnum)
+            .block(&self.block)
+            .i32(self.offset)
+            .i32(self.old_val);
+        match self.new_val {
+            Some(new_val) => bytes.i32(new_val).build(),
+            None => bytes.build(),
+        }
     }
 }
 
 impl SetI32Record {
-    pub fn new() -> Self {
-        Self {}
+    /// a record without the new value, as written before new values were logged
+    pub fn new(txnum: i32, block: BlockId, offset: i32, old_val: i32) -> Self {
+        Self {
+            txnum,
+            block,
+            offset,
+            old_val,
+            new_val: None,
+        }
     }
 
-    pub fn write_to_log(lm: Arc<Mutex<LogManager>>) -> Result<i32, FileError> {
-        let reclen = mem::size_of::<i32>();
+    /// a record that can be redone as well as undone
+    pub fn with_values(
+        txnum: i32,
+        block: BlockId,
+        offset: i32,
+        old_val: i32,
+        new_val: i32,
+    ) -> Self {
+        Self {
+            new_val: Some(new_val),
+            ..Self::new(txnum, block, offset, old_val)
+        }
+    }
 
-        let mut p = Page::new(reclen.try_into().unwrap());
-        p.set_i32(0, TxType::SetI32 as i32)?;
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Self {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+            offset: r.read_i32()?,
+            old_val: r.read_i32()?,
+            new_val: if r.has_more() {
+                Some(r.read_i32()?)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
 
-        lm.lock().unwrap().append(p.contents().to_vec())
+    pub fn old_val(&self) -> i32 {
+        self.old_val
+    }
+    pub fn new_val(&self) -> Option<i32> {
+        self.new_val
     }
 }
 
-#[derive(Debug, Default)]
-pub struct SetStringRecord {}
+/// `<SETSTRING txnum [filename, block] offset old_val>`: the string at `offset` of
+/// `block` was overwritten by `txnum`; `old_val` is what it held before.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetStringRecord {
+    txnum: i32,
+    block: BlockId,
+    offset: i32,
+    old_val: String,
+    /// what `txnum` wrote. records written before new values were logged don't have it
+    new_val: Option<String>,
+}
 
 impl fmt::Display for SetStringRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "<SETSTRING>")
+        f.write_str(&self.summary(usize::MAX))
     }
 }
 
 impl LogRecord for SetStringRecord {
     fn op(&self) -> TxType {
-        TxType::SetI32
+        Self::OP
     }
 
     fn tx_num(&self) -> i32 {
-        -1
+        self.txnum
+    }
+
+    fn target(&self) -> Option<(&BlockId, i32)> {
+        Some((&self.block, self.offset))
+    }
+
+    fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        tx.pin(&self.block)?;
+        let res = tx.set_string(&self.block, self.offset, &self.old_val, false);
+        tx.unpin(&self.block);
+        res
+    }
+
+    /// fails with `RedoUnavailable` for records written before new values were logged
+    fn redo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        let Some(new_val) = self.new_val() else {
+            return Err(LogRecordError::RedoUnavailable.into());
+        };
+        redo_write(tx, &self.block, |tx| {
+            tx.set_string(&self.block, self.offset, new_val, false)
+        })
+    }
+
+    /// layout: op | txnum | filename | block number | offset | old_val [| new_val]
+    fn to_bytes(&self) -> Vec<u8> {
+        let bytes = LogRecordBuilder::new(self.op())
+            .i32(self.txnum)
+            .block(&self.block)
+            .i32(self.offset)
+            .string(&self.old_val);
+        match &self.new_val {
+            Some(new_val) => bytes.string(new_val).build(),
+            None => bytes.build(),
+        }
+    }
+
+    fn summary(&self, width: usize) -> String {
+        let new_val = match &self.new_val {
+            Some(new_val) => format!(" -> \"{}\"", truncate(new_val, width)),
+            None => String::new(),
+        };
+        format!(
+            "<SETSTRING {} [{}, {}] {} \"{}\"{}>",
+            self.txnum,
+            self.block.filename(),
+            self.block.number(),
+            self.offset,
+            truncate(&self.old_val, width),
+            new_val
+        )
     }
 }
 
 impl SetStringRecord {
-    pub fn new() -> Self {
-        Self {}
+    /// a record without the new value, as written before new values were logged
+    pub fn new(txnum: i32, block: BlockId, offset: i32, old_val: impl Into<String>) -> Self {
+        Self {
+            txnum,
+            block,
+            offset,
+            old_val: old_val.into(),
+            new_val: None,
+        }
+    }
+
+    /// a record that can be redone as well as undone
+    pub fn with_values(
+        txnum: i32,
+        block: BlockId,
+        offset: i32,
+        old_val: impl Into<String>,
+        new_val: impl Into<String>,
+    ) -> Self {
+        Self {
+            new_val: Some(new_val.into()),
+            ..Self::new(txnum, block, offset, old_val)
+        }
+    }
+
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Self {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+            offset: r.read_i32()?,
+            old_val: r.read_string()?,
+            new_val: if r.has_more() {
+                Some(r.read_string()?)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn old_val(&self) -> &str {
+        &self.old_val
+    }
+    pub fn new_val(&self) -> Option<&str> {
+        self.new_val.as_deref()
+    }
+}
+
+/// `<SETU64 txnum [filename, block] offset old_val>`: the u64 at `offset` of
+/// `block` was overwritten by `txnum`; `old_val` is what it held before.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetU64Record {
+    txnum: i32,
+    block: BlockId,
+    offset: i32,
+    old_val: u64,
+    /// what `txnum` wrote. records written before new values were logged don't have it
+    new_val: Option<u64>,
+}
+
+impl fmt::Display for SetU64Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<SETU64 {} [{}, {}] {} {}",
+            self.txnum,
+            self.block.filename(),
+            self.block.number(),
+            self.offset,
+            self.old_val
+        )?;
+        if let Some(new_val) = self.new_val {
+            write!(f, " -> {}", new_val)?;
+        }
+        f.write_str(">")
+    }
+}
+
+impl LogRecord for SetU64Record {
+    fn op(&self) -> TxType {
+        Self::OP
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.txnum
+    }
+
+    fn target(&self) -> Option<(&BlockId, i32)> {
+        Some((&self.block, self.offset))
+    }
+
+    fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        tx.pin(&self.block)?;
+        let res = tx.set_u64(&self.block, self.offset, self.old_val, false);
+        tx.unpin(&self.block);
+        res
+    }
+
+    /// fails with `RedoUnavailable` for records written before new values were logged
+    fn redo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        let Some(new_val) = self.new_val else {
+            return Err(LogRecordError::RedoUnavailable.into());
+        };
+        redo_write(tx, &self.block, |tx| {
+            tx.set_u64(&self.block, self.offset, new_val, false)
+        })
+    }
+
+    /// layout: op | txnum | filename | block number | offset | old_val [| new_val]
+    fn to_bytes(&self) -> Vec<u8> {
+        let bytes = LogRecordBuilder::new(self.op())
+            .i32(self.txnum)
+            .block(&self.block)
+            .i32(self.offset)
+            .u64(self.old_val);
+        match self.new_val {
+            Some(new_val) => bytes.u64(new_val).build(),
+            None => bytes.build(),
+        }
+    }
+}
+
+impl SetU64Record {
+    /// a record without the new value, as written before new values were logged
+    pub fn new(txnum: i32, block: BlockId, offset: i32, old_val: u64) -> Self {
+        Self {
+            txnum,
+            block,
+            offset,
+            old_val,
+            new_val: None,
+        }
+    }
+
+    /// a record that can be redone as well as undone
+    pub fn with_values(
+        txnum: i32,
+        block: BlockId,
+        offset: i32,
+        old_val: u64,
+        new_val: u64,
+    ) -> Self {
+        Self {
+            new_val: Some(new_val),
+            ..Self::new(txnum, block, offset, old_val)
+        }
+    }
+
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Self {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+            offset: r.read_i32()?,
+            old_val: r.read_u64()?,
+            new_val: if r.has_more() {
+                Some(r.read_u64()?)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn old_val(&self) -> u64 {
+        self.old_val
+    }
+    pub fn new_val(&self) -> Option<u64> {
+        self.new_val
+    }
+}
+
+/// `<SETBYTES txnum [filename, block] offset old_val>`: the byte range at `offset` of
+/// `block` was overwritten by `txnum`; `old_val` is what it held before.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SetBytesRecord {
+    txnum: i32,
+    block: BlockId,
+    offset: i32,
+    old_val: Vec<u8>,
+    /// what `txnum` wrote. records written before new values were logged don't have it
+    new_val: Option<Vec<u8>>,
+}
+
+impl fmt::Display for SetBytesRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary(usize::MAX))
+    }
+}
+
+impl LogRecord for SetBytesRecord {
+    fn op(&self) -> TxType {
+        Self::OP
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.txnum
+    }
+
+    fn target(&self) -> Option<(&BlockId, i32)> {
+        Some((&self.block, self.offset))
+    }
+
+    /// writes the old length along with the old bytes, so the result doesn't depend on the
+    /// length stored when undo runs
+    fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        tx.pin(&self.block)?;
+        let res = tx.set_bytes(&self.block, self.offset, &self.old_val, false);
+        tx.unpin(&self.block);
+        res
+    }
+
+    /// fails with `RedoUnavailable` for records written before new values were logged
+    fn redo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        let Some(new_val) = self.new_val() else {
+            return Err(LogRecordError::RedoUnavailable.into());
+        };
+        redo_write(tx, &self.block, |tx| {
+            tx.set_bytes(&self.block, self.offset, new_val, false)
+        })
+    }
+
+    /// layout: op | txnum | filename | block number | offset | old_val [| new_val], where the
+    /// values carry their own length so undo and redo restore exactly their range
+    fn to_bytes(&self) -> Vec<u8> {
+        let bytes = LogRecordBuilder::new(self.op())
+            .i32(self.txnum)
+            .block(&self.block)
+            .i32(self.offset)
+            .bytes(&self.old_val);
+        match &self.new_val {
+            Some(new_val) => bytes.bytes(new_val).build(),
+            None => bytes.build(),
+        }
+    }
+
+    fn summary(&self, width: usize) -> String {
+        let show = |bytes: &[u8]| {
+            let mut shown: Vec<String> = bytes.iter().take(width).map(u8::to_string).collect();
+            if bytes.len() > width {
+                shown.push("...".to_string());
+            }
+            shown.join(", ")
+        };
+        let new_val = match &self.new_val {
+            Some(new_val) => format!(" -> [{}]", show(new_val)),
+            None => String::new(),
+        };
+        format!(
+            "<SETBYTES {} [{}, {}] {} [{}]{}>",
+            self.txnum,
+            self.block.filename(),
+            self.block.number(),
+            self.offset,
+            show(&self.old_val),
+            new_val
+        )
+    }
+}
+
+impl SetBytesRecord {
+    /// a record without the new value, as written before new values were logged
+    pub fn new(txnum: i32, block: BlockId, offset: i32, old_val: impl Into<Vec<u8>>) -> Self {
+        Self {
+            txnum,
+            block,
+            offset,
+            old_val: old_val.into(),
+            new_val: None,
+        }
+    }
+
+    /// a record that can be redone as well as undone
+    pub fn with_values(
+        txnum: i32,
+        block: BlockId,
+        offset: i32,
+        old_val: impl Into<Vec<u8>>,
+        new_val: impl Into<Vec<u8>>,
+    ) -> Self {
+        Self {
+            new_val: Some(new_val.into()),
+            ..Self::new(txnum, block, offset, old_val)
+        }
+    }
+
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Self {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+            offset: r.read_i32()?,
+            old_val: r.read_bytes()?,
+            new_val: if r.has_more() {
+                Some(r.read_bytes()?)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn old_val(&self) -> &[u8] {
+        &self.old_val
+    }
+    pub fn new_val(&self) -> Option<&[u8]> {
+        self.new_val.as_deref()
+    }
+}
+
+/// `<CLR txnum [filename, block] offset undo_next_lsn>`: undo of `txnum` restored `offset` of
+/// `block`. every update of `txnum` above `undo_next_lsn` has been undone, so a rollback
+/// interrupted by a crash resumes at `undo_next_lsn` instead of undoing them again.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompensationRecord {
+    txnum: i32,
+    block: BlockId,
+    offset: i32,
+    undo_next_lsn: Lsn,
+}
+
+impl fmt::Display for CompensationRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<CLR {} [{}, {}] {} {}>",
+            self.txnum,
+            self.block.filename(),
+            self.block.number(),
+            self.offset,
+            self.undo_next_lsn
+        )
+    }
+}
+
+impl LogRecord for CompensationRecord {
+    fn op(&self) -> TxType {
+        Self::OP
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.txnum
+    }
+
+    fn undo_next_lsn(&self) -> Option<Lsn> {
+        Some(self.undo_next_lsn)
+    }
+
+    /// layout: op | txnum | filename | block number | offset | undo_next_lsn
+    fn to_bytes(&self) -> Vec<u8> {
+        LogRecordBuilder::new(self.op())
+            .i32(self.txnum)
+            .block(&self.block)
+            .i32(self.offset)
+            .i32(self.undo_next_lsn)
+            .build()
+    }
+}
+
+impl CompensationRecord {
+    pub fn new(txnum: i32, block: BlockId, offset: i32, undo_next_lsn: Lsn) -> Self {
+        Self {
+            txnum,
+            block,
+            offset,
+            undo_next_lsn,
+        }
+    }
+
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Self {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+            offset: r.read_i32()?,
+            undo_next_lsn: r.read_i32()?,
+        })
+    }
+
+    /// same as `undo_next_lsn`, which every compensation record has
+    pub fn undo_next(&self) -> Lsn {
+        self.undo_next_lsn
+    }
+}
+
+/// `<EXTEND txnum [filename, block]>`: `txnum` is about to append `block` to its file.
+/// it is written before the file grows, so recovery can shrink the file back if `txnum` didn't
+/// commit, or grow it again if it did and the new block never reached the disk.
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtendFileRecord {
+    txnum: i32,
+    block: BlockId,
+}
+
+impl fmt::Display for ExtendFileRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "<EXTEND {} [{}, {}]>",
+            self.txnum,
+            self.block.filename(),
+            self.block.number()
+        )
+    }
+}
+
+impl LogRecord for ExtendFileRecord {
+    fn op(&self) -> TxType {
+        Self::OP
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.txnum
+    }
+
+    /// truncates the file back to the blocks it had before the append
+    fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        tx.truncate(self.block.filename(), self.block.number())
+    }
+
+    /// appends empty blocks until the file holds the appended block again
+    fn redo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        while tx.size(self.block.filename())? <= self.block.number() {
+            tx.append(self.block.filename())?;
+        }
+        Ok(())
+    }
+
+    /// layout: op | txnum | filename | new block number
+    fn to_bytes(&self) -> Vec<u8> {
+        LogRecordBuilder::new(self.op())
+            .i32(self.txnum)
+            .block(&self.block)
+            .build()
+    }
+}
+
+impl ExtendFileRecord {
+    pub fn new(txnum: i32, block: BlockId) -> Self {
+        Self { txnum, block }
+    }
+
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Self {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+        })
+    }
+
+    /// the block the append created
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+}
+
+/// `<NOOP len>`: `len` bytes of padding that fill the end of a log block. it belongs to no
+/// transaction, takes no lsn and is skipped by the log iterators.
+#[derive(Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoopRecord {
+    len: usize,
+}
+
+impl fmt::Display for NoopRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<NOOP {}>", self.len)
+    }
+}
+
+impl LogRecord for NoopRecord {
+    fn op(&self) -> TxType {
+        Self::OP
+    }
+
+    fn tx_num(&self) -> i32 {
+        -1
     }
 
-    pub fn write_to_log(lm: Arc<Mutex<LogManager>>) -> Result<i32, FileError> {
-        let reclen = mem::size_of::<i32>();
+    /// layout: op | `len` zero bytes
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = LogRecordBuilder::new(self.op()).build();
+        bytes.resize(I32_SIZE + self.len, 0);
+        bytes
+    }
+}
+
+impl NoopRecord {
+    pub fn new(len: usize) -> Self {
+        Self { len }
+    }
+
+    /// parses the bytes produced by `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LogRecordError> {
+        RecordCursor::new(bytes)?;
+        Ok(Self {
+            len: bytes.len() - I32_SIZE,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        buffer::BufferManager,
+        clock::ManualClock,
+        file::{FileManager, Page, U64_SIZE},
+        record::{create_log_record, decode_record, encode_record, LogRecordKind},
+        test_util::{BufferTx, MockTx, Rng, TxCall},
+    };
+    use std::{fs, time::Duration};
+
+    #[test]
+    fn set_i32_record_round_trip() {
+        let dirname = "__test_27";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+
+        let cases = [
+            (1, BlockId::new("testfile", 0), 0, 0),
+            (2, BlockId::new("testfile", 3), 80, -1),
+            (7, BlockId::new("users", 12), 4092, i32::MIN),
+            (-1, BlockId::new("t", 1), 16, i32::MAX),
+        ];
+        for (txnum, block, offset, val) in cases.iter() {
+            write_record(
+                Arc::clone(&lm),
+                &SetI32Record::new(*txnum, block.clone(), *offset, *val),
+            )
+            .unwrap();
+        }
+
+        let records: Vec<Vec<u8>> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        // the log is read backward
+        for (bytes, (txnum, block, offset, val)) in records.into_iter().zip(cases.iter().rev()) {
+            let rec = SetI32Record::from_bytes(decode_record(&bytes).unwrap()).unwrap();
+            let expected = SetI32Record {
+                txnum: *txnum,
+                block: block.clone(),
+                offset: *offset,
+                old_val: *val,
+                new_val: None,
+            };
+            assert_eq!(rec, expected);
+
+            let rec = create_log_record(&bytes).unwrap();
+            assert_eq!(rec.op(), TxType::SetI32);
+            assert_eq!(rec.tx_num(), *txnum);
+            assert_eq!(rec.to_string(), expected.to_string());
+        }
+        assert_eq!(
+            SetI32Record::from_bytes(&[0; 4])
+                .map(|r| r.to_string())
+                .ok(),
+            None
+        );
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn set_string_record_round_trip() {
+        let dirname = "__test_28";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+
+        let cases = [
+            (2, BlockId::new("users", 3), 44, "alice"),
+            (3, BlockId::new("users", 0), 0, ""),
+            (4, BlockId::new("名前", 5), 120, "héllo wörld"),
+            (5, BlockId::new("t", 1), 8, "🦀🦀"),
+        ];
+        for (txnum, block, offset, val) in cases.iter() {
+            write_record(
+                Arc::clone(&lm),
+                &SetStringRecord::new(*txnum, block.clone(), *offset, *val),
+            )
+            .unwrap();
+        }
+
+        let records: Vec<Vec<u8>> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), cases.len());
+        for (bytes, (txnum, block, offset, val)) in records.into_iter().zip(cases.iter().rev()) {
+            let rec = SetStringRecord::from_bytes(decode_record(&bytes).unwrap()).unwrap();
+            assert_eq!(rec.tx_num(), *txnum);
+            assert_eq!(rec.block(), block);
+            assert_eq!(rec.offset(), *offset);
+            assert_eq!(rec.old_val(), *val);
+
+            let rec = create_log_record(&bytes).unwrap();
+            assert_eq!(rec.op(), TxType::SetString);
+            assert_eq!(rec.tx_num(), *txnum);
+        }
+        let rec = SetStringRecord {
+            txnum: 2,
+            block: BlockId::new("users", 3),
+            offset: 44,
+            old_val: "alice".to_string(),
+            new_val: None,
+        };
+        assert_eq!(rec.to_string(), "<SETSTRING 2 [users, 3] 44 \"alice\">");
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
 
-        let mut p = Page::new(reclen.try_into().unwrap());
-        p.set_i32(0, TxType::SetString as i32)?;
+    #[test]
+    fn transaction_records_carry_their_txnum() {
+        let dirname = "__test_29";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+
+        write_record(Arc::clone(&lm), &StartRecord::new(7)).unwrap();
+        write_record(Arc::clone(&lm), &StartRecord::new(8)).unwrap();
+        write_record(Arc::clone(&lm), &CommitRecord::new(7)).unwrap();
+        write_record(Arc::clone(&lm), &RollbackRecord::new(8)).unwrap();
+
+        let records: Vec<_> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|bytes| create_log_record(&bytes).unwrap())
+            .map(|rec| (rec.op(), rec.tx_num(), rec.to_string()))
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (TxType::Rollback, 8, "<ROLLBACK 8>".to_string()),
+                (TxType::Commit, 7, "<COMMIT 7>".to_string()),
+                (TxType::Start, 8, "<START 8>".to_string()),
+                (TxType::Start, 7, "<START 7>".to_string()),
+            ]
+        );
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn undo_restores_the_old_value() {
+        let dirname = "__test_30";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let block = BlockId::new("testfile", 0);
+        fm.lock().unwrap().append("testfile").unwrap();
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        let mut tx = BufferTx::new(1, Arc::new(bm));
+
+        tx.pin(&block).unwrap();
+        tx.set_i32(&block, 80, 42, false).unwrap();
+        tx.set_string(&block, 40, "one", false).unwrap();
+
+        write_record(
+            Arc::clone(&lm),
+            &SetI32Record::new(1, block.clone(), 80, 42),
+        )
+        .unwrap();
+        tx.set_i32(&block, 80, 99, true).unwrap();
+        write_record(
+            Arc::clone(&lm),
+            &SetStringRecord::new(1, block.clone(), 40, "one"),
+        )
+        .unwrap();
+        tx.set_string(&block, 40, "a longer value", true).unwrap();
+        write_record(Arc::clone(&lm), &StartRecord::new(1)).unwrap();
+
+        let records: Vec<_> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|bytes| create_log_record(&bytes).unwrap())
+            .collect();
+        for rec in records.iter() {
+            rec.undo(&mut tx).unwrap();
+        }
+
+        let buf = tx.buffer(&block);
+        assert_eq!(buf.contents().get_i32(80).unwrap(), 42);
+        assert_eq!(buf.contents().get_string(40).unwrap(), "one");
+        tx.unpin(&block);
+        tx.bm.assert_all_unpinned();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn set_u64_and_set_bytes_records_round_trip_and_undo() {
+        let dirname = "__test_31";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let block = BlockId::new("testfile", 0);
+        fm.lock().unwrap().append("testfile").unwrap();
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        let mut tx = BufferTx::new(3, Arc::new(bm));
+
+        tx.pin(&block).unwrap();
+        tx.set_u64(&block, 8, u64::MAX - 1, false).unwrap();
+        tx.set_bytes(&block, 100, &[1, 2, 3], false).unwrap();
+        tx.set_bytes(&block, 200, &[4, 5, 6, 7, 8, 9], false)
+            .unwrap();
+
+        write_record(
+            Arc::clone(&lm),
+            &SetU64Record::new(3, block.clone(), 8, u64::MAX - 1),
+        )
+        .unwrap();
+        tx.set_u64(&block, 8, 7, true).unwrap();
+        write_record(
+            Arc::clone(&lm),
+            &SetBytesRecord::new(3, block.clone(), 100, [1, 2, 3]),
+        )
+        .unwrap();
+        tx.set_bytes(&block, 100, &[9; 10], true).unwrap();
+        write_record(
+            Arc::clone(&lm),
+            &SetBytesRecord::new(3, block.clone(), 200, [4, 5, 6, 7, 8, 9]),
+        )
+        .unwrap();
+        tx.set_bytes(&block, 200, &[0], true).unwrap();
+
+        let records: Vec<_> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|bytes| create_log_record(&bytes).unwrap())
+            .collect();
+        assert_eq!(
+            records
+                .iter()
+                .map(|rec| (rec.op(), rec.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    TxType::SetBytes,
+                    "<SETBYTES 3 [testfile, 0] 200 [4, 5, 6, 7, 8, 9]>".to_string()
+                ),
+                (
+                    TxType::SetBytes,
+                    "<SETBYTES 3 [testfile, 0] 100 [1, 2, 3]>".to_string()
+                ),
+                (
+                    TxType::SetU64,
+                    format!("<SETU64 3 [testfile, 0] 8 {}>", u64::MAX - 1)
+                ),
+            ]
+        );
+        for rec in records.iter() {
+            rec.undo(&mut tx).unwrap();
+        }
+
+        let buf = tx.buffer(&block);
+        assert_eq!(buf.contents().get_u64(8).unwrap(), u64::MAX - 1);
+        assert_eq!(buf.contents().get_bytes(100).unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            buf.contents().get_bytes(200).unwrap(),
+            vec![4, 5, 6, 7, 8, 9]
+        );
+        tx.unpin(&block);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// the layout every record was written with before `to_bytes`, built with `Page` offsets
+    fn legacy_update(
+        op: TxType,
+        txnum: i32,
+        block: &BlockId,
+        offset: i32,
+        vlen: usize,
+    ) -> (Page, u64) {
+        let tpos = I32_SIZE;
+        let fpos = tpos + I32_SIZE;
+        let bpos = fpos + Page::max_length(block.filename().len()) as usize;
+        let opos = bpos + I32_SIZE;
+        let vpos = opos + I32_SIZE;
+        let mut p = Page::new((vpos + vlen) as i32);
+        p.set_i32(0, op as i32).unwrap();
+        p.set_i32(tpos as u64, txnum).unwrap();
+        p.set_string(fpos as u64, block.filename()).unwrap();
+        p.set_i32(bpos as u64, block.number()).unwrap();
+        p.set_i32(opos as u64, offset).unwrap();
+        (p, vpos as u64)
+    }
+
+    #[test]
+    fn to_bytes_matches_the_page_layout() {
+        let block = BlockId::new("名前", 12);
+
+        let mut p = Page::new(I32_SIZE as i32);
+        p.set_i32(0, TxType::CheckPoint as i32).unwrap();
+        assert_eq!(CheckPointRecord::new().to_bytes(), p.bytes());
+
+        let mut p = Page::new(2 * I32_SIZE as i32);
+        p.set_i32(0, TxType::Commit as i32).unwrap();
+        p.set_i32(I32_SIZE as u64, 7).unwrap();
+        assert_eq!(CommitRecord::new(7).to_bytes(), p.bytes());
+
+        let (mut p, vpos) = legacy_update(TxType::SetI32, 3, &block, 80, I32_SIZE);
+        p.set_i32(vpos, -42).unwrap();
+        let rec = SetI32Record::new(3, block.clone(), 80, -42);
+        assert_eq!(rec.to_bytes(), p.bytes());
+        assert_eq!(SetI32Record::from_bytes(p.bytes()).unwrap(), rec);
+
+        let (mut p, vpos) = legacy_update(
+            TxType::SetString,
+            3,
+            &block,
+            40,
+            Page::max_length(6) as usize,
+        );
+        p.set_string(vpos, "héllo").unwrap();
+        let rec = SetStringRecord::new(3, block.clone(), 40, "héllo");
+        assert_eq!(rec.to_bytes(), p.bytes());
+        assert_eq!(SetStringRecord::from_bytes(p.bytes()).unwrap(), rec);
+
+        let (mut p, vpos) = legacy_update(TxType::SetU64, 3, &block, 8, U64_SIZE);
+        p.set_u64(vpos, u64::MAX - 1).unwrap();
+        let rec = SetU64Record::new(3, block.clone(), 8, u64::MAX - 1);
+        assert_eq!(rec.to_bytes(), p.bytes());
+        assert_eq!(SetU64Record::from_bytes(p.bytes()).unwrap(), rec);
+
+        let (mut p, vpos) = legacy_update(TxType::SetBytes, 3, &block, 100, I32_SIZE + 3);
+        p.set_bytes(vpos, &[1, 2, 3]).unwrap();
+        let rec = SetBytesRecord::new(3, block.clone(), 100, [1, 2, 3]);
+        assert_eq!(rec.to_bytes(), p.bytes());
+        assert_eq!(SetBytesRecord::from_bytes(p.bytes()).unwrap(), rec);
+
+        let (mut p, vpos) = legacy_update(TxType::Compensation, 3, &block, 100, I32_SIZE);
+        p.set_i32(vpos, 17).unwrap();
+        let rec = CompensationRecord::new(3, block, 100, 17);
+        assert_eq!(rec.to_bytes(), p.bytes());
+        assert_eq!(CompensationRecord::from_bytes(p.bytes()).unwrap(), rec);
+    }
+
+    #[test]
+    fn display_and_summary_formats() {
+        let users = || BlockId::new("users", 7);
+        let records: Vec<(Box<dyn LogRecord>, &str, &str)> = vec![
+            (
+                Box::new(CheckPointRecord::new()),
+                "<CHECKPOINT>",
+                "<CHECKPOINT>",
+            ),
+            (
+                Box::new(NqCheckPointRecord::new(vec![3, 5])),
+                "<NQCKPT 3, 5>",
+                "<NQCKPT 3, 5>",
+            ),
+            (
+                Box::new(NqCheckPointRecord::new(vec![])),
+                "<NQCKPT>",
+                "<NQCKPT>",
+            ),
+            (Box::new(StartRecord::new(3)), "<START 3>", "<START 3>"),
+            (Box::new(CommitRecord::new(3)), "<COMMIT 3>", "<COMMIT 3>"),
+            (
+                Box::new(RollbackRecord::new(3)),
+                "<ROLLBACK 3>",
+                "<ROLLBACK 3>",
+            ),
+            (
+                Box::new(SetI32Record::new(3, users(), 80, 42)),
+                "<SETI32 3 [users, 7] 80 42>",
+                "<SETI32 3 [users, 7] 80 42>",
+            ),
+            (
+                Box::new(SetStringRecord::new(3, users(), 44, "alice")),
+                "<SETSTRING 3 [users, 7] 44 \"alice\">",
+                "<SETSTRING 3 [users, 7] 44 \"alice\">",
+            ),
+            (
+                Box::new(SetStringRecord::new(3, users(), 44, "héllo wörld")),
+                "<SETSTRING 3 [users, 7] 44 \"héllo wörld\">",
+                "<SETSTRING 3 [users, 7] 44 \"héllo...\">",
+            ),
+            (
+                Box::new(SetU64Record::new(3, users(), 8, 1 << 40)),
+                "<SETU64 3 [users, 7] 8 1099511627776>",
+                "<SETU64 3 [users, 7] 8 1099511627776>",
+            ),
+            (
+                Box::new(SetBytesRecord::new(3, users(), 100, [1, 2, 3, 4, 5, 6])),
+                "<SETBYTES 3 [users, 7] 100 [1, 2, 3, 4, 5, 6]>",
+                "<SETBYTES 3 [users, 7] 100 [1, 2, 3, 4, 5, ...]>",
+            ),
+            (
+                Box::new(SetBytesRecord::new(3, users(), 100, [])),
+                "<SETBYTES 3 [users, 7] 100 []>",
+                "<SETBYTES 3 [users, 7] 100 []>",
+            ),
+            (
+                Box::new(SetI32Record::with_values(3, users(), 80, 42, 43)),
+                "<SETI32 3 [users, 7] 80 42 -> 43>",
+                "<SETI32 3 [users, 7] 80 42 -> 43>",
+            ),
+            (
+                Box::new(SetU64Record::with_values(3, users(), 8, 1, 2)),
+                "<SETU64 3 [users, 7] 8 1 -> 2>",
+                "<SETU64 3 [users, 7] 8 1 -> 2>",
+            ),
+            (
+                Box::new(SetStringRecord::with_values(
+                    3,
+                    users(),
+                    44,
+                    "alice",
+                    "bob, the builder",
+                )),
+                "<SETSTRING 3 [users, 7] 44 \"alice\" -> \"bob, the builder\">",
+                "<SETSTRING 3 [users, 7] 44 \"alice\" -> \"bob, ...\">",
+            ),
+            (
+                Box::new(SetBytesRecord::with_values(
+                    3,
+                    users(),
+                    100,
+                    [],
+                    [1, 2, 3, 4, 5, 6],
+                )),
+                "<SETBYTES 3 [users, 7] 100 [] -> [1, 2, 3, 4, 5, 6]>",
+                "<SETBYTES 3 [users, 7] 100 [] -> [1, 2, 3, 4, 5, ...]>",
+            ),
+            (
+                Box::new(CompensationRecord::new(3, users(), 80, 11)),
+                "<CLR 3 [users, 7] 80 11>",
+                "<CLR 3 [users, 7] 80 11>",
+            ),
+            (
+                Box::new(ExtendFileRecord::new(3, BlockId::new("users", 8))),
+                "<EXTEND 3 [users, 8]>",
+                "<EXTEND 3 [users, 8]>",
+            ),
+            (Box::new(NoopRecord::new(12)), "<NOOP 12>", "<NOOP 12>"),
+        ];
+        for (rec, display, summary) in records {
+            assert_eq!(rec.to_string(), display);
+            assert_eq!(rec.summary(5), summary);
+            assert_eq!(rec.summary(usize::MAX), display);
+        }
+    }
+
+    #[test]
+    fn transaction_records_carry_a_timestamp() {
+        let clock = ManualClock::new();
+        let micros = clock.micros_since_epoch();
+        let start = StartRecord::stamped(4, &clock);
+        clock.advance(Duration::from_millis(250));
+        let commit = CommitRecord::stamped(4, &clock);
+        let rollback = RollbackRecord::stamped(5, &clock);
+
+        let parsed = StartRecord::from_bytes(&start.to_bytes()).unwrap();
+        assert_eq!(parsed.timestamp(), Some(micros));
+        assert_eq!(parsed.to_string(), format!("<START 4 @{}>", micros));
+        let parsed = CommitRecord::from_bytes(&commit.to_bytes()).unwrap();
+        assert_eq!(parsed.timestamp(), Some(micros + 250_000));
+        assert_eq!(parsed, commit);
+        let parsed = create_log_record(&encode_record(&rollback.to_bytes())).unwrap();
+        assert_eq!(
+            parsed.to_string(),
+            format!("<ROLLBACK 5 @{}>", micros + 250_000)
+        );
+
+        // written before records were stamped
+        let old = LogRecordBuilder::new(TxType::Commit).i32(4).build();
+        let parsed = CommitRecord::from_bytes(&old).unwrap();
+        assert_eq!(parsed.timestamp(), None);
+        assert_eq!(parsed.to_string(), "<COMMIT 4>");
+    }
+
+    #[test]
+    fn undo_is_idempotent() {
+        let block = BlockId::new("testfile", 0);
+        let mut tx = MockTx::new();
+
+        let records: Vec<Box<dyn LogRecord>> = vec![
+            Box::new(SetI32Record::new(1, block.clone(), 0, -7)),
+            Box::new(SetStringRecord::new(1, block.clone(), 8, "old")),
+            Box::new(SetU64Record::new(1, block.clone(), 100, u64::MAX)),
+            Box::new(SetBytesRecord::new(1, block.clone(), 200, [1, 2, 3])),
+        ];
+        let assert_old_values = |tx: &mut MockTx| {
+            let page = tx.page(&block);
+            assert_eq!(page.get_i32(0).unwrap(), -7);
+            assert_eq!(page.get_string(8).unwrap(), "old");
+            assert_eq!(page.get_u64(100).unwrap(), u64::MAX);
+            assert_eq!(&page.bytes()[200..207], &[0, 0, 0, 3, 1, 2, 3]);
+        };
+
+        tx.set_i32(&block, 0, 5, true).unwrap();
+        tx.set_string(&block, 8, "a much longer value", true)
+            .unwrap();
+        tx.set_u64(&block, 100, 0, true).unwrap();
+        tx.set_bytes(&block, 200, &[9; 40], true).unwrap();
+
+        for rec in records.iter().chain(records.iter()) {
+            rec.undo(&mut tx).unwrap();
+        }
+        assert_old_values(&mut tx);
+
+        // an unrelated write in between, with a different stored length
+        tx.set_i32(&block, 0, 6, true).unwrap();
+        tx.set_string(&block, 8, "", true).unwrap();
+        tx.set_bytes(&block, 200, &[], true).unwrap();
+        for rec in records.iter() {
+            rec.undo(&mut tx).unwrap();
+        }
+        assert_old_values(&mut tx);
+
+        // every undo pins the block, writes without logging and unpins it again
+        let undos: Vec<&TxCall> = tx
+            .calls
+            .iter()
+            .filter(|call| {
+                !matches!(
+                    call,
+                    TxCall::SetI32(.., true)
+                        | TxCall::SetString(.., true)
+                        | TxCall::SetU64(.., true)
+                        | TxCall::SetBytes(.., true)
+                )
+            })
+            .collect();
+        assert_eq!(undos.len(), 12 * 3);
+        for calls in undos.chunks(3) {
+            assert!(
+                matches!(
+                    calls,
+                    [
+                        TxCall::Pin(_),
+                        TxCall::SetI32(.., false)
+                            | TxCall::SetString(.., false)
+                            | TxCall::SetU64(.., false)
+                            | TxCall::SetBytes(.., false),
+                        TxCall::Unpin(_),
+                    ]
+                ),
+                "{:?}",
+                calls
+            );
+        }
+    }
+
+    #[test]
+    fn redo_rewrites_updates_whose_flush_was_lost() {
+        let dirname = "__test_43";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let block = BlockId::new("testfile", 0);
+        fm.lock().unwrap().append("testfile").unwrap();
+        let records: Vec<Box<dyn LogRecord>> = vec![
+            Box::new(SetI32Record::with_values(1, block.clone(), 0, 0, -7)),
+            Box::new(SetStringRecord::with_values(1, block.clone(), 8, "", "new")),
+            Box::new(SetU64Record::with_values(
+                1,
+                block.clone(),
+                100,
+                0,
+                u64::MAX,
+            )),
+            Box::new(SetBytesRecord::with_values(
+                1,
+                block.clone(),
+                200,
+                [],
+                [1, 2, 3],
+            )),
+            Box::new(CommitRecord::new(1)),
+        ];
+
+        // the writes reach the buffers, but the buffers are never flushed
+        let bm = Arc::new(BufferManager::new(fm.clone(), lm.clone(), 3));
+        let mut tx = BufferTx::new(1, bm);
+        tx.pin(&block).unwrap();
+        tx.set_i32(&block, 0, -7, true).unwrap();
+        tx.set_string(&block, 8, "new", true).unwrap();
+        tx.unpin(&block);
+        drop(tx);
+
+        let bm = Arc::new(BufferManager::new(fm.clone(), lm.clone(), 3));
+        let mut tx = BufferTx::new(1, bm.clone());
+        for rec in records.iter().chain(records.iter()) {
+            rec.redo(&mut tx).unwrap();
+        }
+        bm.flush_all(1).unwrap();
+        let mut page = Page::new(fm.lock().unwrap().block_size());
+        fm.lock().unwrap().read(&block, &mut page).unwrap();
+        assert_eq!(page.get_i32(0).unwrap(), -7);
+        assert_eq!(page.get_string(8).unwrap(), "new");
+        assert_eq!(page.get_u64(100).unwrap(), u64::MAX);
+        assert_eq!(page.get_bytes(200).unwrap(), [1, 2, 3]);
+
+        assert!(matches!(
+            SetI32Record::new(1, block.clone(), 0, 0).redo(&mut tx),
+            Err(tx::TransactionError::LogRecordError(
+                LogRecordError::RedoUnavailable
+            ))
+        ));
+
+        // a redo past the end of the file doesn't grow it, only a redone append does
+        let past_end = BlockId::new("testfile", 2);
+        let update = SetI32Record::with_values(1, past_end.clone(), 0, 0, 5);
+        assert!(matches!(
+            update.redo(&mut tx),
+            Err(tx::TransactionError::BufferError(BufferError::BlockOutOfRange(b))) if b == past_end
+        ));
+        assert_eq!(tx.size("testfile").unwrap(), 1);
+        ExtendFileRecord::new(1, past_end.clone())
+            .redo(&mut tx)
+            .unwrap();
+        update.redo(&mut tx).unwrap();
+        assert_eq!(tx.size("testfile").unwrap(), 3);
+
+        drop(tx);
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn new_values_cost_their_own_encoding() {
+        let block = BlockId::new("users", 7);
+        let size = |rec: &dyn LogRecord| encode_record(&rec.to_bytes()).len();
+
+        let old = SetI32Record::new(3, block.clone(), 80, 42);
+        let both = SetI32Record::with_values(3, block.clone(), 80, 42, 43);
+        assert_eq!(size(&both) - size(&old), I32_SIZE);
+        // version | length | op | txnum | "users" | block | offset | old | new
+        assert_eq!(size(&both), 1 + 4 + 4 + 4 + (4 + 5) + 4 + 4 + 4 + 4);
+
+        let old = SetU64Record::new(3, block.clone(), 8, 1);
+        let both = SetU64Record::with_values(3, block.clone(), 8, 1, 2);
+        assert_eq!(size(&both) - size(&old), U64_SIZE);
+
+        let old = SetStringRecord::new(3, block.clone(), 44, "alice");
+        let both = SetStringRecord::with_values(3, block.clone(), 44, "alice", "bob");
+        assert_eq!(size(&both) - size(&old), Page::max_length(3) as usize);
+
+        let old = SetBytesRecord::new(3, block.clone(), 100, [1, 2]);
+        let both = SetBytesRecord::with_values(3, block, 100, [1, 2], [3; 10]);
+        assert_eq!(size(&both) - size(&old), I32_SIZE + 10);
+    }
+
+    /// a record of a random type with random fields
+    fn random_record(rng: &mut Rng) -> LogRecordKind {
+        let txnum = rng.i32();
+        let filename = rng.string(20) + "f";
+        let block = BlockId::new(filename, rng.i32());
+        let offset = rng.i32();
+        let clock = ManualClock::new();
+        clock.advance(Duration::from_micros(rng.below(1 << 40) as u64));
+        match rng.below(16) {
+            0 => LogRecordKind::CheckPoint(CheckPointRecord::new()),
+            1 => LogRecordKind::Start(StartRecord::stamped(txnum, &clock)),
+            2 => LogRecordKind::Commit(CommitRecord::new(txnum)),
+            3 => LogRecordKind::Rollback(RollbackRecord::stamped(txnum, &clock)),
+            4 => LogRecordKind::SetI32(SetI32Record::new(txnum, block, offset, rng.i32())),
+            5 => LogRecordKind::SetString(SetStringRecord::new(
+                txnum,
+                block,
+                offset,
+                rng.string(4096),
+            )),
+            6 => LogRecordKind::SetU64(SetU64Record::new(txnum, block, offset, rng.next_u64())),
+            7 => LogRecordKind::SetBytes(SetBytesRecord::new(txnum, block, offset, rng.bytes(300))),
+            8 => LogRecordKind::Compensation(CompensationRecord::new(
+                txnum,
+                block,
+                offset,
+                rng.i32(),
+            )),
+            9 => LogRecordKind::Noop(NoopRecord::new(rng.below(100))),
+            10 => LogRecordKind::ExtendFile(ExtendFileRecord::new(txnum, block)),
+            11 => LogRecordKind::SetI32(SetI32Record::with_values(
+                txnum,
+                block,
+                offset,
+                rng.i32(),
+                rng.i32(),
+            )),
+            12 => LogRecordKind::SetString(SetStringRecord::with_values(
+                txnum,
+                block,
+                offset,
+                rng.string(2048),
+                rng.string(2048),
+            )),
+            13 => LogRecordKind::SetU64(SetU64Record::with_values(
+                txnum,
+                block,
+                offset,
+                rng.next_u64(),
+                rng.next_u64(),
+            )),
+            14 => LogRecordKind::SetBytes(SetBytesRecord::with_values(
+                txnum,
+                block,
+                offset,
+                rng.bytes(300),
+                rng.bytes(300),
+            )),
+            _ => LogRecordKind::NqCheckPoint(NqCheckPointRecord::new(
+                (0..rng.below(10)).map(|_| rng.i32()).collect(),
+            )),
+        }
+    }
+
+    #[test]
+    fn random_records_round_trip() {
+        let mut rng = Rng::new(0x5eed);
+        for _ in 0..2000 {
+            let rec = random_record(&mut rng);
+            let bytes = encode_record(&rec.to_bytes());
+            assert_eq!(
+                create_log_record(&bytes).unwrap(),
+                rec,
+                "{}",
+                rec.summary(80)
+            );
+        }
+    }
 
-        lm.lock().unwrap().append(p.contents().to_vec())
+    #[test]
+    fn mutated_records_never_panic() {
+        let mut rng = Rng::new(0xbad);
+        for _ in 0..2000 {
+            let rec = random_record(&mut rng);
+            let payload = rec.to_bytes();
+            for mut bytes in [payload.clone(), encode_record(&payload)] {
+                let i = rng.below(bytes.len());
+                bytes[i] = rng.next_u64() as u8;
+                if let Ok(parsed) = create_log_record(&bytes) {
+                    parsed.to_string();
+                }
+                bytes.truncate(rng.below(bytes.len()));
+                let _ = create_log_record(&bytes);
+            }
+        }
     }
 }
@@ -1,67 +1,1108 @@
 use crate::{
-    file::Page,
+    file::{BlockId, FileError, I32_SIZE, U64_SIZE},
+    log::Lsn,
     log_records::{
-        CheckPointRecord, CommitRecord, RollbackRecord, SetI32Record, SetStringRecord, StartRecord,
+        CheckPointRecord, CommitRecord, CompensationRecord, ExtendFileRecord, NoopRecord,
+        NqCheckPointRecord, RollbackRecord, SetBytesRecord, SetI32Record, SetStringRecord,
+        SetU64Record, StartRecord,
     },
+    tx::{self, TxOps},
 };
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
 #[derive(Debug)]
 pub enum LogRecordError {
-    UnknownRecord,
+    /// the op code is neither built in nor registered
+    UnknownOp {
+        code: i32,
+    },
+    /// the record was written in a newer format than this build reads
+    UnsupportedVersion {
+        found: u8,
+    },
+    /// the payload length stored in the record doesn't match the bytes that follow it
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// the payload is too short to hold an op
+    ShortRecord {
+        len: usize,
+    },
+    /// the record was written without the new value, so it can't be redone
+    RedoUnavailable,
+    /// the op code belongs to the built-in records and can't be registered
+    ReservedOp(i32),
+    /// the record is damaged from byte `offset` on: its checksum doesn't match, or its fields
+    /// don't parse as a record of its op
+    Corrupt {
+        reason: String,
+        offset: usize,
+    },
+    FileError(FileError),
 }
 
 impl fmt::Display for LogRecordError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            LogRecordError::UnknownRecord => {
-                write!(f, "Unknown Log record")
+            LogRecordError::UnknownOp { code } => {
+                write!(f, "Unknown log record op {}", code)
+            }
+            LogRecordError::UnsupportedVersion { found } => {
+                write!(f, "Unsupported log record version {}", found)
+            }
+            LogRecordError::LengthMismatch { expected, actual } => write!(
+                f,
+                "Log record payload should be {} bytes but is {}",
+                expected, actual
+            ),
+            LogRecordError::ShortRecord { len } => {
+                write!(f, "Log record of {} bytes is too short to hold an op", len)
+            }
+            LogRecordError::RedoUnavailable => {
+                write!(f, "Log record has no new value to redo")
             }
+            LogRecordError::ReservedOp(op) => {
+                write!(f, "Log record op {} is reserved for built-in records", op)
+            }
+            LogRecordError::Corrupt { reason, offset } => {
+                write!(f, "Log record is corrupt at byte {}: {}", offset, reason)
+            }
+            LogRecordError::FileError(err) => write!(f, "Log record error: {}", err),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-pub enum TxType {
-    CheckPoint = 0,
-    Start = 1,
-    Commit = 2,
-    Rollback = 3,
-    SetI32 = 4,
-    SetString = 5,
-}
-
-impl TxType {
-    fn from_int(n: i32) -> Option<Self> {
-        match n {
-            0 => Some(TxType::CheckPoint),
-            1 => Some(TxType::Start),
-            2 => Some(TxType::Commit),
-            3 => Some(TxType::Rollback),
-            4 => Some(TxType::SetI32),
-            5 => Some(TxType::SetString),
+impl std::error::Error for LogRecordError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LogRecordError::FileError(err) => Some(err),
             _ => None,
         }
     }
 }
 
-pub trait LogRecord {
+impl From<FileError> for LogRecordError {
+    fn from(value: FileError) -> Self {
+        LogRecordError::FileError(value)
+    }
+}
+
+/// the op code of a built-in record type, declared once in `log_record_types!`
+pub trait RecordOp {
+    const OP: TxType;
+}
+
+/// declares the built-in record types: each line ties a `TxType` variant, its op code and the
+/// name it is displayed with to the record struct that parses it. `TxType` and its conversions,
+/// `LogRecordKind`, `create_log_record` and every record's `RecordOp` are generated from this one
+/// list, so a record type can't be added without all of them.
+macro_rules! log_record_types {
+    ($($variant:ident = $code:literal as $name:literal => $record:ident,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[repr(i32)]
+        pub enum TxType {
+            $($variant = $code,)*
+            /// a record type registered with a `RecordRegistry`. its op code is
+            /// `LogRecord::op_code`
+            Custom = -1,
+        }
+
+        impl TxType {
+            /// every built-in record type
+            pub const ALL: &'static [TxType] = &[$(TxType::$variant,)*];
+        }
+
+        /// the built-in record type written with op code `n`. registered op codes, and `Custom`'s
+        /// own -1, aren't built in and fail with `UnknownOp`.
+        impl TryFrom<i32> for TxType {
+            type Error = LogRecordError;
+
+            fn try_from(n: i32) -> Result<Self, Self::Error> {
+                match n {
+                    $($code => Ok(TxType::$variant),)*
+                    code => Err(LogRecordError::UnknownOp { code }),
+                }
+            }
+        }
+
+        impl fmt::Display for TxType {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(match self {
+                    $(TxType::$variant => $name,)*
+                    TxType::Custom => "CUSTOM",
+                })
+            }
+        }
+
+        $(
+            impl RecordOp for $record {
+                const OP: TxType = TxType::$variant;
+            }
+        )*
+
+        /// a log record parsed into its concrete type. matching on it, rather than going through
+        /// `LogRecord`, gives callers the block and old value of an update, and a compile error
+        /// wherever a new record type isn't handled yet.
+        ///
+        /// With the `serde` feature it (de)serializes tagged with its type, e.g.
+        /// `{"type":"Commit","txnum":4,"timestamp":null}`. registered records are skipped.
+        #[derive(Debug, PartialEq)]
+        #[cfg_attr(
+            feature = "serde",
+            derive(serde::Serialize, serde::Deserialize),
+            serde(tag = "type")
+        )]
+        pub enum LogRecordKind {
+            $($variant($record),)*
+            #[cfg_attr(feature = "serde", serde(skip))]
+            Custom(CustomRecord),
+        }
+
+        impl LogRecordKind {
+            /// the record behind the `LogRecord` interface
+            pub fn record(&self) -> &dyn LogRecord {
+                match self {
+                    $(LogRecordKind::$variant(rec) => rec,)*
+                    LogRecordKind::Custom(rec) => rec.0.as_ref(),
+                }
+            }
+
+            /// for code still written against `Box<dyn LogRecord>`. prefer matching on the kind.
+            pub fn into_dyn(self) -> Box<dyn LogRecord> {
+                match self {
+                    $(LogRecordKind::$variant(rec) => Box::new(rec),)*
+                    LogRecordKind::Custom(rec) => rec.0,
+                }
+            }
+        }
+
+        /// parses a record as it was read from the log into its concrete type
+        ///
+        /// Fails with `ShortRecord` if there is no op, `UnknownOp` if the op isn't built in,
+        /// and `Corrupt` if the rest of the payload doesn't parse as a record of that op.
+        pub fn create_log_record(bytes: &[u8]) -> Result<LogRecordKind, LogRecordError> {
+            let payload = decode_record(bytes)?;
+            let op = TxType::try_from(read_op(payload)?)?;
+            // offsets in the payload, shifted to offsets in `bytes`
+            let corrupt = |err| match err {
+                LogRecordError::Corrupt { reason, offset } => LogRecordError::Corrupt {
+                    reason: format!("not a valid {} record, {}", op, reason),
+                    offset: offset + bytes.len() - payload.len(),
+                },
+                err => err,
+            };
+            Ok(match op {
+                $(TxType::$variant => {
+                    LogRecordKind::$variant($record::from_bytes(payload).map_err(corrupt)?)
+                })*
+                TxType::Custom => unreachable!("custom records have no op code of their own"),
+            })
+        }
+    };
+}
+
+log_record_types! {
+    CheckPoint = 0 as "CHECKPOINT" => CheckPointRecord,
+    Start = 1 as "START" => StartRecord,
+    Commit = 2 as "COMMIT" => CommitRecord,
+    Rollback = 3 as "ROLLBACK" => RollbackRecord,
+    SetI32 = 4 as "SETI32" => SetI32Record,
+    SetString = 5 as "SETSTRING" => SetStringRecord,
+    SetU64 = 6 as "SETU64" => SetU64Record,
+    SetBytes = 7 as "SETBYTES" => SetBytesRecord,
+    Compensation = 8 as "CLR" => CompensationRecord,
+    Noop = 9 as "NOOP" => NoopRecord,
+    ExtendFile = 10 as "EXTEND" => ExtendFileRecord,
+    NqCheckPoint = 11 as "NQCKPT" => NqCheckPointRecord,
+}
+
+pub trait LogRecord: fmt::Display {
     fn op(&self) -> TxType;
     fn tx_num(&self) -> i32;
-    // fn undo(&mut self, tx_num: u64);
+
+    /// the op code written in front of the record. registered records override this
+    fn op_code(&self) -> i32 {
+        self.op() as i32
+    }
+
+    /// restores the value this record overwrote. records that change no data do nothing.
+    ///
+    /// undo must be idempotent: recovery may undo the same update more than once, e.g. when it
+    /// crashes itself. it writes the logged old value as it is, without reading what is stored
+    /// now, so undoing twice, or again after some other write, leaves the same bytes.
+    fn undo(&self, _tx: &mut dyn TxOps) -> tx::Result<()> {
+        Ok(())
+    }
+
+    /// writes the value this record logged again, without logging it. records that change no
+    /// data do nothing. like undo, redo must be idempotent.
+    fn redo(&self, _tx: &mut dyn TxOps) -> tx::Result<()> {
+        Ok(())
+    }
+
+    /// the block and offset an update record wrote to
+    fn target(&self) -> Option<(&BlockId, i32)> {
+        None
+    }
+
+    /// for a compensation record, the lsn at or below which undo of its transaction resumes
+    fn undo_next_lsn(&self) -> Option<Lsn> {
+        None
+    }
+
+    /// the bytes `write_record` appends to the log
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// like `Display`, but values longer than `width` are cut short
+    fn summary(&self, _width: usize) -> String {
+        self.to_string()
+    }
+}
+
+/// the format `encode_record` writes: version | payload length | payload.
+///
+/// version 0 is a bare payload. it starts with the op as a big-endian i32, so its first byte
+/// is always 0 and can't be mistaken for a later version.
+/// versions 1 and 2 are framed like 3 and 4, but their update records hold no new value.
+pub const RECORD_VERSION: u8 = 3;
+
+/// like `RECORD_VERSION`, followed by a CRC32 of the payload: version | length | payload | crc
+pub const CHECKED_RECORD_VERSION: u8 = 4;
+
+const LEGACY_RECORD_VERSION: u8 = 1;
+const LEGACY_CHECKED_RECORD_VERSION: u8 = 2;
+
+const HEADER_SIZE: usize = 1 + I32_SIZE;
+const CRC_SIZE: usize = 4;
+
+/// frames a record's payload in the current format
+pub fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.push(RECORD_VERSION);
+    bytes.extend_from_slice(&(payload.len() as i32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// frames a record's payload in the current format, followed by its checksum. for records that
+/// are copied around outside the log, where the log's own framing doesn't protect them.
+pub fn encode_checked_record(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len() + CRC_SIZE);
+    bytes.push(CHECKED_RECORD_VERSION);
+    bytes.extend_from_slice(&(payload.len() as i32).to_be_bytes());
+    bytes.extend_from_slice(payload);
+    bytes.extend_from_slice(&crc32(payload).to_be_bytes());
+    bytes
+}
+
+/// CRC-32 (IEEE), the checksum of checked records
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// strips the framing from a record in any supported format, returning its payload.
+/// the checksum of a checked record is validated, other records have none to validate.
+pub fn decode_record(bytes: &[u8]) -> Result<&[u8], LogRecordError> {
+    match bytes.first() {
+        None | Some(0) => Ok(bytes),
+        Some(&CHECKED_RECORD_VERSION | &LEGACY_CHECKED_RECORD_VERSION) => {
+            let Some(split) = bytes.len().checked_sub(CRC_SIZE) else {
+                return Err(LogRecordError::LengthMismatch {
+                    expected: HEADER_SIZE + CRC_SIZE,
+                    actual: bytes.len(),
+                });
+            };
+            let (framed, crc) = bytes.split_at(split);
+            let payload = decode_payload(framed)?;
+            if crc32(payload).to_be_bytes() != crc {
+                return Err(LogRecordError::Corrupt {
+                    reason: "checksum mismatch".to_string(),
+                    offset: HEADER_SIZE,
+                });
+            }
+            Ok(payload)
+        }
+        Some(&RECORD_VERSION | &LEGACY_RECORD_VERSION) => decode_payload(bytes),
+        Some(&found) => Err(LogRecordError::UnsupportedVersion { found }),
+    }
+}
+
+/// the payload after the version and length, checked against the length
+fn decode_payload(bytes: &[u8]) -> Result<&[u8], LogRecordError> {
+    let actual = bytes.len().saturating_sub(HEADER_SIZE);
+    let Some(len) = bytes.get(1..HEADER_SIZE) else {
+        return Err(LogRecordError::LengthMismatch {
+            expected: HEADER_SIZE,
+            actual: bytes.len(),
+        });
+    };
+    let expected = i32::from_be_bytes(len.try_into().unwrap()) as usize;
+    if expected != actual {
+        return Err(LogRecordError::LengthMismatch { expected, actual });
+    }
+    Ok(&bytes[HEADER_SIZE..])
+}
+
+/// rewrites a record in any supported format in the current one. checked records stay checked.
+///
+/// Built-in records are parsed and written again field by field, which brings an old layout up
+/// to date. this is best-effort: fields an old record never had stay missing, e.g. an update
+/// logged before new values were keeps no new value and still can't be redone. records of an
+/// unknown op, such as registered ones, are reframed as they are.
+pub fn migrate_record(bytes: &[u8]) -> Result<Vec<u8>, LogRecordError> {
+    let payload = match create_log_record(bytes) {
+        Ok(rec) => rec.to_bytes(),
+        Err(LogRecordError::UnknownOp { .. }) => decode_record(bytes)?.to_vec(),
+        Err(err) => return Err(err),
+    };
+    Ok(match bytes.first() {
+        Some(&CHECKED_RECORD_VERSION | &LEGACY_CHECKED_RECORD_VERSION) => {
+            encode_checked_record(&payload)
+        }
+        _ => encode_record(&payload),
+    })
+}
+
+/// a noop record that takes exactly `len` bytes in the current format, or None when `len`
+/// is too short to hold one
+pub fn padding(len: usize) -> Option<Vec<u8>> {
+    let payload_len = len.checked_sub(HEADER_SIZE)?;
+    if payload_len < I32_SIZE {
+        return None;
+    }
+    let mut payload = LogRecordBuilder::new(TxType::Noop).build();
+    payload.resize(payload_len, 0);
+    Some(encode_record(&payload))
 }
 
-pub fn create_log_record(bytes: Vec<u8>) -> Result<Box<dyn LogRecord>, LogRecordError> {
-    let mut p = Page::from(bytes);
-    let tx_type = TxType::from_int(p.get_i32(0).unwrap());
+/// whether `bytes` is a noop written by `padding`
+pub fn is_padding(bytes: &[u8]) -> bool {
+    let noop = (TxType::Noop as i32).to_be_bytes();
+    matches!(
+        bytes.first(),
+        Some(&RECORD_VERSION | &LEGACY_RECORD_VERSION)
+    ) && decode_record(bytes).is_ok_and(|payload| payload.get(..I32_SIZE) == Some(&noop[..]))
+}
+
+/// the transaction of a record, read from the field after its op without parsing the rest.
+/// -1 for the records of no transaction, like `LogRecord::tx_num`. None for a record of an op
+/// that isn't built in, e.g. a registered one: only its parser knows where its transaction is.
+pub fn peek_tx_num(bytes: &[u8]) -> Result<Option<i32>, LogRecordError> {
+    let payload = decode_record(bytes)?;
+    let op = match TxType::try_from(read_op(payload)?) {
+        Ok(op) => op,
+        Err(LogRecordError::UnknownOp { .. }) => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match op {
+        TxType::CheckPoint | TxType::NqCheckPoint | TxType::Noop => Ok(Some(-1)),
+        TxType::Start
+        | TxType::Commit
+        | TxType::Rollback
+        | TxType::SetI32
+        | TxType::SetString
+        | TxType::SetU64
+        | TxType::SetBytes
+        | TxType::Compensation
+        | TxType::ExtendFile => Ok(Some(RecordCursor::new(payload)?.read_i32()?)),
+        TxType::Custom => unreachable!("custom records have no op code of their own"),
+    }
+}
+
+/// lays out a record payload field by field, starting with its op:
+/// `LogRecordBuilder::new(TxType::SetI32).i32(txnum).block(&block).i32(offset).i32(val).build()`
+#[derive(Debug)]
+pub struct LogRecordBuilder {
+    buf: Vec<u8>,
+}
+
+impl LogRecordBuilder {
+    pub fn new(op: TxType) -> Self {
+        Self::with_op_code(op as i32)
+    }
+
+    /// starts a record with a registered op code
+    pub fn with_op_code(op: i32) -> Self {
+        Self { buf: Vec::new() }.i32(op)
+    }
+
+    pub fn i32(mut self, n: i32) -> Self {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    pub fn u64(mut self, n: u64) -> Self {
+        self.buf.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    pub fn i64(self, n: i64) -> Self {
+        self.u64(n as u64)
+    }
+
+    /// `bytes` behind an i32 length prefix, the way `Page::set_bytes` writes them
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self = self.i32(bytes.len() as i32);
+        self.buf.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn string(self, s: &str) -> Self {
+        self.bytes(s.as_bytes())
+    }
+
+    /// the block's filename, then its number
+    pub fn block(self, block: &BlockId) -> Self {
+        self.string(block.filename()).i32(block.number())
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// reads back the fields of a payload in the order a `LogRecordBuilder` laid them out.
+///
+/// The payload comes from disk, so nothing in it is trusted: every read, including the lengths
+/// stored in front of strings and bytes, is checked against the end of the payload, and a field
+/// that doesn't fit fails with `Corrupt` at the offset it starts at.
+#[derive(Debug)]
+pub struct RecordCursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    op: i32,
+}
+
+impl<'a> RecordCursor<'a> {
+    /// reads the op, leaving the cursor at the first field after it
+    pub fn new(payload: &'a [u8]) -> Result<Self, LogRecordError> {
+        let mut cursor = Self {
+            bytes: payload,
+            pos: 0,
+            op: 0,
+        };
+        cursor.op = cursor.read_i32()?;
+        Ok(cursor)
+    }
+
+    pub fn op(&self) -> i32 {
+        self.op
+    }
+
+    /// the offset of the next field in the payload
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /// whether any fields are left to read. fields added to a record later are only present
+    /// in records written since, so they are read only when this is true.
+    pub fn has_more(&self) -> bool {
+        self.pos < self.bytes.len()
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, LogRecordError> {
+        let field = self.take(I32_SIZE, "i32")?;
+        Ok(i32::from_be_bytes(field.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, LogRecordError> {
+        let field = self.take(U64_SIZE, "u64")?;
+        Ok(u64::from_be_bytes(field.try_into().unwrap()))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64, LogRecordError> {
+        Ok(self.read_u64()? as i64)
+    }
+
+    /// bytes behind an i32 length prefix, the way `LogRecordBuilder::bytes` writes them
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, LogRecordError> {
+        let start = self.pos;
+        let len = self.read_i32()?;
+        let Ok(len) = usize::try_from(len) else {
+            return Err(corrupt(format!("negative length {}", len), start));
+        };
+        self.take(len, "bytes")
+            .map(<[u8]>::to_vec)
+            .map_err(|_| corrupt(format!("{} bytes run past the end", len), start))
+    }
+
+    pub fn read_string(&mut self) -> Result<String, LogRecordError> {
+        let start = self.pos;
+        String::from_utf8(self.read_bytes()?)
+            .map_err(|_| corrupt("string is not valid UTF-8".to_string(), start))
+    }
+
+    /// the block's filename, then its number
+    pub fn read_block(&mut self) -> Result<BlockId, LogRecordError> {
+        let filename = self.read_string()?;
+        Ok(BlockId::new(filename, self.read_i32()?))
+    }
+
+    /// the next `len` bytes, or `Corrupt` if the payload ends before them
+    fn take(&mut self, len: usize, field: &str) -> Result<&'a [u8], LogRecordError> {
+        let end = self.pos.saturating_add(len);
+        let Some(bytes) = self.bytes.get(self.pos..end) else {
+            return Err(corrupt(format!("{} runs past the end", field), self.pos));
+        };
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+fn corrupt(reason: String, offset: usize) -> LogRecordError {
+    LogRecordError::Corrupt { reason, offset }
+}
+
+impl fmt::Display for LogRecordKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.record().fmt(f)
+    }
+}
+
+impl LogRecord for LogRecordKind {
+    fn op(&self) -> TxType {
+        self.record().op()
+    }
+
+    fn tx_num(&self) -> i32 {
+        self.record().tx_num()
+    }
+
+    fn op_code(&self) -> i32 {
+        self.record().op_code()
+    }
+
+    fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        self.record().undo(tx)
+    }
+
+    fn redo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+        self.record().redo(tx)
+    }
+
+    fn target(&self) -> Option<(&BlockId, i32)> {
+        self.record().target()
+    }
+
+    fn undo_next_lsn(&self) -> Option<Lsn> {
+        self.record().undo_next_lsn()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.record().to_bytes()
+    }
+
+    fn summary(&self, width: usize) -> String {
+        self.record().summary(width)
+    }
+}
+
+/// a record of a type registered with a `RecordRegistry`
+pub struct CustomRecord(pub Box<dyn LogRecord>);
+
+impl fmt::Debug for CustomRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CustomRecord({})", self.0)
+    }
+}
+
+impl PartialEq for CustomRecord {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bytes() == other.0.to_bytes()
+    }
+}
+
+/// parses the payload of a registered record type, op code included
+pub type RecordParser = fn(&[u8]) -> Result<Box<dyn LogRecord>, LogRecordError>;
+
+/// op codes below this are reserved for the built-in records
+pub const FIRST_CUSTOM_OP: i32 = 1 << 16;
+
+/// the record types an application added on top of the built-in ones.
+///
+/// Each database keeps its own registry, so types registered for one never show up in another.
+#[derive(Debug, Default)]
+pub struct RecordRegistry {
+    parsers: HashMap<i32, RecordParser>,
+}
+
+impl RecordRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// parses records starting with `op_code` with `parser`, replacing any parser registered
+    /// for it before. fails with `ReservedOp` below `FIRST_CUSTOM_OP`.
+    pub fn register(&mut self, op_code: i32, parser: RecordParser) -> Result<(), LogRecordError> {
+        if op_code < FIRST_CUSTOM_OP {
+            return Err(LogRecordError::ReservedOp(op_code));
+        }
+        self.parsers.insert(op_code, parser);
+        Ok(())
+    }
+
+    /// like `create_log_record`, falling back to the registered parsers for unknown op codes
+    pub fn create_log_record(&self, bytes: &[u8]) -> Result<LogRecordKind, LogRecordError> {
+        match create_log_record(bytes) {
+            Err(LogRecordError::UnknownOp { code }) => match self.parsers.get(&code) {
+                Some(parser) => Ok(LogRecordKind::Custom(CustomRecord(parser(decode_record(
+                    bytes,
+                )?)?))),
+                None => Err(LogRecordError::UnknownOp { code }),
+            },
+            parsed => parsed,
+        }
+    }
+}
+
+/// the op a record payload starts with
+fn read_op(payload: &[u8]) -> Result<i32, LogRecordError> {
+    match payload.get(..I32_SIZE) {
+        Some(op) => Ok(i32::from_be_bytes(op.try_into().unwrap())),
+        None => Err(LogRecordError::ShortRecord { len: payload.len() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file::FileManager,
+        log::LogManager,
+        log_records::write_record,
+        test_util::{v0_records, V0_RECORD_STRINGS},
+    };
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn v0_records_still_parse_and_migrate() {
+        let records = v0_records();
+        let parsed: Vec<String> = records
+            .iter()
+            .map(|bytes| create_log_record(bytes).unwrap().to_string())
+            .collect();
+        assert_eq!(parsed, V0_RECORD_STRINGS);
+
+        for bytes in records {
+            let tx_num = create_log_record(&bytes).unwrap().tx_num();
+            assert_eq!(peek_tx_num(&bytes).unwrap(), Some(tx_num));
+            let migrated = migrate_record(&bytes).unwrap();
+            assert_eq!(migrated[0], RECORD_VERSION);
+            assert_eq!(
+                create_log_record(&migrated).unwrap(),
+                create_log_record(&bytes).unwrap()
+            );
+            let checked = migrate_record(&encode_checked_record(&bytes)).unwrap();
+            assert_eq!(checked[0], CHECKED_RECORD_VERSION);
+        }
+
+        // records of unknown op are only reframed
+        let custom = FIRST_CUSTOM_OP.to_be_bytes();
+        assert_eq!(migrate_record(&custom).unwrap(), encode_record(&custom));
+    }
+
+    #[test]
+    fn records_are_versioned() {
+        let rec = SetStringRecord::new(4, BlockId::new("users", 3), 44, "alice");
+
+        // version 0: the bare payload written before records carried a version
+        let v0 = create_log_record(&rec.to_bytes()).unwrap();
+        assert_eq!(v0.to_string(), rec.to_string());
+
+        let dirname = "__test_35";
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        write_record(Arc::clone(&lm), &rec).unwrap();
+        write_record(Arc::clone(&lm), &CommitRecord::new(4)).unwrap();
+        let logged: Vec<Vec<u8>> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(logged[1], encode_record(&rec.to_bytes()));
+        assert_eq!(logged[1][0], RECORD_VERSION);
+        let logged: Vec<String> = logged
+            .into_iter()
+            .map(|bytes| create_log_record(&bytes).unwrap().to_string())
+            .collect();
+        assert_eq!(logged, vec!["<COMMIT 4>".to_string(), rec.to_string()]);
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+
+        let mut newer = encode_record(&rec.to_bytes());
+        newer[0] = CHECKED_RECORD_VERSION + 1;
+        assert!(matches!(
+            create_log_record(&newer),
+            Err(LogRecordError::UnsupportedVersion { found: 5 })
+        ));
+
+        // records framed before update records carried new values
+        let mut v1 = encode_record(&rec.to_bytes());
+        v1[0] = LEGACY_RECORD_VERSION;
+        assert_eq!(create_log_record(&v1).unwrap().to_string(), rec.to_string());
+
+        let mut truncated = encode_record(&rec.to_bytes());
+        truncated.pop();
+        let len = rec.to_bytes().len();
+        assert!(matches!(
+            create_log_record(&truncated),
+            Err(LogRecordError::LengthMismatch { expected, actual }) if expected == len && actual == len - 1
+        ));
+    }
+
+    #[test]
+    fn cursor_reads_back_what_the_builder_wrote() {
+        let block = BlockId::new("名前", 12);
+        let bytes = LogRecordBuilder::new(TxType::SetBytes)
+            .i32(-3)
+            .block(&block)
+            .u64(u64::MAX)
+            .string("")
+            .bytes(&[1, 2, 3])
+            .build();
+
+        let mut r = RecordCursor::new(&bytes).unwrap();
+        assert_eq!(r.op(), TxType::SetBytes as i32);
+        assert_eq!(r.read_i32().unwrap(), -3);
+        assert_eq!(r.read_block().unwrap(), block);
+        assert_eq!(r.read_u64().unwrap(), u64::MAX);
+        assert_eq!(r.read_string().unwrap(), "");
+        assert_eq!(r.read_bytes().unwrap(), vec![1, 2, 3]);
+        assert!(!r.has_more());
+        assert!(matches!(
+            r.read_i32(),
+            Err(LogRecordError::Corrupt { offset, .. }) if offset == bytes.len()
+        ));
+    }
+
+    #[test]
+    fn cursor_rejects_lengths_and_strings_that_dont_fit() {
+        let cases = [
+            (
+                LogRecordBuilder::new(TxType::SetBytes).i32(-1).build(),
+                "negative length -1",
+            ),
+            (
+                LogRecordBuilder::new(TxType::SetBytes)
+                    .i32(i32::MAX)
+                    .build(),
+                "2147483647 bytes run past the end",
+            ),
+            (
+                LogRecordBuilder::new(TxType::SetBytes)
+                    .bytes(&[0xff, 0xfe])
+                    .build(),
+                "string is not valid UTF-8",
+            ),
+        ];
+        for (bytes, reason) in cases {
+            let mut r = RecordCursor::new(&bytes).unwrap();
+            match r.read_string() {
+                Err(LogRecordError::Corrupt { reason: r, offset }) => {
+                    assert_eq!((r.as_str(), offset), (reason, I32_SIZE))
+                }
+                other => panic!("expected Corrupt, got {:?}", other),
+            }
+        }
+        assert!(matches!(
+            RecordCursor::new(&[0, 0, 0]),
+            Err(LogRecordError::Corrupt { offset: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn parsed_records_match_on_their_type() {
+        let block = BlockId::new("users", 2);
+        let log: Vec<Box<dyn LogRecord>> = vec![
+            Box::new(CheckPointRecord::new()),
+            Box::new(StartRecord::new(1)),
+            Box::new(SetI32Record::new(1, block.clone(), 8, 42)),
+            Box::new(SetStringRecord::new(1, block.clone(), 16, "bob")),
+            Box::new(SetU64Record::new(1, block.clone(), 32, 7)),
+            Box::new(SetBytesRecord::new(1, block.clone(), 48, [9, 9])),
+            Box::new(CompensationRecord::new(1, block.clone(), 8, 2)),
+            Box::new(ExtendFileRecord::new(1, block.clone())),
+            Box::new(NoopRecord::new(4)),
+            Box::new(NqCheckPointRecord::new(vec![1, 3])),
+            Box::new(RollbackRecord::new(1)),
+            Box::new(CommitRecord::new(2)),
+        ];
+
+        for rec in log.iter() {
+            let parsed = create_log_record(&encode_record(&rec.to_bytes())).unwrap();
+            assert_eq!(parsed.to_string(), rec.to_string());
+            assert_eq!(parsed.op(), rec.op());
+            match &parsed {
+                LogRecordKind::CheckPoint(_) | LogRecordKind::Noop(_) => {
+                    assert_eq!(rec.tx_num(), -1)
+                }
+                LogRecordKind::Start(r) => assert_eq!(r.timestamp(), None),
+                LogRecordKind::Rollback(r) => assert_eq!(r.tx_num(), 1),
+                LogRecordKind::Commit(r) => assert_eq!(r.tx_num(), 2),
+                LogRecordKind::SetI32(r) => assert_eq!(r.old_val(), 42),
+                LogRecordKind::SetString(r) => assert_eq!(r.old_val(), "bob"),
+                LogRecordKind::SetU64(r) => assert_eq!(r.old_val(), 7),
+                LogRecordKind::SetBytes(r) => assert_eq!(r.old_val(), &[9, 9]),
+                LogRecordKind::Compensation(r) => assert_eq!(r.undo_next_lsn(), Some(2)),
+                LogRecordKind::ExtendFile(r) => assert_eq!(r.block(), &block),
+                LogRecordKind::NqCheckPoint(r) => assert_eq!(r.txnums(), &[1, 3]),
+                LogRecordKind::Custom(_) => unreachable!("no records are registered"),
+            }
+        }
+    }
+
+    #[test]
+    fn checked_records_detect_corruption() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let block = BlockId::new("users", 2);
+        let log: Vec<Box<dyn LogRecord>> = vec![
+            Box::new(CheckPointRecord::new()),
+            Box::new(StartRecord::new(1)),
+            Box::new(CommitRecord::new(1)),
+            Box::new(RollbackRecord::new(1)),
+            Box::new(SetI32Record::new(1, block.clone(), 8, 42)),
+            Box::new(SetStringRecord::new(1, block.clone(), 16, "bob")),
+            Box::new(SetU64Record::new(1, block.clone(), 32, 7)),
+            Box::new(SetBytesRecord::new(1, block.clone(), 48, [9, 9])),
+            Box::new(CompensationRecord::new(1, block.clone(), 8, 2)),
+            Box::new(ExtendFileRecord::new(1, block.clone())),
+            Box::new(NoopRecord::new(4)),
+            Box::new(NqCheckPointRecord::new(vec![1, 3])),
+        ];
+        for rec in log.iter() {
+            let payload = rec.to_bytes();
+            let checked = encode_checked_record(&payload);
+            assert_eq!(decode_record(&checked).unwrap(), payload);
+            assert_eq!(
+                create_log_record(&checked).unwrap().to_string(),
+                rec.to_string()
+            );
+
+            // every byte after the header is covered: each field and the checksum itself
+            for i in HEADER_SIZE..checked.len() {
+                let mut corrupt = checked.clone();
+                corrupt[i] ^= 0x10;
+                assert!(
+                    matches!(
+                        create_log_record(&corrupt),
+                        Err(LogRecordError::Corrupt {
+                            offset: HEADER_SIZE,
+                            ..
+                        })
+                    ),
+                    "flipping byte {} of {}",
+                    i,
+                    rec
+                );
+            }
+
+            // records without a checksum aren't validated
+            assert_eq!(
+                create_log_record(&payload).unwrap().to_string(),
+                rec.to_string()
+            );
+            let unchecked = encode_record(&payload);
+            assert_eq!(
+                create_log_record(&unchecked).unwrap().to_string(),
+                rec.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn every_record_type_parses_back_to_its_op() {
+        let block = BlockId::new("users", 2);
+        for op in TxType::ALL {
+            // no wildcard: a new record type fails to compile here until it is covered
+            let rec: Box<dyn LogRecord> = match op {
+                TxType::CheckPoint => Box::new(CheckPointRecord::new()),
+                TxType::Start => Box::new(StartRecord::new(1)),
+                TxType::Commit => Box::new(CommitRecord::new(1)),
+                TxType::Rollback => Box::new(RollbackRecord::new(1)),
+                TxType::SetI32 => Box::new(SetI32Record::new(1, block.clone(), 0, 0)),
+                TxType::SetString => Box::new(SetStringRecord::new(1, block.clone(), 0, "")),
+                TxType::SetU64 => Box::new(SetU64Record::new(1, block.clone(), 0, 0)),
+                TxType::SetBytes => Box::new(SetBytesRecord::new(1, block.clone(), 0, [])),
+                TxType::Compensation => Box::new(CompensationRecord::new(1, block.clone(), 0, 0)),
+                TxType::Noop => Box::new(NoopRecord::new(0)),
+                TxType::ExtendFile => Box::new(ExtendFileRecord::new(1, block.clone())),
+                TxType::NqCheckPoint => Box::new(NqCheckPointRecord::new(vec![1])),
+                TxType::Custom => unreachable!("custom records aren't built in"),
+            };
+            assert_eq!(&rec.op(), op);
+            assert_eq!(TxType::try_from(rec.op_code()).ok().as_ref(), Some(op));
+            let bytes = encode_record(&rec.to_bytes());
+            let parsed = create_log_record(&bytes).unwrap();
+            assert_eq!(&parsed.op(), op);
+            assert_eq!(peek_tx_num(&bytes).unwrap(), Some(rec.tx_num()));
+        }
+        let custom = LogRecordBuilder::with_op_code(FIRST_CUSTOM_OP)
+            .i32(1)
+            .build();
+        assert_eq!(peek_tx_num(&encode_record(&custom)).unwrap(), None);
+        assert!(TxType::try_from(TxType::Custom as i32).is_err());
+    }
+
+    #[test]
+    fn op_codes_convert_back_to_their_type() {
+        for &op in TxType::ALL {
+            assert_eq!(TxType::try_from(op as i32).ok(), Some(op));
+        }
+        for code in [-1, 12, FIRST_CUSTOM_OP] {
+            assert!(matches!(
+                TxType::try_from(code),
+                Err(LogRecordError::UnknownOp { code: c }) if c == code
+            ));
+        }
+        assert_eq!(TxType::SetI32.to_string(), "SETI32");
+        assert_eq!(TxType::Compensation.to_string(), "CLR");
+        assert_eq!(TxType::Custom.to_string(), "CUSTOM");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn parsed_records_round_trip_through_json() {
+        let block = BlockId::new("users", 2);
+        let log: Vec<Box<dyn LogRecord>> = vec![
+            Box::new(CheckPointRecord::new()),
+            Box::new(StartRecord::new(1)),
+            Box::new(SetI32Record::with_values(1, block.clone(), 8, 42, 43)),
+            Box::new(SetStringRecord::new(1, block.clone(), 16, "bob")),
+            Box::new(SetU64Record::with_values(1, block.clone(), 32, 7, 8)),
+            Box::new(SetBytesRecord::new(1, block.clone(), 48, [9, 9])),
+            Box::new(CompensationRecord::new(1, block.clone(), 8, 2)),
+            Box::new(ExtendFileRecord::new(1, block.clone())),
+            Box::new(NoopRecord::new(4)),
+            Box::new(RollbackRecord::new(1)),
+            Box::new(CommitRecord::new(2)),
+        ];
+        let parsed: Vec<LogRecordKind> = log
+            .iter()
+            .map(|rec| create_log_record(&encode_record(&rec.to_bytes())).unwrap())
+            .collect();
+
+        let json = serde_json::to_string(&parsed).unwrap();
+        let back: Vec<LogRecordKind> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, parsed);
+
+        let set_i32 = serde_json::to_value(&parsed[2]).unwrap();
+        assert_eq!(
+            set_i32,
+            serde_json::json!({
+                "type": "SetI32",
+                "txnum": 1,
+                "block": {"filename": "users", "number": 2},
+                "offset": 8,
+                "old_val": 42,
+                "new_val": 43,
+            })
+        );
+        let commit = serde_json::to_value(&parsed[10]).unwrap();
+        assert_eq!(
+            commit,
+            serde_json::json!({"type": "Commit", "txnum": 2, "timestamp": null})
+        );
+    }
+
+    #[test]
+    fn malformed_records_are_errors() {
+        for bytes in [&[][..], &[0, 0, 4][..], &encode_record(&[0, 4])[..]] {
+            assert!(matches!(
+                create_log_record(bytes),
+                Err(LogRecordError::ShortRecord { len }) if len < I32_SIZE
+            ));
+        }
+        assert!(matches!(
+            create_log_record(&encode_record(&999i32.to_be_bytes())),
+            Err(LogRecordError::UnknownOp { code: 999 })
+        ));
+
+        // without optional trailing fields, so that every cut is missing a required one
+        let block = BlockId::new("users", 2);
+        let log: Vec<Box<dyn LogRecord>> = vec![
+            Box::new(StartRecord::new(1)),
+            Box::new(CommitRecord::new(1)),
+            Box::new(RollbackRecord::new(1)),
+            Box::new(SetI32Record::new(1, block.clone(), 8, 42)),
+            Box::new(SetStringRecord::new(1, block.clone(), 16, "bob")),
+            Box::new(SetU64Record::new(1, block.clone(), 32, 7)),
+            Box::new(SetBytesRecord::new(1, block.clone(), 48, [9, 9])),
+            Box::new(CompensationRecord::new(1, block.clone(), 8, 2)),
+            Box::new(ExtendFileRecord::new(1, block.clone())),
+        ];
+        for rec in log.iter() {
+            let payload = rec.to_bytes();
+            for len in I32_SIZE..payload.len() {
+                assert!(
+                    matches!(
+                        create_log_record(&payload[..len]),
+                        // the field the cut went through
+                        Err(LogRecordError::Corrupt { offset, .. }) if (I32_SIZE..=len).contains(&offset)
+                    ),
+                    "{} cut to {} bytes",
+                    rec,
+                    len
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn errors_describe_what_went_wrong() {
+        let mut checked = encode_checked_record(&CommitRecord::new(4).to_bytes());
+        checked[HEADER_SIZE] ^= 1;
+        let mut short_commit = encode_record(&CommitRecord::new(4).to_bytes());
+        short_commit.truncate(short_commit.len() - 1);
+        short_commit[1..HEADER_SIZE].copy_from_slice(&(I32_SIZE as i32 + 3).to_be_bytes());
+        let cases = [
+            (
+                create_log_record(&encode_record(&999i32.to_be_bytes())),
+                "Unknown log record op 999",
+            ),
+            (
+                create_log_record(&[9, 0, 0, 0, 0]),
+                "Unsupported log record version 9",
+            ),
+            (
+                create_log_record(&encode_record(&[0, 4])),
+                "Log record of 2 bytes is too short to hold an op",
+            ),
+            (
+                create_log_record(&checked),
+                "Log record is corrupt at byte 5: checksum mismatch",
+            ),
+        ];
+        for (result, message) in cases {
+            assert_eq!(result.unwrap_err().to_string(), message);
+        }
+
+        let err = create_log_record(&short_commit).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Log record is corrupt at byte 9: not a valid COMMIT record, i32 runs past the end"
+        );
 
-    match tx_type {
-        Some(TxType::CheckPoint) => Ok(Box::new(CheckPointRecord::new())),
-        Some(TxType::Start) => Ok(Box::new(StartRecord::new())),
-        Some(TxType::Commit) => Ok(Box::new(CommitRecord::new())),
-        Some(TxType::Rollback) => Ok(Box::new(RollbackRecord::new())),
-        Some(TxType::SetI32) => Ok(Box::new(SetI32Record::new())),
-        Some(TxType::SetString) => Ok(Box::new(SetStringRecord::new())),
-        _ => Err(LogRecordError::UnknownRecord),
+        // wrapped, the cause is still there to report
+        let err = tx::TransactionError::from(err);
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(source
+            .to_string()
+            .starts_with("Log record is corrupt at byte 9"));
     }
 }
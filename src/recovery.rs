@@ -1,59 +1,1951 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    buffer::BufferManager,
-    log::LogManager,
-    log_records::{CommitRecord, RollbackRecord, StartRecord},
-    record::{create_log_record, LogRecord, TxType},
-    tx::Transaction,
+    buffer::{Buffer, BufferManager},
+    clock::SystemClock,
+    file::BlockId,
+    log::{LogManager, Lsn},
+    log_records::{
+        CheckPointRecord, CommitRecord, CompensationRecord, ExtendFileRecord, NqCheckPointRecord,
+        RollbackRecord, SetBytesRecord, SetI32Record, SetStringRecord, SetU64Record, StartRecord,
+    },
+    record::{
+        encode_record, peek_tx_num, LogRecord, LogRecordError, LogRecordKind, RecordRegistry,
+    },
+    tx::{self, TransactionError, TxOps},
 };
 
+/// what `recover` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecoveryReport {
+    /// records read from the log, the checkpoint the scan stopped at included
+    pub log_records_scanned: usize,
+    /// the checkpoint the scan stopped at, if it reached one
+    pub checkpoint_lsn: Option<Lsn>,
+    /// transactions whose commit was read, so their updates were redone
+    pub txs_committed: usize,
+    /// transactions whose rollback was read, so their updates were left alone
+    pub txs_rolled_back: usize,
+    /// unfinished transactions with at least one update undone
+    pub txs_undone: usize,
+    pub undo_actions: usize,
+    /// updates written again, not counting those the page already held
+    pub redo_actions: usize,
+    pub duration: Duration,
+    /// the log ended with the checkpoint of a clean shutdown, so recovery was skipped
+    pub clean: bool,
+}
+
+/// the checkpoint recovery reads the log back to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckpointInfo {
+    pub lsn: Lsn,
+    /// the transactions a non-quiescent checkpoint listed as running. empty for a quiescent one
+    pub active: Vec<i32>,
+}
+
+/// what `recover` would do, found by reading the log. transactions are in ascending order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalysisResult {
+    /// the winners, whose updates are redone
+    pub committed: Vec<i32>,
+    /// left alone: their updates were undone before they finished
+    pub rolled_back: Vec<i32>,
+    /// the losers, which neither committed nor rolled back. their updates are undone
+    pub active_at_crash: Vec<i32>,
+    /// the lsn of the oldest record recovery reads, where the redo pass starts
+    pub redo_start_lsn: Lsn,
+    pub checkpoint: Option<CheckpointInfo>,
+}
+
+/// what `rollback` did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RollbackReport {
+    /// records read from the log, of any transaction
+    pub log_records_scanned: usize,
+    /// records parsed in full: this transaction's, and registered ones
+    pub log_records_parsed: usize,
+    pub undo_actions: usize,
+}
+
+/// the end of the log, back to where recovery stops reading
+struct LogTail {
+    /// the records with their lsns, newest first
+    records: Vec<(Lsn, LogRecordKind)>,
+    /// the checkpoint the records stop at
+    checkpoint: Option<CheckpointInfo>,
+}
+
 pub struct RecoveryManager {
     lm: Arc<Mutex<LogManager>>,
-    bm: Arc<Mutex<BufferManager>>,
-    tx: Arc<Mutex<Transaction>>,
+    bm: Arc<BufferManager>,
     tx_num: i32,
+    start_lsn: Lsn,
+    /// parses the record types the application registered
+    registry: Arc<RecordRegistry>,
+    /// runs once the transaction committed or rolled back
+    on_complete: Option<Box<dyn Fn() + Send + Sync>>,
+    records_written: AtomicUsize,
+    bytes_written: AtomicUsize,
 }
 
 impl RecoveryManager {
+    /// starts transaction `tx_num` by logging its start record. fails if the record can't be
+    /// appended, e.g. because the full log block before it couldn't be written.
     pub fn new(
-        tx: Arc<Mutex<Transaction>>,
         tx_num: i32,
         lm: Arc<Mutex<LogManager>>,
-        bm: Arc<Mutex<BufferManager>>,
-    ) -> Self {
-        let rm = Self {
-            tx,
-            lm: Arc::clone(&lm),
+        bm: Arc<BufferManager>,
+    ) -> tx::Result<Self> {
+        let mut rm = Self {
+            lm,
             bm,
             tx_num,
+            start_lsn: 0,
+            registry: Arc::default(),
+            on_complete: None,
+            records_written: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
         };
-        StartRecord::write_to_log(lm).unwrap();
-        rm
+        rm.start_lsn = rm.write(&StartRecord::stamped(tx_num, &SystemClock))?;
+        Ok(rm)
+    }
+
+    /// the lsn of this transaction's start record. none of its records come before it
+    pub fn start_lsn(&self) -> Lsn {
+        self.start_lsn
+    }
+
+    /// the log records this manager wrote, its start record included
+    pub fn records_written(&self) -> usize {
+        self.records_written.load(Ordering::Relaxed)
+    }
+
+    /// the size of the records in `records_written`, as framed in the log
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// reads the log with `registry`, so that registered records take part in rollback
+    pub fn with_registry(mut self, registry: Arc<RecordRegistry>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// runs `hook` once commit or rollback succeeded, e.g. for the transaction to unpin the
+    /// buffers it still holds
+    pub fn on_complete(mut self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_complete = Some(Box::new(hook));
+        self
+    }
+
+    /// logs the commit record and flushes the log through it, which is what makes the commit
+    /// durable. only then are the transaction's buffers flushed: they don't have to reach the
+    /// disk for the commit to hold, since recovery redoes them, but writing them now keeps the
+    /// redo pass short. if that flush fails the transaction stays committed.
+    pub fn commit(&self) -> tx::Result<()> {
+        let lsn = self.write(&CommitRecord::stamped(self.tx_num, &SystemClock))?;
+        self.lm.lock().unwrap().flush_with_lsn(lsn)?;
+        self.bm.flush_all(self.tx_num)?;
+        self.complete();
+        Ok(())
+    }
+
+    /// undoes the transaction's updates, flushes the undone pages, then logs the rollback
+    /// record and flushes the log through it. the pages have to reach the disk first: recovery
+    /// leaves a transaction that rolled back alone, so it wouldn't undo the updates again.
+    pub fn rollback(&self, tx: &mut dyn TxOps) -> tx::Result<RollbackReport> {
+        let report = self.do_rollback(tx)?;
+        self.bm.flush_all(self.tx_num)?;
+        let lsn = self.write(&RollbackRecord::stamped(self.tx_num, &SystemClock))?;
+        self.lm.lock().unwrap().flush_with_lsn(lsn)?;
+        self.complete();
+        Ok(report)
+    }
+
+    fn complete(&self) {
+        if let Some(hook) = &self.on_complete {
+            hook();
+        }
+    }
+
+    /// logs that this transaction is about to write `new_val` at `offset` of the block in `buf`,
+    /// with what is there now as the old value. the caller then writes the value and passes the
+    /// returned lsn to `Buffer::set_modified`, so the record reaches the disk before the page.
+    pub fn set_i32(&self, buf: &Buffer, offset: i32, new_val: i32) -> tx::Result<Lsn> {
+        let old_val = buf.contents().get_i32(offset as u64)?;
+        let block = buf.block().ok_or(TransactionError::UnassignedBuffer)?;
+        let rec = SetI32Record::with_values(self.tx_num, block, offset, old_val, new_val);
+        self.write(&rec)
+    }
+
+    /// like `set_i32`, for a string
+    pub fn set_string(&self, buf: &Buffer, offset: i32, new_val: &str) -> tx::Result<Lsn> {
+        let old_val = buf.contents().get_string(offset as u64)?;
+        let block = buf.block().ok_or(TransactionError::UnassignedBuffer)?;
+        let rec = SetStringRecord::with_values(self.tx_num, block, offset, old_val, new_val);
+        self.write(&rec)
     }
 
-    pub fn commit(&self) {
-        self.bm.lock().unwrap().flush_all(self.tx_num);
-        let lsn = CommitRecord::write_to_log(Arc::clone(&self.lm)).unwrap();
-        self.lm.lock().unwrap().flush_with_lsn(lsn).unwrap();
+    /// like `set_i32`, for a u64
+    pub fn set_u64(&self, buf: &Buffer, offset: i32, new_val: u64) -> tx::Result<Lsn> {
+        let old_val = buf.contents().get_u64(offset as u64)?;
+        let block = buf.block().ok_or(TransactionError::UnassignedBuffer)?;
+        let rec = SetU64Record::with_values(self.tx_num, block, offset, old_val, new_val);
+        self.write(&rec)
     }
 
-    pub fn rollback(&mut self) {
-        self.do_rollback();
-        self.bm.lock().unwrap().flush_all(self.tx_num);
-        let lsn = RollbackRecord::write_to_log(Arc::clone(&self.lm)).unwrap();
-        self.lm.lock().unwrap().flush_with_lsn(lsn).unwrap();
+    /// like `set_i32`, for a byte slice
+    pub fn set_bytes(&self, buf: &Buffer, offset: i32, new_val: &[u8]) -> tx::Result<Lsn> {
+        let old_val = buf.contents().get_bytes(offset as u64)?;
+        let block = buf.block().ok_or(TransactionError::UnassignedBuffer)?;
+        let rec = SetBytesRecord::with_values(self.tx_num, block, offset, old_val, new_val);
+        self.write(&rec)
     }
 
-    fn do_rollback(&mut self) {
+    /// appends a block to `filename`. the append is logged, and the log flushed, before the file
+    /// grows, so that recovery knows about every block a transaction added.
+    ///
+    /// The logged block is the one at the current end of the file, so nothing else may append
+    /// to it in between: the caller holds the end-of-file xlock of `filename`. fails with
+    /// `AppendMismatch` if the file grew anyway, since undo and redo would then resize it to
+    /// the wrong length.
+    pub fn append(&self, tx: &mut dyn TxOps, filename: &str) -> tx::Result<BlockId> {
+        let block = BlockId::new(filename, tx.size(filename)?);
+        let rec = ExtendFileRecord::new(self.tx_num, block.clone());
+        let lsn = self.write(&rec)?;
+        self.lm.lock().unwrap().flush_with_lsn(lsn)?;
+        let appended = tx.append(filename)?;
+        if appended != block {
+            return Err(TransactionError::AppendMismatch(block, appended));
+        }
+        Ok(appended)
+    }
+
+    /// quiescent checkpoint: flushes every dirty buffer, then logs a checkpoint record and
+    /// flushes the log through it. recovery reads the log back no further than the last
+    /// checkpoint, since everything before it is on disk and finished.
+    ///
+    /// The caller must make sure no transaction is active: the checkpoint only blocks new pins
+    /// while it runs, it doesn't wait for running transactions to finish.
+    pub fn checkpoint(&self) -> tx::Result<Lsn> {
+        let bm = &self.bm;
+        let token = bm.checkpoint()?;
+        let lsn = self.write(&CheckPointRecord::new()).and_then(|lsn| {
+            self.lm.lock().unwrap().flush_with_lsn(lsn)?;
+            Ok(lsn)
+        });
+        bm.release(token);
+        lsn
+    }
+
+    /// non-quiescent checkpoint: flushes every dirty buffer without blocking pins, then logs a
+    /// checkpoint record listing `active` and flushes the log through it. recovery reads the log
+    /// back past the record only as far as the start of the earliest transaction in `active`.
+    ///
+    /// `active` must hold every transaction that is running, this one included if it has logged
+    /// anything, and no transaction may start until the checkpoint returns.
+    pub fn checkpoint_nonquiescent(&self, active: &[i32]) -> tx::Result<Lsn> {
+        self.bm.checkpoint_nonquiescent()?;
+        let rec = NqCheckPointRecord::new(active.to_vec());
+        let lsn = self.write(&rec)?;
+        self.lm.lock().unwrap().flush_with_lsn(lsn)?;
+        Ok(lsn)
+    }
+
+    /// brings the database back to a consistent state at startup, after the previous process
+    /// died at any point. the log back to the last checkpoint, or for a non-quiescent one to
+    /// the start of the earliest transaction it lists, is sorted by `analyze`, then
+    /// replayed in two passes:
+    ///
+    /// 1. undo, newest first: the updates and file growth of every transaction that neither
+    ///    committed nor rolled back are undone.
+    /// 2. redo, oldest first: the updates and file growth of committed transactions are
+    ///    written again, in case their buffers never reached the disk.
+    ///
+    /// Recovery ends with a checkpoint, so the next one stops there. undo and redo are both
+    /// idempotent, so if recovery itself crashes it can simply be run again.
+    pub fn recover(&self, tx: &mut dyn TxOps) -> tx::Result<RecoveryReport> {
+        let started = Instant::now();
+        let mut report = RecoveryReport::default();
+        // read the records first: undo pins buffers, and a pin may have to flush the log
+        let tail = self.records_to_recover(&mut report)?;
+        let analysis = self.analysis(&tail);
+        report.checkpoint_lsn = analysis.checkpoint.as_ref().map(|ckpt| ckpt.lsn);
+        report.txs_committed = analysis.committed.len();
+        report.txs_rolled_back = analysis.rolled_back.len();
+        let losers = analysis.active_at_crash.into_iter().collect();
+        self.undo_unfinished(tx, &tail.records, &losers, &mut report)?;
+        let winners = analysis.committed.into_iter().collect();
+        self.redo_committed(tx, &tail.records, &winners, &mut report)?;
+        self.checkpoint()?;
+        report.duration = started.elapsed();
+        Ok(report)
+    }
+
+    /// the analysis `recover` starts with: which transactions it would undo and redo, and how
+    /// far back it would read the log. nothing is undone, redone or logged, so tooling can ask
+    /// what recovery would do without doing it.
+    pub fn analyze(&self) -> tx::Result<AnalysisResult> {
+        let tail = self.records_to_recover(&mut RecoveryReport::default())?;
+        Ok(self.analysis(&tail))
+    }
+
+    /// the part of the log `recover` replays
+    fn records_to_recover(&self, report: &mut RecoveryReport) -> tx::Result<LogTail> {
         let mut lm = self.lm.lock().unwrap();
-        let mut iter = lm.iterator().unwrap();
-        while iter.has_next() {
-            let bytes = iter.next().unwrap();
-            let rec = create_log_record(bytes).unwrap();
-            if rec.tx_num() == self.tx_num && rec.op() == TxType::Start {
-                return;
+        let mut iter = lm.iterator()?;
+        let mut records = Vec::new();
+        let mut checkpoint = None;
+        // after the newest non-quiescent checkpoint: the listed transactions not started yet
+        let mut unstarted: Option<HashSet<i32>> = None;
+        loop {
+            let lsn = iter.next_lsn();
+            let Some(bytes) = iter.next() else {
+                break;
+            };
+            let bytes = bytes?;
+            let rec = self.registry.create_log_record(&bytes)?;
+            report.log_records_scanned += 1;
+            match &rec {
+                LogRecordKind::CheckPoint(_) => {
+                    checkpoint = Some(CheckpointInfo {
+                        lsn,
+                        active: Vec::new(),
+                    });
+                    break;
+                }
+                LogRecordKind::NqCheckPoint(ckpt) if unstarted.is_none() => {
+                    checkpoint = Some(CheckpointInfo {
+                        lsn,
+                        active: ckpt.txnums().to_vec(),
+                    });
+                    if ckpt.txnums().is_empty() {
+                        break;
+                    }
+                    unstarted = Some(ckpt.txnums().iter().copied().collect());
+                    continue;
+                }
+                _ => {}
+            }
+            let started = match (&rec, &mut unstarted) {
+                (LogRecordKind::Start(start), Some(unstarted)) => {
+                    unstarted.remove(&start.tx_num());
+                    unstarted.is_empty()
+                }
+                _ => false,
+            };
+            records.push((lsn, rec));
+            if started {
+                break;
+            }
+        }
+        Ok(LogTail {
+            records,
+            checkpoint,
+        })
+    }
+
+    /// sorts the transactions of `tail` by how they ended
+    fn analysis(&self, tail: &LogTail) -> AnalysisResult {
+        let mut committed = BTreeSet::new();
+        let mut rolled_back = BTreeSet::new();
+        let mut seen = BTreeSet::new();
+        for (_, rec) in &tail.records {
+            match rec {
+                LogRecordKind::Commit(rec) => {
+                    committed.insert(rec.tx_num());
+                }
+                LogRecordKind::Rollback(rec) => {
+                    rolled_back.insert(rec.tx_num());
+                }
+                LogRecordKind::CheckPoint(_)
+                | LogRecordKind::NqCheckPoint(_)
+                | LogRecordKind::Noop(_) => continue,
+                LogRecordKind::Start(_)
+                | LogRecordKind::SetI32(_)
+                | LogRecordKind::SetString(_)
+                | LogRecordKind::SetU64(_)
+                | LogRecordKind::SetBytes(_)
+                | LogRecordKind::Compensation(_)
+                | LogRecordKind::ExtendFile(_)
+                | LogRecordKind::Custom(_) => {}
+            }
+            seen.insert(rec.tx_num());
+        }
+        // this transaction, recovering, has logged nothing but its start
+        seen.remove(&self.tx_num);
+        let redo_start_lsn = match (tail.records.last(), &tail.checkpoint) {
+            (Some(&(lsn, _)), _) => lsn,
+            (None, Some(ckpt)) => ckpt.lsn + 1,
+            (None, None) => 1,
+        };
+        AnalysisResult {
+            active_at_crash: seen
+                .iter()
+                .filter(|txnum| !committed.contains(txnum) && !rolled_back.contains(txnum))
+                .copied()
+                .collect(),
+            committed: committed.into_iter().collect(),
+            rolled_back: rolled_back.into_iter().collect(),
+            redo_start_lsn,
+            checkpoint: tail.checkpoint.clone(),
+        }
+    }
+
+    /// the undo pass of `recover`, over `records` newest first: undoes the updates of `losers`
+    fn undo_unfinished(
+        &self,
+        tx: &mut dyn TxOps,
+        records: &[(Lsn, LogRecordKind)],
+        losers: &HashSet<i32>,
+        report: &mut RecoveryReport,
+    ) -> tx::Result<()> {
+        let mut undone = HashSet::new();
+        for (_, rec) in records {
+            match rec {
+                rec @ (LogRecordKind::SetI32(_)
+                | LogRecordKind::SetString(_)
+                | LogRecordKind::SetU64(_)
+                | LogRecordKind::SetBytes(_)
+                | LogRecordKind::ExtendFile(_)
+                | LogRecordKind::Custom(_)) => {
+                    if losers.contains(&rec.tx_num()) {
+                        rec.undo(tx)?;
+                        undone.insert(rec.tx_num());
+                        report.undo_actions += 1;
+                    }
+                }
+                LogRecordKind::CheckPoint(_)
+                | LogRecordKind::NqCheckPoint(_)
+                | LogRecordKind::Start(_)
+                | LogRecordKind::Commit(_)
+                | LogRecordKind::Rollback(_)
+                | LogRecordKind::Compensation(_)
+                | LogRecordKind::Noop(_) => {}
+            }
+        }
+        report.txs_undone = undone.len();
+        Ok(())
+    }
+
+    /// the redo pass of `recover`, over `records` oldest first: redoes the updates of
+    /// `committed`. updates the page already holds by its page lsn, and appends the file
+    /// already holds, are skipped, and a redone update raises the page lsn to its own.
+    fn redo_committed(
+        &self,
+        tx: &mut dyn TxOps,
+        records: &[(Lsn, LogRecordKind)],
+        committed: &HashSet<i32>,
+        report: &mut RecoveryReport,
+    ) -> tx::Result<()> {
+        for &(lsn, ref rec) in records.iter().rev() {
+            match rec {
+                rec @ (LogRecordKind::SetI32(_)
+                | LogRecordKind::SetString(_)
+                | LogRecordKind::SetU64(_)
+                | LogRecordKind::SetBytes(_)
+                | LogRecordKind::ExtendFile(_)
+                | LogRecordKind::Custom(_)) => {
+                    if !committed.contains(&rec.tx_num()) {
+                        continue;
+                    }
+                    let block = rec.target().map(|(block, _)| block);
+                    if let Some(block) = block {
+                        if self.page_lsn(block)? >= lsn {
+                            continue;
+                        }
+                    }
+                    // the same for an append, which has no page lsn: the file still holds it
+                    if let LogRecordKind::ExtendFile(rec) = rec {
+                        if tx.size(rec.block().filename())? > rec.block().number() {
+                            continue;
+                        }
+                    }
+                    match rec.redo(tx) {
+                        // logged before new values were, by versions whose commit flushed the
+                        // buffers before it was logged, so the updates are on disk already
+                        Err(TransactionError::LogRecordError(LogRecordError::RedoUnavailable)) => {
+                            continue
+                        }
+                        res => res?,
+                    }
+                    report.redo_actions += 1;
+                    if let Some(block) = block {
+                        self.set_page_lsn(block, lsn)?;
+                    }
+                }
+                LogRecordKind::CheckPoint(_)
+                | LogRecordKind::NqCheckPoint(_)
+                | LogRecordKind::Start(_)
+                | LogRecordKind::Commit(_)
+                | LogRecordKind::Rollback(_)
+                | LogRecordKind::Compensation(_)
+                | LogRecordKind::Noop(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// the page lsn of `block`, see `Buffer::page_lsn`
+    fn page_lsn(&self, block: &BlockId) -> tx::Result<Lsn> {
+        let bm = &self.bm;
+        let buf = bm.pin(block.clone())?;
+        let lsn = buf.page_lsn();
+        bm.unpin(buf);
+        Ok(lsn?)
+    }
+
+    /// records that the page of `block` holds the update logged at `lsn`
+    fn set_page_lsn(&self, block: &BlockId, lsn: Lsn) -> tx::Result<()> {
+        let bm = &self.bm;
+        let buf = bm.pin(block.clone())?;
+        let res = buf.set_modified(self.tx_num, lsn);
+        bm.unpin(buf);
+        Ok(res?)
+    }
+
+    /// undoes this transaction's updates newest first, logging a compensation record after each.
+    /// updates already covered by a compensation record are skipped, so a rollback cut short by
+    /// a crash resumes where it stopped instead of undoing them twice. the log is read back no
+    /// further than the start record.
+    fn do_rollback(&self, tx: &mut dyn TxOps) -> tx::Result<RollbackReport> {
+        // the iterator reads through the storage, not the log manager, so the log is unlocked
+        // while undoing: undo pins buffers, a pin may flush the log, and undo appends records
+        let mut iter = self.lm.lock().unwrap().iterator()?.down_to(self.start_lsn);
+        let mut undo_next = i32::MAX;
+        let mut report = RollbackReport::default();
+        loop {
+            let lsn = iter.next_lsn();
+            let Some(bytes) = iter.next() else {
+                break;
+            };
+            let bytes = bytes?;
+            report.log_records_scanned += 1;
+            // the records of other transactions are skipped before they are parsed
+            if peek_tx_num(&bytes)?.is_some_and(|tx_num| tx_num != self.tx_num) {
+                continue;
+            }
+            let rec = self.registry.create_log_record(&bytes)?;
+            report.log_records_parsed += 1;
+            if rec.tx_num() != self.tx_num {
+                continue;
+            }
+            let undone = match rec {
+                LogRecordKind::Start(_) => break,
+                LogRecordKind::Compensation(clr) => {
+                    undo_next = undo_next.min(clr.undo_next());
+                    false
+                }
+                _ if lsn > undo_next => false,
+                LogRecordKind::SetI32(rec) => {
+                    self.undo_update(tx, &rec, rec.block(), rec.offset(), lsn)?;
+                    true
+                }
+                LogRecordKind::SetString(rec) => {
+                    self.undo_update(tx, &rec, rec.block(), rec.offset(), lsn)?;
+                    true
+                }
+                LogRecordKind::SetU64(rec) => {
+                    self.undo_update(tx, &rec, rec.block(), rec.offset(), lsn)?;
+                    true
+                }
+                LogRecordKind::SetBytes(rec) => {
+                    self.undo_update(tx, &rec, rec.block(), rec.offset(), lsn)?;
+                    true
+                }
+                LogRecordKind::ExtendFile(rec) => {
+                    rec.undo(tx)?;
+                    true
+                }
+                LogRecordKind::Custom(rec) => {
+                    match rec.0.target() {
+                        Some((block, offset)) => {
+                            self.undo_update(tx, rec.0.as_ref(), block, offset, lsn)?
+                        }
+                        None => rec.0.undo(tx)?,
+                    }
+                    true
+                }
+                LogRecordKind::CheckPoint(_)
+                | LogRecordKind::NqCheckPoint(_)
+                | LogRecordKind::Commit(_)
+                | LogRecordKind::Rollback(_)
+                | LogRecordKind::Noop(_) => false,
+            };
+            if undone {
+                report.undo_actions += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// undoes the update logged at `lsn` and logs that it was undone
+    fn undo_update(
+        &self,
+        tx: &mut dyn TxOps,
+        rec: &dyn LogRecord,
+        block: &BlockId,
+        offset: i32,
+        lsn: Lsn,
+    ) -> tx::Result<()> {
+        rec.undo(tx)?;
+        let clr = CompensationRecord::new(self.tx_num, block.clone(), offset, lsn - 1);
+        self.write(&clr)?;
+        Ok(())
+    }
+
+    /// appends `rec` to the log, like `write_record`, and counts it
+    fn write(&self, rec: &impl LogRecord) -> tx::Result<Lsn> {
+        let bytes = encode_record(&rec.to_bytes());
+        let len = bytes.len();
+        let lsn = self.lm.lock().unwrap().append(bytes)?;
+        self.records_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(len, Ordering::Relaxed);
+        Ok(lsn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file::{BlockId, BlockStorage, FileManager, Page, I32_SIZE},
+        log_records::write_record,
+        record::{
+            create_log_record, encode_record, LogRecordBuilder, LogRecordError, RecordCursor,
+            TxType, FIRST_CUSTOM_OP,
+        },
+        test_util::{
+            BufferTx, FailingStorage, FaultInjectingStorage, Faults, MockTx, RecordingStorage,
+            TxCall, WriteOrderStorage,
+        },
+        tx::TxOps,
+    };
+    use std::{
+        fmt, fs,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    };
+
+    fn open(dirname: &str) -> (Arc<Mutex<LogManager>>, Arc<BufferManager>) {
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        fm.lock().unwrap().append("testfile").unwrap();
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(fm.clone(), Arc::clone(&lm), 3);
+        (lm, Arc::new(bm))
+    }
+
+    /// commits `txnum` after 40 updates of the string at offset 200 of the first block, enough
+    /// to fill a few log blocks
+    fn commit_history(lm: &Arc<Mutex<LogManager>>, bm: &Arc<BufferManager>, txnum: i32) {
+        let block = BlockId::new("testfile", 0);
+        let rm = RecoveryManager::new(txnum, Arc::clone(lm), Arc::clone(bm)).unwrap();
+        let mut tx = BufferTx::new(txnum, Arc::clone(bm));
+        tx.pin(&block).unwrap();
+        for i in 0..40 {
+            let old = tx.buffer(&block).contents().get_string(200).unwrap();
+            let new = format!("{}{}", txnum, "x".repeat(i + 100));
+            let rec = SetStringRecord::with_values(txnum, block.clone(), 200, old, &new);
+            write_record(Arc::clone(lm), &rec).unwrap();
+            tx.set_string(&block, 200, &new, true).unwrap();
+        }
+        tx.unpin(&block);
+        rm.commit().unwrap();
+    }
+
+    /// opens `dirname` again after a crash, recording every block read into `reads`
+    fn reopen_recording(
+        dirname: &str,
+        reads: &Arc<Mutex<Vec<BlockId>>>,
+    ) -> (Arc<Mutex<LogManager>>, Arc<BufferManager>) {
+        let storage: Arc<Mutex<dyn BlockStorage>> = Arc::new(Mutex::new(RecordingStorage {
+            inner: FileManager::new(dirname).unwrap(),
+            reads: Arc::clone(reads),
+            writes: Arc::default(),
+        }));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&storage), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(Arc::clone(&storage), Arc::clone(&lm), 3));
+        (lm, bm)
+    }
+
+    /// the numbers of the log blocks in `reads`
+    fn log_reads(reads: &Mutex<Vec<BlockId>>) -> Vec<i32> {
+        reads
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|b| b.filename() == "test_log")
+            .map(BlockId::number)
+            .collect()
+    }
+
+    /// logs that `tx` sets the i32 at `offset` of `block` to `val`, then sets it
+    fn log_and_set_i32(
+        rm: &RecoveryManager,
+        tx: &mut BufferTx,
+        block: &BlockId,
+        offset: i32,
+        val: i32,
+    ) {
+        tx.pin(block).unwrap();
+        let lsn = rm.set_i32(tx.buffer(block), offset, val).unwrap();
+        tx.set_i32(block, offset, val, false).unwrap();
+        tx.buffer(block).set_modified(rm.tx_num, lsn).unwrap();
+        tx.unpin(block);
+    }
+
+    /// the updates of the crash workload, in order: (txnum, block number, offset, value). each
+    /// place is written by one transaction only, and holds 0 unless that transaction committed.
+    const CRASH_WORKLOAD: [(i32, i32, i32, i32); 8] = [
+        (1, 0, 8, 11),
+        (3, 1, 8, 31),
+        (2, 2, 8, 21),
+        (4, 3, 8, 41),
+        (1, 3, 16, 12),
+        (3, 0, 16, 32),
+        (2, 1, 16, 22),
+        (4, 2, 16, 42),
+    ];
+
+    /// runs `CRASH_WORKLOAD` in a new database in `dirname` under `faults`: 1 and 2 commit,
+    /// 3 rolls back and 4 is still running, with its buffers flushed, when the process dies
+    fn run_crash_workload(dirname: &str, faults: &Arc<Mutex<Faults>>) {
+        let mut fm = FileManager::new(dirname).unwrap();
+        for _ in 0..4 {
+            fm.append("testfile").unwrap();
+        }
+        let storage: Arc<Mutex<dyn BlockStorage>> = Arc::new(Mutex::new(
+            FaultInjectingStorage::new(fm, Arc::clone(faults)),
+        ));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&storage), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(Arc::clone(&storage), Arc::clone(&lm), 3));
+        let mut txs: Vec<(RecoveryManager, BufferTx)> = (1..=4)
+            .map(|txnum| {
+                let rm = RecoveryManager::new(txnum, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+                (rm, BufferTx::new(txnum, Arc::clone(&bm)))
+            })
+            .collect();
+        for (i, &(txnum, block, offset, val)) in CRASH_WORKLOAD.iter().enumerate() {
+            let (rm, tx) = &mut txs[txnum as usize - 1];
+            log_and_set_i32(rm, tx, &BlockId::new("testfile", block), offset, val);
+            match i {
+                5 => txs[0].0.commit().unwrap(),
+                6 => {
+                    let (rm, tx) = &mut txs[2];
+                    rm.rollback(tx).unwrap();
+                }
+                _ => {}
+            }
+        }
+        txs[1].0.commit().unwrap();
+        bm.flush_all(4).unwrap();
+    }
+
+    #[test]
+    fn rollback_resumes_after_crash_without_undoing_twice() {
+        let dirname = "__test_33";
+        let block = BlockId::new("testfile", 0);
+        {
+            let (lm, bm) = open(dirname);
+            let mut tx = BufferTx::new(5, Arc::clone(&bm));
+            let rm = RecoveryManager::new(5, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+
+            tx.pin(&block).unwrap();
+            tx.set_i32(&block, 80, 1, false).unwrap();
+            tx.set_string(&block, 40, "one", false).unwrap();
+            write_record(Arc::clone(&lm), &SetI32Record::new(5, block.clone(), 80, 1)).unwrap();
+            tx.set_i32(&block, 80, 2, true).unwrap();
+            write_record(Arc::clone(&lm), &SetI32Record::new(5, block.clone(), 80, 2)).unwrap();
+            tx.set_i32(&block, 80, 3, true).unwrap();
+            write_record(
+                Arc::clone(&lm),
+                &SetStringRecord::new(5, block.clone(), 40, "one"),
+            )
+            .unwrap();
+            tx.set_string(&block, 40, "two", true).unwrap();
+            tx.unpin(&block);
+
+            // crash after two of the three undos reached the disk
+            tx.writes = 0;
+            tx.fail_after = Some(2);
+            assert!(rm.rollback(&mut tx).is_err());
+            assert_eq!(tx.writes, 2);
+            bm.flush_all(5).unwrap();
+            lm.lock().unwrap().flush().unwrap();
+        }
+
+        let (lm, bm) = open(dirname);
+        let rm = RecoveryManager {
+            lm,
+            bm: Arc::clone(&bm),
+            tx_num: 5,
+            start_lsn: 1,
+            registry: Arc::default(),
+            on_complete: None,
+            records_written: AtomicUsize::new(0),
+            bytes_written: AtomicUsize::new(0),
+        };
+        let mut tx = BufferTx::new(5, bm);
+        let report = rm.rollback(&mut tx).unwrap();
+        assert_eq!(tx.writes, 1);
+        // the two compensation records, the three updates and the start record
+        assert_eq!(
+            report,
+            RollbackReport {
+                log_records_scanned: 6,
+                log_records_parsed: 6,
+                undo_actions: 1,
             }
+        );
+
+        tx.pin(&block).unwrap();
+        assert_eq!(tx.buffer(&block).contents().get_i32(80).unwrap(), 1);
+        assert_eq!(tx.buffer(&block).contents().get_string(40).unwrap(), "one");
+        tx.unpin(&block);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn rollback_logs_compensation_records_without_deadlocking() {
+        let dirname = "__test_49";
+        let (lm, bm) = open(dirname);
+        let blocks: Vec<BlockId> = (0..4).map(|n| BlockId::new("testfile", n)).collect();
+
+        let (done, finished) = mpsc::channel();
+        let handle = thread::spawn({
+            let (lm, bm, blocks) = (Arc::clone(&lm), Arc::clone(&bm), blocks.clone());
+            move || {
+                let rm = RecoveryManager::new(1, lm, Arc::clone(&bm)).unwrap();
+                let mut tx = BufferTx::new(1, bm);
+                while tx.size("testfile").unwrap() < 4 {
+                    tx.append("testfile").unwrap();
+                }
+                // more blocks than buffers: undo evicts dirty buffers, which flushes the log
+                for i in 0..30 {
+                    let block = &blocks[i % blocks.len()];
+                    let val = "y".repeat(100 + i);
+                    tx.pin(block).unwrap();
+                    let lsn = rm.set_string(tx.buffer(block), 100, &val).unwrap();
+                    tx.set_string(block, 100, &val, false).unwrap();
+                    tx.buffer(block).set_modified(1, lsn).unwrap();
+                    tx.unpin(block);
+                }
+                rm.rollback(&mut tx).unwrap();
+                done.send(()).unwrap();
+            }
+        });
+        finished
+            .recv_timeout(Duration::from_secs(30))
+            .expect("rollback did not finish");
+        handle.join().unwrap();
+
+        let mut tx = BufferTx::new(2, Arc::clone(&bm));
+        for block in &blocks {
+            tx.pin(block).unwrap();
+            assert_eq!(tx.buffer(block).contents().get_string(100).unwrap(), "");
+            tx.unpin(block);
         }
+        let records: Vec<TxType> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|bytes| create_log_record(&bytes).unwrap().op())
+            .collect();
+        assert_eq!(records[0], TxType::Rollback);
+        assert_eq!(
+            records
+                .iter()
+                .filter(|&&op| op == TxType::Compensation)
+                .count(),
+            30
+        );
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn commit_makes_the_commit_record_durable() {
+        let dirname = "__test_34";
+        let (lm, bm) = open(dirname);
+        let rm = RecoveryManager::new(9, Arc::clone(&lm), bm).unwrap();
+        rm.commit().unwrap();
+
+        let mut lm = lm.lock().unwrap();
+        assert_eq!(lm.durable_lsn(), 2);
+        let records: Vec<_> = lm
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .map(|bytes| create_log_record(&bytes).unwrap())
+            .map(|rec| (rec.op(), rec.tx_num()))
+            .collect();
+        assert_eq!(records, vec![(TxType::Commit, 9), (TxType::Start, 9)]);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn start_fails_when_its_record_cannot_be_logged() {
+        let dirname = "__test_48";
+        let fail_writes = Arc::new(AtomicBool::new(false));
+        let storage: Arc<Mutex<dyn BlockStorage>> = Arc::new(Mutex::new(FailingStorage {
+            inner: FileManager::new(dirname).unwrap(),
+            fail_reads: Arc::default(),
+            fail_writes: Arc::clone(&fail_writes),
+        }));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&storage), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(Arc::clone(&storage), Arc::clone(&lm), 3));
+        // fill the log block, so the start record has to write it out first
+        lm.lock().unwrap().append(vec![0; 4080]).unwrap();
+
+        fail_writes.store(true, Ordering::SeqCst);
+        let res = RecoveryManager::new(1, Arc::clone(&lm), Arc::clone(&bm));
+        assert!(matches!(res, Err(TransactionError::FileError(_))));
+
+        fail_writes.store(false, Ordering::SeqCst);
+        let rm = RecoveryManager::new(1, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        assert_eq!(rm.start_lsn(), 2);
+        rm.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_torn_older_log_block_fails_rollback_and_recovery() {
+        let dirname = "__test_111";
+        let (lm, bm) = open(dirname);
+        let block = BlockId::new("testfile", 0);
+        let rm = RecoveryManager::new(1, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let mut tx = BufferTx::new(1, Arc::clone(&bm));
+        log_and_set_i32(&rm, &mut tx, &block, 8, 11);
+        commit_history(&lm, &bm, 2);
+        lm.lock().unwrap().flush().unwrap();
+        assert!(lm.lock().unwrap().size() > 1);
+
+        // the newest record of the first log block, the block of 1's start record, claims
+        // more bytes than the block holds
+        let mut fm = FileManager::new(dirname).unwrap();
+        let first = BlockId::new("test_log", 0);
+        let mut page = Page::new(fm.block_size());
+        fm.read(&first, &mut page).unwrap();
+        let newest = page.get_i32(0).unwrap();
+        page.set_i32(newest as u64, i32::MAX).unwrap();
+        fm.write(&first, &page).unwrap();
+
+        assert!(matches!(
+            rm.rollback(&mut tx),
+            Err(TransactionError::FileError(_))
+        ));
+        let rm = RecoveryManager::new(3, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let mut tx = BufferTx::new(3, Arc::clone(&bm));
+        assert!(matches!(
+            rm.recover(&mut tx),
+            Err(TransactionError::FileError(_))
+        ));
+
+        drop(tx);
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn logging_a_change_of_an_unassigned_buffer_fails() {
+        let dirname = "__test_107";
+        let fm: Arc<Mutex<dyn BlockStorage>> =
+            Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&fm), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(Arc::clone(&fm), Arc::clone(&lm), 3));
+        let rm = RecoveryManager::new(1, Arc::clone(&lm), bm).unwrap();
+        let buf = Buffer::new(fm, lm);
+        let offset = 80;
+        assert!(matches!(
+            rm.set_i32(&buf, offset, 1),
+            Err(TransactionError::UnassignedBuffer)
+        ));
+        assert!(matches!(
+            rm.set_string(&buf, offset, "one"),
+            Err(TransactionError::UnassignedBuffer)
+        ));
+        assert!(matches!(
+            rm.set_u64(&buf, offset, 1),
+            Err(TransactionError::UnassignedBuffer)
+        ));
+        assert!(matches!(
+            rm.set_bytes(&buf, offset, &[1]),
+            Err(TransactionError::UnassignedBuffer)
+        ));
+        rm.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn file_lengths_follow_the_committed_history() {
+        let dirname = "__test_37";
+        let recovery =
+            |lm: &Arc<Mutex<LogManager>>, bm: &Arc<BufferManager>, txnum| RecoveryManager {
+                lm: Arc::clone(lm),
+                bm: Arc::clone(bm),
+                tx_num: txnum,
+                start_lsn: 0,
+                registry: Arc::default(),
+                on_complete: None,
+                records_written: AtomicUsize::new(0),
+                bytes_written: AtomicUsize::new(0),
+            };
+        for lose_committed_block in [false, true] {
+            {
+                let (lm, bm) = open(dirname);
+                let committed = RecoveryManager::new(7, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+                let mut tx = BufferTx::new(7, Arc::clone(&bm));
+                assert_eq!(committed.append(&mut tx, "testfile").unwrap().number(), 1);
+                committed.commit().unwrap();
+
+                let unfinished = recovery(&lm, &bm, 8);
+                let mut tx = BufferTx::new(8, Arc::clone(&bm));
+                assert_eq!(unfinished.append(&mut tx, "testfile").unwrap().number(), 2);
+
+                // crash after logging the append, before the file grew
+                let rec = ExtendFileRecord::new(9, BlockId::new("testfile", 3));
+                let lsn = write_record(Arc::clone(&lm), &rec).unwrap();
+                lm.lock().unwrap().flush_with_lsn(lsn).unwrap();
+                assert_eq!(bm.length("testfile").unwrap(), 3);
+                if lose_committed_block {
+                    bm.truncate("testfile", 1).unwrap();
+                }
+            }
+
+            let (lm, bm) = open(dirname);
+            let mut tx = BufferTx::new(10, Arc::clone(&bm));
+            let rm = recovery(&lm, &bm, 10);
+            rm.recover(&mut tx).unwrap();
+            assert_eq!(tx.size("testfile").unwrap(), 2);
+
+            // the checkpoint ends what the next recovery reads
+            rm.recover(&mut tx).unwrap();
+            assert_eq!(tx.size("testfile").unwrap(), 2);
+
+            drop(tx);
+            fs::remove_dir_all(dirname).expect("failed to remove dir");
+        }
+    }
+
+    #[test]
+    fn rollback_reads_the_log_back_only_to_its_start() {
+        let dirname = "__test_54";
+        let block = BlockId::new("testfile", 0);
+        {
+            let (lm, bm) = open(dirname);
+            commit_history(&lm, &bm, 1);
+        }
+        let log_len = || {
+            FileManager::new(dirname)
+                .unwrap()
+                .length("test_log")
+                .unwrap()
+        };
+
+        let reads = Arc::default();
+        let (lm, bm) = reopen_recording(dirname, &reads);
+        let rm = RecoveryManager::new(2, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let start_block = log_len() - 1;
+        let mut tx = BufferTx::new(2, Arc::clone(&bm));
+        log_and_set_i32(&rm, &mut tx, &block, 8, 2);
+        // 10 other transactions write 1,000 records after this one started
+        for i in 0..1000 {
+            let rec = SetI32Record::with_values(10 + i % 10, block.clone(), 16, 0, i);
+            write_record(Arc::clone(&lm), &rec).unwrap();
+        }
+        let last_block = log_len() - 1;
+        assert!(start_block > 0 && last_block > start_block + 5);
+
+        reads.lock().unwrap().clear();
+        let report = rm.rollback(&mut tx).unwrap();
+        assert_eq!(
+            report,
+            RollbackReport {
+                log_records_scanned: 1002,
+                log_records_parsed: 2,
+                undo_actions: 1,
+            }
+        );
+        // the history before the start record is never read
+        let expected: Vec<i32> = (start_block..=last_block).rev().collect();
+        assert_eq!(log_reads(&reads), expected);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn rollback_truncates_appended_blocks() {
+        let dirname = "__test_38";
+        let (lm, bm) = open(dirname);
+        let rm = RecoveryManager::new(4, lm, bm).unwrap();
+        let mut tx = MockTx::new();
+        tx.sizes.insert("testfile".to_string(), 1);
+        let block = rm.append(&mut tx, "testfile").unwrap();
+        assert_eq!(block.number(), 1);
+        tx.set_i32(&block, 0, 42, true).unwrap();
+
+        rm.rollback(&mut tx).unwrap();
+        assert_eq!(tx.size("testfile").unwrap(), 1);
+        assert_eq!(
+            tx.calls.last(),
+            Some(&TxCall::Truncate("testfile".to_string(), 1))
+        );
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// a `MockTx` whose file grows by a block of another transaction before each append
+    struct RacedAppends(MockTx);
+
+    impl TxOps for RacedAppends {
+        fn pin(&mut self, block: &BlockId) -> tx::Result<()> {
+            self.0.pin(block)
+        }
+
+        fn unpin(&mut self, block: &BlockId) {
+            self.0.unpin(block)
+        }
+
+        fn set_i32(&mut self, block: &BlockId, offset: i32, val: i32, ok: bool) -> tx::Result<()> {
+            self.0.set_i32(block, offset, val, ok)
+        }
+
+        fn set_string(
+            &mut self,
+            block: &BlockId,
+            offset: i32,
+            val: &str,
+            ok: bool,
+        ) -> tx::Result<()> {
+            self.0.set_string(block, offset, val, ok)
+        }
+
+        fn set_u64(&mut self, block: &BlockId, offset: i32, val: u64, ok: bool) -> tx::Result<()> {
+            self.0.set_u64(block, offset, val, ok)
+        }
+
+        fn set_bytes(
+            &mut self,
+            block: &BlockId,
+            offset: i32,
+            val: &[u8],
+            ok: bool,
+        ) -> tx::Result<()> {
+            self.0.set_bytes(block, offset, val, ok)
+        }
+
+        fn size(&mut self, filename: &str) -> tx::Result<i32> {
+            self.0.size(filename)
+        }
+
+        fn append(&mut self, filename: &str) -> tx::Result<BlockId> {
+            self.0.append(filename)?;
+            self.0.append(filename)
+        }
+
+        fn truncate(&mut self, filename: &str, len: i32) -> tx::Result<()> {
+            self.0.truncate(filename, len)
+        }
+    }
+
+    #[test]
+    fn an_append_that_is_not_the_logged_block_fails() {
+        let dirname = "__test_112";
+        let (lm, bm) = open(dirname);
+        let rm = RecoveryManager::new(4, lm, bm).unwrap();
+        let mut tx = RacedAppends(MockTx::new());
+        tx.0.sizes.insert("testfile".to_string(), 1);
+        assert!(matches!(
+            rm.append(&mut tx, "testfile"),
+            Err(TransactionError::AppendMismatch(logged, appended))
+                if logged.number() == 1 && appended.number() == 2
+        ));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn commit_and_rollback_write_the_log_and_pages_in_order() {
+        let dirname = "__test_55";
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let mut fm = FileManager::new(dirname).unwrap();
+        for _ in 0..4 {
+            fm.append("testfile").unwrap();
+        }
+        let block_size = fm.block_size();
+        let storage: Arc<Mutex<dyn BlockStorage>> = Arc::new(Mutex::new(WriteOrderStorage {
+            inner: fm,
+            writes: Arc::clone(&writes),
+        }));
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&storage), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(Arc::clone(&storage), Arc::clone(&lm), 3));
+
+        let completed = Arc::new(AtomicBool::new(false));
+        let hook = {
+            let completed = Arc::clone(&completed);
+            move || completed.store(true, Ordering::SeqCst)
+        };
+        let rm = RecoveryManager::new(7, Arc::clone(&lm), Arc::clone(&bm))
+            .unwrap()
+            .on_complete(hook);
+        let mut tx = BufferTx::new(7, Arc::clone(&bm));
+        // four blocks through three buffers, so the first is written out before the commit
+        for n in 0..4 {
+            log_and_set_i32(&rm, &mut tx, &BlockId::new("testfile", n), 8, n + 1);
+        }
+        let committing = writes.lock().unwrap().len();
+        assert!(!completed.load(Ordering::SeqCst));
+        rm.commit().unwrap();
+        assert!(completed.load(Ordering::SeqCst));
+
+        let rm = RecoveryManager::new(8, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let mut tx = BufferTx::new(8, Arc::clone(&bm));
+        log_and_set_i32(&rm, &mut tx, &BlockId::new("testfile", 0), 8, 80);
+        let rolling_back = writes.lock().unwrap().len();
+        rm.rollback(&mut tx).unwrap();
+
+        // the log fits in its first block, so each log write holds every durable record
+        let mut durable = Vec::new();
+        let (mut commit_at, mut rollback_at) = (None, None);
+        let mut data_writes = Vec::new();
+        for (i, (block, bytes)) in writes.lock().unwrap().iter().enumerate() {
+            let page = Page::from(bytes.clone());
+            if block.filename() == "test_log" {
+                assert_eq!(block.number(), 0);
+                durable.clear();
+                let mut pos = page.get_i32(0).unwrap();
+                while pos < block_size {
+                    let bytes = page.get_bytes(pos as u64).unwrap();
+                    pos += (I32_SIZE + bytes.len()) as i32;
+                    durable.push(create_log_record(&bytes).unwrap());
+                }
+                let logged = |op| durable.iter().any(|rec| rec.op() == op);
+                commit_at = commit_at.or(logged(TxType::Commit).then_some(i));
+                rollback_at = rollback_at.or(logged(TxType::Rollback).then_some(i));
+            } else {
+                // write-ahead: every update on the page is in the log on disk
+                let page_lsn = page.get_u64(0).unwrap();
+                assert!(
+                    page_lsn <= durable.len() as u64,
+                    "write {} of {:?}",
+                    i,
+                    block
+                );
+                data_writes.push(i);
+            }
+        }
+        let commit_at = commit_at.unwrap();
+        let rollback_at = rollback_at.unwrap();
+        assert!(data_writes.iter().any(|&i| i < committing));
+        // committed: the commit record first, then the pages. rolled back: the undone page
+        // first, then the rollback record
+        assert!(commit_at >= committing);
+        assert!(data_writes.iter().all(|&i| i < committing || i > commit_at));
+        let after_commit = data_writes
+            .iter()
+            .filter(|&&i| i > commit_at && i < rolling_back);
+        assert_eq!(after_commit.count(), 3);
+        assert!(data_writes
+            .iter()
+            .any(|&i| i > rolling_back && i < rollback_at));
+        assert!(data_writes.iter().all(|&i| i < rollback_at));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn updates_are_logged_with_their_old_values_ahead_of_the_page() {
+        let dirname = "__test_45";
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let storage: Arc<Mutex<dyn BlockStorage>> = Arc::new(Mutex::new(RecordingStorage {
+            inner: FileManager::new(dirname).unwrap(),
+            reads: Arc::default(),
+            writes: Arc::clone(&writes),
+        }));
+        storage.lock().unwrap().append("testfile").unwrap();
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&storage), "test_log".to_string()).unwrap(),
+        ));
+        let bm = Arc::new(BufferManager::new(Arc::clone(&storage), Arc::clone(&lm), 3));
+        let rm = RecoveryManager::new(3, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+
+        let block = BlockId::new("testfile", 0);
+        let buf = bm.pin(block.clone()).unwrap();
+        buf.contents_mut().set_i32(80, 5).unwrap();
+        let lsn = rm.set_i32(&buf, 80, 6).unwrap();
+        buf.contents_mut().set_i32(80, 6).unwrap();
+        buf.set_modified(3, lsn).unwrap();
+        let lsn = rm.set_string(&buf, 40, "new").unwrap();
+        buf.contents_mut().set_string(40, "new").unwrap();
+        buf.set_modified(3, lsn).unwrap();
+        assert_eq!(buf.contents().get_i32(80).unwrap(), 6);
+        assert_eq!(buf.contents().get_string(40).unwrap(), "new");
+        bm.unpin(buf);
+
+        // the records go to the disk before the page they describe
+        assert!(lm.lock().unwrap().durable_lsn() < lsn);
+        writes.lock().unwrap().clear();
+        bm.flush_all(3).unwrap();
+        let written = writes.lock().unwrap().clone();
+        let data = written.iter().position(|b| *b == block);
+        let log = written.iter().position(|b| b.filename() == "test_log");
+        assert!(data.is_some() && log < data, "wrote {:?}", written);
+        assert!(lm.lock().unwrap().durable_lsn() >= lsn);
+
+        let logged: Vec<String> = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .map(Result::unwrap)
+            .take(2)
+            .map(|bytes| create_log_record(&bytes).unwrap().to_string())
+            .collect();
+        assert_eq!(
+            logged,
+            vec![
+                "<SETSTRING 3 [testfile, 0] 40 \"\" -> \"new\">",
+                "<SETI32 3 [testfile, 0] 80 5 -> 6>",
+            ]
+        );
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn recovery_reads_the_log_back_to_the_last_checkpoint() {
+        let dirname = "__test_46";
+        let block = BlockId::new("testfile", 0);
+        let checkpoint_block = {
+            let (lm, bm) = open(dirname);
+            for txnum in 1..=3 {
+                commit_history(&lm, &bm, txnum);
+            }
+            let rm = RecoveryManager::new(4, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+            rm.checkpoint().unwrap();
+            let checkpoint_block = bm.length("test_log").unwrap() - 1;
+            assert!(checkpoint_block > 1);
+
+            // committed, but its buffer never reaches the disk
+            let rec = SetI32Record::with_values(5, block.clone(), 8, 0, 5);
+            write_record(Arc::clone(&lm), &rec).unwrap();
+            write_record(Arc::clone(&lm), &CommitRecord::new(5)).unwrap();
+            // unfinished, but its buffer does reach the disk
+            let mut tx = BufferTx::new(6, Arc::clone(&bm));
+            let rec = SetI32Record::with_values(6, block.clone(), 16, 0, 6);
+            write_record(Arc::clone(&lm), &rec).unwrap();
+            tx.pin(&block).unwrap();
+            tx.set_i32(&block, 16, 6, true).unwrap();
+            tx.unpin(&block);
+            bm.flush_all(6).unwrap();
+            lm.lock().unwrap().flush().unwrap();
+            checkpoint_block
+        };
+
+        let reads = Arc::default();
+        let (lm, bm) = reopen_recording(dirname, &reads);
+        let rm = RecoveryManager::new(7, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let mut tx = BufferTx::new(7, Arc::clone(&bm));
+        reads.lock().unwrap().clear();
+        rm.recover(&mut tx).unwrap();
+
+        let log_reads = log_reads(&reads);
+        assert!(!log_reads.is_empty());
+        assert!(
+            log_reads.iter().all(|&n| n >= checkpoint_block),
+            "read log blocks {:?}, the checkpoint is in {}",
+            log_reads,
+            checkpoint_block
+        );
+
+        tx.pin(&block).unwrap();
+        let page = tx.buffer(&block).contents();
+        assert_eq!(page.get_i32(8).unwrap(), 5);
+        assert_eq!(page.get_i32(16).unwrap(), 0);
+        assert_eq!(
+            page.get_string(200).unwrap(),
+            format!("3{}", "x".repeat(139))
+        );
+        drop(page);
+        tx.unpin(&block);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn recovery_reads_past_a_nonquiescent_checkpoint_to_the_earliest_active_start() {
+        let dirname = "__test_47";
+        let block = BlockId::new("testfile", 0);
+        let (start_block, checkpoint_block) = {
+            let (lm, bm) = open(dirname);
+            for txnum in 1..=3 {
+                commit_history(&lm, &bm, txnum);
+            }
+            // a long-lived transaction, running across the checkpoint
+            let rm = RecoveryManager::new(10, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+            let start_block = bm.length("test_log").unwrap() - 1;
+            let mut tx = BufferTx::new(10, Arc::clone(&bm));
+            tx.pin(&block).unwrap();
+            let lsn = rm.set_i32(tx.buffer(&block), 8, 10).unwrap();
+            tx.set_i32(&block, 8, 10, false).unwrap();
+            tx.buffer(&block).set_modified(10, lsn).unwrap();
+            tx.unpin(&block);
+
+            commit_history(&lm, &bm, 11);
+            rm.checkpoint_nonquiescent(&[10]).unwrap();
+            let checkpoint_block = bm.length("test_log").unwrap() - 1;
+            assert!(checkpoint_block > start_block);
+
+            tx.pin(&block).unwrap();
+            let lsn = rm.set_i32(tx.buffer(&block), 16, 10).unwrap();
+            tx.set_i32(&block, 16, 10, false).unwrap();
+            tx.buffer(&block).set_modified(10, lsn).unwrap();
+            tx.unpin(&block);
+            bm.flush_all(10).unwrap();
+            (start_block, checkpoint_block)
+        };
+        assert!(start_block > 1);
+
+        let reads = Arc::default();
+        let (lm, bm) = reopen_recording(dirname, &reads);
+        let rm = RecoveryManager::new(12, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let mut tx = BufferTx::new(12, Arc::clone(&bm));
+        reads.lock().unwrap().clear();
+        rm.recover(&mut tx).unwrap();
+
+        let log_reads = log_reads(&reads);
+        assert!(log_reads.contains(&start_block));
+        assert!(log_reads.contains(&checkpoint_block));
+        assert!(
+            log_reads.iter().all(|&n| n >= start_block),
+            "read log blocks {:?}, transaction 10 started in {}",
+            log_reads,
+            start_block
+        );
+
+        tx.pin(&block).unwrap();
+        let page = tx.buffer(&block).contents();
+        assert_eq!(page.get_i32(8).unwrap(), 0);
+        assert_eq!(page.get_i32(16).unwrap(), 0);
+        assert_eq!(
+            page.get_string(200).unwrap(),
+            format!("11{}", "x".repeat(139))
+        );
+        drop(page);
+        tx.unpin(&block);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn recovery_keeps_exactly_the_committed_updates_at_every_power_cut() {
+        let dirname = "__test_50";
+        let faults = Arc::new(Mutex::new(Faults::default()));
+        run_crash_workload(dirname, &faults);
+        let writes = faults.lock().unwrap().writes.len();
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+        assert!(writes > 10);
+
+        for cut in 0..=writes {
+            let faults = Arc::new(Mutex::new(Faults {
+                power_cut: Some(cut),
+                ..Faults::default()
+            }));
+            run_crash_workload(dirname, &faults);
+
+            let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+            let lm = Arc::new(Mutex::new(
+                LogManager::new(fm.clone(), "test_log".to_string()).unwrap(),
+            ));
+            let bm = Arc::new(BufferManager::new(fm, Arc::clone(&lm), 3));
+            let rm = RecoveryManager::new(5, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+            let mut tx = BufferTx::new(5, Arc::clone(&bm));
+            rm.recover(&mut tx).unwrap();
+
+            // a commit counts once its record is on disk
+            let committed: HashSet<i32> = lm
+                .lock()
+                .unwrap()
+                .iterator()
+                .unwrap()
+                .map(Result::unwrap)
+                .map(|bytes| create_log_record(&bytes).unwrap())
+                .filter(|rec| rec.op() == TxType::Commit)
+                .map(|rec| rec.tx_num())
+                .collect();
+            assert!(committed.is_subset(&HashSet::from([1, 2])));
+            for (txnum, block, offset, val) in CRASH_WORKLOAD {
+                let block = BlockId::new("testfile", block);
+                tx.pin(&block).unwrap();
+                let expected = if committed.contains(&txnum) { val } else { 0 };
+                assert_eq!(
+                    tx.buffer(&block).contents().get_i32(offset as u64).unwrap(),
+                    expected,
+                    "power cut before write {} of {}, update of {} at {:?} {}",
+                    cut,
+                    writes,
+                    txnum,
+                    block,
+                    offset
+                );
+                tx.unpin(&block);
+            }
+
+            drop(tx);
+            fs::remove_dir_all(dirname).expect("failed to remove dir");
+        }
+    }
+
+    #[test]
+    fn recover_undoes_unfinished_transactions() {
+        let dirname = "__test_44";
+        let block = BlockId::new("testfile", 0);
+        {
+            let (lm, bm) = open(dirname);
+            let rms: Vec<RecoveryManager> = (1..=4)
+                .map(|txnum| RecoveryManager::new(txnum, Arc::clone(&lm), Arc::clone(&bm)).unwrap())
+                .collect();
+            let mut txs: Vec<BufferTx> = (1..=4)
+                .map(|txnum| BufferTx::new(txnum, Arc::clone(&bm)))
+                .collect();
+
+            // each transaction writes its number twice to its own offset, interleaved
+            for round in 1..=2 {
+                for (i, tx) in txs.iter_mut().enumerate() {
+                    let (txnum, offset) = (tx.txnum, 8 * (i as i32 + 1));
+                    let old = (round - 1) * txnum;
+                    let rec =
+                        SetI32Record::with_values(txnum, block.clone(), offset, old, round * txnum);
+                    write_record(Arc::clone(&lm), &rec).unwrap();
+                    tx.pin(&block).unwrap();
+                    tx.set_i32(&block, offset, round * txnum, true).unwrap();
+                    tx.unpin(&block);
+                }
+            }
+            let rec = SetStringRecord::with_values(4, block.clone(), 40, "", "lost");
+            write_record(Arc::clone(&lm), &rec).unwrap();
+            txs[3].pin(&block).unwrap();
+            txs[3].set_string(&block, 40, "lost", true).unwrap();
+            txs[3].unpin(&block);
+
+            rms[0].commit().unwrap();
+            rms[2].commit().unwrap();
+            // the uncommitted updates reached the disk too before the crash
+            for txnum in [2, 4] {
+                bm.flush_all(txnum).unwrap();
+            }
+            lm.lock().unwrap().flush().unwrap();
+        }
+
+        let (lm, bm) = open(dirname);
+        let rm = RecoveryManager::new(5, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+        let mut tx = BufferTx::new(5, Arc::clone(&bm));
+        rm.recover(&mut tx).unwrap();
+        drop(tx);
+        drop(bm);
+
+        // read back from the disk, past any buffer
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        let mut page = Page::new(fm.lock().unwrap().block_size());
+        fm.lock().unwrap().read(&block, &mut page).unwrap();
+        let values: Vec<i32> = (1..=4).map(|i| page.get_i32(8 * i).unwrap()).collect();
+        assert_eq!(values, vec![2, 0, 6, 0]);
+        assert_eq!(page.get_string(40).unwrap(), "");
+
+        let last = lm
+            .lock()
+            .unwrap()
+            .iterator()
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(create_log_record(&last).unwrap().op(), TxType::CheckPoint);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// an application record: `txnum` split `block`, undone by writing a marker at offset 0
+    struct SplitRecord {
+        txnum: i32,
+        block: BlockId,
+    }
+
+    const SPLIT_OP: i32 = FIRST_CUSTOM_OP + 1;
+
+    impl fmt::Display for SplitRecord {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "<SPLIT {} {}>", self.txnum, self.block)
+        }
+    }
+
+    impl LogRecord for SplitRecord {
+        fn op(&self) -> TxType {
+            TxType::Custom
+        }
+
+        fn op_code(&self) -> i32 {
+            SPLIT_OP
+        }
+
+        fn tx_num(&self) -> i32 {
+            self.txnum
+        }
+
+        fn undo(&self, tx: &mut dyn TxOps) -> tx::Result<()> {
+            tx.pin(&self.block)?;
+            let res = tx.set_i32(&self.block, 0, -99, false);
+            tx.unpin(&self.block);
+            res
+        }
+
+        fn to_bytes(&self) -> Vec<u8> {
+            LogRecordBuilder::with_op_code(self.op_code())
+                .i32(self.txnum)
+                .block(&self.block)
+                .build()
+        }
+    }
+
+    fn parse_split(bytes: &[u8]) -> std::result::Result<Box<dyn LogRecord>, LogRecordError> {
+        let mut r = RecordCursor::new(bytes)?;
+        Ok(Box::new(SplitRecord {
+            txnum: r.read_i32()?,
+            block: r.read_block()?,
+        }))
+    }
+
+    #[test]
+    fn registered_records_take_part_in_rollback() {
+        let dirname = "__test_40";
+        let (lm, bm) = open(dirname);
+        let block = BlockId::new("testfile", 0);
+        let split = SplitRecord {
+            txnum: 3,
+            block: block.clone(),
+        };
+
+        let mut registry = RecordRegistry::new();
+        assert!(matches!(
+            registry.register(TxType::SetI32 as i32, parse_split),
+            Err(LogRecordError::ReservedOp(4))
+        ));
+        let bytes = encode_record(&split.to_bytes());
+        assert!(matches!(
+            registry.create_log_record(&bytes),
+            Err(LogRecordError::UnknownOp { code: SPLIT_OP })
+        ));
+        registry.register(SPLIT_OP, parse_split).unwrap();
+        assert_eq!(
+            registry.create_log_record(&bytes).unwrap().to_string(),
+            split.to_string()
+        );
+
+        let rm = RecoveryManager::new(3, Arc::clone(&lm), Arc::clone(&bm))
+            .unwrap()
+            .with_registry(Arc::new(registry));
+        write_record(Arc::clone(&lm), &split).unwrap();
+        let mut tx = MockTx::new();
+        rm.rollback(&mut tx).unwrap();
+
+        assert_eq!(
+            tx.calls,
+            vec![
+                TxCall::Pin(block.clone()),
+                TxCall::SetI32(block.clone(), 0, -99, false),
+                TxCall::Unpin(block.clone()),
+            ]
+        );
+        assert_eq!(tx.page(&block).get_i32(0).unwrap(), -99);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn redo_skips_updates_the_page_already_holds() {
+        let dirname = "__test_52";
+        let flushed = BlockId::new("testfile", 0);
+        let lost = BlockId::new("testfile", 1);
+        {
+            let (lm, bm) = open(dirname);
+            let rm = RecoveryManager::new(6, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+            let mut tx = BufferTx::new(6, Arc::clone(&bm));
+            assert_eq!(tx.append("testfile").unwrap(), lost);
+            log_and_set_i32(&rm, &mut tx, &flushed, 8, 66);
+            rm.commit().unwrap();
+
+            // the commit record reaches the disk, the dirty buffer never does
+            let rm = RecoveryManager::new(7, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+            let mut tx = BufferTx::new(7, Arc::clone(&bm));
+            log_and_set_i32(&rm, &mut tx, &lost, 8, 77);
+            let lsn = write_record(Arc::clone(&lm), &CommitRecord::new(7)).unwrap();
+            lm.lock().unwrap().flush_with_lsn(lsn).unwrap();
+        }
+
+        // the same records replayed twice, as by a recovery that crashed before its checkpoint
+        for redo_writes in [1, 0] {
+            let (lm, bm) = open(dirname);
+            let rm = RecoveryManager::new(10, lm, Arc::clone(&bm)).unwrap();
+            let mut tx = BufferTx::new(10, Arc::clone(&bm));
+            let mut report = RecoveryReport::default();
+            let tail = rm.records_to_recover(&mut report).unwrap();
+            let analysis = rm.analysis(&tail);
+            assert_eq!(analysis.committed, [6, 7]);
+            assert!(analysis.active_at_crash.is_empty());
+            let committed = HashSet::from([6, 7]);
+            rm.redo_committed(&mut tx, &tail.records, &committed, &mut report)
+                .unwrap();
+            assert_eq!(tx.writes, redo_writes);
+            assert_eq!(report.redo_actions, redo_writes);
+            bm.flush_all(10).unwrap();
+        }
+
+        let mut fm = FileManager::new(dirname).unwrap();
+        let mut page = Page::new(fm.block_size());
+        for (block, val) in [(flushed, 66), (lost, 77)] {
+            fm.read(&block, &mut page).unwrap();
+            assert_eq!(page.get_i32(8).unwrap(), val);
+            assert!(page.get_u64(0).unwrap() > 0);
+        }
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn analysis_matches_hand_computed_expectations() {
+        let dirname = "__test_56";
+        let block = BlockId::new("testfile", 0);
+        let start = |txnum| LogRecordKind::Start(StartRecord::new(txnum));
+        let set = |txnum| {
+            let rec = SetI32Record::with_values(txnum, block.clone(), 8, 0, txnum);
+            LogRecordKind::SetI32(rec)
+        };
+        let commit = |txnum| LogRecordKind::Commit(CommitRecord::new(txnum));
+        let rollback = |txnum| LogRecordKind::Rollback(RollbackRecord::new(txnum));
+        let cases = [
+            // no checkpoint: the whole log is read
+            (
+                vec![
+                    start(1),
+                    set(1),
+                    start(2),
+                    set(2),
+                    commit(1),
+                    start(3),
+                    set(3),
+                    rollback(3),
+                ],
+                AnalysisResult {
+                    committed: vec![1],
+                    rolled_back: vec![3],
+                    active_at_crash: vec![2],
+                    redo_start_lsn: 1,
+                    checkpoint: None,
+                },
+            ),
+            // a quiescent checkpoint hides everything before it
+            (
+                vec![
+                    start(1),
+                    set(1),
+                    commit(1),
+                    LogRecordKind::CheckPoint(CheckPointRecord::new()),
+                    start(2),
+                    set(2),
+                    start(3),
+                    commit(3),
+                ],
+                AnalysisResult {
+                    committed: vec![3],
+                    rolled_back: vec![],
+                    active_at_crash: vec![2],
+                    redo_start_lsn: 5,
+                    checkpoint: Some(CheckpointInfo {
+                        lsn: 4,
+                        active: vec![],
+                    }),
+                },
+            ),
+            // a non-quiescent one only back to the start of the transactions it lists
+            (
+                vec![
+                    start(4),
+                    set(4),
+                    commit(4),
+                    start(1),
+                    start(2),
+                    set(1),
+                    set(2),
+                    commit(2),
+                    LogRecordKind::NqCheckPoint(NqCheckPointRecord::new(vec![1])),
+                    start(3),
+                    set(3),
+                    commit(1),
+                ],
+                AnalysisResult {
+                    committed: vec![1, 2],
+                    rolled_back: vec![],
+                    active_at_crash: vec![3],
+                    redo_start_lsn: 4,
+                    checkpoint: Some(CheckpointInfo {
+                        lsn: 9,
+                        active: vec![1],
+                    }),
+                },
+            ),
+        ];
+        for (records, expected) in cases {
+            {
+                let (lm, _bm) = open(dirname);
+                for rec in &records {
+                    write_record(Arc::clone(&lm), rec).unwrap();
+                }
+                lm.lock().unwrap().flush().unwrap();
+            }
+            let (lm, bm) = open(dirname);
+            let rm = RecoveryManager::new(99, Arc::clone(&lm), bm).unwrap();
+            assert_eq!(rm.analyze().unwrap(), expected);
+            // nothing was logged
+            let lsn = write_record(Arc::clone(&lm), &CommitRecord::new(99)).unwrap();
+            assert_eq!(lsn, rm.start_lsn() + 1);
+
+            fs::remove_dir_all(dirname).expect("failed to remove dir");
+        }
+    }
+
+    #[test]
+    fn recovery_reports_what_each_pass_visited() {
+        let dirname = "__test_53";
+        let block = BlockId::new("testfile", 0);
+        fn write(lm: &Arc<Mutex<LogManager>>, rec: &impl LogRecord) -> Lsn {
+            write_record(Arc::clone(lm), rec).unwrap()
+        }
+        {
+            let (lm, _bm) = open(dirname);
+            // finished before the checkpoint, so never read
+            write(&lm, &StartRecord::new(1));
+            write(&lm, &SetI32Record::with_values(1, block.clone(), 8, 0, 1));
+            write(&lm, &CommitRecord::new(1));
+            assert_eq!(write(&lm, &CheckPointRecord::new()), 4);
+            for txnum in 2..=5 {
+                write(&lm, &StartRecord::new(txnum));
+            }
+            write(&lm, &SetI32Record::with_values(2, block.clone(), 16, 0, 2));
+            write(&lm, &SetI32Record::with_values(3, block.clone(), 24, 0, 3));
+            write(
+                &lm,
+                &SetStringRecord::with_values(3, block.clone(), 100, "", "three"),
+            );
+            write(&lm, &SetI32Record::with_values(4, block.clone(), 32, 0, 4));
+            write(&lm, &SetI32Record::with_values(5, block.clone(), 40, 0, 5));
+            write(&lm, &CommitRecord::new(2));
+            let lsn = write(&lm, &RollbackRecord::new(4));
+            lm.lock().unwrap().flush_with_lsn(lsn).unwrap();
+        }
+
+        let (lm, bm) = open(dirname);
+        let rm = RecoveryManager::new(10, lm, bm).unwrap();
+        let mut tx = MockTx::new();
+        tx.sizes.insert("testfile".to_string(), 1);
+        let report = rm.recover(&mut tx).unwrap();
+        assert_eq!(
+            report,
+            RecoveryReport {
+                // the start of the recovering transaction, the 11 records after the
+                // checkpoint, and the checkpoint itself
+                log_records_scanned: 13,
+                checkpoint_lsn: Some(4),
+                txs_committed: 1,
+                txs_rolled_back: 1,
+                // 3 and 5. 10 has nothing to undo
+                txs_undone: 2,
+                undo_actions: 3,
+                redo_actions: 1,
+                duration: report.duration,
+                clean: false,
+            }
+        );
+        assert_eq!(tx.page(&block).get_i32(16).unwrap(), 2);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn committed_updates_are_redone_after_a_crash() {
+        let dirname = "__test_41";
+        let block = BlockId::new("testfile", 0);
+        {
+            let (lm, bm) = open(dirname);
+            let rm = RecoveryManager::new(6, Arc::clone(&lm), Arc::clone(&bm)).unwrap();
+            let mut tx = BufferTx::new(6, Arc::clone(&bm));
+            tx.pin(&block).unwrap();
+            let rec = SetI32Record::with_values(6, block.clone(), 80, 0, 77);
+            write_record(Arc::clone(&lm), &rec).unwrap();
+            tx.set_i32(&block, 80, 77, true).unwrap();
+            let rec = SetStringRecord::with_values(6, block.clone(), 20, "", "kept");
+            write_record(Arc::clone(&lm), &rec).unwrap();
+            tx.set_string(&block, 20, "kept", true).unwrap();
+            tx.unpin(&block);
+
+            // the commit record reaches the disk, the dirty buffer never does
+            let lsn = write_record(Arc::clone(&lm), &CommitRecord::new(6)).unwrap();
+            lm.lock().unwrap().flush_with_lsn(lsn).unwrap();
+            drop(rm);
+        }
+
+        let (lm, bm) = open(dirname);
+        let mut tx = BufferTx::new(10, Arc::clone(&bm));
+        tx.pin(&block).unwrap();
+        assert_eq!(tx.buffer(&block).contents().get_i32(80).unwrap(), 0);
+        tx.unpin(&block);
+
+        let rm = RecoveryManager::new(10, lm, Arc::clone(&bm)).unwrap();
+        rm.recover(&mut tx).unwrap();
+
+        let mut fm = FileManager::new(dirname).unwrap();
+        let mut page = Page::new(fm.block_size());
+        fm.read(&block, &mut page).unwrap();
+        assert_eq!(page.get_i32(80).unwrap(), 77);
+        assert_eq!(page.get_string(20).unwrap(), "kept");
+
+        // records without new values can't be redone
+        let old = SetI32Record::new(6, block.clone(), 80, 0);
+        assert!(matches!(
+            old.redo(&mut tx),
+            Err(tx::TransactionError::LogRecordError(
+                LogRecordError::RedoUnavailable
+            ))
+        ));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
     }
 }
@@ -0,0 +1,256 @@
+use std::fmt;
+
+/// tells the replacement policy how a pinned block is going to be accessed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccessHint {
+    #[default]
+    Normal,
+    /// the block is read once as part of a scan and is unlikely to be needed again soon
+    Sequential,
+}
+
+/// decides which unpinned buffer is replaced when a new block has to be read.
+///
+/// Buffers are identified by their index in the buffer pool.
+/// Empty buffers are always used before the policy is asked for a victim.
+pub trait ReplacementPolicy: fmt::Debug + Send {
+    /// buffer `index` was pinned with `hint`
+    fn on_pin(&mut self, _index: usize, _hint: AccessHint) {}
+
+    /// buffer `index` now holds a different block than before
+    fn on_load(&mut self, _index: usize) {}
+
+    /// the last pin of buffer `index` was released
+    fn on_unpin(&mut self, _index: usize) {}
+
+    /// buffer `index` was removed from the pool; the buffers after it move down by one
+    fn on_remove(&mut self, _index: usize) {}
+
+    /// chooses the buffer to replace among the unpinned `candidates` (in pool order)
+    fn choose_victim(&mut self, candidates: &[usize]) -> Option<usize>;
+}
+
+/// Naive algorithm: choose first unpinned buffer
+///
+/// Buffers pinned with `AccessHint::Sequential` are replaced first, most recently
+/// unpinned first (MRU), so that a scan keeps recycling the same buffer.
+#[derive(Debug, Default)]
+pub struct NaivePolicy {
+    /// for each sequentially accessed buffer, when its last pin was released
+    sequential: Vec<Option<u64>>,
+    ticks: u64,
+}
+
+impl NaivePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slot(&mut self, index: usize) -> &mut Option<u64> {
+        if self.sequential.len() <= index {
+            self.sequential.resize(index + 1, None);
+        }
+        &mut self.sequential[index]
+    }
+}
+
+impl ReplacementPolicy for NaivePolicy {
+    fn on_pin(&mut self, index: usize, hint: AccessHint) {
+        *self.slot(index) = match hint {
+            AccessHint::Sequential => Some(0),
+            AccessHint::Normal => None,
+        };
+    }
+
+    fn on_unpin(&mut self, index: usize) {
+        self.ticks += 1;
+        let ticks = self.ticks;
+        if let Some(unpinned_at) = self.slot(index) {
+            *unpinned_at = ticks;
+        }
+    }
+
+    fn on_remove(&mut self, index: usize) {
+        if index < self.sequential.len() {
+            self.sequential.remove(index);
+        }
+    }
+
+    fn choose_victim(&mut self, candidates: &[usize]) -> Option<usize> {
+        candidates
+            .iter()
+            .filter_map(|i| self.sequential.get(*i).copied().flatten().map(|t| (t, *i)))
+            .max()
+            .map(|(_, i)| i)
+            .or_else(|| candidates.first().copied())
+    }
+}
+
+/// Least recently used: replaces the buffer whose last pin is the oldest.
+#[derive(Debug, Default)]
+pub struct LruPolicy {
+    last_pinned: Vec<u64>,
+    ticks: u64,
+}
+
+impl LruPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ReplacementPolicy for LruPolicy {
+    fn on_pin(&mut self, index: usize, _hint: AccessHint) {
+        if self.last_pinned.len() <= index {
+            self.last_pinned.resize(index + 1, 0);
+        }
+        self.ticks += 1;
+        self.last_pinned[index] = self.ticks;
+    }
+
+    fn on_remove(&mut self, index: usize) {
+        if index < self.last_pinned.len() {
+            self.last_pinned.remove(index);
+        }
+    }
+
+    fn choose_victim(&mut self, candidates: &[usize]) -> Option<usize> {
+        candidates
+            .iter()
+            .min_by_key(|i| self.last_pinned.get(**i).copied().unwrap_or(0))
+            .copied()
+    }
+}
+
+/// the last two references to the block held by a buffer
+#[derive(Debug, Default, Clone, Copy)]
+struct History {
+    last: Option<u64>,
+    penultimate: Option<u64>,
+    /// pinned since the last reference was recorded
+    pinned: bool,
+}
+
+/// LRU-2: replaces the buffer whose second to last reference is the oldest.
+///
+/// Blocks referenced only once (e.g. by a scan) have no second to last reference and
+/// are replaced before any block that was referenced twice, oldest first.
+/// Pins while the buffer is already pinned are correlated with the pin before them
+/// and don't count as a new reference.
+#[derive(Debug, Default)]
+pub struct Lru2Policy {
+    history: Vec<History>,
+    ticks: u64,
+}
+
+impl Lru2Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slot(&mut self, index: usize) -> &mut History {
+        if self.history.len() <= index {
+            self.history.resize(index + 1, History::default());
+        }
+        &mut self.history[index]
+    }
+}
+
+impl ReplacementPolicy for Lru2Policy {
+    fn on_pin(&mut self, index: usize, _hint: AccessHint) {
+        self.ticks += 1;
+        let ticks = self.ticks;
+        let history = self.slot(index);
+        if !history.pinned {
+            history.penultimate = history.last;
+            history.pinned = true;
+        }
+        history.last = Some(ticks);
+    }
+
+    fn on_load(&mut self, index: usize) {
+        *self.slot(index) = History::default();
+    }
+
+    fn on_unpin(&mut self, index: usize) {
+        self.slot(index).pinned = false;
+    }
+
+    fn on_remove(&mut self, index: usize) {
+        if index < self.history.len() {
+            self.history.remove(index);
+        }
+    }
+
+    fn choose_victim(&mut self, candidates: &[usize]) -> Option<usize> {
+        candidates
+            .iter()
+            .min_by_key(|i| {
+                let history = self.history.get(**i).copied().unwrap_or_default();
+                (history.penultimate, history.last)
+            })
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn naive_policy_prefers_recently_released_sequential_buffers() {
+        let mut policy = NaivePolicy::new();
+        assert_eq!(policy.choose_victim(&[0, 1, 2]), Some(0));
+        assert_eq!(policy.choose_victim(&[]), None);
+
+        policy.on_pin(1, AccessHint::Sequential);
+        policy.on_pin(2, AccessHint::Sequential);
+        policy.on_unpin(2);
+        policy.on_unpin(1);
+        assert_eq!(policy.choose_victim(&[0, 1, 2]), Some(1));
+        assert_eq!(policy.choose_victim(&[0, 2]), Some(2));
+
+        // pinned again for a normal access, it isn't a scan buffer anymore
+        policy.on_pin(1, AccessHint::Normal);
+        policy.on_unpin(1);
+        assert_eq!(policy.choose_victim(&[0, 1]), Some(0));
+    }
+
+    #[test]
+    fn lru_policy_replaces_least_recently_pinned() {
+        let mut policy = LruPolicy::new();
+        for i in [0, 1, 2, 0] {
+            policy.on_pin(i, AccessHint::Normal);
+        }
+        assert_eq!(policy.choose_victim(&[0, 1, 2]), Some(1));
+        assert_eq!(policy.choose_victim(&[0, 2]), Some(2));
+    }
+
+    #[test]
+    fn lru2_policy_ignores_correlated_pins() {
+        let mut policy = Lru2Policy::new();
+        // buffer 0 is pinned twice within one pin, buffer 1 in two separate pins
+        policy.on_pin(0, AccessHint::Normal);
+        policy.on_pin(0, AccessHint::Normal);
+        policy.on_unpin(0);
+        policy.on_pin(1, AccessHint::Normal);
+        policy.on_unpin(1);
+        policy.on_pin(1, AccessHint::Normal);
+        policy.on_unpin(1);
+        assert_eq!(policy.choose_victim(&[0, 1]), Some(0));
+
+        // two more uncorrelated pins give buffer 0 the newer history
+        policy.on_pin(0, AccessHint::Normal);
+        policy.on_unpin(0);
+        assert_eq!(policy.choose_victim(&[0, 1]), Some(0));
+        policy.on_pin(0, AccessHint::Normal);
+        policy.on_unpin(0);
+        assert_eq!(policy.choose_victim(&[0, 1]), Some(1));
+
+        // a new block starts without history
+        policy.on_load(0);
+        policy.on_pin(0, AccessHint::Normal);
+        policy.on_unpin(0);
+        assert_eq!(policy.choose_victim(&[0, 1]), Some(0));
+    }
+}
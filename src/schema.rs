@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+
+use crate::file::{Page, I32_SIZE};
+
+/// the most bytes a char takes in UTF-8. a string field holds `length` chars of any kind, so
+/// it is sized for this many bytes per char
+pub const MAX_CHAR_BYTES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    Int,
+    String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldInfo {
+    pub field_type: FieldType,
+    /// the most chars of a string field, 0 for an int field
+    pub length: usize,
+}
+
+impl FieldInfo {
+    /// the bytes the field takes in a slot
+    pub fn size(&self) -> i32 {
+        match self.field_type {
+            FieldType::Int => I32_SIZE as i32,
+            FieldType::String => Page::max_length(self.length * MAX_CHAR_BYTES),
+        }
+    }
+}
+
+/// the fields of a record, in the order they were added
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    fields: Vec<String>,
+    info: HashMap<String, FieldInfo>,
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds `name`, or changes its type if it is there already
+    pub fn add_field(&mut self, name: impl Into<String>, field_type: FieldType, length: usize) {
+        let name = name.into();
+        if !self.info.contains_key(&name) {
+            self.fields.push(name.clone());
+        }
+        self.info.insert(name, FieldInfo { field_type, length });
+    }
+
+    pub fn add_int_field(&mut self, name: impl Into<String>) {
+        self.add_field(name, FieldType::Int, 0);
+    }
+
+    /// a string of at most `max_len` chars
+    pub fn add_string_field(&mut self, name: impl Into<String>, max_len: usize) {
+        self.add_field(name, FieldType::String, max_len);
+    }
+
+    /// adds field `name` of `other`, if it has one
+    pub fn add(&mut self, name: &str, other: &Schema) {
+        if let Some(info) = other.info(name) {
+            self.add_field(name, info.field_type, info.length);
+        }
+    }
+
+    /// adds every field of `other`
+    pub fn add_all(&mut self, other: &Schema) {
+        for name in other.fields() {
+            self.add(name, other);
+        }
+    }
+
+    /// the names of the fields, in order
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn has_field(&self, name: &str) -> bool {
+        self.info.contains_key(name)
+    }
+
+    pub fn info(&self, name: &str) -> Option<FieldInfo> {
+        self.info.get(name).copied()
+    }
+
+    pub fn field_type(&self, name: &str) -> Option<FieldType> {
+        self.info(name).map(|info| info.field_type)
+    }
+
+    pub fn length(&self, name: &str) -> Option<usize> {
+        self.info(name).map(|info| info.length)
+    }
+}
+
+/// where the fields of a schema sit in a slot of a record page. a slot starts with its flag,
+/// an i32, and the fields follow in the order of the schema
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    schema: Schema,
+    offsets: HashMap<String, i32>,
+    slot_size: i32,
+}
+
+impl Layout {
+    pub fn new(schema: Schema) -> Self {
+        let mut offsets = HashMap::new();
+        let mut pos = I32_SIZE as i32;
+        for name in schema.fields() {
+            offsets.insert(name.clone(), pos);
+            pos += schema.info[name].size();
+        }
+        Layout {
+            schema,
+            offsets,
+            slot_size: pos,
+        }
+    }
+
+    /// a layout whose offsets were computed before, as the catalog keeps them
+    pub fn new_with_offsets(schema: Schema, offsets: HashMap<String, i32>, slot_size: i32) -> Self {
+        Layout {
+            schema,
+            offsets,
+            slot_size,
+        }
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// the offset of field `name` from the start of a slot
+    pub fn offset(&self, name: &str) -> Option<i32> {
+        self.offsets.get(name).copied()
+    }
+
+    /// the bytes of a slot, its flag included
+    pub fn slot_size(&self) -> i32 {
+        self.slot_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_places_fields_after_the_flag() {
+        let mut schema = Schema::new();
+        schema.add_int_field("id");
+        schema.add_string_field("name", 10);
+        schema.add_int_field("age");
+        schema.add_string_field("note", 3);
+        assert_eq!(schema.fields(), ["id", "name", "age", "note"]);
+        assert_eq!(schema.field_type("name"), Some(FieldType::String));
+        assert_eq!(schema.length("note"), Some(3));
+        assert!(!schema.has_field("email"));
+
+        let layout = Layout::new(schema.clone());
+        let offsets: Vec<_> = schema
+            .fields()
+            .iter()
+            .map(|name| layout.offset(name).unwrap())
+            .collect();
+        // strings are sized for four bytes per char
+        assert_eq!(offsets, [4, 8, 52, 56]);
+        assert_eq!(layout.slot_size(), 72);
+        assert_eq!(layout.offset("email"), None);
+
+        let offsets = schema
+            .fields()
+            .iter()
+            .map(|name| (name.clone(), layout.offset(name).unwrap()))
+            .collect();
+        assert_eq!(Layout::new_with_offsets(schema, offsets, 72), layout);
+    }
+
+    #[test]
+    fn a_string_of_multi_byte_chars_fits_its_field() {
+        let mut schema = Schema::new();
+        schema.add_string_field("name", 10);
+        schema.add_int_field("age");
+        let layout = Layout::new(schema);
+        let (name, age) = (
+            layout.offset("name").unwrap(),
+            layout.offset("age").unwrap(),
+        );
+
+        let mut page = Page::new(layout.slot_size());
+        let crabs = "🦀".repeat(10);
+        page.set_string(name as u64, &crabs).unwrap();
+        page.set_i32(age as u64, 42).unwrap();
+        assert_eq!(page.get_string(name as u64).unwrap(), crabs);
+        assert_eq!(page.get_i32(age as u64).unwrap(), 42);
+    }
+
+    #[test]
+    fn fields_are_added_once() {
+        let mut people = Schema::new();
+        people.add_int_field("id");
+        people.add_string_field("name", 10);
+        let mut schema = Schema::new();
+        schema.add("name", &people);
+        schema.add("email", &people);
+        schema.add_all(&people);
+        schema.add_string_field("name", 20);
+        assert_eq!(schema.fields(), ["name", "id"]);
+        assert_eq!(schema.length("name"), Some(20));
+    }
+}
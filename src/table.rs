@@ -0,0 +1,548 @@
+use std::{fmt, thread};
+
+use crate::{
+    buffer::PAGE_DATA_OFFSET,
+    file::BlockId,
+    schema::{FieldType, Layout},
+    tx::{Transaction, TransactionError},
+};
+
+/// the flag of a slot that holds no record
+pub const EMPTY: i32 = 0;
+/// the flag of a slot that holds a record
+pub const USED: i32 = 1;
+
+#[derive(Debug)]
+pub enum TableError {
+    /// the layout has no such field
+    UnknownField(String),
+    /// the slot doesn't fit in the block
+    SlotOutOfRange(usize),
+    /// the block is past the end of the table's file
+    BlockOutOfRange(i32),
+    /// the string has more chars than its field holds
+    StringTooLong(String, usize),
+    /// the scan is before the first record or past the last
+    NoRecord,
+    TransactionError(TransactionError),
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TableError::UnknownField(name) => write!(f, "Table error: no field {:?}", name),
+            TableError::SlotOutOfRange(slot) => {
+                write!(f, "Table error: slot {} is past the end of the block", slot)
+            }
+            TableError::BlockOutOfRange(block_num) => {
+                write!(
+                    f,
+                    "Table error: block {} is past the end of the file",
+                    block_num
+                )
+            }
+            TableError::StringTooLong(name, length) => write!(
+                f,
+                "Table error: field {:?} holds at most {} chars",
+                name, length
+            ),
+            TableError::NoRecord => write!(f, "Table error: the scan is not on a record"),
+            TableError::TransactionError(err) => write!(f, "Table error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TableError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TableError::TransactionError(err) => Some(err),
+            TableError::UnknownField(_)
+            | TableError::SlotOutOfRange(_)
+            | TableError::BlockOutOfRange(_)
+            | TableError::StringTooLong(..)
+            | TableError::NoRecord => None,
+        }
+    }
+}
+
+impl From<TransactionError> for TableError {
+    fn from(value: TransactionError) -> Self {
+        TableError::TransactionError(value)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, TableError>;
+
+/// the records of one block, in slots of the size `layout` gives them. slots start after the
+/// page lsn and each begins with its `EMPTY` or `USED` flag.
+///
+/// Every read and update goes through the transaction, locked and logged like any other.
+/// the block stays pinned as long as the record page lives.
+pub struct RecordPage<'a> {
+    tx: &'a mut Transaction,
+    block: BlockId,
+    layout: &'a Layout,
+}
+
+impl<'a> RecordPage<'a> {
+    pub fn new(tx: &'a mut Transaction, block: BlockId, layout: &'a Layout) -> Result<Self> {
+        tx.pin(&block)?;
+        Ok(RecordPage { tx, block, layout })
+    }
+
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+
+    /// the number of slots that fit in the block
+    pub fn capacity(&self) -> usize {
+        capacity(self.tx.block_size(), self.layout)
+    }
+
+    pub fn get_int(&mut self, slot: usize, field: &str) -> Result<i32> {
+        let offset = self.field_offset(slot, field)?;
+        Ok(self.tx.get_int(&self.block, offset)?)
+    }
+
+    pub fn set_int(&mut self, slot: usize, field: &str, val: i32) -> Result<()> {
+        let offset = self.field_offset(slot, field)?;
+        Ok(self.tx.set_int(&self.block, offset, val, true)?)
+    }
+
+    pub fn get_string(&mut self, slot: usize, field: &str) -> Result<String> {
+        let offset = self.field_offset(slot, field)?;
+        Ok(self.tx.get_string(&self.block, offset)?)
+    }
+
+    pub fn set_string(&mut self, slot: usize, field: &str, val: &str) -> Result<()> {
+        let offset = self.field_offset(slot, field)?;
+        let length = self.layout.schema().length(field).unwrap_or_default();
+        if val.chars().count() > length {
+            return Err(TableError::StringTooLong(field.to_string(), length));
+        }
+        Ok(self.tx.set_string(&self.block, offset, val, true)?)
+    }
+
+    /// empties every slot, with its fields zero or empty. nothing is logged: the block is
+    /// meant to be new, and its append is what rollback and recovery undo
+    pub fn format(&mut self) -> Result<()> {
+        for slot in 0..self.capacity() {
+            let offset = self.offset(slot);
+            self.tx.set_int(&self.block, offset, EMPTY, false)?;
+            for field in self.layout.schema().fields() {
+                let offset = offset + self.layout.offset(field).unwrap_or_default();
+                match self.layout.schema().field_type(field) {
+                    Some(FieldType::Int) => self.tx.set_int(&self.block, offset, 0, false)?,
+                    Some(FieldType::String) => {
+                        self.tx.set_string(&self.block, offset, "", false)?
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// marks `slot` empty
+    pub fn delete(&mut self, slot: usize) -> Result<()> {
+        self.set_flag(slot, EMPTY)
+    }
+
+    /// the first used slot after `slot`, or from the start for none
+    pub fn next_after(&mut self, slot: Option<usize>) -> Result<Option<usize>> {
+        self.search_after(slot, USED)
+    }
+
+    /// claims the first empty slot after `slot`, or from the start for none. none if the
+    /// block is full
+    pub fn insert_after(&mut self, slot: Option<usize>) -> Result<Option<usize>> {
+        let found = self.search_after(slot, EMPTY)?;
+        if let Some(slot) = found {
+            self.set_flag(slot, USED)?;
+        }
+        Ok(found)
+    }
+
+    fn search_after(&mut self, slot: Option<usize>, flag: i32) -> Result<Option<usize>> {
+        let start = slot.map_or(0, |slot| slot + 1);
+        for slot in start..self.capacity() {
+            let offset = self.offset(slot);
+            if self.tx.get_int(&self.block, offset)? == flag {
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_flag(&mut self, slot: usize, flag: i32) -> Result<()> {
+        self.check_slot(slot)?;
+        let offset = self.offset(slot);
+        Ok(self.tx.set_int(&self.block, offset, flag, true)?)
+    }
+
+    fn check_slot(&self, slot: usize) -> Result<()> {
+        if slot >= self.capacity() {
+            return Err(TableError::SlotOutOfRange(slot));
+        }
+        Ok(())
+    }
+
+    fn field_offset(&self, slot: usize, field: &str) -> Result<i32> {
+        self.check_slot(slot)?;
+        let offset = self
+            .layout
+            .offset(field)
+            .ok_or_else(|| TableError::UnknownField(field.to_string()))?;
+        Ok(self.offset(slot) + offset)
+    }
+
+    /// where `slot` starts in the block
+    fn offset(&self, slot: usize) -> i32 {
+        PAGE_DATA_OFFSET + slot as i32 * self.layout.slot_size()
+    }
+}
+
+/// the record page unpins its block when it goes away
+impl Drop for RecordPage<'_> {
+    fn drop(&mut self) {
+        // the transaction unpins everything when it finishes, and a panic may have poisoned
+        // the buffer manager
+        if thread::panicking() {
+            return;
+        }
+        self.tx.unpin(&self.block);
+    }
+}
+
+/// the number of slots of `layout` that fit in a block of `block_size` bytes
+fn capacity(block_size: i32, layout: &Layout) -> usize {
+    ((block_size - PAGE_DATA_OFFSET) / layout.slot_size()) as usize
+}
+
+/// where a record is: the block of its table's file and the slot in the block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Rid {
+    pub block_num: i32,
+    pub slot: usize,
+}
+
+/// the file that holds the records of table `table_name`
+pub fn table_file(table_name: &str) -> String {
+    format!("{}.tbl", table_name)
+}
+
+/// a cursor over the records of a table, block after block of its file. it starts before the
+/// first record; `next` moves to the next one and `insert` to a new one, appending a formatted
+/// block to the file when the last is full.
+///
+/// The current block stays pinned until the scan moves off it or is closed.
+pub struct TableScan<'a> {
+    tx: &'a mut Transaction,
+    layout: &'a Layout,
+    filename: String,
+    /// none while the file has no block
+    block: Option<BlockId>,
+    /// none before the first slot of the block, the block's capacity past its last
+    slot: Option<usize>,
+}
+
+impl<'a> TableScan<'a> {
+    pub fn new(tx: &'a mut Transaction, table_name: &str, layout: &'a Layout) -> Result<Self> {
+        let mut scan = TableScan {
+            tx,
+            layout,
+            filename: table_file(table_name),
+            block: None,
+            slot: None,
+        };
+        scan.before_first()?;
+        Ok(scan)
+    }
+
+    /// moves before the first record
+    pub fn before_first(&mut self) -> Result<()> {
+        if self.tx.size(&self.filename)? == 0 {
+            self.unpin();
+            self.slot = None;
+            return Ok(());
+        }
+        self.move_to_block(0)
+    }
+
+    /// moves to the next record, if there is one
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        while let Some(block) = self.block.clone() {
+            let slot = self.slot;
+            let next = self.page(&block)?.next_after(slot)?;
+            self.slot = next;
+            if self.slot.is_some() {
+                return Ok(true);
+            }
+            if block.number() + 1 >= self.tx.size(&self.filename)? {
+                // past the last record, where `next` stays
+                self.slot = Some(self.capacity());
+                return Ok(false);
+            }
+            self.move_to_block(block.number() + 1)?;
+        }
+        Ok(false)
+    }
+
+    pub fn has_field(&self, field: &str) -> bool {
+        self.layout.schema().has_field(field)
+    }
+
+    pub fn get_int(&mut self, field: &str) -> Result<i32> {
+        let (mut page, slot) = self.current()?;
+        page.get_int(slot, field)
+    }
+
+    pub fn set_int(&mut self, field: &str, val: i32) -> Result<()> {
+        let (mut page, slot) = self.current()?;
+        page.set_int(slot, field, val)
+    }
+
+    pub fn get_string(&mut self, field: &str) -> Result<String> {
+        let (mut page, slot) = self.current()?;
+        page.get_string(slot, field)
+    }
+
+    pub fn set_string(&mut self, field: &str, val: &str) -> Result<()> {
+        let (mut page, slot) = self.current()?;
+        page.set_string(slot, field, val)
+    }
+
+    /// moves to a new record, in the first empty slot after the current one. its fields are
+    /// zero or empty
+    pub fn insert(&mut self) -> Result<()> {
+        loop {
+            if let Some(block) = self.block.clone() {
+                let slot = self.slot;
+                let inserted = self.page(&block)?.insert_after(slot)?;
+                self.slot = inserted;
+                if self.slot.is_some() {
+                    return Ok(());
+                }
+                if block.number() + 1 < self.tx.size(&self.filename)? {
+                    self.move_to_block(block.number() + 1)?;
+                    continue;
+                }
+            }
+            self.move_to_new_block()?;
+        }
+    }
+
+    /// deletes the current record. the scan stays on its slot until it moves
+    pub fn delete(&mut self) -> Result<()> {
+        let (mut page, slot) = self.current()?;
+        page.delete(slot)
+    }
+
+    /// where the current record is, if the scan is on one
+    pub fn rid(&self) -> Option<Rid> {
+        match (&self.block, self.slot) {
+            (Some(block), Some(slot)) if slot < self.capacity() => Some(Rid {
+                block_num: block.number(),
+                slot,
+            }),
+            _ => None,
+        }
+    }
+
+    /// moves to the record at `rid`. fails with `BlockOutOfRange` or `SlotOutOfRange` if the
+    /// file has no such block or the block no such slot
+    pub fn move_to_rid(&mut self, rid: Rid) -> Result<()> {
+        if rid.block_num < 0 || rid.block_num >= self.tx.size(&self.filename)? {
+            return Err(TableError::BlockOutOfRange(rid.block_num));
+        }
+        if rid.slot >= self.capacity() {
+            return Err(TableError::SlotOutOfRange(rid.slot));
+        }
+        self.move_to_block(rid.block_num)?;
+        self.slot = Some(rid.slot);
+        Ok(())
+    }
+
+    /// unpins the current block; dropping the scan does the same
+    pub fn close(self) {}
+
+    fn move_to_block(&mut self, block_num: i32) -> Result<()> {
+        let block = BlockId::new(&self.filename, block_num);
+        if self.block.as_ref() != Some(&block) {
+            self.tx.pin(&block)?;
+            self.unpin();
+            self.block = Some(block);
+        }
+        self.slot = None;
+        Ok(())
+    }
+
+    fn move_to_new_block(&mut self) -> Result<()> {
+        let block = self.tx.append(&self.filename)?;
+        self.move_to_block(block.number())?;
+        self.page(&block)?.format()
+    }
+
+    /// a record page of `block`, which the scan keeps pinned
+    fn page(&mut self, block: &BlockId) -> Result<RecordPage<'_>> {
+        RecordPage::new(self.tx, block.clone(), self.layout)
+    }
+
+    fn capacity(&self) -> usize {
+        capacity(self.tx.block_size(), self.layout)
+    }
+
+    fn current(&mut self) -> Result<(RecordPage<'_>, usize)> {
+        let (Some(block), Some(slot)) = (self.block.clone(), self.slot) else {
+            return Err(TableError::NoRecord);
+        };
+        if slot >= self.capacity() {
+            return Err(TableError::NoRecord);
+        }
+        Ok((self.page(&block)?, slot))
+    }
+
+    fn unpin(&mut self) {
+        if let Some(block) = self.block.take() {
+            self.tx.unpin(&block);
+        }
+    }
+}
+
+/// see `RecordPage::drop`
+impl Drop for TableScan<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+        self.unpin();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file::Page, schema::Schema, RSDB};
+
+    fn people() -> Layout {
+        let mut schema = Schema::new();
+        schema.add_int_field("id");
+        schema.add_string_field("name", 8);
+        Layout::new(schema)
+    }
+
+    #[test]
+    fn insert_until_full_delete_and_reinsert() {
+        let db = RSDB::new_in_memory(4096, 8).unwrap();
+        let layout = people();
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("people").unwrap();
+        let mut page = RecordPage::new(&mut tx, block, &layout).unwrap();
+        page.format().unwrap();
+        // a slot is the flag, the id and a name of up to 8 chars of 4 bytes
+        assert_eq!(layout.slot_size(), 4 + 4 + 4 + 32);
+        assert_eq!(page.capacity(), (4096 - 8) / 44);
+
+        let mut slot = None;
+        while let Some(next) = page.insert_after(slot).unwrap() {
+            page.set_int(next, "id", next as i32).unwrap();
+            page.set_string(next, "name", &format!("p{}", next))
+                .unwrap();
+            slot = Some(next);
+        }
+        assert_eq!(slot, Some(page.capacity() - 1));
+
+        page.delete(40).unwrap();
+        assert_eq!(page.next_after(Some(39)).unwrap(), Some(41));
+        assert_eq!(page.insert_after(None).unwrap(), Some(40));
+        assert_eq!(page.get_int(40, "id").unwrap(), 40);
+        assert_eq!(page.insert_after(None).unwrap(), None);
+
+        assert!(matches!(
+            page.get_int(0, "email"),
+            Err(TableError::UnknownField(_))
+        ));
+        assert!(matches!(
+            page.set_string(0, "name", "ninechars"),
+            Err(TableError::StringTooLong(..))
+        ));
+        assert!(matches!(
+            page.delete(page.capacity()),
+            Err(TableError::SlotOutOfRange(_))
+        ));
+        drop(page);
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn a_scan_past_the_last_record_is_on_no_record() {
+        let db = RSDB::new_in_memory(4096, 8).unwrap();
+        let layout = people();
+        let mut tx = db.new_tx().unwrap();
+        let mut scan = TableScan::new(&mut tx, "people", &layout).unwrap();
+        scan.insert().unwrap();
+        scan.set_int("id", 1).unwrap();
+        let first = scan.rid().unwrap();
+        scan.before_first().unwrap();
+        assert!(scan.next().unwrap());
+        assert!(!scan.next().unwrap());
+        assert!(!scan.next().unwrap());
+        assert_eq!(scan.rid(), None);
+        assert!(matches!(scan.get_int("id"), Err(TableError::NoRecord)));
+
+        let capacity = capacity(4096, &layout);
+        assert!(matches!(
+            scan.move_to_rid(Rid {
+                block_num: 0,
+                slot: capacity
+            }),
+            Err(TableError::SlotOutOfRange(slot)) if slot == capacity
+        ));
+        for block_num in [-1, 1] {
+            assert!(matches!(
+                scan.move_to_rid(Rid { block_num, slot: 0 }),
+                Err(TableError::BlockOutOfRange(b)) if b == block_num
+            ));
+        }
+        scan.move_to_rid(first).unwrap();
+        assert_eq!(scan.get_int("id").unwrap(), 1);
+        scan.close();
+        tx.commit().unwrap();
+    }
+
+    #[test]
+    fn a_rolled_back_insert_leaves_the_slot_empty() {
+        let db = RSDB::new_in_memory(4096, 8).unwrap();
+        let layout = people();
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("people").unwrap();
+        RecordPage::new(&mut tx, block.clone(), &layout)
+            .unwrap()
+            .format()
+            .unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.new_tx().unwrap();
+        let mut page = RecordPage::new(&mut tx, block.clone(), &layout).unwrap();
+        assert_eq!(page.insert_after(None).unwrap(), Some(0));
+        page.set_int(0, "id", 7).unwrap();
+        drop(page);
+        tx.rollback().unwrap();
+
+        let mut tx = db.new_read_only_tx().unwrap();
+        let mut page = RecordPage::new(&mut tx, block.clone(), &layout).unwrap();
+        assert_eq!(page.next_after(None).unwrap(), None);
+        drop(page);
+        tx.commit().unwrap();
+        db.checkpoint().unwrap();
+        let mut disk = Page::new(4096);
+        db.storage()
+            .lock()
+            .unwrap()
+            .read(&block, &mut disk)
+            .unwrap();
+        assert_eq!(disk.get_i32(PAGE_DATA_OFFSET as u64).unwrap(), EMPTY);
+        assert_eq!(disk.get_i32(PAGE_DATA_OFFSET as u64 + 4).unwrap(), 0);
+    }
+}
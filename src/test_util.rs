@@ -0,0 +1,531 @@
+//! helpers shared by the tests of several modules
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    buffer::{Buffer, BufferError, BufferManager},
+    file::{self, BlockId, BlockStorage, FileManager, Page, BLOCK_SIZE, I32_SIZE},
+    tx::{self, TxOps},
+};
+
+/// records as the first versions wrote them: bare payloads, without version or new values.
+/// the bytes are checked in and must keep parsing.
+pub const V0_RECORDS: &[u8] = include_bytes!("../fixtures/v0_records.bin");
+
+/// what `V0_RECORDS` holds
+pub const V0_RECORD_STRINGS: &[&str] = &[
+    "<CHECKPOINT>",
+    "<START 1>",
+    "<SETI32 1 [users, 7] 80 42>",
+    "<SETSTRING 1 [users, 7] 40 \"héllo\">",
+    "<SETU64 1 [users, 7] 8 18446744073709551614>",
+    "<SETBYTES 1 [users, 7] 100 [1, 2, 3]>",
+    "<COMMIT 1>",
+    "<START 2>",
+    "<SETI32 2 [users, 8] 0 -1>",
+    "<CLR 2 [users, 8] 0 8>",
+    "<ROLLBACK 2>",
+];
+
+/// splits `V0_RECORDS` into its records, each stored behind an i32 length
+pub fn v0_records() -> Vec<Vec<u8>> {
+    let page = Page::from(V0_RECORDS.to_vec());
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while pos < V0_RECORDS.len() {
+        let rec = page.get_bytes(pos as u64).unwrap();
+        pos += I32_SIZE + rec.len();
+        records.push(rec);
+    }
+    records
+}
+
+/// a small deterministic random generator (xorshift64*) for randomized tests
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// a number in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// an i32 that is one of the edge cases a quarter of the time
+    pub fn i32(&mut self) -> i32 {
+        const EDGES: [i32; 5] = [i32::MIN, -1, 0, 1, i32::MAX];
+        match self.below(4) {
+            0 => EDGES[self.below(EDGES.len())],
+            _ => self.next_u64() as i32,
+        }
+    }
+
+    /// up to `max_len` characters, mixing ascii with multi-byte ones
+    pub fn string(&mut self, max_len: usize) -> String {
+        const CHARS: [char; 8] = ['a', 'z', '0', ' ', '"', 'é', '名', '🦀'];
+        let len = self.below(max_len + 1);
+        (0..len).map(|_| CHARS[self.below(CHARS.len())]).collect()
+    }
+
+    pub fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.below(max_len + 1);
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+/// a call made to a `MockTx`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxCall {
+    Pin(BlockId),
+    Unpin(BlockId),
+    SetI32(BlockId, i32, i32, bool),
+    SetString(BlockId, i32, String, bool),
+    SetU64(BlockId, i32, u64, bool),
+    SetBytes(BlockId, i32, Vec<u8>, bool),
+    Append(String),
+    Truncate(String, i32),
+}
+
+/// records the calls made to it and keeps the pages they write in memory, standing in for a
+/// transaction without any storage
+#[derive(Debug, Default)]
+pub struct MockTx {
+    pub calls: Vec<TxCall>,
+    pub pages: HashMap<BlockId, Page>,
+    /// the number of blocks in each file
+    pub sizes: HashMap<String, i32>,
+}
+
+impl MockTx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the page of `block`, empty until something is written to it
+    pub fn page(&mut self, block: &BlockId) -> &mut Page {
+        self.pages
+            .entry(block.clone())
+            .or_insert_with(|| Page::new(BLOCK_SIZE))
+    }
+
+    fn write(
+        &mut self,
+        call: TxCall,
+        block: &BlockId,
+        f: impl FnOnce(&mut Page) -> file::Result<()>,
+    ) -> tx::Result<()> {
+        self.calls.push(call);
+        Ok(f(self.page(block))?)
+    }
+}
+
+impl TxOps for MockTx {
+    fn pin(&mut self, block: &BlockId) -> tx::Result<()> {
+        self.calls.push(TxCall::Pin(block.clone()));
+        Ok(())
+    }
+
+    fn unpin(&mut self, block: &BlockId) {
+        self.calls.push(TxCall::Unpin(block.clone()));
+    }
+
+    fn set_i32(&mut self, block: &BlockId, offset: i32, val: i32, ok: bool) -> tx::Result<()> {
+        let call = TxCall::SetI32(block.clone(), offset, val, ok);
+        self.write(call, block, |p| p.set_i32(offset as u64, val))
+    }
+
+    fn set_string(&mut self, block: &BlockId, offset: i32, val: &str, ok: bool) -> tx::Result<()> {
+        let call = TxCall::SetString(block.clone(), offset, val.to_string(), ok);
+        self.write(call, block, |p| p.set_string(offset as u64, val))
+    }
+
+    fn set_u64(&mut self, block: &BlockId, offset: i32, val: u64, ok: bool) -> tx::Result<()> {
+        let call = TxCall::SetU64(block.clone(), offset, val, ok);
+        self.write(call, block, |p| p.set_u64(offset as u64, val))
+    }
+
+    fn set_bytes(&mut self, block: &BlockId, offset: i32, val: &[u8], ok: bool) -> tx::Result<()> {
+        let call = TxCall::SetBytes(block.clone(), offset, val.to_vec(), ok);
+        self.write(call, block, |p| p.set_bytes(offset as u64, val))
+    }
+
+    fn size(&mut self, filename: &str) -> tx::Result<i32> {
+        Ok(self.sizes.get(filename).copied().unwrap_or(0))
+    }
+
+    fn append(&mut self, filename: &str) -> tx::Result<BlockId> {
+        self.calls.push(TxCall::Append(filename.to_string()));
+        let size = self.sizes.entry(filename.to_string()).or_insert(0);
+        *size += 1;
+        Ok(BlockId::new(filename, *size - 1))
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> tx::Result<()> {
+        self.calls.push(TxCall::Truncate(filename.to_string(), len));
+        if let Some(size) = self.sizes.get_mut(filename) {
+            *size = (*size).min(len);
+        }
+        self.pages
+            .retain(|block, _| block.filename() != filename || block.number() < len);
+        Ok(())
+    }
+}
+
+/// writes straight into pinned buffers, standing in for a transaction
+pub struct BufferTx {
+    pub txnum: i32,
+    pub bm: Arc<BufferManager>,
+    pinned: Vec<(BlockId, Arc<Buffer>)>,
+    /// how many values have been written
+    pub writes: usize,
+    /// once this many values have been written, every further write fails
+    pub fail_after: Option<usize>,
+}
+
+impl BufferTx {
+    pub fn new(txnum: i32, bm: Arc<BufferManager>) -> Self {
+        Self {
+            txnum,
+            bm,
+            pinned: Vec::new(),
+            writes: 0,
+            fail_after: None,
+        }
+    }
+
+    pub fn buffer(&self, block: &BlockId) -> &Arc<Buffer> {
+        self.pinned
+            .iter()
+            .find_map(|(b, buf)| (b == block).then_some(buf))
+            .expect("block is not pinned")
+    }
+
+    fn write(
+        &mut self,
+        block: &BlockId,
+        f: impl FnOnce(&mut Page) -> tx::Result<()>,
+    ) -> tx::Result<()> {
+        if self.fail_after.is_some_and(|n| self.writes >= n) {
+            return Err(BufferError::BufferAbort.into());
+        }
+        let buf = self.buffer(block);
+        f(&mut buf.contents_mut())?;
+        buf.set_modified(self.txnum, -1)?;
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+impl TxOps for BufferTx {
+    fn pin(&mut self, block: &BlockId) -> tx::Result<()> {
+        let buf = self.bm.pin(block.clone())?;
+        self.pinned.push((block.clone(), buf));
+        Ok(())
+    }
+
+    fn unpin(&mut self, block: &BlockId) {
+        if let Some(i) = self.pinned.iter().position(|(b, _)| b == block) {
+            let (_, buf) = self.pinned.remove(i);
+            self.bm.unpin(buf);
+        }
+    }
+
+    fn set_i32(&mut self, block: &BlockId, offset: i32, val: i32, _: bool) -> tx::Result<()> {
+        self.write(block, |p| Ok(p.set_i32(offset as u64, val)?))
+    }
+
+    fn set_string(&mut self, block: &BlockId, offset: i32, val: &str, _: bool) -> tx::Result<()> {
+        self.write(block, |p| Ok(p.set_string(offset as u64, val)?))
+    }
+
+    fn set_u64(&mut self, block: &BlockId, offset: i32, val: u64, _: bool) -> tx::Result<()> {
+        self.write(block, |p| Ok(p.set_u64(offset as u64, val)?))
+    }
+
+    fn set_bytes(&mut self, block: &BlockId, offset: i32, val: &[u8], _: bool) -> tx::Result<()> {
+        self.write(block, |p| Ok(p.set_bytes(offset as u64, val)?))
+    }
+
+    fn size(&mut self, filename: &str) -> tx::Result<i32> {
+        Ok(self.bm.length(filename)?)
+    }
+
+    fn append(&mut self, filename: &str) -> tx::Result<BlockId> {
+        let bm = &self.bm;
+        let (block, buf) = bm.pin_new(filename)?;
+        bm.unpin(buf);
+        Ok(block)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> tx::Result<()> {
+        Ok(self.bm.truncate(filename, len)?)
+    }
+}
+
+/// a `FileManager` that records the blocks it reads and writes, in order
+#[derive(Debug)]
+pub struct RecordingStorage {
+    pub inner: FileManager,
+    pub reads: Arc<Mutex<Vec<BlockId>>>,
+    pub writes: Arc<Mutex<Vec<BlockId>>>,
+}
+
+impl BlockStorage for RecordingStorage {
+    fn block_size(&self) -> i32 {
+        self.inner.block_size()
+    }
+
+    fn stats(&self) -> file::FileStats {
+        self.inner.stats()
+    }
+
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> file::Result<()> {
+        self.reads.lock().unwrap().push(block_id.clone());
+        self.inner.read(block_id, p)
+    }
+
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> file::Result<()> {
+        self.writes.lock().unwrap().push(block_id.clone());
+        self.inner.write(block_id, p)
+    }
+
+    fn append(&mut self, filename: &str) -> file::Result<BlockId> {
+        self.inner.append(filename)
+    }
+
+    fn length(&mut self, filename: &str) -> file::Result<i32> {
+        self.inner.length(filename)
+    }
+
+    fn exists(&self, filename: &str) -> bool {
+        self.inner.exists(filename)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> file::Result<()> {
+        self.inner.truncate(filename, len)
+    }
+}
+
+/// a block and the bytes written to it
+pub type Write = (BlockId, Vec<u8>);
+
+/// a `FileManager` that records every write with the bytes written, in order
+#[derive(Debug)]
+pub struct WriteOrderStorage {
+    pub inner: FileManager,
+    pub writes: Arc<Mutex<Vec<Write>>>,
+}
+
+impl BlockStorage for WriteOrderStorage {
+    fn block_size(&self) -> i32 {
+        self.inner.block_size()
+    }
+
+    fn stats(&self) -> file::FileStats {
+        self.inner.stats()
+    }
+
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> file::Result<()> {
+        self.inner.read(block_id, p)
+    }
+
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> file::Result<()> {
+        let write = (block_id.clone(), p.bytes().to_vec());
+        self.writes.lock().unwrap().push(write);
+        self.inner.write(block_id, p)
+    }
+
+    fn append(&mut self, filename: &str) -> file::Result<BlockId> {
+        self.inner.append(filename)
+    }
+
+    fn length(&mut self, filename: &str) -> file::Result<i32> {
+        self.inner.length(filename)
+    }
+
+    fn exists(&self, filename: &str) -> bool {
+        self.inner.exists(filename)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> file::Result<()> {
+        self.inner.truncate(filename, len)
+    }
+}
+
+/// a `FileManager` whose reads and writes fail while `fail_reads` and `fail_writes` are set
+#[derive(Debug)]
+pub struct FailingStorage {
+    pub inner: FileManager,
+    pub fail_reads: Arc<AtomicBool>,
+    pub fail_writes: Arc<AtomicBool>,
+}
+
+impl BlockStorage for FailingStorage {
+    fn block_size(&self) -> i32 {
+        self.inner.block_size()
+    }
+
+    fn stats(&self) -> file::FileStats {
+        self.inner.stats()
+    }
+
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> file::Result<()> {
+        if self.fail_reads.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("injected read failure").into());
+        }
+        self.inner.read(block_id, p)
+    }
+
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> file::Result<()> {
+        if self.fail_writes.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("injected write failure").into());
+        }
+        self.inner.write(block_id, p)
+    }
+
+    fn append(&mut self, filename: &str) -> file::Result<BlockId> {
+        self.inner.append(filename)
+    }
+
+    fn length(&mut self, filename: &str) -> file::Result<i32> {
+        self.inner.length(filename)
+    }
+
+    fn exists(&self, filename: &str) -> bool {
+        self.inner.exists(filename)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> file::Result<()> {
+        self.inner.truncate(filename, len)
+    }
+}
+
+/// the faults a `FaultInjectingStorage` injects. writes are counted from 0, appends and
+/// truncates aren't counted.
+#[derive(Debug, Default)]
+pub struct Faults {
+    /// this write fails
+    pub fail_write: Option<usize>,
+    /// this write is reported as done, but never happens
+    pub drop_write: Option<usize>,
+    /// the power is cut before this write: from then on nothing reaches the disk. the process
+    /// keeps reading what it wrote since, as it would from the OS cache, until it is dropped.
+    pub power_cut: Option<usize>,
+    /// every block written so far, in order, including the ones that failed or never happened
+    pub writes: Vec<BlockId>,
+}
+
+/// a `FileManager` that injects the `faults` it shares with the test
+#[derive(Debug)]
+pub struct FaultInjectingStorage {
+    inner: FileManager,
+    faults: Arc<Mutex<Faults>>,
+    /// the blocks written since the power cut
+    volatile: HashMap<BlockId, Vec<u8>>,
+    /// the lengths of the files appended to or truncated since the power cut
+    volatile_lengths: HashMap<String, i32>,
+}
+
+impl FaultInjectingStorage {
+    pub fn new(inner: FileManager, faults: Arc<Mutex<Faults>>) -> Self {
+        Self {
+            inner,
+            faults,
+            volatile: HashMap::new(),
+            volatile_lengths: HashMap::new(),
+        }
+    }
+
+    /// whether the next write would be past the power cut
+    fn power_is_cut(&self) -> bool {
+        let faults = self.faults.lock().unwrap();
+        faults.power_cut.is_some_and(|n| faults.writes.len() >= n)
+    }
+}
+
+impl BlockStorage for FaultInjectingStorage {
+    fn block_size(&self) -> i32 {
+        self.inner.block_size()
+    }
+
+    fn stats(&self) -> file::FileStats {
+        self.inner.stats()
+    }
+
+    fn read(&mut self, block_id: &BlockId, p: &mut Page) -> file::Result<()> {
+        if let Some(bytes) = self.volatile.get(block_id) {
+            p.contents().copy_from_slice(bytes);
+            return Ok(());
+        }
+        if block_id.number() >= self.inner.length(block_id.filename())? {
+            // appended since the power cut and never written
+            p.contents().fill(0);
+            return Ok(());
+        }
+        self.inner.read(block_id, p)
+    }
+
+    fn write(&mut self, block_id: &BlockId, p: &Page) -> file::Result<()> {
+        let mut faults = self.faults.lock().unwrap();
+        let n = faults.writes.len();
+        faults.writes.push(block_id.clone());
+        if faults.fail_write == Some(n) {
+            return Err(std::io::Error::other("injected write failure").into());
+        }
+        if faults.drop_write == Some(n) {
+            return Ok(());
+        }
+        if faults.power_cut.is_some_and(|cut| n >= cut) {
+            self.volatile.insert(block_id.clone(), p.bytes().to_vec());
+            return Ok(());
+        }
+        drop(faults);
+        self.inner.write(block_id, p)
+    }
+
+    fn append(&mut self, filename: &str) -> file::Result<BlockId> {
+        if !self.power_is_cut() {
+            return self.inner.append(filename);
+        }
+        let len = self.length(filename)?;
+        self.volatile_lengths.insert(filename.to_string(), len + 1);
+        Ok(BlockId::new(filename, len))
+    }
+
+    fn length(&mut self, filename: &str) -> file::Result<i32> {
+        match self.volatile_lengths.get(filename) {
+            Some(&len) => Ok(len),
+            None => self.inner.length(filename),
+        }
+    }
+
+    fn exists(&self, filename: &str) -> bool {
+        self.volatile_lengths.contains_key(filename) || self.inner.exists(filename)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> file::Result<()> {
+        if !self.power_is_cut() {
+            return self.inner.truncate(filename, len);
+        }
+        let len = self.length(filename)?.min(len);
+        self.volatile_lengths.insert(filename.to_string(), len);
+        self.volatile
+            .retain(|block, _| block.filename() != filename || block.number() < len);
+        Ok(())
+    }
+}
@@ -1,81 +1,2468 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buffer::{Buffer, BufferError, BufferManager, PAGE_DATA_OFFSET},
+    clock::{Clock, Sleeper, SystemClock},
+    concurrency::{end_of_file, ConcurrencyError, ConcurrencyManager, IsolationLevel, LockTable},
+    file::{self, BlockId, BlockStorage, FileError, Page},
+    log::{LogManager, Lsn},
+    record::{self, LogRecordError},
+    recovery::{RecoveryManager, RecoveryReport},
+};
+
+#[derive(Debug)]
+pub enum TransactionError {
+    BufferError(BufferError),
+    ConcurrencyError(ConcurrencyError),
+    FileError(FileError),
+    LogRecordError(LogRecordError),
+    BlockNotPinned(BlockId),
+    /// the transaction has already committed or rolled back
+    AlreadyFinished(i32),
+    /// the transaction is read-only and tried to change the database
+    ReadOnly(i32),
+    /// the transaction ran longer than the transaction manager allows. it can only roll back
+    Expired(i32),
+    /// pinning the block would take the transaction past its share of the buffer pool
+    TooManyPins(BlockId),
+    /// the buffer to log a change of holds no block
+    UnassignedBuffer,
+    /// the offset is inside the page lsn at the start of every block, see `PAGE_DATA_OFFSET`
+    ReservedOffset(i32),
+    /// the block an append added, second, isn't the one its log record names, first: the file
+    /// grew in between, see `RecoveryManager::append`
+    AppendMismatch(BlockId, BlockId),
+}
+
+impl TransactionError {
+    /// whether the transaction gave up waiting for a lock or a buffer, so that running it
+    /// again after it rolled back may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            TransactionError::ConcurrencyError(
+                ConcurrencyError::LockAbort(_)
+                    | ConcurrencyError::Die(_)
+                    | ConcurrencyError::Deadlock(..)
+            ) | TransactionError::BufferError(BufferError::BufferAbort)
+        )
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::BufferError(err) => write!(f, "Transaction error: {}", err),
+            TransactionError::ConcurrencyError(err) => write!(f, "Transaction error: {}", err),
+            TransactionError::FileError(err) => write!(f, "Transaction error: {}", err),
+            TransactionError::LogRecordError(err) => write!(f, "Transaction error: {}", err),
+            TransactionError::BlockNotPinned(block) => {
+                write!(f, "Transaction error: block {:?} is not pinned", block)
+            }
+            TransactionError::AlreadyFinished(txnum) => write!(
+                f,
+                "Transaction error: transaction {} has already committed or rolled back",
+                txnum
+            ),
+            TransactionError::ReadOnly(txnum) => {
+                write!(f, "Transaction error: transaction {} is read-only", txnum)
+            }
+            TransactionError::Expired(txnum) => write!(
+                f,
+                "Transaction error: transaction {} ran too long and has to roll back",
+                txnum
+            ),
+            TransactionError::TooManyPins(block) => write!(
+                f,
+                "Transaction error: pinning {} would leave too few buffers for others",
+                block
+            ),
+            TransactionError::UnassignedBuffer => {
+                write!(
+                    f,
+                    "Transaction error: the buffer is not assigned to a block"
+                )
+            }
+            TransactionError::ReservedOffset(offset) => write!(
+                f,
+                "Transaction error: offset {} is reserved for the page lsn",
+                offset
+            ),
+            TransactionError::AppendMismatch(logged, appended) => write!(
+                f,
+                "Transaction error: appended {} but logged the append of {}",
+                appended, logged
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransactionError::BufferError(err) => Some(err),
+            TransactionError::ConcurrencyError(err) => Some(err),
+            TransactionError::FileError(err) => Some(err),
+            TransactionError::LogRecordError(err) => Some(err),
+            TransactionError::BlockNotPinned(_)
+            | TransactionError::AlreadyFinished(_)
+            | TransactionError::ReadOnly(_)
+            | TransactionError::Expired(_)
+            | TransactionError::TooManyPins(_)
+            | TransactionError::UnassignedBuffer
+            | TransactionError::ReservedOffset(_)
+            | TransactionError::AppendMismatch(..) => None,
+        }
+    }
+}
+
+impl From<BufferError> for TransactionError {
+    fn from(value: BufferError) -> Self {
+        TransactionError::BufferError(value)
+    }
+}
+
+impl From<ConcurrencyError> for TransactionError {
+    fn from(value: ConcurrencyError) -> Self {
+        TransactionError::ConcurrencyError(value)
+    }
+}
+
+impl From<FileError> for TransactionError {
+    fn from(value: FileError) -> Self {
+        TransactionError::FileError(value)
+    }
+}
+
+impl From<LogRecordError> for TransactionError {
+    fn from(value: LogRecordError) -> Self {
+        TransactionError::LogRecordError(value)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, TransactionError>;
+
+/// the part of a transaction that log records need to undo and redo themselves, and that
+/// `RecoveryManager` rolls back and recovers through, so neither depends on `Transaction`.
+///
+/// `ok_to_log` is false when the write restores an old value and must not be logged again.
+pub trait TxOps {
+    fn pin(&mut self, block: &BlockId) -> Result<()>;
+    fn unpin(&mut self, block: &BlockId);
+    fn set_i32(&mut self, block: &BlockId, offset: i32, val: i32, ok_to_log: bool) -> Result<()>;
+    fn set_string(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: &str,
+        ok_to_log: bool,
+    ) -> Result<()>;
+    fn set_u64(&mut self, block: &BlockId, offset: i32, val: u64, ok_to_log: bool) -> Result<()>;
+    fn set_bytes(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: &[u8],
+        ok_to_log: bool,
+    ) -> Result<()>;
+
+    /// the number of blocks in `filename`
+    fn size(&mut self, filename: &str) -> Result<i32>;
+    /// appends an empty block to `filename` without logging it
+    fn append(&mut self, filename: &str) -> Result<BlockId>;
+    /// shrinks `filename` to its first `len` blocks without logging it
+    fn truncate(&mut self, filename: &str, len: i32) -> Result<()>;
+}
+
+/// hands out transaction numbers. each database has its own, so numbers are unique within it
+#[derive(Debug)]
+pub struct TxNumberAllocator {
+    next: AtomicI32,
+}
+
+impl TxNumberAllocator {
+    /// starts after `last`, the highest number already used
+    pub fn new(last: i32) -> Self {
+        Self {
+            next: AtomicI32::new(last + 1),
+        }
+    }
+
+    /// starts after the highest transaction number in the log, so that numbers don't repeat
+    /// after a restart
+    pub fn recovered(lm: &mut LogManager) -> Result<Self> {
+        let mut last = 0;
+        for bytes in lm.forward_iterator()? {
+            // a registered record's transaction has a start record of its own
+            if let Some(txnum) = record::peek_tx_num(&bytes?)? {
+                last = last.max(txnum);
+            }
+        }
+        Ok(Self::new(last))
+    }
+
+    /// the next number, higher than every one handed out before
+    pub fn allocate(&self) -> i32 {
+        self.next.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+/// a transaction that is running, as the transaction manager knows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxInfo {
+    pub txnum: i32,
+    /// by the clock of the transaction manager
+    pub started_at: Instant,
+    /// read-only transactions log nothing, so they have no start record to go back to
+    pub read_only: bool,
+    /// set once the transaction is older than the maximum age and the expiry policy was applied
+    pub expired: bool,
+}
+
+/// what the transaction manager does with a transaction older than its maximum age
+#[derive(Clone, Default)]
+pub enum ExpiryPolicy {
+    /// every later operation of the transaction fails with `TransactionError::Expired`, except
+    /// rollback
+    #[default]
+    Fail,
+    /// the callback gets the transaction's `TxInfo`, once, and the application decides
+    Notify(Arc<dyn Fn(&TxInfo) + Send + Sync>),
+}
+
+impl fmt::Debug for ExpiryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpiryPolicy::Fail => write!(f, "Fail"),
+            ExpiryPolicy::Notify(_) => write!(f, "Notify(..)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TransactionManagerOptions {
+    /// time source for transaction ages
+    pub clock: Arc<dyn Clock>,
+    /// how long a transaction may run before the expiry policy applies to it. none for no limit
+    pub max_age: Option<Duration>,
+    pub expiry: ExpiryPolicy,
+    /// buffers of the pool a single transaction may not pin, so that it can't starve the
+    /// others. a transaction may always pin one buffer, however small the pool
+    pub pin_reserve: u64,
+}
+
+impl Default for TransactionManagerOptions {
+    fn default() -> Self {
+        TransactionManagerOptions {
+            clock: Arc::new(SystemClock),
+            max_age: None,
+            expiry: ExpiryPolicy::default(),
+            pin_reserve: 1,
+        }
+    }
+}
+
+/// numbers the transactions of a database and keeps track of those that are running, for
+/// non-quiescent checkpoints and shutdown. it only keeps their `TxInfo`, not the transactions.
+///
+/// A forgotten transaction holds its locks and pins forever, so transactions may be given a
+/// maximum age. a transaction's age is checked whenever it requests a lock, and `sweep` checks
+/// every running transaction, for an application that runs it periodically.
+#[derive(Debug)]
+pub struct TransactionManager {
+    numbers: TxNumberAllocator,
+    active: Mutex<BTreeMap<i32, TxInfo>>,
+    clock: Arc<dyn Clock>,
+    max_age: Option<Duration>,
+    expiry: ExpiryPolicy,
+    pin_reserve: u64,
+}
+
+impl TransactionManager {
+    pub fn new(numbers: TxNumberAllocator) -> Self {
+        Self::new_with_options(numbers, TransactionManagerOptions::default())
+    }
+
+    pub fn new_with_options(
+        numbers: TxNumberAllocator,
+        options: TransactionManagerOptions,
+    ) -> Self {
+        Self {
+            numbers,
+            active: Mutex::new(BTreeMap::new()),
+            clock: options.clock,
+            max_age: options.max_age,
+            expiry: options.expiry,
+            pin_reserve: options.pin_reserve,
+        }
+    }
+
+    /// numbers transactions after the highest number in the log, see `TxNumberAllocator`
+    pub fn recovered(lm: &mut LogManager) -> Result<Self> {
+        Self::recovered_with_options(lm, TransactionManagerOptions::default())
+    }
+
+    pub fn recovered_with_options(
+        lm: &mut LogManager,
+        options: TransactionManagerOptions,
+    ) -> Result<Self> {
+        Ok(Self::new_with_options(
+            TxNumberAllocator::recovered(lm)?,
+            options,
+        ))
+    }
+
+    /// applies the expiry policy to every running transaction that is older than the maximum
+    /// age and wasn't expired before, and returns them
+    pub fn sweep(&self) -> Vec<TxInfo> {
+        let expired: Vec<TxInfo> = {
+            let mut active = self.active.lock().unwrap();
+            active
+                .values_mut()
+                .filter_map(|info| self.expire(info))
+                .collect()
+        };
+        self.notify(&expired);
+        expired
+    }
+
+    /// like `sweep`, for transaction `txnum` alone. fails with `Expired` if it expired under
+    /// `ExpiryPolicy::Fail`, now or before
+    fn check_age(&self, txnum: i32) -> Result<()> {
+        let expired = {
+            let mut active = self.active.lock().unwrap();
+            active.get_mut(&txnum).and_then(|info| self.expire(info))
+        };
+        self.notify(expired.as_slice());
+        if self.is_expired(txnum) {
+            return Err(TransactionError::Expired(txnum));
+        }
+        Ok(())
+    }
+
+    /// marks `info` expired if it is over age, and returns it if it wasn't expired before
+    fn expire(&self, info: &mut TxInfo) -> Option<TxInfo> {
+        let max_age = self.max_age?;
+        let age = self.age(info);
+        if info.expired || age <= max_age {
+            return None;
+        }
+        info.expired = true;
+        Some(info.clone())
+    }
+
+    /// runs the callback of `ExpiryPolicy::Notify` for each of `expired`. the registry isn't
+    /// locked meanwhile, so the callback may look at it
+    fn notify(&self, expired: &[TxInfo]) {
+        match &self.expiry {
+            ExpiryPolicy::Fail => {}
+            ExpiryPolicy::Notify(callback) => expired.iter().for_each(|info| callback(info)),
+        }
+    }
+
+    /// whether operations of `txnum` fail because it expired
+    fn is_expired(&self, txnum: i32) -> bool {
+        match self.expiry {
+            ExpiryPolicy::Fail => self
+                .active
+                .lock()
+                .unwrap()
+                .get(&txnum)
+                .is_some_and(|info| info.expired),
+            ExpiryPolicy::Notify(_) => false,
+        }
+    }
+
+    /// the running transactions, oldest first
+    pub fn active_transactions(&self) -> Vec<TxInfo> {
+        self.active.lock().unwrap().values().cloned().collect()
+    }
+
+    /// the running transaction that started first
+    pub fn oldest_active(&self) -> Option<TxInfo> {
+        self.active.lock().unwrap().values().next().cloned()
+    }
+
+    /// how long the transaction of `info` has been running, by the clock of the manager
+    pub fn age(&self, info: &TxInfo) -> Duration {
+        self.clock.now().saturating_duration_since(info.started_at)
+    }
+
+    fn allocate(&self) -> i32 {
+        self.numbers.allocate()
+    }
+
+    fn register(&self, txnum: i32, read_only: bool) {
+        let info = TxInfo {
+            txnum,
+            started_at: self.clock.now(),
+            read_only,
+            expired: false,
+        };
+        self.active.lock().unwrap().insert(txnum, info);
+    }
+
+    fn deregister(&self, txnum: i32) {
+        self.active.lock().unwrap().remove(&txnum);
+    }
+}
+
+/// the buffers a transaction has pinned, with how many times it pinned each. the manager is
+/// pinned once per block, and unpinned once the transaction unpinned it as often as it pinned it.
+pub struct BufferList {
+    /// shared without an outer lock: a pin waiting for a buffer must not hold off the unpins
+    /// of other transactions
+    bm: Arc<BufferManager>,
+    buffers: HashMap<BlockId, (Arc<Buffer>, usize)>,
+    /// how many distinct blocks may be pinned at once
+    max_buffers: usize,
+    stats: PinStats,
+}
+
+/// the pins of a transaction, for `TxStats`
+#[derive(Debug, Default)]
+struct PinStats {
+    blocks: HashSet<BlockId>,
+    pins: usize,
+    hits: usize,
+    misses: usize,
+}
+
+impl BufferList {
+    pub fn new(bm: Arc<BufferManager>) -> Self {
+        Self {
+            bm,
+            buffers: HashMap::new(),
+            max_buffers: usize::MAX,
+            stats: PinStats::default(),
+        }
+    }
+
+    /// fails pins of more than `max_buffers` distinct blocks at once with `TooManyPins`
+    pub fn with_max_buffers(mut self, max_buffers: usize) -> Self {
+        self.max_buffers = max_buffers;
+        self
+    }
+
+    /// the buffer of `block`, if it is pinned
+    pub fn get_buffer(&self, block: &BlockId) -> Option<&Arc<Buffer>> {
+        self.buffers.get(block).map(|(buf, _)| buf)
+    }
+
+    pub fn pin(&mut self, block: &BlockId) -> Result<()> {
+        let hit = if let Some((_, pins)) = self.buffers.get_mut(block) {
+            *pins += 1;
+            true
+        } else if self.buffers.len() >= self.max_buffers {
+            return Err(TransactionError::TooManyPins(block.clone()));
+        } else {
+            let (buf, hit) = self.bm.pin_reporting_hit(block.clone())?;
+            self.buffers.insert(block.clone(), (buf, 1));
+            hit
+        };
+        self.stats.blocks.insert(block.clone());
+        self.stats.pins += 1;
+        if hit {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        Ok(())
+    }
+
+    /// releases one pin of `block`. a block that isn't pinned is left alone
+    pub fn unpin(&mut self, block: &BlockId) {
+        let Some((_, pins)) = self.buffers.get_mut(block) else {
+            return;
+        };
+        *pins -= 1;
+        if *pins == 0 {
+            let (buf, _) = self.buffers.remove(block).unwrap();
+            self.bm.unpin(buf);
+        }
+    }
+
+    /// releases every pin, as at the end of the transaction
+    pub fn unpin_all(&mut self) {
+        let bm = &self.bm;
+        for (_, (buf, _)) in self.buffers.drain() {
+            bm.unpin(buf);
+        }
+    }
+}
+
+/// a transaction's pinned buffers as `TxOps`, for the recovery manager to roll back through.
+/// it borrows the fields of `Transaction` it needs, so the recovery manager can be borrowed
+/// alongside it.
+struct TxBuffers<'a> {
+    tx_num: i32,
+    recovery: &'a RecoveryManager,
+    buffers: &'a mut BufferList,
+}
+
+impl TxBuffers<'_> {
+    /// writes a value into the page of the pinned `block`. `log` logs the update when
+    /// `ok_to_log` is set; either way the buffer is marked modified by this transaction.
+    fn write(
+        &mut self,
+        block: &BlockId,
+        ok_to_log: bool,
+        log: impl FnOnce(&RecoveryManager, &Buffer) -> Result<Lsn>,
+        set: impl FnOnce(&mut Page) -> file::Result<()>,
+    ) -> Result<()> {
+        let buf = self
+            .buffers
+            .get_buffer(block)
+            .ok_or_else(|| TransactionError::BlockNotPinned(block.clone()))?;
+        let lsn = if ok_to_log {
+            log(self.recovery, buf)?
+        } else {
+            -1
+        };
+        set(&mut buf.contents_mut())?;
+        buf.set_modified(self.tx_num, lsn)?;
+        Ok(())
+    }
+}
+
+impl TxOps for TxBuffers<'_> {
+    fn pin(&mut self, block: &BlockId) -> Result<()> {
+        self.buffers.pin(block)
+    }
+
+    fn unpin(&mut self, block: &BlockId) {
+        self.buffers.unpin(block)
+    }
+
+    fn set_i32(&mut self, block: &BlockId, offset: i32, val: i32, ok_to_log: bool) -> Result<()> {
+        self.write(
+            block,
+            ok_to_log,
+            |rm, buf| rm.set_i32(buf, offset, val),
+            |p| p.set_i32(offset as u64, val),
+        )
+    }
+
+    fn set_string(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: &str,
+        ok_to_log: bool,
+    ) -> Result<()> {
+        self.write(
+            block,
+            ok_to_log,
+            |rm, buf| rm.set_string(buf, offset, val),
+            |p| p.set_string(offset as u64, val),
+        )
+    }
+
+    fn set_u64(&mut self, block: &BlockId, offset: i32, val: u64, ok_to_log: bool) -> Result<()> {
+        self.write(
+            block,
+            ok_to_log,
+            |rm, buf| rm.set_u64(buf, offset, val),
+            |p| p.set_u64(offset as u64, val),
+        )
+    }
+
+    fn set_bytes(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: &[u8],
+        ok_to_log: bool,
+    ) -> Result<()> {
+        self.write(
+            block,
+            ok_to_log,
+            |rm, buf| rm.set_bytes(buf, offset, val),
+            |p| p.set_bytes(offset as u64, val),
+        )
+    }
+
+    fn size(&mut self, filename: &str) -> Result<i32> {
+        Ok(self.buffers.bm.length(filename)?)
+    }
+
+    fn append(&mut self, filename: &str) -> Result<BlockId> {
+        let bm = &self.buffers.bm;
+        let (block, buf) = bm.pin_new(filename)?;
+        bm.unpin(buf);
+        Ok(block)
+    }
+
+    fn truncate(&mut self, filename: &str, len: i32) -> Result<()> {
+        Ok(self.buffers.bm.truncate(filename, len)?)
+    }
+}
+
+/// what a transaction may do
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TxMode {
+    #[default]
+    ReadWrite,
+    /// reads only, under shared locks. nothing is logged, not even the start and commit
+    ReadOnly,
+    /// like `ReadOnly`, but at `IsolationLevel::ReadUncommitted`: it takes no locks, so it may
+    /// see uncommitted updates
+    ReadUncommitted,
+    /// the transaction that recovers the database at startup, see `Transaction::new_recovery`.
+    /// it takes no locks and doesn't log its updates
+    Recovery,
+}
+
+/// what a transaction cost, see `Transaction::stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TxStats {
+    /// distinct blocks the transaction pinned
+    pub blocks_pinned: usize,
+    /// every pin, repeated pins of a block included
+    pub pins: usize,
+    /// pins of blocks that were in memory, in the pool or pinned by the transaction already
+    pub buffer_hits: usize,
+    /// pins that read their block from disk
+    pub buffer_misses: usize,
+    /// the records the transaction logged, those of its rollback included
+    pub log_records: usize,
+    pub log_bytes: usize,
+    /// locks granted, upgrades from shared to exclusive counting as exclusive
+    pub slocks: usize,
+    pub xlocks: usize,
+    pub lock_wait: Duration,
+    /// from the start of the transaction until it committed or rolled back, or until now
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for TxStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks pinned ({} pins, {} hits, {} misses), {} log records ({} bytes), \
+             {} shared and {} exclusive locks ({:?} waiting), {:?} elapsed",
+            self.blocks_pinned,
+            self.pins,
+            self.buffer_hits,
+            self.buffer_misses,
+            self.log_records,
+            self.log_bytes,
+            self.slocks,
+            self.xlocks,
+            self.lock_wait,
+            self.elapsed
+        )
+    }
+}
+
 /// Transaction:
 /// 1. manage buffers
 /// 2. generate log records for each update and write them to the log file
 /// 3. rollback transaction on demand
 /// 4. guarantee the program will satisfy the ACID isolation property
-pub struct Transaction {}
+///
+/// a transaction is `Send`: it may be started on one thread and handed to another, since its
+/// locks belong to its number rather than to a thread. it is not meant to be shared, and every
+/// operation takes `&mut self`, so one transaction is used by one thread at a time. concurrent
+/// work runs as separate transactions over the same managers and lock table.
+///
+/// ```
+/// use rsdb::RSDB;
+///
+/// let db = RSDB::new("__doc_transaction", 4096, 8)?;
+/// let mut tx = db.new_tx()?;
+/// let block = tx.append("testfile")?;
+/// tx.pin(&block)?;
+/// tx.set_int(&block, 80, 42, true)?;
+/// tx.commit()?;
+/// # std::fs::remove_dir_all("__doc_transaction")?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Transaction {
+    fm: Arc<Mutex<dyn BlockStorage>>,
+    lm: Arc<Mutex<LogManager>>,
+    bm: Arc<BufferManager>,
+    /// none for a read-only transaction, which logs nothing
+    recovery: Option<RecoveryManager>,
+    concurrency: ConcurrencyManager,
+    transactions: Arc<TransactionManager>,
+    buffers: BufferList,
+    tx_num: i32,
+    mode: TxMode,
+    /// set once the transaction commits or rolls back; every operation fails after that
+    finished: bool,
+    started_at: Instant,
+    /// how long the transaction ran, once it finished
+    elapsed: Option<Duration>,
+}
 
 impl Transaction {
-    pub fn commit() {}
+    /// starts a transaction registered with `transactions` and locking through `lock_table`,
+    /// the transaction manager and lock table of the database the managers belong to, and logs
+    /// its start record
+    pub fn new(
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        bm: Arc<BufferManager>,
+        transactions: Arc<TransactionManager>,
+        lock_table: Arc<LockTable>,
+    ) -> Result<Self> {
+        Self::new_with_mode(fm, lm, bm, transactions, lock_table, TxMode::ReadWrite)
+    }
+
+    /// like `new`, for a transaction in `mode`. only a read-write transaction logs its start
+    pub fn new_with_mode(
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        bm: Arc<BufferManager>,
+        transactions: Arc<TransactionManager>,
+        lock_table: Arc<LockTable>,
+        mode: TxMode,
+    ) -> Result<Self> {
+        let isolation = IsolationLevel::default();
+        Self::new_with_isolation(fm, lm, bm, transactions, lock_table, mode, isolation)
+    }
+
+    /// like `new_with_mode`, for a transaction that reads at `isolation` rather than
+    /// serializable. a `TxMode::ReadUncommitted` transaction always reads uncommitted data
+    pub fn new_with_isolation(
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        bm: Arc<BufferManager>,
+        transactions: Arc<TransactionManager>,
+        lock_table: Arc<LockTable>,
+        mode: TxMode,
+        isolation: IsolationLevel,
+    ) -> Result<Self> {
+        let isolation = match mode {
+            TxMode::ReadUncommitted => IsolationLevel::ReadUncommitted,
+            TxMode::ReadWrite | TxMode::ReadOnly | TxMode::Recovery => isolation,
+        };
+        let tx_num = transactions.allocate();
+        let recovery = match mode {
+            TxMode::ReadWrite | TxMode::Recovery => Some(RecoveryManager::new(
+                tx_num,
+                Arc::clone(&lm),
+                Arc::clone(&bm),
+            )?),
+            TxMode::ReadOnly | TxMode::ReadUncommitted => None,
+        };
+        transactions.register(tx_num, recovery.is_none());
+        let max_buffers = bm.size().saturating_sub(transactions.pin_reserve).max(1);
+        Ok(Self {
+            fm,
+            lm,
+            buffers: BufferList::new(Arc::clone(&bm)).with_max_buffers(max_buffers as usize),
+            bm,
+            recovery,
+            concurrency: ConcurrencyManager::new_with_isolation(tx_num, lock_table, isolation),
+            transactions,
+            tx_num,
+            mode,
+            finished: false,
+            started_at: Instant::now(),
+            elapsed: None,
+        })
+    }
+
+    /// the transaction that brings the database back to a consistent state at startup with
+    /// `recover`. nothing else runs meanwhile, so it takes no locks, and it doesn't log its own
+    /// updates, even when asked to
+    pub fn new_recovery(
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        bm: Arc<BufferManager>,
+        transactions: Arc<TransactionManager>,
+    ) -> Result<Self> {
+        let lock_table = Arc::new(LockTable::new());
+        Self::new_with_mode(fm, lm, bm, transactions, lock_table, TxMode::Recovery)
+    }
+
+    pub fn tx_num(&self) -> i32 {
+        self.tx_num
+    }
+
+    pub fn mode(&self) -> TxMode {
+        self.mode
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.concurrency.isolation()
+    }
+
+    /// what the transaction cost so far. the counts stay as they were once it finished
+    pub fn stats(&self) -> TxStats {
+        let pins = &self.buffers.stats;
+        let locks = self.concurrency.stats();
+        let (log_records, log_bytes) = self.recovery.as_ref().map_or((0, 0), |recovery| {
+            (recovery.records_written(), recovery.bytes_written())
+        });
+        TxStats {
+            blocks_pinned: pins.blocks.len(),
+            pins: pins.pins,
+            buffer_hits: pins.hits,
+            buffer_misses: pins.misses,
+            log_records,
+            log_bytes,
+            slocks: locks.slocks,
+            xlocks: locks.xlocks,
+            lock_wait: locks.wait,
+            elapsed: self.elapsed.unwrap_or_else(|| self.started_at.elapsed()),
+        }
+    }
+
+    /// logs the commit, flushes the transaction's pages and releases its buffers. the
+    /// transaction is finished afterwards. a read-only transaction has nothing to log or flush
+    pub fn commit(&mut self) -> Result<()> {
+        self.check_active()?;
+        if let Some(recovery) = &self.recovery {
+            recovery.commit()?;
+        }
+        self.finish();
+        Ok(())
+    }
+
     /// execute rollback a specified transaction `T`
     ///
     /// Algorithm
     /// 1. Set the current record to be the most recent log record.
     /// 2. Do until the current record is the start record for T:
-    ///     a) If the current record is an update record for T then:
-    ///         Write the saved old value to the specified location
-    ///     b) Move to the previous record in the log
+    ///    a) If the current record is an update record for T then:
+    ///    Write the saved old value to the specified location
+    ///    b) Move to the previous record in the log
     /// 3. Append a rollback record to the log
     ///
     /// This algorithm reads the log backwards from the end,
     /// instead of forward from the beginning for the efficiency amd the correctness.
-    pub fn rollback() {}
+    pub fn rollback(&mut self) -> Result<()> {
+        // unlike everything else, an expired transaction may still roll back
+        if self.finished {
+            return Err(TransactionError::AlreadyFinished(self.tx_num));
+        }
+        if let Some(recovery) = &self.recovery {
+            let mut ops = TxBuffers {
+                tx_num: self.tx_num,
+                recovery,
+                buffers: &mut self.buffers,
+            };
+            recovery.rollback(&mut ops)?;
+        }
+        self.finish();
+        Ok(())
+    }
 
     ///
     /// Algorithm
     /// # the undo stage
     /// 1. For each log record
-    ///     a) If the current record is a commit record then:
-    ///         Add that transaction to the lost of committed transactions.
-    ///     b) If the current record is a rollback record then:
-    ///         Add that transaction to the lost of rolled-back transactions.
-    ///     c) If the current record is an update record for a transaction not on the committed or rollback list, then:
-    ///         Restore the old value at the specified location.
+    ///    a) If the current record is a commit record then:
+    ///    Add that transaction to the lost of committed transactions.
+    ///    b) If the current record is a rollback record then:
+    ///    Add that transaction to the lost of rolled-back transactions.
+    ///    c) If the current record is an update record for a transaction not on the committed or rollback list, then:
+    ///    Restore the old value at the specified location.
     ///
     /// # the redo stage
     /// 2. For each log record
-    ///     If the current record is an update record and that transaction is on the committed list,
-    ///         then: Restore the new value at the specified location.
-    pub fn recover() {}
+    ///    If the current record is an update record and that transaction is on the committed list,
+    ///    then: Restore the new value at the specified location.
+    ///
+    /// Meant for the transaction of `new_recovery`, before any other transaction starts.
+    pub fn recover(&mut self) -> Result<RecoveryReport> {
+        self.writable()?;
+        self.bm.flush_all(self.tx_num)?;
+        self.with_recovery(|recovery, ops| recovery.recover(ops))
+    }
+
+    pub fn pin(&mut self, block: &BlockId) -> Result<()> {
+        self.check_active()?;
+        self.buffers.pin(block)
+    }
+
+    pub fn unpin(&mut self, block: &BlockId) {
+        self.buffers.unpin(block)
+    }
+
+    /// pins `block`, runs `f` with a view of it and unpins it again, whether `f` succeeds,
+    /// fails or panics
+    pub fn with_block<R>(
+        &mut self,
+        block: &BlockId,
+        f: impl FnOnce(&mut BlockView) -> Result<R>,
+    ) -> Result<R> {
+        self.pin(block)?;
+        let mut view = BlockView {
+            tx: self,
+            block: block.clone(),
+        };
+        f(&mut view)
+    }
+
+    /// the i32 at `offset` of `block`, which the transaction must have pinned
+    pub fn get_int(&mut self, block: &BlockId, offset: i32) -> Result<i32> {
+        self.read(block, |page| page.get_i32(offset as u64))
+    }
+
+    /// writes `val` at `offset` of the pinned `block`. the update is logged first unless
+    /// `ok_to_log` is false, as when undo restores an old value.
+    pub fn set_int(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: i32,
+        ok_to_log: bool,
+    ) -> Result<()> {
+        self.writable()?;
+        check_offset(offset)?;
+        self.xlock(block)?;
+        let ok_to_log = self.logs_updates(ok_to_log);
+        self.ops()?.set_i32(block, offset, val, ok_to_log)
+    }
+
+    /// the string at `offset` of `block`, which the transaction must have pinned
+    pub fn get_string(&mut self, block: &BlockId, offset: i32) -> Result<String> {
+        self.read(block, |page| page.get_string(offset as u64))
+    }
+
+    /// like `set_int`, for a string. the whole previous string is logged, so undo restores
+    /// it even when `val` is shorter
+    pub fn set_string(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: &str,
+        ok_to_log: bool,
+    ) -> Result<()> {
+        self.writable()?;
+        check_offset(offset)?;
+        self.xlock(block)?;
+        let ok_to_log = self.logs_updates(ok_to_log);
+        self.ops()?.set_string(block, offset, val, ok_to_log)
+    }
+
+    /// reads the pinned `block` with `read` under a shared lock, which the isolation level
+    /// may release right after
+    fn read<T>(
+        &mut self,
+        block: &BlockId,
+        read: impl FnOnce(&Page) -> file::Result<T>,
+    ) -> Result<T> {
+        let buf = Arc::clone(self.buffer(block)?);
+        self.slock(block)?;
+        let val = read(&buf.contents());
+        self.concurrency.end_read();
+        Ok(val?)
+    }
+
+    fn buffer(&self, block: &BlockId) -> Result<&Arc<Buffer>> {
+        self.check_active()?;
+        self.buffers
+            .get_buffer(block)
+            .ok_or_else(|| TransactionError::BlockNotPinned(block.clone()))
+    }
+
+    fn ops(&mut self) -> Result<TxBuffers<'_>> {
+        self.check_active()?;
+        let Some(recovery) = &self.recovery else {
+            return Err(TransactionError::ReadOnly(self.tx_num));
+        };
+        Ok(TxBuffers {
+            tx_num: self.tx_num,
+            recovery,
+            buffers: &mut self.buffers,
+        })
+    }
+
+    /// runs `f` with the recovery manager and the transaction's buffers as `TxOps`
+    fn with_recovery<T>(
+        &mut self,
+        f: impl FnOnce(&RecoveryManager, &mut dyn TxOps) -> Result<T>,
+    ) -> Result<T> {
+        let mut ops = self.ops()?;
+        let recovery = ops.recovery;
+        f(recovery, &mut ops)
+    }
+
+    /// fails once the transaction finished, or expired (see `TransactionManager`)
+    fn check_active(&self) -> Result<()> {
+        if self.finished {
+            return Err(TransactionError::AlreadyFinished(self.tx_num));
+        }
+        if self.transactions.is_expired(self.tx_num) {
+            return Err(TransactionError::Expired(self.tx_num));
+        }
+        Ok(())
+    }
+
+    /// fails unless the transaction is active and may change the database
+    fn writable(&self) -> Result<()> {
+        self.check_active()?;
+        if self.recovery.is_none() {
+            return Err(TransactionError::ReadOnly(self.tx_num));
+        }
+        Ok(())
+    }
+
+    /// takes a shared lock on `block` as the isolation level wants it, none for the recovery
+    /// transaction. like `xlock`, it checks the age of the transaction first
+    fn slock(&mut self, block: &BlockId) -> Result<()> {
+        self.transactions.check_age(self.tx_num)?;
+        match self.mode {
+            TxMode::ReadWrite | TxMode::ReadOnly | TxMode::ReadUncommitted => {
+                self.concurrency.slock(block)
+            }
+            TxMode::Recovery => Ok(()),
+        }
+    }
+
+    /// takes an exclusive lock on `block`, once the transaction manager checked that the
+    /// transaction isn't over age. the recovery transaction takes none
+    fn xlock(&mut self, block: &BlockId) -> Result<()> {
+        self.transactions.check_age(self.tx_num)?;
+        match self.mode {
+            TxMode::ReadWrite | TxMode::ReadOnly | TxMode::ReadUncommitted => {
+                self.concurrency.xlock(block)
+            }
+            TxMode::Recovery => Ok(()),
+        }
+    }
+
+    /// whether an update the caller asked to log with `ok_to_log` is logged
+    fn logs_updates(&self, ok_to_log: bool) -> bool {
+        ok_to_log && self.mode != TxMode::Recovery
+    }
+
+    /// drops the transaction without rolling it back: its updates stay in the buffers, and
+    /// its pins and locks are never released. it stays registered as running, since recovery
+    /// has to undo its updates after a restart
+    pub fn forget(mut self) {
+        self.finished = true;
+    }
+
+    /// releases everything the transaction holds once it has committed or rolled back
+    fn finish(&mut self) {
+        self.concurrency.release();
+        self.buffers.unpin_all();
+        self.transactions.deregister(self.tx_num);
+        self.finished = true;
+        self.elapsed = Some(self.started_at.elapsed());
+    }
+
+    /// the u64 at `offset` of `block`, which the transaction must have pinned
+    pub fn get_u64(&mut self, block: &BlockId, offset: i32) -> Result<u64> {
+        self.read(block, |page| page.get_u64(offset as u64))
+    }
+
+    /// like `set_int`, for a u64
+    pub fn set_u64(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: u64,
+        ok_to_log: bool,
+    ) -> Result<()> {
+        self.writable()?;
+        check_offset(offset)?;
+        self.xlock(block)?;
+        let ok_to_log = self.logs_updates(ok_to_log);
+        self.ops()?.set_u64(block, offset, val, ok_to_log)
+    }
+
+    /// the length-prefixed bytes at `offset` of `block`, which the transaction must have pinned
+    pub fn get_bytes(&mut self, block: &BlockId, offset: i32) -> Result<Vec<u8>> {
+        self.read(block, |page| page.get_bytes(offset as u64))
+    }
+
+    /// like `set_int`, for a byte slice. like a string, the whole previous slice is logged
+    pub fn set_bytes(
+        &mut self,
+        block: &BlockId,
+        offset: i32,
+        val: &[u8],
+        ok_to_log: bool,
+    ) -> Result<()> {
+        self.writable()?;
+        check_offset(offset)?;
+        self.xlock(block)?;
+        let ok_to_log = self.logs_updates(ok_to_log);
+        self.ops()?.set_bytes(block, offset, val, ok_to_log)
+    }
+
+    /// the number of unpinned buffers in the pool
+    pub fn available_buffs(&self) -> u64 {
+        self.bm.available()
+    }
+
+    /// the number of blocks in `filename`
+    pub fn size(&mut self, filename: &str) -> Result<i32> {
+        self.check_active()?;
+        self.slock(&end_of_file(filename))?;
+        let len = self.bm.length(filename);
+        self.concurrency.end_read();
+        Ok(len?)
+    }
+
+    /// appends a block to `filename` and returns it. the append is logged, so rollback and
+    /// recovery truncate the block away again if the transaction doesn't commit
+    pub fn append(&mut self, filename: &str) -> Result<BlockId> {
+        self.writable()?;
+        self.xlock(&end_of_file(filename))?;
+        self.with_recovery(|recovery, ops| recovery.append(ops, filename))
+    }
+
+    pub fn block_size(&self) -> i32 {
+        self.fm.lock().unwrap().block_size()
+    }
+}
+
+/// a transaction that is dropped before it commits or rolls back is rolled back
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // a panic while a manager was locked poisons it, and panicking again here would abort
+        if thread::panicking()
+            && (self.fm.is_poisoned() || self.lm.is_poisoned() || self.bm.is_poisoned())
+        {
+            eprintln!(
+                "transaction {} was not rolled back; recovery will undo it",
+                self.tx_num
+            );
+            return;
+        }
+        if let Err(err) = self.rollback() {
+            eprintln!("rollback of transaction {} failed: {}", self.tx_num, err);
+            self.finish();
+        }
+    }
+}
+
+/// one block a transaction pinned with `Transaction::with_block`. its reads and updates are
+/// those of the transaction, locked and logged the same way, without repeating the block
+pub struct BlockView<'a> {
+    tx: &'a mut Transaction,
+    block: BlockId,
+}
+
+impl BlockView<'_> {
+    pub fn block(&self) -> &BlockId {
+        &self.block
+    }
+
+    pub fn get_int(&mut self, offset: i32) -> Result<i32> {
+        self.tx.get_int(&self.block, offset)
+    }
+
+    pub fn set_int(&mut self, offset: i32, val: i32, ok_to_log: bool) -> Result<()> {
+        self.tx.set_int(&self.block, offset, val, ok_to_log)
+    }
+
+    pub fn get_string(&mut self, offset: i32) -> Result<String> {
+        self.tx.get_string(&self.block, offset)
+    }
 
-    pub fn pin() {}
-    pub fn unpin() {}
-    pub fn get_u64() {}
-    pub fn get_string() {}
-    pub fn set_u64() {}
-    pub fn set_string() {}
-    pub fn available_buff() {}
+    pub fn set_string(&mut self, offset: i32, val: &str, ok_to_log: bool) -> Result<()> {
+        self.tx.set_string(&self.block, offset, val, ok_to_log)
+    }
+
+    pub fn get_u64(&mut self, offset: i32) -> Result<u64> {
+        self.tx.get_u64(&self.block, offset)
+    }
+
+    pub fn set_u64(&mut self, offset: i32, val: u64, ok_to_log: bool) -> Result<()> {
+        self.tx.set_u64(&self.block, offset, val, ok_to_log)
+    }
+
+    pub fn get_bytes(&mut self, offset: i32) -> Result<Vec<u8>> {
+        self.tx.get_bytes(&self.block, offset)
+    }
 
-    pub fn size() {}
-    pub fn append() {}
-    pub fn block_size() {}
+    pub fn set_bytes(&mut self, offset: i32, val: &[u8], ok_to_log: bool) -> Result<()> {
+        self.tx.set_bytes(&self.block, offset, val, ok_to_log)
+    }
+}
+
+/// the view unpins its block when it goes away, on a panic of the closure too
+impl Drop for BlockView<'_> {
+    fn drop(&mut self) {
+        // see `Transaction::drop`
+        if thread::panicking() && self.tx.bm.is_poisoned() {
+            return;
+        }
+        self.tx.unpin(&self.block);
+    }
+}
+
+/// how `run_with_retries` retries a transaction that gave up waiting for a lock or a buffer
+#[derive(Debug, Clone)]
+pub struct RetryOptions {
+    /// how many times the transaction runs at most, the first time included
+    pub max_attempts: u32,
+    /// the backoff after the first failed attempt. it doubles after each further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// what waits out the backoffs
+    pub sleeper: Arc<dyn Sleeper>,
+    /// seed of the jitter, which picks each backoff at random from the upper half of its range
+    /// so that transactions that collided don't retry in step
+    pub seed: u64,
+}
+
+impl Default for RetryOptions {
+    fn default() -> Self {
+        RetryOptions {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(100),
+            sleeper: Arc::new(SystemClock),
+            seed: SystemClock.micros_since_epoch() as u64,
+        }
+    }
+}
+
+/// fails with `ReservedOffset` if a value written at `offset` would overwrite the page lsn
+fn check_offset(offset: i32) -> Result<()> {
+    if offset < PAGE_DATA_OFFSET {
+        return Err(TransactionError::ReservedOffset(offset));
+    }
+    Ok(())
+}
+
+/// runs `f` in a transaction started by `begin` and commits it. when `f` fails because the
+/// transaction gave up waiting for a lock or a buffer, see `TransactionError::is_retryable`,
+/// the transaction rolls back and `f` runs again in a new one after a backoff, up to
+/// `max_attempts` times. any other error is returned at once, after the rollback
+pub fn run_with_retries<T>(
+    mut begin: impl FnMut() -> Result<Transaction>,
+    options: RetryOptions,
+    mut f: impl FnMut(&mut Transaction) -> Result<T>,
+) -> Result<T> {
+    let mut backoff = options.initial_backoff;
+    let mut jitter = options.seed.max(1);
+    let mut attempt = 1;
+    loop {
+        let mut tx = begin()?;
+        let err = match f(&mut tx) {
+            Ok(val) => {
+                tx.commit()?;
+                return Ok(val);
+            }
+            Err(err) => err,
+        };
+        // `f` may have finished the transaction itself
+        if !tx.finished {
+            tx.rollback()?;
+        }
+        if !err.is_retryable() || attempt >= options.max_attempts {
+            return Err(err);
+        }
+        // xorshift64
+        jitter ^= jitter << 13;
+        jitter ^= jitter >> 7;
+        jitter ^= jitter << 17;
+        let half = backoff / 2;
+        let extra = Duration::from_nanos(jitter % (half.as_nanos() as u64 + 1));
+        options.sleeper.sleep(backoff - half + extra);
+        backoff = (backoff * 2).min(options.max_backoff);
+        attempt += 1;
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::{Arc, Mutex};
-
-    use crate::{
-        buffer::BufferManager,
-        file::{BlockId, FileManager},
-        log::LogManager,
+    use std::{
+        fs,
+        panic::{self, AssertUnwindSafe},
+        sync::mpsc,
+        thread,
+        time::Duration,
     };
 
+    use super::*;
+    use crate::{clock::ManualClock, concurrency::LockTableOptions, file::FileManager};
+
+    /// what the transactions of one database share
+    struct Db {
+        fm: Arc<Mutex<dyn BlockStorage>>,
+        lm: Arc<Mutex<LogManager>>,
+        bm: Arc<BufferManager>,
+        transactions: Arc<TransactionManager>,
+        lock_table: Arc<LockTable>,
+    }
+
+    impl Db {
+        fn open(dirname: &str) -> Self {
+            let fm: Arc<Mutex<dyn BlockStorage>> =
+                Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+            let lm = Arc::new(Mutex::new(
+                LogManager::new(Arc::clone(&fm), "test_log".to_string()).unwrap(),
+            ));
+            let bm = BufferManager::new(Arc::clone(&fm), Arc::clone(&lm), 3);
+            let transactions = TransactionManager::recovered(&mut lm.lock().unwrap()).unwrap();
+            Self {
+                fm,
+                lm,
+                bm: Arc::new(bm),
+                transactions: Arc::new(transactions),
+                lock_table: Arc::new(LockTable::new()),
+            }
+        }
+
+        fn begin(&self) -> Transaction {
+            self.begin_with_mode(TxMode::ReadWrite)
+        }
+
+        fn begin_with_mode(&self, mode: TxMode) -> Transaction {
+            Transaction::new_with_mode(
+                Arc::clone(&self.fm),
+                Arc::clone(&self.lm),
+                Arc::clone(&self.bm),
+                Arc::clone(&self.transactions),
+                Arc::clone(&self.lock_table),
+                mode,
+            )
+            .unwrap()
+        }
+
+        fn begin_with_isolation(&self, isolation: IsolationLevel) -> Transaction {
+            Transaction::new_with_isolation(
+                Arc::clone(&self.fm),
+                Arc::clone(&self.lm),
+                Arc::clone(&self.bm),
+                Arc::clone(&self.transactions),
+                Arc::clone(&self.lock_table),
+                TxMode::ReadWrite,
+                isolation,
+            )
+            .unwrap()
+        }
+
+        fn recovery(&self) -> Transaction {
+            Transaction::new_recovery(
+                Arc::clone(&self.fm),
+                Arc::clone(&self.lm),
+                Arc::clone(&self.bm),
+                Arc::clone(&self.transactions),
+            )
+            .unwrap()
+        }
+
+        fn append(&self, filename: &str) -> BlockId {
+            self.fm.lock().unwrap().append(filename).unwrap()
+        }
+
+        fn available(&self) -> u64 {
+            self.bm.available()
+        }
+    }
+
+    /// reads `block` past the buffer pool, straight from the file
+    fn read_from_disk(dirname: &str, block: &BlockId) -> Page {
+        let mut fm = FileManager::new(dirname).unwrap();
+        let mut page = Page::new(fm.block_size());
+        fm.read(block, &mut page).unwrap();
+        page
+    }
+
+    #[test]
+    fn writes_over_the_page_lsn_are_rejected() {
+        let dirname = "__test_108";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        for offset in [0, 4, PAGE_DATA_OFFSET - 1] {
+            assert!(matches!(
+                tx.set_int(&b, offset, 1, true),
+                Err(TransactionError::ReservedOffset(o)) if o == offset
+            ));
+        }
+        assert!(matches!(
+            tx.set_string(&b, 0, "lsn", true),
+            Err(TransactionError::ReservedOffset(0))
+        ));
+        assert!(matches!(
+            tx.set_u64(&b, 0, u64::MAX, true),
+            Err(TransactionError::ReservedOffset(0))
+        ));
+        let res = tx.with_block(&b, |view| view.set_bytes(-4, &[1], true));
+        assert!(matches!(res, Err(TransactionError::ReservedOffset(-4))));
+
+        tx.set_int(&b, PAGE_DATA_OFFSET, 1, true).unwrap();
+        assert_eq!(tx.get_u64(&b, 0).unwrap(), 0);
+        tx.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
     fn test_transaction() {
-        let fm = Arc::new(Mutex::new(FileManager::new("test_tx").unwrap()));
-        let lm = Arc::new(Mutex::new(LogManager::new(
-            Arc::clone(&fm),
-            "test_log".to_string(),
-        )));
-        let mut bm = BufferManager::new(Arc::clone(&fm), Arc::clone(&lm), 3);
-
-        let tx1 = Transaction::new(fm, lm, bm);
-        let b = BlockId::new("t0", 0);
-        tx1.pin(b);
-        tx1.set_u64(b, 80, 1, false);
-        tx1.set_string(b, 40, "one", false);
-        tx1.commit();
-
-        let tx2 = Transaction::new(fm, lm, bm);
+        let dirname = "__test_59";
+        let db = Db::open(dirname);
+
+        let b = db.append("t0");
+        let mut tx1 = db.begin();
+        tx1.pin(&b).unwrap();
+        tx1.set_int(&b, 80, 1, false).unwrap();
+        tx1.set_string(&b, 40, "one", false).unwrap();
+        assert_eq!(tx1.get_int(&b, 80).unwrap(), 1);
+        assert_eq!(tx1.get_string(&b, 40).unwrap(), "one");
+        tx1.commit().unwrap();
+
+        let page = read_from_disk(dirname, &b);
+        assert_eq!(page.get_i32(80).unwrap(), 1);
+        assert_eq!(page.get_string(40).unwrap(), "one");
+
+        let tx2 = db.begin();
+        assert!(tx1.tx_num() < tx2.tx_num());
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn rollback_restores_the_value_on_disk() {
+        let dirname = "__test_61";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+
+        let mut tx = db.begin();
+        assert!(matches!(
+            tx.get_int(&b, 80),
+            Err(TransactionError::BlockNotPinned(block)) if block == b
+        ));
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 2, true).unwrap();
+        assert_eq!(tx.get_int(&b, 80).unwrap(), 2);
+        tx.rollback().unwrap();
+        assert_eq!(db.available(), 3);
+
+        assert_eq!(read_from_disk(dirname, &b).get_i32(80).unwrap(), 1);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn rollback_restores_ints_and_whole_strings() {
+        let dirname = "__test_62";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 7, true).unwrap();
+        tx.set_string(&b, 40, "a longer string", true).unwrap();
+        tx.commit().unwrap();
+
+        // a shorter string leaves the tail of the longer one in the page, and an empty one
+        // leaves all of it
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 1, true).unwrap();
+        tx.set_string(&b, 40, "one", true).unwrap();
+        assert_eq!(tx.get_string(&b, 40).unwrap(), "one");
+        tx.set_string(&b, 40, "", true).unwrap();
+        assert_eq!(tx.get_string(&b, 40).unwrap(), "");
+        tx.rollback().unwrap();
+
+        let page = read_from_disk(dirname, &b);
+        assert_eq!(page.get_i32(80).unwrap(), 7);
+        assert_eq!(page.get_string(40).unwrap(), "a longer string");
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn finished_transactions_release_their_buffers_and_reject_operations() {
+        let dirname = "__test_63";
+        let db = Db::open(dirname);
+        let blocks: Vec<BlockId> = (0..2).map(|_| db.append("testfile")).collect();
+        let finished = |res: Result<()>, txnum| {
+            assert!(matches!(res, Err(TransactionError::AlreadyFinished(n)) if n == txnum));
+        };
+
+        for commit in [true, false] {
+            let mut tx = db.begin();
+            for block in &blocks {
+                tx.pin(block).unwrap();
+                tx.set_int(block, 80, 1, true).unwrap();
+            }
+            tx.pin(&blocks[0]).unwrap();
+            assert_eq!(db.available(), 1);
+            if commit {
+                tx.commit().unwrap();
+            } else {
+                tx.rollback().unwrap();
+            }
+            assert_eq!(db.available(), 3);
+
+            let txnum = tx.tx_num();
+            finished(tx.commit(), txnum);
+            finished(tx.rollback(), txnum);
+            finished(tx.pin(&blocks[0]), txnum);
+            finished(tx.set_int(&blocks[0], 80, 2, true), txnum);
+            finished(tx.set_string(&blocks[0], 80, "two", true), txnum);
+            finished(tx.get_int(&blocks[0], 80).map(|_| ()), txnum);
+            finished(tx.get_string(&blocks[0], 80).map(|_| ()), txnum);
+            assert_eq!(db.available(), 3);
+        }
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn concurrent_appends_get_distinct_blocks() {
+        let dirname = "__test_64";
+        let db = Arc::new(Db::open(dirname));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    let mut tx = db.begin();
+                    let blocks: Vec<BlockId> =
+                        (0..5).map(|_| tx.append("testfile").unwrap()).collect();
+                    tx.commit().unwrap();
+                    blocks
+                })
+            })
+            .collect();
+        let mut numbers: Vec<i32> = handles
+            .into_iter()
+            .flat_map(|h| h.join().unwrap())
+            .map(|b| b.number())
+            .collect();
+        numbers.sort();
+        assert_eq!(numbers, (0..20).collect::<Vec<_>>());
+
+        let mut tx = db.begin();
+        assert_eq!(tx.size("testfile").unwrap(), 20);
+        assert_eq!(tx.block_size(), file::BLOCK_SIZE);
+        tx.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// runs `op` in a new transaction on another thread once `ready` is received, and records
+    /// in `events` when it is done
+    fn run_after(
+        db: &Arc<Db>,
+        ready: mpsc::Receiver<()>,
+        events: &Arc<Mutex<Vec<&'static str>>>,
+        op: impl FnOnce(&mut Transaction) + Send + 'static,
+    ) -> thread::JoinHandle<()> {
+        let (db, events) = (Arc::clone(db), Arc::clone(events));
+        thread::spawn(move || {
+            let mut tx = db.begin();
+            ready.recv().unwrap();
+            op(&mut tx);
+            events.lock().unwrap().push("other");
+            tx.commit().unwrap();
+        })
+    }
+
+    #[test]
+    fn a_block_that_was_read_is_not_written_until_the_reader_finishes() {
+        let dirname = "__test_65";
+        let db = Arc::new(Db::open(dirname));
+        let b = db.append("testfile");
+        let events = Arc::default();
+        let (ready_tx, ready) = mpsc::channel();
+
+        let mut reader = db.begin();
+        reader.pin(&b).unwrap();
+        assert_eq!(reader.get_int(&b, 80).unwrap(), 0);
+        let writer = run_after(&db, ready, &events, {
+            let b = b.clone();
+            move |tx| {
+                tx.pin(&b).unwrap();
+                tx.set_int(&b, 80, 1, true).unwrap();
+            }
+        });
+        ready_tx.send(()).unwrap();
+        // give the writer time to reach the lock; it must wait there however long this is
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(reader.get_int(&b, 80).unwrap(), 0);
+        events.lock().unwrap().push("reader");
+        reader.commit().unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(*events.lock().unwrap(), ["reader", "other"]);
+        assert_eq!(read_from_disk(dirname, &b).get_i32(80).unwrap(), 1);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn size_waits_for_an_uncommitted_append() {
+        let dirname = "__test_66";
+        let db = Arc::new(Db::open(dirname));
+        db.append("testfile");
+        let events = Arc::default();
+        let (ready_tx, ready) = mpsc::channel();
+
+        let mut appender = db.begin();
+        appender.append("testfile").unwrap();
+        let reader = run_after(&db, ready, &events, |tx| {
+            assert_eq!(tx.size("testfile").unwrap(), 2);
+        });
+        ready_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        events.lock().unwrap().push("appender");
+        appender.commit().unwrap();
+        reader.join().unwrap();
+
+        assert_eq!(*events.lock().unwrap(), ["appender", "other"]);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_pin_waiting_for_a_buffer_does_not_block_unpins() {
+        let dirname = "__test_109";
+        let db = Arc::new(Db::open(dirname));
+        let blocks: Vec<BlockId> = (0..4).map(|_| db.append("testfile")).collect();
+        let events = Arc::default();
+        let (ready_tx, ready) = mpsc::channel();
+
+        // a transaction is kept from the last buffer of the pool, so two fill it
+        let mut holder = db.begin();
+        for block in &blocks[..2] {
+            holder.pin(block).unwrap();
+        }
+        let mut other_holder = db.begin();
+        other_holder.pin(&blocks[2]).unwrap();
+        assert_eq!(db.available(), 0);
+        let pinner = run_after(&db, ready, &events, {
+            let b = blocks[3].clone();
+            move |tx| tx.pin(&b).unwrap()
+        });
+        ready_tx.send(()).unwrap();
+        // give the pinner time to start waiting for a buffer
+        thread::sleep(Duration::from_millis(50));
+        events.lock().unwrap().push("holder");
+        holder.unpin(&blocks[0]);
+        pinner.join().unwrap();
+        holder.commit().unwrap();
+        other_holder.commit().unwrap();
+
+        assert_eq!(*events.lock().unwrap(), ["holder", "other"]);
+        assert_eq!(db.available(), 3);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_dropped_transaction_is_rolled_back() {
+        let dirname = "__test_67";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 1, true).unwrap();
+        tx.commit().unwrap();
+
+        {
+            let mut tx = db.begin();
+            tx.pin(&b).unwrap();
+            tx.set_int(&b, 80, 2, true).unwrap();
+        }
+        assert_eq!(db.available(), 3);
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        assert_eq!(tx.get_int(&b, 80).unwrap(), 1);
+        tx.commit().unwrap();
+
+        // a forgotten transaction isn't rolled back, and keeps its pin
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 3, true).unwrap();
+        tx.forget();
+        assert_eq!(db.available(), 2);
+        let buf = db.bm.pin(b.clone()).unwrap();
+        assert_eq!(buf.contents().get_i32(80).unwrap(), 3);
+        db.bm.unpin(buf);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_transaction_is_rolled_back_when_its_thread_panics() {
+        let dirname = "__test_68";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut tx = db.begin();
+            tx.pin(&b).unwrap();
+            tx.set_int(&b, 80, 1, true).unwrap();
+            panic!("the application failed halfway");
+        }));
+        assert!(res.is_err());
+
+        // the update is undone and the lock released, so this doesn't wait
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        assert_eq!(tx.get_int(&b, 80).unwrap(), 0);
+        tx.set_int(&b, 80, 2, true).unwrap();
+        tx.commit().unwrap();
+        assert_eq!(db.available(), 3);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn failures_are_returned_as_typed_errors() {
+        let dirname = "__test_69";
+        let mut db = Db::open(dirname);
+        db.lock_table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            max_wait: Duration::from_millis(50),
+            ..Default::default()
+        }));
+        let b = db.append("testfile");
+
+        let mut writer = db.begin();
+        assert!(matches!(
+            writer.set_int(&b, 80, 1, true),
+            Err(TransactionError::BlockNotPinned(block)) if block == b
+        ));
+        writer.pin(&b).unwrap();
+        writer.set_int(&b, 80, 1, true).unwrap();
+
+        let mut reader = db.begin();
+        reader.pin(&b).unwrap();
+        assert!(matches!(
+            reader.get_int(&b, 80),
+            Err(TransactionError::ConcurrencyError(ConcurrencyError::LockAbort(block))) if block == b
+        ));
+        reader.rollback().unwrap();
+        writer.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn recover_undoes_the_updates_of_an_unfinished_transaction() {
+        let dirname = "__test_70";
+        let b = {
+            let db = Db::open(dirname);
+            let b = db.append("testfile");
+            let mut tx = db.begin();
+            tx.pin(&b).unwrap();
+            tx.set_u64(&b, 80, 1, true).unwrap();
+            tx.commit().unwrap();
+
+            // the update reaches the disk, but the transaction never finishes
+            let mut tx = db.begin();
+            tx.pin(&b).unwrap();
+            tx.set_u64(&b, 80, 2, true).unwrap();
+            db.bm.flush_all(tx.tx_num()).unwrap();
+            tx.forget();
+            b
+        };
+        assert_eq!(read_from_disk(dirname, &b).get_u64(80).unwrap(), 2);
+
+        let db = Db::open(dirname);
+        let mut tx = db.recovery();
+        let report = tx.recover().unwrap();
+        assert_eq!(report.txs_undone, 1);
+        tx.pin(&b).unwrap();
+        assert_eq!(tx.get_u64(&b, 80).unwrap(), 1);
+        assert_eq!(tx.available_buffs(), 2);
+        tx.commit().unwrap();
+        assert_eq!(read_from_disk(dirname, &b).get_u64(80).unwrap(), 1);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn the_recovery_transaction_neither_locks_nor_logs_its_updates() {
+        let dirname = "__test_80";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let mut holder = db.begin();
+        holder.pin(&b).unwrap();
+        holder.set_int(&b, 80, 1, true).unwrap();
+        let log_records = || db.lm.lock().unwrap().iterator().unwrap().count();
+
+        let mut tx = db.recovery();
+        assert_eq!(tx.mode(), TxMode::Recovery);
+        let logged = log_records();
+        tx.pin(&b).unwrap();
+        // the exclusive lock of `holder` doesn't stop it
+        assert_eq!(tx.get_int(&b, 80).unwrap(), 1);
+        tx.set_int(&b, 80, 2, true).unwrap();
+        assert_eq!(log_records(), logged);
+        assert_eq!(tx.stats().slocks + tx.stats().xlocks, 0);
+        tx.commit().unwrap();
+        holder.rollback().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn read_only_transactions_neither_log_nor_write() {
+        let dirname = "__test_71";
+        let db = Db::open(dirname);
+        let blocks: Vec<BlockId> = (0..2).map(|_| db.append("testfile")).collect();
+        let log_records = || db.lm.lock().unwrap().iterator().unwrap().count();
+        let logged = log_records();
+
+        let mut reader = db.begin_with_mode(TxMode::ReadOnly);
+        reader.pin(&blocks[0]).unwrap();
+        assert_eq!(reader.get_int(&blocks[0], 80).unwrap(), 0);
+        assert_eq!(reader.size("testfile").unwrap(), 2);
+        let txnum = reader.tx_num();
+        assert!(matches!(
+            reader.set_int(&blocks[0], 80, 1, true),
+            Err(TransactionError::ReadOnly(n)) if n == txnum
+        ));
+        assert!(matches!(
+            reader.append("testfile"),
+            Err(TransactionError::ReadOnly(_))
+        ));
+        assert_eq!(log_records(), logged);
+
+        // a writer of another block doesn't wait for the reader
+        let mut writer = db.begin();
+        writer.pin(&blocks[1]).unwrap();
+        writer.set_int(&blocks[1], 80, 1, true).unwrap();
+        writer.commit().unwrap();
+        let logged = log_records();
+
+        reader.commit().unwrap();
+        assert_eq!(log_records(), logged);
+        assert_eq!(db.available(), 3);
+
+        // a dirty reader sees an update that isn't committed, without waiting for its lock
+        let mut writer = db.begin();
+        writer.pin(&blocks[0]).unwrap();
+        writer.set_int(&blocks[0], 80, 2, true).unwrap();
+        let mut dirty = db.begin_with_mode(TxMode::ReadUncommitted);
+        dirty.pin(&blocks[0]).unwrap();
+        assert_eq!(dirty.get_int(&blocks[0], 80).unwrap(), 2);
+        dirty.rollback().unwrap();
+        writer.rollback().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn the_transaction_manager_tracks_running_transactions() {
+        let dirname = "__test_72";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let txnums = |infos: &[TxInfo]| -> Vec<(i32, bool)> {
+            infos.iter().map(|t| (t.txnum, t.read_only)).collect()
+        };
+
+        let mut first = db.begin();
+        let reader = db.begin_with_mode(TxMode::ReadOnly);
+        let mut writer = db.begin();
+        writer.pin(&b).unwrap();
+        writer.set_int(&b, 80, 1, true).unwrap();
+        first.commit().unwrap();
+        let active = db.transactions.active_transactions();
+        assert_eq!(txnums(&active), [(2, true), (3, false)]);
+        assert_eq!(db.transactions.oldest_active(), Some(active[0].clone()));
+
+        // a checkpoint lists the running transactions that logged anything, itself included
+        let rm = RecoveryManager::new(
+            db.transactions.allocate(),
+            Arc::clone(&db.lm),
+            Arc::clone(&db.bm),
+        )
+        .unwrap();
+        let mut running: Vec<i32> = active
+            .iter()
+            .filter(|t| !t.read_only)
+            .map(|t| t.txnum)
+            .collect();
+        running.push(4);
+        let lsn = rm.checkpoint_nonquiescent(&running).unwrap();
+        rm.commit().unwrap();
+        let checkpoint = rm.analyze().unwrap().checkpoint.unwrap();
+        assert_eq!((checkpoint.lsn, checkpoint.active), (lsn, vec![3, 4]));
+
+        writer.rollback().unwrap();
+        drop(reader);
+        assert!(db.transactions.active_transactions().is_empty());
+        assert_eq!(db.transactions.oldest_active(), None);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn u64s_and_bytes_round_trip_and_roll_back() {
+        let dirname = "__test_73";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let long = b"a byte slice longer than the next".to_vec();
+
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_u64(&b, 80, u64::MAX - 1, true).unwrap();
+        tx.set_bytes(&b, 100, &long, true).unwrap();
+        assert_eq!(tx.get_u64(&b, 80).unwrap(), u64::MAX - 1);
+        assert_eq!(tx.get_bytes(&b, 100).unwrap(), long);
+        tx.commit().unwrap();
+
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_u64(&b, 80, 7, true).unwrap();
+        tx.set_bytes(&b, 100, b"short", true).unwrap();
+        assert_eq!(tx.get_bytes(&b, 100).unwrap(), b"short");
+        tx.set_bytes(&b, 100, &[], true).unwrap();
+        assert!(tx.get_bytes(&b, 100).unwrap().is_empty());
+        tx.rollback().unwrap();
+
+        let page = read_from_disk(dirname, &b);
+        assert_eq!(page.get_u64(80).unwrap(), u64::MAX - 1);
+        assert_eq!(page.get_bytes(100).unwrap(), long);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_transaction_can_move_between_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Transaction>();
+
+        let dirname = "__test_74";
+        let db = Arc::new(Db::open(dirname));
+        let b = db.append("testfile");
+
+        let mut tx = db.begin();
+        tx.pin(&b).unwrap();
+        tx.set_int(&b, 80, 42, true).unwrap();
+        let block = b.clone();
+        let mut tx = thread::spawn(move || {
+            // the exclusive lock taken on the first thread is still the transaction's
+            assert_eq!(tx.get_int(&block, 80).unwrap(), 42);
+            tx.set_int(&block, 84, 43, true).unwrap();
+            tx
+        })
+        .join()
+        .unwrap();
+        tx.commit().unwrap();
+
+        let page = read_from_disk(dirname, &b);
+        assert_eq!(page.get_i32(80).unwrap(), 42);
+        assert_eq!(page.get_i32(84).unwrap(), 43);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn stats_count_pins_log_records_and_locks() {
+        let dirname = "__test_76";
+        let db = Db::open(dirname);
+        let blocks: Vec<BlockId> = (0..2).map(|_| db.append("testfile")).collect();
+        let log_records = || db.lm.lock().unwrap().iterator().unwrap().count();
+        let logged = log_records();
+
+        let mut tx = db.begin();
+        tx.pin(&blocks[0]).unwrap();
+        tx.pin(&blocks[0]).unwrap();
+        tx.pin(&blocks[1]).unwrap();
+        tx.unpin(&blocks[1]);
+        // still in the pool
+        tx.pin(&blocks[1]).unwrap();
+        tx.get_int(&blocks[0], 80).unwrap();
+        tx.set_int(&blocks[0], 80, 1, true).unwrap();
+        tx.set_string(&blocks[1], 40, "one", true).unwrap();
+        tx.get_string(&blocks[1], 40).unwrap();
+        tx.rollback().unwrap();
+
+        let stats = tx.stats();
+        assert_eq!(
+            stats,
+            TxStats {
+                blocks_pinned: 2,
+                // the rollback pins each block it undoes an update of once more
+                pins: 6,
+                buffer_hits: 4,
+                buffer_misses: 2,
+                // start, two updates, their two compensation records and the rollback
+                log_records: 6,
+                log_bytes: stats.log_bytes,
+                slocks: 1,
+                xlocks: 2,
+                lock_wait: stats.lock_wait,
+                elapsed: stats.elapsed,
+            }
+        );
+        assert_eq!(log_records(), logged + stats.log_records);
+        assert!(stats.log_bytes > 0);
+        // nothing runs on after the rollback
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(tx.stats(), stats);
+
+        let mut reader = db.begin_with_mode(TxMode::ReadOnly);
+        reader.pin(&blocks[0]).unwrap();
+        assert_eq!(reader.get_int(&blocks[0], 80).unwrap(), 0);
+        let stats = reader.stats();
+        assert_eq!(
+            (stats.log_records, stats.slocks, stats.buffer_hits),
+            (0, 1, 1)
+        );
+        reader.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// a database whose transactions may run for a minute by `clock`
+    fn open_with_max_age(dirname: &str, clock: Arc<ManualClock>, expiry: ExpiryPolicy) -> Db {
+        let mut db = Db::open(dirname);
+        let options = TransactionManagerOptions {
+            clock,
+            max_age: Some(Duration::from_secs(60)),
+            expiry,
+            ..Default::default()
+        };
+        db.transactions = Arc::new(TransactionManager::new_with_options(
+            TxNumberAllocator::new(0),
+            options,
+        ));
+        db
+    }
+
+    #[test]
+    fn an_expired_transaction_can_only_roll_back() {
+        let dirname = "__test_77";
+        let clock = Arc::new(ManualClock::new());
+        let db = open_with_max_age(dirname, clock.clone(), ExpiryPolicy::Fail);
+        let blocks: Vec<BlockId> = (0..2).map(|_| db.append("testfile")).collect();
+
+        let mut old = db.begin();
+        old.pin(&blocks[0]).unwrap();
+        old.set_int(&blocks[0], 80, 1, true).unwrap();
+        clock.advance(Duration::from_secs(61));
+        let mut young = db.begin();
+
+        let txnum = old.tx_num();
+        assert!(matches!(
+            old.get_int(&blocks[0], 80),
+            Err(TransactionError::Expired(n)) if n == txnum
+        ));
+        assert!(matches!(
+            old.pin(&blocks[1]),
+            Err(TransactionError::Expired(_))
+        ));
+        assert!(matches!(old.commit(), Err(TransactionError::Expired(_))));
+        let active = db.transactions.active_transactions();
+        assert_eq!(
+            active.iter().map(|info| info.expired).collect::<Vec<_>>(),
+            vec![true, false]
+        );
+
+        young.pin(&blocks[1]).unwrap();
+        young.set_int(&blocks[1], 80, 2, true).unwrap();
+        young.commit().unwrap();
+        old.rollback().unwrap();
+        assert!(db.transactions.active_transactions().is_empty());
+        assert_eq!(read_from_disk(dirname, &blocks[0]).get_i32(80).unwrap(), 0);
+        assert_eq!(read_from_disk(dirname, &blocks[1]).get_i32(80).unwrap(), 2);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn the_application_is_told_about_an_expired_transaction_once() {
+        let dirname = "__test_78";
+        let clock = Arc::new(ManualClock::new());
+        let told = Arc::new(Mutex::new(Vec::new()));
+        let expiry = ExpiryPolicy::Notify(Arc::new({
+            let told = Arc::clone(&told);
+            move |info: &TxInfo| told.lock().unwrap().push(info.txnum)
+        }));
+        let db = open_with_max_age(dirname, clock.clone(), expiry);
+        let b = db.append("testfile");
+
+        let mut old = db.begin();
+        clock.advance(Duration::from_secs(61));
+        let young = db.begin();
+        let expired = db.transactions.sweep();
+        assert_eq!(
+            expired.iter().map(|info| info.txnum).collect::<Vec<_>>(),
+            vec![old.tx_num()]
+        );
+        assert!(expired[0].expired);
+        assert_eq!(*told.lock().unwrap(), vec![old.tx_num()]);
+        assert!(db.transactions.sweep().is_empty());
+
+        // it is up to the application, so the transaction goes on
+        old.pin(&b).unwrap();
+        old.set_int(&b, 80, 1, true).unwrap();
+        old.commit().unwrap();
+        drop(young);
+        assert_eq!(told.lock().unwrap().len(), 1);
+        assert_eq!(read_from_disk(dirname, &b).get_i32(80).unwrap(), 1);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn a_transaction_cannot_pin_the_whole_pool() {
+        let dirname = "__test_79";
+        let db = Db::open(dirname);
+        let blocks: Vec<BlockId> = (0..3).map(|_| db.append("testfile")).collect();
+        let mut tx = db.begin();
+        assert_eq!(tx.available_buffs(), 3);
+
+        tx.pin(&blocks[0]).unwrap();
+        tx.pin(&blocks[1]).unwrap();
+        assert!(matches!(
+            tx.pin(&blocks[2]),
+            Err(TransactionError::TooManyPins(b)) if b == blocks[2]
+        ));
+        assert_eq!(tx.available_buffs(), 1);
+        // blocks it holds already don't take another buffer
+        tx.pin(&blocks[1]).unwrap();
+        // and the buffer left is there for others
+        let mut other = db.begin();
+        other.pin(&blocks[2]).unwrap();
+        assert_eq!(tx.available_buffs(), 0);
+        other.commit().unwrap();
+
+        tx.unpin(&blocks[0]);
+        tx.pin(&blocks[2]).unwrap();
+        tx.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn transaction_numbers_are_per_database_and_survive_a_restart() {
+        let (a, b) = ("__test_57", "__test_58");
+        let start = |db: &Db| db.begin().tx_num();
+        {
+            let db_a = Db::open(a);
+            let db_b = Db::open(b);
+            assert_eq!(start(&db_a), 1);
+            assert_eq!(start(&db_a), 2);
+            assert_eq!(start(&db_b), 1);
+            assert_eq!(start(&db_a), 3);
+            db_a.lm.lock().unwrap().flush().unwrap();
+        }
+
+        // the start records are in the log, so the numbers carry on where they stopped
+        assert_eq!(start(&Db::open(a)), 4);
+
+        fs::remove_dir_all(a).expect("failed to remove dir");
+        fs::remove_dir_all(b).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn buffers_stay_pinned_until_every_pin_is_released() {
+        let dirname = "__test_60";
+        let db = Db::open(dirname);
+        let blocks: Vec<BlockId> = (0..3).map(|_| db.append("testfile")).collect();
+        let mut tx = db.begin();
+
+        tx.pin(&blocks[0]).unwrap();
+        tx.pin(&blocks[0]).unwrap();
+        assert_eq!(db.available(), 2);
+        tx.unpin(&blocks[0]);
+        assert!(tx.buffers.get_buffer(&blocks[0]).is_some());
+        assert_eq!(db.available(), 2);
+        tx.unpin(&blocks[0]);
+        assert!(tx.buffers.get_buffer(&blocks[0]).is_none());
+        assert_eq!(db.available(), 3);
+
+        // one buffer of the pool is kept from the transaction
+        for block in &blocks[..2] {
+            tx.pin(block).unwrap();
+        }
+        tx.pin(&blocks[1]).unwrap();
+        assert_eq!(db.available(), 1);
+        tx.buffers.unpin_all();
+        assert_eq!(db.available(), 3);
+        assert!(blocks.iter().all(|b| tx.buffers.get_buffer(b).is_none()));
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// a database whose lock requests give up at once, so that a request that would wait fails
+    fn open_impatient(dirname: &str) -> Db {
+        let mut db = Db::open(dirname);
+        db.lock_table = Arc::new(LockTable::new_with_options(LockTableOptions {
+            max_wait: Duration::from_millis(20),
+            ..Default::default()
+        }));
+        db
+    }
+
+    fn is_lock_abort<T>(res: Result<T>) -> bool {
+        matches!(
+            res,
+            Err(TransactionError::ConcurrencyError(
+                ConcurrencyError::LockAbort(_)
+            ))
+        )
+    }
+
+    #[test]
+    fn only_read_uncommitted_transactions_see_uncommitted_updates() {
+        let dirname = "__test_82";
+        let db = open_impatient(dirname);
+        let b = db.append("testfile");
+        let mut writer = db.begin();
+        writer.pin(&b).unwrap();
+        writer.set_int(&b, 80, 1, true).unwrap();
+
+        for isolation in [
+            IsolationLevel::Serializable,
+            IsolationLevel::RepeatableRead,
+            IsolationLevel::ReadCommitted,
+        ] {
+            let mut reader = db.begin_with_isolation(isolation);
+            reader.pin(&b).unwrap();
+            assert!(is_lock_abort(reader.get_int(&b, 80)), "{:?}", isolation);
+            reader.rollback().unwrap();
+        }
+        let mut dirty = db.begin_with_isolation(IsolationLevel::ReadUncommitted);
+        assert_eq!(dirty.isolation(), IsolationLevel::ReadUncommitted);
+        dirty.pin(&b).unwrap();
+        assert_eq!(dirty.get_int(&b, 80).unwrap(), 1);
+        dirty.rollback().unwrap();
+        writer.rollback().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn read_committed_transactions_may_read_a_block_twice_and_see_two_values() {
+        let dirname = "__test_83";
+        let db = open_impatient(dirname);
+        let b = db.append("testfile");
+        let update = |val: i32| {
+            let mut writer = db.begin();
+            writer.pin(&b).unwrap();
+            let res = writer.set_int(&b, 80, val, true);
+            match res {
+                Ok(()) => writer.commit().unwrap(),
+                Err(_) => writer.rollback().unwrap(),
+            }
+            res
+        };
+
+        let mut reader = db.begin_with_isolation(IsolationLevel::ReadCommitted);
+        reader.pin(&b).unwrap();
+        assert_eq!(reader.get_int(&b, 80).unwrap(), 0);
+        // the shared lock, and the intention lock on the file, went with the read
+        assert_eq!(db.lock_table.entries(), 0);
+        update(1).unwrap();
+        assert_eq!(reader.get_int(&b, 80).unwrap(), 1);
+        reader.commit().unwrap();
+
+        let mut reader = db.begin_with_isolation(IsolationLevel::RepeatableRead);
+        reader.pin(&b).unwrap();
+        assert_eq!(reader.get_int(&b, 80).unwrap(), 1);
+        assert!(is_lock_abort(update(2)));
+        assert_eq!(reader.get_int(&b, 80).unwrap(), 1);
+        reader.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn only_serializable_transactions_keep_a_file_from_growing() {
+        let dirname = "__test_84";
+        let db = open_impatient(dirname);
+        db.append("testfile");
+        let append = || {
+            let mut appender = db.begin();
+            let res = appender.append("testfile");
+            match res {
+                Ok(_) => appender.commit().unwrap(),
+                Err(_) => appender.rollback().unwrap(),
+            }
+            res
+        };
+
+        let mut reader = db.begin_with_isolation(IsolationLevel::Serializable);
+        assert_eq!(reader.size("testfile").unwrap(), 1);
+        assert!(is_lock_abort(append()));
+        assert_eq!(reader.size("testfile").unwrap(), 1);
+        reader.commit().unwrap();
+
+        // a phantom block shows up under a repeatable read
+        let mut reader = db.begin_with_isolation(IsolationLevel::RepeatableRead);
+        assert_eq!(reader.size("testfile").unwrap(), 1);
+        append().unwrap();
+        assert_eq!(reader.size("testfile").unwrap(), 2);
+        reader.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn with_block_unpins_its_block_when_the_closure_fails_or_panics() {
+        let dirname = "__test_85";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let mut tx = db.begin();
+
+        let val = tx
+            .with_block(&b, |view| {
+                view.set_int(80, 1, true)?;
+                view.set_string(84, "one", true)?;
+                view.get_int(80)
+            })
+            .unwrap();
+        assert_eq!(val, 1);
+        assert_eq!(db.available(), 3);
+
+        let res: Result<()> = tx.with_block(&b, |view| {
+            view.set_int(80, 2, true)?;
+            view.get_string(db.fm.lock().unwrap().block_size())?;
+            Ok(())
+        });
+        assert!(matches!(res, Err(TransactionError::FileError(_))));
+        assert_eq!(db.available(), 3);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            tx.with_block(&b, |view| -> Result<()> {
+                view.set_int(80, 3, true)?;
+                panic!("the closure panicked");
+            })
+        }));
+        assert!(res.is_err());
+        assert_eq!(db.available(), 3);
+
+        // the updates went through the log like any other
+        tx.rollback().unwrap();
+        let mut tx = db.begin();
+        let val = tx.with_block(&b, |view| view.get_int(80)).unwrap();
+        assert_eq!(val, 0);
+        tx.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    /// options that sleep on `clock`, from a first backoff of 10ms
+    fn retry_options(clock: &Arc<ManualClock>, max_attempts: u32) -> RetryOptions {
+        RetryOptions {
+            max_attempts,
+            initial_backoff: Duration::from_millis(10),
+            sleeper: clock.clone(),
+            seed: 42,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn run_with_retries_retries_until_the_lock_is_free() {
+        let dirname = "__test_86";
+        let db = open_impatient(dirname);
+        let b = db.append("testfile");
+        let mut holder = Some(db.begin());
+        holder.as_mut().unwrap().pin(&b).unwrap();
+        holder.as_mut().unwrap().set_int(&b, 80, 1, true).unwrap();
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+
+        let mut attempts = 0;
+        let val = run_with_retries(
+            || Ok(db.begin()),
+            retry_options(&clock, 5),
+            |tx| {
+                attempts += 1;
+                if attempts == 3 {
+                    holder.take().unwrap().rollback()?;
+                }
+                tx.with_block(&b, |view| {
+                    let val = view.get_int(80)? + 2;
+                    view.set_int(80, val, true)?;
+                    Ok(val)
+                })
+            },
+        )
+        .unwrap();
+        assert_eq!((val, attempts), (2, 3));
+        // backoffs of 5 to 10ms, then of 10 to 20ms
+        let slept = clock.now().duration_since(start);
+        assert!(
+            (Duration::from_millis(15)..=Duration::from_millis(30)).contains(&slept),
+            "{:?}",
+            slept
+        );
+        assert_eq!(db.lock_table.entries(), 0);
+        assert_eq!(db.available(), 3);
+
+        // the attempts that failed were rolled back, and the last one committed
+        let mut tx = db.begin();
+        assert_eq!(tx.with_block(&b, |view| view.get_int(80)).unwrap(), 2);
+        tx.commit().unwrap();
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn run_with_retries_gives_up_after_max_attempts() {
+        let dirname = "__test_87";
+        let db = open_impatient(dirname);
+        let b = db.append("testfile");
+        let mut holder = db.begin();
+        holder.pin(&b).unwrap();
+        holder.set_int(&b, 80, 1, true).unwrap();
+        let clock = Arc::new(ManualClock::new());
+
+        let mut attempts = 0;
+        let res = run_with_retries(
+            || Ok(db.begin()),
+            retry_options(&clock, 3),
+            |tx| {
+                attempts += 1;
+                tx.with_block(&b, |view| view.set_int(80, 2, true))
+            },
+        );
+        assert!(is_lock_abort(res));
+        assert_eq!(attempts, 3);
+        holder.rollback().unwrap();
+        assert_eq!(db.lock_table.entries(), 0);
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
+    }
+
+    #[test]
+    fn run_with_retries_passes_other_errors_through() {
+        let dirname = "__test_88";
+        let db = Db::open(dirname);
+        let b = db.append("testfile");
+        let clock = Arc::new(ManualClock::new());
+        let start = clock.now();
+
+        let mut attempts = 0;
+        let res = run_with_retries(
+            || Ok(db.begin()),
+            retry_options(&clock, 5),
+            |tx| {
+                attempts += 1;
+                tx.set_int(&b, 80, 1, true)
+            },
+        );
+        assert!(matches!(res, Err(TransactionError::BlockNotPinned(_))));
+        assert_eq!(attempts, 1);
+        assert_eq!(clock.now(), start);
+        // the failed transaction rolled back and left nothing running
+        assert!(db.transactions.active_transactions().is_empty());
+
+        fs::remove_dir_all(dirname).expect("failed to remove dir");
     }
 }
@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex};
+
+use rsdb::{
+    buffer::BufferManager,
+    concurrency::{LockTable, LockTableOptions},
+    file::{BlockStorage, FileManager, MemoryStorage, BLOCK_SIZE},
+    log::LogManager,
+    tx::{Transaction, TransactionManager},
+};
+
+/// what the transactions of one database share
+pub struct Db {
+    pub fm: Arc<Mutex<dyn BlockStorage>>,
+    pub lm: Arc<Mutex<LogManager>>,
+    pub bm: Arc<BufferManager>,
+    pub transactions: Arc<TransactionManager>,
+    pub lock_table: Arc<LockTable>,
+}
+
+impl Db {
+    pub fn open(dirname: &str, lock_options: LockTableOptions) -> Self {
+        let fm = Arc::new(Mutex::new(FileManager::new(dirname).unwrap()));
+        Self::open_storage(fm, lock_options)
+    }
+
+    /// new storage kept in memory. opening `open_storage` on it again is a restart
+    pub fn memory() -> Arc<Mutex<dyn BlockStorage>> {
+        Arc::new(Mutex::new(MemoryStorage::new(BLOCK_SIZE)))
+    }
+
+    pub fn open_storage(fm: Arc<Mutex<dyn BlockStorage>>, lock_options: LockTableOptions) -> Self {
+        let lm = Arc::new(Mutex::new(
+            LogManager::new(Arc::clone(&fm), "test_log".to_string()).unwrap(),
+        ));
+        let bm = BufferManager::new(Arc::clone(&fm), Arc::clone(&lm), 8);
+        let transactions = TransactionManager::recovered(&mut lm.lock().unwrap()).unwrap();
+        Self {
+            fm,
+            lm,
+            bm: Arc::new(bm),
+            transactions: Arc::new(transactions),
+            lock_table: Arc::new(LockTable::new_with_options(lock_options)),
+        }
+    }
+
+    pub fn begin(&self) -> Transaction {
+        Transaction::new(
+            Arc::clone(&self.fm),
+            Arc::clone(&self.lm),
+            Arc::clone(&self.bm),
+            Arc::clone(&self.transactions),
+            Arc::clone(&self.lock_table),
+        )
+        .unwrap()
+    }
+
+    /// the transaction that recovers the database, as at startup
+    pub fn recovery(&self) -> Transaction {
+        Transaction::new_recovery(
+            Arc::clone(&self.fm),
+            Arc::clone(&self.lm),
+            Arc::clone(&self.bm),
+            Arc::clone(&self.transactions),
+        )
+        .unwrap()
+    }
+}
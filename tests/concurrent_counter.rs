@@ -0,0 +1,96 @@
+mod common;
+
+use std::{fs, sync::Arc, thread, time::Duration};
+
+use common::Db;
+use rsdb::{
+    buffer::PAGE_DATA_OFFSET,
+    concurrency::LockTableOptions,
+    tx::{self, RetryOptions},
+};
+
+const THREADS: i32 = 8;
+const INCREMENTS: i32 = 50;
+
+/// the counter sits after the page lsn, at the start of the page's data
+const COUNTER: i32 = PAGE_DATA_OFFSET;
+
+/// two transactions that read the counter and then both want to write it deadlock, so give up
+/// on a lock quickly and let the loser retry
+fn lock_options() -> LockTableOptions {
+    LockTableOptions {
+        max_wait: Duration::from_millis(20),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn concurrent_increments_all_land() {
+    let dirname = "__test_75";
+    increment_concurrently(|| Db::open(dirname, lock_options()));
+    fs::remove_dir_all(dirname).expect("failed to remove dir");
+}
+
+#[test]
+fn concurrent_increments_all_land_in_memory() {
+    let memory = Db::memory();
+    increment_concurrently(|| Db::open_storage(memory.clone(), lock_options()));
+}
+
+/// `open` opens the same database each time, as a restart would
+fn increment_concurrently(open: impl Fn() -> Db) {
+    let db = Arc::new(open());
+    let mut tx = db.begin();
+    let block = tx.append("counter").unwrap();
+    tx.with_block(&block, |view| view.set_int(COUNTER, 0, false))
+        .unwrap();
+    tx.commit().unwrap();
+
+    let handles: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let (db, block) = (Arc::clone(&db), block.clone());
+            thread::spawn(move || {
+                for _ in 0..INCREMENTS {
+                    let options = RetryOptions {
+                        max_attempts: 1000,
+                        max_backoff: Duration::from_millis(20),
+                        // threads that start together would otherwise share a seed
+                        seed: i as u64 + 1,
+                        ..Default::default()
+                    };
+                    tx::run_with_retries(
+                        || Ok(db.begin()),
+                        options,
+                        |tx| {
+                            tx.with_block(&block, |view| {
+                                let val = view.get_int(COUNTER)?;
+                                view.set_int(COUNTER, val + 1, true)
+                            })
+                        },
+                    )
+                    .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let mut tx = db.begin();
+    let counter = tx.with_block(&block, |view| view.get_int(COUNTER)).unwrap();
+    assert_eq!(counter, THREADS * INCREMENTS);
+    tx.commit().unwrap();
+    drop(db);
+
+    // every increment committed or rolled back, so a restart has nothing to undo or redo
+    let db = open();
+    let mut tx = db.recovery();
+    let report = tx.recover().unwrap();
+    assert_eq!(report.undo_actions, 0);
+    assert_eq!(report.redo_actions, 0);
+    assert_eq!(report.txs_undone, 0);
+    let counter = tx.with_block(&block, |view| view.get_int(COUNTER)).unwrap();
+    assert_eq!(counter, THREADS * INCREMENTS);
+    tx.commit().unwrap();
+}
@@ -0,0 +1,72 @@
+mod common;
+
+use std::fs;
+
+use common::Db;
+use rsdb::{buffer::PAGE_DATA_OFFSET, concurrency::LockTableOptions};
+
+/// the value sits after the page lsn, at the start of the page's data
+const VALUE: i32 = PAGE_DATA_OFFSET;
+
+#[test]
+fn recovery_undoes_a_transaction_cut_short_by_a_crash() {
+    let dirname = "__test_81";
+    recover_from_a_crash(|| Db::open(dirname, LockTableOptions::default()));
+    fs::remove_dir_all(dirname).expect("failed to remove dir");
+}
+
+#[test]
+fn recovery_undoes_a_transaction_cut_short_by_a_crash_in_memory() {
+    let memory = Db::memory();
+    recover_from_a_crash(|| Db::open_storage(memory.clone(), LockTableOptions::default()));
+}
+
+/// `open` opens the same database each time, as a restart would
+fn recover_from_a_crash(open: impl Fn() -> Db) {
+    let block = {
+        let db = open();
+        let mut tx = db.begin();
+        let block = tx.append("data").unwrap();
+        tx.with_block(&block, |view| {
+            view.set_int(VALUE, 1, true)?;
+            view.set_string(VALUE + 4, "committed", true)
+        })
+        .unwrap();
+        tx.commit().unwrap();
+
+        // the updates and the new block reach the disk, but the commit never does
+        let mut tx = db.begin();
+        tx.with_block(&block, |view| {
+            view.set_int(VALUE, 2, true)?;
+            view.set_string(VALUE + 4, "lost", true)
+        })
+        .unwrap();
+        tx.append("data").unwrap();
+        db.bm.flush_all(tx.tx_num()).unwrap();
+        tx.forget();
+        block
+    };
+
+    let db = open();
+    let mut tx = db.recovery();
+    let report = tx.recover().unwrap();
+    assert_eq!(report.txs_undone, 1);
+    // the two updates and the append
+    assert_eq!(report.undo_actions, 3);
+    let (val, string) = tx
+        .with_block(&block, |view| {
+            Ok((view.get_int(VALUE)?, view.get_string(VALUE + 4)?))
+        })
+        .unwrap();
+    assert_eq!((val, string.as_str()), (1, "committed"));
+    assert_eq!(tx.size("data").unwrap(), 1);
+    tx.commit().unwrap();
+    drop(db);
+
+    // recovering again finds nothing left to do
+    let db = open();
+    let mut tx = db.recovery();
+    let report = tx.recover().unwrap();
+    assert_eq!((report.txs_undone, report.undo_actions), (0, 0));
+    tx.commit().unwrap();
+}
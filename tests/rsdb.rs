@@ -0,0 +1,131 @@
+use std::fs;
+
+use rsdb::{
+    buffer::PAGE_DATA_OFFSET,
+    file::{BlockId, FileManager, Page},
+    log::LOG_HEADER_SIZE,
+    tx::TransactionError,
+    Error, RSDBConfig, RSDB,
+};
+
+#[test]
+fn committed_values_outlive_the_database_handle() {
+    let dirname = "__test_90";
+    let block = {
+        let db = RSDB::new(dirname, 4096, 8).unwrap();
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("testfile").unwrap();
+        tx.pin(&block).unwrap();
+        tx.set_int(&block, PAGE_DATA_OFFSET, 42, true).unwrap();
+        tx.set_string(&block, PAGE_DATA_OFFSET + 4, "forty-two", true)
+            .unwrap();
+        tx.commit().unwrap();
+        block
+    };
+
+    let db = RSDB::new(dirname, 4096, 8).unwrap();
+    let mut tx = db.new_read_only_tx().unwrap();
+    tx.pin(&block).unwrap();
+    assert_eq!(tx.get_int(&block, PAGE_DATA_OFFSET).unwrap(), 42);
+    assert_eq!(
+        tx.get_string(&block, PAGE_DATA_OFFSET + 4).unwrap(),
+        "forty-two"
+    );
+    assert_eq!(tx.size("testfile").unwrap(), 1);
+    tx.commit().unwrap();
+    assert_eq!(db.available(), 8);
+
+    fs::remove_dir_all(dirname).expect("failed to remove dir");
+}
+
+#[test]
+fn an_in_memory_database_runs_transactions() {
+    let db = RSDB::new_in_memory(4096, 8).unwrap();
+    let mut tx = db.new_tx().unwrap();
+    let block = tx.append("testfile").unwrap();
+    tx.with_block(&block, |view| view.set_int(PAGE_DATA_OFFSET, 42, true))
+        .unwrap();
+    tx.commit().unwrap();
+
+    let mut tx = db.new_tx().unwrap();
+    tx.with_block(&block, |view| view.set_int(PAGE_DATA_OFFSET, 43, true))
+        .unwrap();
+    tx.append("testfile").unwrap();
+    tx.rollback().unwrap();
+
+    let mut tx = db.new_read_only_tx().unwrap();
+    let val = tx
+        .with_block(&block, |view| view.get_int(PAGE_DATA_OFFSET))
+        .unwrap();
+    assert_eq!(val, 42);
+    assert_eq!(tx.size("testfile").unwrap(), 1);
+    tx.commit().unwrap();
+    assert_eq!(db.available(), 8);
+    db.close().unwrap();
+}
+
+#[test]
+fn a_torn_log_fails_to_open_instead_of_panicking() {
+    let dirname = "__test_105";
+    let db = RSDBConfig::new(dirname).log_file("wal").open().unwrap();
+    let mut tx = db.new_tx().unwrap();
+    let block = tx.append("testfile").unwrap();
+    tx.with_block(&block, |view| view.set_int(PAGE_DATA_OFFSET, 42, true))
+        .unwrap();
+    tx.commit().unwrap();
+    db.close().unwrap();
+
+    // a crash cut the newest record short, its length runs past the end of the block
+    let mut fm = FileManager::new(dirname).unwrap();
+    let last = BlockId::new("wal", fm.length("wal").unwrap() - 1);
+    let mut page = Page::new(fm.block_size());
+    fm.read(&last, &mut page).unwrap();
+    let newest = page.get_i32(0).unwrap();
+    assert!(newest > LOG_HEADER_SIZE as i32);
+    page.set_i32(newest as u64, fm.block_size()).unwrap();
+    fm.write(&last, &page).unwrap();
+
+    let opened = RSDBConfig::new(dirname).log_file("wal").open();
+    assert!(matches!(opened, Err(Error::FileError(_))));
+
+    fs::remove_dir_all(dirname).expect("failed to remove dir");
+}
+
+#[test]
+fn a_torn_older_log_block_fails_to_open_instead_of_panicking() {
+    let dirname = "__test_110";
+    {
+        let db = RSDBConfig::new(dirname).log_file("wal").open().unwrap();
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("testfile").unwrap();
+        tx.with_block(&block, |view| view.set_int(PAGE_DATA_OFFSET, 1, true))
+            .unwrap();
+        // left running, so recovery reads the log back to its start record
+        tx.forget();
+        let mut tx = db.new_tx().unwrap();
+        let block = tx.append("otherfile").unwrap();
+        tx.with_block(&block, |view| {
+            (0..200).try_for_each(|i| view.set_int(PAGE_DATA_OFFSET, i, true))
+        })
+        .unwrap();
+        tx.commit().unwrap();
+    }
+
+    // the first block of the log is torn, the last one is intact
+    let mut fm = FileManager::new(dirname).unwrap();
+    assert!(fm.length("wal").unwrap() > 1);
+    let first = BlockId::new("wal", 0);
+    let mut page = Page::new(fm.block_size());
+    fm.read(&first, &mut page).unwrap();
+    let newest = page.get_i32(0).unwrap();
+    page.set_i32(newest as u64, fm.block_size()).unwrap();
+    fm.write(&first, &page).unwrap();
+
+    let opened = RSDBConfig::new(dirname).log_file("wal").open();
+    assert!(matches!(
+        opened,
+        Err(Error::TransactionError(TransactionError::FileError(_)))
+    ));
+
+    fs::remove_dir_all(dirname).expect("failed to remove dir");
+}
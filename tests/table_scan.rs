@@ -0,0 +1,104 @@
+use std::fs;
+
+use rsdb::{
+    schema::{Layout, Schema},
+    table::{table_file, TableScan},
+    tx::Transaction,
+    RSDB,
+};
+
+const RECORDS: i32 = 1000;
+
+fn people() -> Layout {
+    let mut schema = Schema::new();
+    schema.add_int_field("id");
+    schema.add_string_field("name", 10);
+    Layout::new(schema)
+}
+
+/// the number of records in `people` and the sum of their ids
+fn count_and_sum(tx: &mut Transaction, layout: &Layout) -> (i32, i32) {
+    let mut scan = TableScan::new(tx, "people", layout).unwrap();
+    let (mut count, mut sum) = (0, 0);
+    while scan.next().unwrap() {
+        let id = scan.get_int("id").unwrap();
+        assert_eq!(scan.get_string("name").unwrap(), format!("person{}", id));
+        count += 1;
+        sum += id;
+    }
+    scan.close();
+    (count, sum)
+}
+
+#[test]
+fn records_span_blocks_and_outlive_the_database_handle() {
+    let dirname = "__test_100";
+    let layout = people();
+    let odd_sum = (0..RECORDS).filter(|id| id % 2 == 1).sum();
+    {
+        let db = RSDB::new(dirname, 4096, 8).unwrap();
+        let mut tx = db.new_tx().unwrap();
+        let mut scan = TableScan::new(&mut tx, "people", &layout).unwrap();
+        for id in 0..RECORDS {
+            scan.insert().unwrap();
+            scan.set_int("id", id).unwrap();
+            scan.set_string("name", &format!("person{}", id)).unwrap();
+        }
+        let last = scan.rid().unwrap();
+        scan.close();
+        assert!(tx.size(&table_file("people")).unwrap() > 1);
+        assert_eq!(
+            count_and_sum(&mut tx, &layout),
+            (RECORDS, (0..RECORDS).sum())
+        );
+
+        let mut scan = TableScan::new(&mut tx, "people", &layout).unwrap();
+        scan.move_to_rid(last).unwrap();
+        assert_eq!(scan.get_int("id").unwrap(), RECORDS - 1);
+        scan.before_first().unwrap();
+        while scan.next().unwrap() {
+            if scan.get_int("id").unwrap() % 2 == 0 {
+                scan.delete().unwrap();
+            }
+        }
+        assert!(scan.get_int("id").is_err());
+        scan.close();
+        assert_eq!(count_and_sum(&mut tx, &layout), (RECORDS / 2, odd_sum));
+        tx.commit().unwrap();
+        assert_eq!(db.available(), 8);
+        db.close().unwrap();
+    }
+
+    let db = RSDB::new(dirname, 4096, 8).unwrap();
+    let mut tx = db.new_read_only_tx().unwrap();
+    assert_eq!(count_and_sum(&mut tx, &layout), (RECORDS / 2, odd_sum));
+    tx.commit().unwrap();
+    db.close().unwrap();
+
+    fs::remove_dir_all(dirname).expect("failed to remove dir");
+}
+
+#[test]
+fn a_rolled_back_insert_truncates_the_file() {
+    let db = RSDB::new_in_memory(4096, 8).unwrap();
+    let layout = people();
+    let mut tx = db.new_tx().unwrap();
+    let before = tx.size(&table_file("people")).unwrap();
+    let mut scan = TableScan::new(&mut tx, "people", &layout).unwrap();
+    for id in 0..300 {
+        scan.insert().unwrap();
+        scan.set_int("id", id).unwrap();
+        scan.set_string("name", &format!("person{}", id)).unwrap();
+    }
+    scan.close();
+    assert!(tx.size(&table_file("people")).unwrap() > before + 1);
+    tx.rollback().unwrap();
+
+    // the appends are logged too, so the rollback truncates the file back to its old size
+    let mut tx = db.new_read_only_tx().unwrap();
+    assert_eq!(tx.size(&table_file("people")).unwrap(), before);
+    assert_eq!(count_and_sum(&mut tx, &layout), (0, 0));
+    tx.commit().unwrap();
+    assert_eq!(db.available(), 8);
+    db.close().unwrap();
+}